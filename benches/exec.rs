@@ -0,0 +1,52 @@
+// 对比解释执行(Engine::exec_fn)和编译执行(Program::exec)：cargo bench
+
+#[macro_use]
+extern crate criterion;
+extern crate logic;
+
+use criterion::Criterion;
+use logic::{Engine, Context, VarBindingList};
+
+// 循环多、语句简单的函数，主要开销在语句调度和变量求值
+const SCRIPT: &str = r#"
+fn bench(n: i32) {
+    total := 0
+    loop 100 {
+        x = n
+        loop 10 {
+            x += 1
+            y = x * 2
+            total := total + y
+        }
+        call add(v: x)
+    }
+    return total
+}
+
+fn add(v: i32) {
+    sum := sum + v
+}
+"#;
+
+fn exec(c: &mut Criterion) {
+    let mut engine = Engine::new();
+    engine.load_script(SCRIPT, &mut Context::new()).expect("valid script");
+    let program = engine.compile("bench").expect("compiled");
+    let mut args = VarBindingList::new();
+    args.set_binding("n", "int:1");
+    let mut context = Context::new();
+
+    let mut group = c.benchmark_group("loop");
+    group.bench_function("interpret", |b| b.iter(|| {
+        context.globals.set_binding("sum", "int:0");
+        engine.exec_fn("bench", &args, &mut context).expect("ok");
+    }));
+    group.bench_function("compiled", |b| b.iter(|| {
+        context.globals.set_binding("sum", "int:0");
+        program.exec(&args, &mut context).expect("ok");
+    }));
+    group.finish();
+}
+
+criterion_group!(benches, exec);
+criterion_main!(benches);
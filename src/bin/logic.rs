@@ -0,0 +1,11 @@
+// 命令行工具：logic run/validate/list，用法见logic --help
+
+extern crate logic;
+
+use std::io;
+use std::process;
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    process::exit(logic::run_cli(&args, &mut io::stdout(), &mut io::stderr()));
+}
@@ -0,0 +1,215 @@
+use std::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::io::Write;
+use utils::{to_hex, split_lr};
+
+/// 经典CAN数据帧的最大数据长度
+pub const CAN_MAX_LEN: usize = 8;
+
+/// CAN数据帧
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub id: u32,
+    /// 是否为扩展帧（29位ID）
+    pub extended: bool,
+    pub data: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(id: u32, data: &[u8]) -> Frame {
+        Frame {
+            id,
+            extended: false,
+            data: data.to_vec(),
+        }
+    }
+}
+
+/// CAN总线，负责收发数据帧
+/// 引擎执行指令产生的数据帧通过Context.bus发出
+pub trait Bus {
+    fn send(&mut self, frame: &Frame) -> Result<(), String>;
+    /// 接收一帧；timeout内没有收到时返回Ok(None)
+    fn recv(&mut self, timeout: Duration) -> Result<Option<Frame>, String>;
+}
+
+/// 内存中的回环总线，成对使用：一端发送的帧由另一端接收
+/// 两端可以在不同线程中使用，便于在测试中模拟总线上的其他节点
+pub struct LoopbackBus {
+    tx: Sender<Frame>,
+    rx: Receiver<Frame>,
+}
+
+impl LoopbackBus {
+    pub fn pair() -> (LoopbackBus, LoopbackBus) {
+        let (tx1, rx1) = channel();
+        let (tx2, rx2) = channel();
+        (LoopbackBus { tx: tx1, rx: rx2 }, LoopbackBus { tx: tx2, rx: rx1 })
+    }
+}
+
+impl Bus for LoopbackBus {
+    fn send(&mut self, frame: &Frame) -> Result<(), String> {
+        self.tx.send(frame.clone()).map_err(|_| "Loopback peer disconnected".to_string())
+    }
+
+    fn recv(&mut self, timeout: Duration) -> Result<Option<Frame>, String> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(frame) => Ok(Some(frame)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err("Loopback peer disconnected".to_string()),
+        }
+    }
+}
+
+/// DumpBus输出的格式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DumpFormat {
+    /// candump默认的格式，如"  can0  321   [2]  01 02"
+    Text,
+    /// candump -l的日志格式，如"(1697040000.123456) can0 321#0102"
+    Log,
+}
+
+/// 将发送的数据帧以candump的格式写出（不连接实际的总线）；接收时总是立即返回Ok(None)
+pub struct DumpBus {
+    writer: Box<dyn Write>,
+    format: DumpFormat,
+    /// 输出中的接口名称，默认为"can0"
+    pub interface: String,
+}
+
+impl DumpBus {
+    pub fn new(writer: Box<dyn Write>, format: DumpFormat) -> DumpBus {
+        DumpBus {
+            writer,
+            format,
+            interface: "can0".to_string(),
+        }
+    }
+}
+
+impl Bus for DumpBus {
+    fn send(&mut self, frame: &Frame) -> Result<(), String> {
+        let line = match self.format {
+            DumpFormat::Text => candump_text(frame, &self.interface),
+            DumpFormat::Log => {
+                let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+                candump_log(frame, &self.interface, time)
+            }
+        };
+        writeln!(self.writer, "{}", line).and_then(|_| self.writer.flush()).map_err(|err| err.to_string())
+    }
+
+    fn recv(&mut self, timeout: Duration) -> Result<Option<Frame>, String> {
+        Ok(None)
+    }
+}
+
+/// 在发送前将每一帧交给回调函数（用于显示或记录），再转发给实际的总线（如果有）
+pub struct TapBus {
+    inner: Option<Box<dyn Bus>>,
+    tap: Box<dyn FnMut(&Frame)>,
+}
+
+impl TapBus {
+    pub fn new(inner: Option<Box<dyn Bus>>, tap: Box<dyn FnMut(&Frame)>) -> TapBus {
+        TapBus { inner, tap }
+    }
+}
+
+impl Bus for TapBus {
+    fn send(&mut self, frame: &Frame) -> Result<(), String> {
+        (self.tap)(frame);
+        match self.inner {
+            Some(ref mut bus) => bus.send(frame),
+            None => Ok(()),
+        }
+    }
+
+    fn recv(&mut self, timeout: Duration) -> Result<Option<Frame>, String> {
+        match self.inner {
+            Some(ref mut bus) => bus.recv(timeout),
+            None => Ok(None),
+        }
+    }
+}
+
+// 标准帧3位，扩展帧8位十六进制
+fn candump_id(frame: &Frame) -> String {
+    if frame.extended { format!("{:08X}", frame.id) } else { format!("{:03X}", frame.id) }
+}
+
+/// candump默认格式的一行，如"  can0  321   [2]  01 02"
+pub fn candump_text(frame: &Frame, interface: &str) -> String {
+    let id = candump_id(frame);
+    format!("  {}  {}   [{}]  {}", interface, id, frame.data.len(), to_hex(&frame.data)).trim_end().to_string()
+}
+
+/// candump日志格式的一行，time为Unix时间，如"(1697040000.123456) can0 321#0102"
+pub fn candump_log(frame: &Frame, interface: &str, time: Duration) -> String {
+    format!("({}.{:06}) {} {}", time.as_secs(), time.subsec_micros(), interface, format_frame(frame))
+}
+
+/// candump日志格式中的帧，如"321#0102"；扩展帧的ID为8位
+pub fn format_frame(frame: &Frame) -> String {
+    format!("{}#{}", candump_id(frame), to_hex(&frame.data).replace(' ', ""))
+}
+
+/// 解析format_frame()的结果
+pub fn parse_frame(text: &str) -> Result<Frame, String> {
+    let error = || format!("Invalid frame: {}", text);
+    let (id, data) = split_lr(text, "#");
+    if !text.contains('#') || id.is_empty() || data.len() % 2 != 0 {
+        return Err(error());
+    }
+    let data = (0..data.len()).step_by(2)
+        .map(|i| data.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(error)?;
+    Ok(Frame {
+        id: u32::from_str_radix(id, 16).map_err(|_| error())?,
+        extended: id.len() == 8,
+        data,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bus, Frame, LoopbackBus, candump_text, candump_log, format_frame, parse_frame};
+    use std::time::Duration;
+
+    #[test]
+    fn test_loopback() {
+        let (mut a, mut b) = LoopbackBus::pair();
+        a.send(&Frame::new(0x100, &[1, 2, 3])).expect("ok");
+        b.send(&Frame::new(0x200, &[4])).expect("ok");
+        assert_eq!(b.recv(Duration::from_millis(10)), Ok(Some(Frame::new(0x100, &[1, 2, 3]))));
+        assert_eq!(b.recv(Duration::from_millis(10)), Ok(None));
+        assert_eq!(a.recv(Duration::from_millis(10)), Ok(Some(Frame::new(0x200, &[4]))));
+        drop(b);
+        assert!(a.send(&Frame::new(0x100, &[])).is_err());
+    }
+
+    #[test]
+    fn test_candump() {
+        let frame = Frame::new(0x321, &[1, 0xAB]);
+        assert_eq!(candump_text(&frame, "can0"), "  can0  321   [2]  01 AB");
+        assert_eq!(candump_log(&frame, "can0", Duration::new(1697040000, 123456789)), "(1697040000.123456) can0 321#01AB");
+        let frame = Frame { id: 0x18FF0010, extended: true, data: Vec::new() };
+        assert_eq!(candump_text(&frame, "vcan1"), "  vcan1  18FF0010   [0]");
+        assert_eq!(candump_log(&frame, "vcan1", Duration::new(5, 0)), "(5.000000) vcan1 18FF0010#");
+    }
+
+    #[test]
+    fn test_frame_text() {
+        for frame in [Frame::new(0x321, &[1, 0xAB]), Frame::new(0x7E8, &[]), Frame { id: 0x18FF0010, extended: true, data: vec![0xFF] }] {
+            assert_eq!(parse_frame(&format_frame(&frame)), Ok(frame));
+        }
+        assert_eq!(parse_frame("321#0"), Err("Invalid frame: 321#0".to_string()));
+        assert!(parse_frame("321").is_err());
+        assert!(parse_frame("#01").is_err());
+        assert!(parse_frame("xyz#01").is_err());
+        assert!(parse_frame("321#é1").is_err());
+    }
+}
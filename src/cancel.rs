@@ -0,0 +1,234 @@
+// 协作式取消：其他线程调用CancelToken::cancel()后，正在执行的函数在下一条语句之前
+// （或等待接收数据帧时，最迟POLL_INTERVAL之后）以错误CANCELLED结束
+//
+// 设置Context.cancel后由FnDef::exec()、Program::exec()和Debugger检查；诊断、CANopen语句和J1939传输协议
+// 通过CancelBus收发，等待响应时也能及时结束。执行被取消后调用Engine.cleanup指定的函数（如使设备进入安全状态）。
+// 取消是持久的：再次执行前需要调用CancelToken::reset()。
+
+use bus::{Bus, Frame};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// 执行被取消时的错误（执行语句时后面是调用栈）
+pub const CANCELLED: &str = "Cancelled";
+
+/// 等待接收时检查取消的间隔
+pub const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// 取消执行的标志，clone后可以在其他线程中使用
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    /// 取消正在执行（及此后执行）的函数
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// 清除取消的标志
+    pub fn reset(&self) {
+        self.cancelled.store(false, Ordering::SeqCst);
+    }
+}
+
+/// 在发送前和等待接收时检查取消的总线；cancel为None时直接使用bus
+pub struct CancelBus<'a> {
+    bus: &'a mut dyn Bus,
+    cancel: Option<&'a CancelToken>,
+}
+
+impl<'a> CancelBus<'a> {
+    pub fn new(bus: &'a mut dyn Bus, cancel: Option<&'a CancelToken>) -> CancelBus<'a> {
+        CancelBus { bus, cancel }
+    }
+
+    fn check(&self) -> Result<(), String> {
+        match self.cancel {
+            Some(cancel) if cancel.is_cancelled() => Err(CANCELLED.to_string()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<'a> Bus for CancelBus<'a> {
+    fn send(&mut self, frame: &Frame) -> Result<(), String> {
+        self.check()?;
+        self.bus.send(frame)
+    }
+
+    // 每次最多等待POLL_INTERVAL
+    fn recv(&mut self, timeout: Duration) -> Result<Option<Frame>, String> {
+        if self.cancel.is_none() {
+            return self.bus.recv(timeout);
+        }
+        let deadline = Instant::now() + timeout;
+        loop {
+            self.check()?;
+            let wait = deadline.saturating_duration_since(Instant::now()).min(POLL_INTERVAL);
+            if let Some(frame) = self.bus.recv(wait)? {
+                return Ok(Some(frame));
+            }
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cancel::{CancelToken, CancelBus, CANCELLED};
+    use engine::{Engine, Context};
+    use variable::VarBindingList;
+    use bus::{Bus, Frame, LoopbackBus};
+    use uds::UdsClient;
+    use std::thread;
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    use std::time::{Duration, Instant};
+
+    const SCRIPT: &str = r#"ins lamp(a: u32, b: u32 = 1) = 0x321
+
+fn main() {
+    send lamp(a: 1)
+    loop 4000000000 {
+        x := 1
+    }
+}
+
+fn read() {
+    diag ReadDataByIdentifier(did: 0xF190)
+}
+
+fn safe() {
+    send lamp(a: 0)
+}
+
+fn short() {
+    return 1
+}
+"#;
+
+    // 在另一个线程中延迟取消
+    fn cancel_later(token: &CancelToken) -> thread::JoinHandle<()> {
+        let token = token.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            token.cancel();
+        })
+    }
+
+    fn frames(peer: &mut LoopbackBus) -> Vec<u8> {
+        let mut values = Vec::new();
+        while let Ok(Some(frame)) = peer.recv(Duration::from_millis(1)) {
+            values.push(frame.data[3]);
+        }
+        values
+    }
+
+    #[test]
+    fn test_bus() {
+        let (mut bus, mut peer) = LoopbackBus::pair();
+        let token = CancelToken::new();
+        {
+            let mut bus = CancelBus::new(&mut bus, Some(&token));
+            peer.send(&Frame::new(0x100, &[1])).expect("ok");
+            assert_eq!(bus.recv(Duration::from_secs(5)), Ok(Some(Frame::new(0x100, &[1]))));
+            assert_eq!(bus.recv(Duration::from_millis(25)), Ok(None));
+            let start = Instant::now();
+            let canceller = cancel_later(&token);
+            assert_eq!(bus.recv(Duration::from_secs(5)), Err(CANCELLED.to_string()));
+            assert!(start.elapsed() < Duration::from_secs(1));
+            canceller.join().expect("join");
+            assert_eq!(bus.send(&Frame::new(0x100, &[2])), Err(CANCELLED.to_string()));
+        }
+        token.reset();
+        let mut bus = CancelBus::new(&mut bus, Some(&token));
+        bus.send(&Frame::new(0x100, &[3])).expect("ok");
+        assert_eq!(peer.recv(Duration::from_millis(10)), Ok(Some(Frame::new(0x100, &[3]))));
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut engine = Engine::new();
+        let mut context = Context::new();
+        engine.load_script(SCRIPT, &mut context).expect("ok");
+        engine.cleanup = Some("safe".to_string());
+        let (bus, mut peer) = LoopbackBus::pair();
+        context.bus = Some(Box::new(bus));
+        let token = CancelToken::new();
+        context.cancel = Some(token.clone());
+        let args = VarBindingList::new();
+        let program = engine.compile("main").expect("ok");
+
+        for compiled in [false, true] {
+            token.reset();
+            let canceller = cancel_later(&token);
+            let result = if compiled { program.exec(&args, &mut context) } else { engine.exec_fn("main", &args, &mut context) };
+            canceller.join().expect("join");
+            assert!(result.expect_err("cancelled").starts_with("Cancelled at main #"));
+            assert!(context.calls.is_empty() && context.abort.is_none());
+            // 清理函数在返回前执行
+            assert_eq!(frames(&mut peer), vec![1, 0]);
+            // 取消是持久的；清理函数不受语句数和截止时间的限制
+            context.fuel = Some(0);
+            context.deadline = Some(Instant::now());
+            let result = if compiled { program.exec(&args, &mut context) } else { engine.exec_fn("main", &args, &mut context) };
+            assert_eq!(result, Err("Cancelled at main #0".to_string()));
+            assert_eq!(frames(&mut peer), vec![0]);
+            assert_eq!(context.fuel, Some(0));
+            context.fuel = None;
+            context.deadline = None;
+        }
+        token.reset();
+        assert_eq!(engine.exec_fn("short", &args, &mut context), Ok(()));
+        assert!(frames(&mut peer).is_empty());
+
+        // 清理函数不存在时编译和解释执行一样在取消后记录错误
+        engine.cleanup = Some("none".to_string());
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let lines = log.clone();
+        context.logger = Some(Box::new(move |line: &str| lines.borrow_mut().push(line.to_string())));
+        let program = engine.compile("main").expect("ok");
+        token.cancel();
+        for compiled in [false, true] {
+            log.borrow_mut().clear();
+            let result = if compiled { program.exec(&args, &mut context) } else { engine.exec_fn("main", &args, &mut context) };
+            assert_eq!(result, Err("Cancelled at main #0".to_string()));
+            assert_eq!(*log.borrow(), vec!["[ERROR] Cancelled at main #0", "[ERROR] No such fn: none"]);
+        }
+    }
+
+    #[test]
+    fn test_cancel_wait() {
+        let mut engine = Engine::new();
+        let mut context = Context::new();
+        engine.load_script(SCRIPT, &mut context).expect("ok");
+        let (bus, _peer) = LoopbackBus::pair();
+        context.bus = Some(Box::new(bus));
+        let mut uds = UdsClient::new(0x7E0, 0x7E8);
+        uds.p2 = Duration::from_secs(5);
+        context.uds = Some(uds);
+        let token = CancelToken::new();
+        context.cancel = Some(token.clone());
+
+        // 没有响应的诊断请求在取消后及时结束
+        let start = Instant::now();
+        let canceller = cancel_later(&token);
+        let result = engine.exec_fn("read", &VarBindingList::new(), &mut context);
+        canceller.join().expect("join");
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(result.expect_err("cancelled").contains(CANCELLED));
+    }
+}
@@ -0,0 +1,768 @@
+// CANopen (CiA 301)：NMT命令、SDO客户端（快速和分段传输），以及从EDS文件读取PDO映射
+// 只支持11位COB-ID的预定义连接集(0x600+节点/0x580+节点)，不支持SDO块传输
+
+use bus::{Bus, Frame};
+use cancel::CancelBus;
+use engine::Context;
+use statement::Stmt;
+use variable::{VarDef, VarBindingList};
+use instruction::InsDef;
+use signal::{SignalLayout, ByteOrder};
+use dbc::{dbc_ident, signal_type};
+use utils::{to_hex, parse_hex, parse_int, split_lr};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// SDO上传/下载的最大数据长度（本实现的限制）
+pub const SDO_MAX_LEN: usize = 0x10000;
+
+const SDO_ABORT: u8 = 0x80;
+const ABORT_TOGGLE: u32 = 0x0503_0000;
+const ABORT_COMMAND: u32 = 0x0504_0001;
+
+/// NMT命令
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NmtCommand {
+    Start = 0x01,
+    Stop = 0x02,
+    PreOperational = 0x80,
+    ResetNode = 0x81,
+    ResetCommunication = 0x82,
+}
+
+impl NmtCommand {
+    pub fn from_name(name: &str) -> Option<NmtCommand> {
+        match name {
+            "Start" => Some(NmtCommand::Start),
+            "Stop" => Some(NmtCommand::Stop),
+            "PreOperational" => Some(NmtCommand::PreOperational),
+            "ResetNode" => Some(NmtCommand::ResetNode),
+            "ResetCommunication" => Some(NmtCommand::ResetCommunication),
+            _ => None,
+        }
+    }
+}
+
+/// 发送NMT命令；node_id为0表示所有节点
+pub fn send_nmt(bus: &mut dyn Bus, command: NmtCommand, node_id: u8) -> Result<(), String> {
+    bus.send(&Frame::new(0x000, &[command as u8, node_id]))
+}
+
+/// SDO传输的结果
+#[derive(Debug, Clone, PartialEq)]
+pub enum SdoResponse {
+    /// 传输成功；上传时为读到的数据，下载时为空
+    Data(Vec<u8>),
+    /// 被服务器中止，带中止代码
+    Abort(u32),
+}
+
+/// 常见SDO中止代码的含义
+pub fn abort_name(code: u32) -> &'static str {
+    match code {
+        0x0503_0000 => "toggle bit not alternated",
+        0x0504_0000 => "SDO protocol timed out",
+        0x0504_0001 => "command specifier not valid",
+        0x0601_0000 => "unsupported access to an object",
+        0x0601_0001 => "attempt to read a write only object",
+        0x0601_0002 => "attempt to write a read only object",
+        0x0602_0000 => "object does not exist",
+        0x0604_0041 => "object cannot be mapped to the PDO",
+        0x0607_0010 => "data type does not match, length of service parameter does not match",
+        0x0609_0011 => "sub-index does not exist",
+        0x0609_0030 => "value range of parameter exceeded",
+        0x0800_0000 => "general error",
+        0x0800_0022 => "data cannot be transferred because of the present device state",
+        _ => "unknown",
+    }
+}
+
+/// SDO客户端，访问一个节点的对象字典
+pub struct SdoClient {
+    pub node_id: u8,
+    /// 等待服务器响应的超时
+    pub timeout: Duration,
+}
+
+impl SdoClient {
+    pub fn new(node_id: u8) -> SdoClient {
+        SdoClient {
+            node_id,
+            timeout: Duration::from_millis(1000),
+        }
+    }
+
+    /// 读取对象字典的index/subindex；4字节以内为快速传输，否则为分段传输
+    pub fn upload(&self, bus: &mut dyn Bus, index: u16, sub: u8) -> Result<SdoResponse, String> {
+        let response = match self.request(bus, index, sub, [0x40, 0, 0, 0, 0, 0, 0, 0])? {
+            Ok(response) => response,
+            Err(code) => return Ok(SdoResponse::Abort(code)),
+        };
+        if response[0] & 0xE0 != 0x40 {
+            return self.abort(bus, index, sub, ABORT_COMMAND, response[0]);
+        }
+        if response[0] & 0x02 != 0 {
+            // 快速传输
+            let len = if response[0] & 0x01 != 0 { 4 - ((response[0] >> 2) & 0x03) as usize } else { 4 };
+            return Ok(SdoResponse::Data(response[4..4 + len].to_vec()));
+        }
+        let size = if response[0] & 0x01 != 0 {
+            Some(u32::from_le_bytes([response[4], response[5], response[6], response[7]]) as usize)
+        } else {
+            None
+        };
+
+        let mut data = Vec::new();
+        let mut toggle = 0u8;
+        loop {
+            let response = match self.request(bus, index, sub, [0x60 | toggle, 0, 0, 0, 0, 0, 0, 0])? {
+                Ok(response) => response,
+                Err(code) => return Ok(SdoResponse::Abort(code)),
+            };
+            if response[0] & 0xE0 != 0x00 {
+                return self.abort(bus, index, sub, ABORT_COMMAND, response[0]);
+            }
+            if response[0] & 0x10 != toggle {
+                return self.abort(bus, index, sub, ABORT_TOGGLE, response[0]);
+            }
+            let len = 7 - ((response[0] >> 1) & 0x07) as usize;
+            data.extend_from_slice(&response[1..1 + len]);
+            if data.len() > SDO_MAX_LEN {
+                return Err(format!("SDO upload of {:04X}sub{:X} too long", index, sub));
+            }
+            if response[0] & 0x01 != 0 {
+                break;
+            }
+            toggle ^= 0x10;
+        }
+        match size {
+            Some(size) if size != data.len() => {
+                Err(format!("SDO upload of {:04X}sub{:X}: expected {} bytes, got {}", index, sub, size, data.len()))
+            }
+            _ => Ok(SdoResponse::Data(data)),
+        }
+    }
+
+    /// 写入对象字典的index/subindex；4字节以内为快速传输，否则为分段传输
+    pub fn download(&self, bus: &mut dyn Bus, index: u16, sub: u8, data: &[u8]) -> Result<SdoResponse, String> {
+        if data.is_empty() || data.len() > SDO_MAX_LEN {
+            return Err(format!("Invalid SDO download length: {}", data.len()));
+        }
+        if data.len() <= 4 {
+            let mut request = [0x23 | ((4 - data.len() as u8) << 2), 0, 0, 0, 0, 0, 0, 0];
+            request[4..4 + data.len()].copy_from_slice(data);
+            return match self.request(bus, index, sub, request)? {
+                Ok(response) if response[0] == 0x60 => Ok(SdoResponse::Data(Vec::new())),
+                Ok(response) => self.abort(bus, index, sub, ABORT_COMMAND, response[0]),
+                Err(code) => Ok(SdoResponse::Abort(code)),
+            };
+        }
+
+        let size = (data.len() as u32).to_le_bytes();
+        match self.request(bus, index, sub, [0x21, 0, 0, 0, size[0], size[1], size[2], size[3]])? {
+            Ok(ref response) if response[0] == 0x60 => {}
+            Ok(response) => return self.abort(bus, index, sub, ABORT_COMMAND, response[0]),
+            Err(code) => return Ok(SdoResponse::Abort(code)),
+        }
+        let mut toggle = 0u8;
+        let segments = data.len().div_ceil(7);
+        for (i, segment) in data.chunks(7).enumerate() {
+            let last = (i + 1 == segments) as u8;
+            let mut request = [toggle | ((7 - segment.len() as u8) << 1) | last, 0, 0, 0, 0, 0, 0, 0];
+            request[1..1 + segment.len()].copy_from_slice(segment);
+            match self.request(bus, index, sub, request)? {
+                Ok(ref response) if response[0] & 0xE0 != 0x20 => {
+                    return self.abort(bus, index, sub, ABORT_COMMAND, response[0]);
+                }
+                Ok(ref response) if response[0] & 0x10 != toggle => {
+                    return self.abort(bus, index, sub, ABORT_TOGGLE, response[0]);
+                }
+                Ok(_) => {}
+                Err(code) => return Ok(SdoResponse::Abort(code)),
+            }
+            toggle ^= 0x10;
+        }
+        Ok(SdoResponse::Data(Vec::new()))
+    }
+
+    // 发送请求并等待响应；初始化请求（命令字不是分段的）填入index/subindex，并检查响应中的index/subindex
+    // 内层的Err为服务器发来的中止代码
+    fn request(&self, bus: &mut dyn Bus, index: u16, sub: u8, mut request: [u8; 8]) -> Result<Result<[u8; 8], u32>, String> {
+        // 初始化请求的命令字为001xxxxx(下载)或010xxxxx(上传)
+        let initiate = request[0] & 0xE0 == 0x20 || request[0] & 0xE0 == 0x40;
+        if initiate {
+            request[1..4].copy_from_slice(&[index as u8, (index >> 8) as u8, sub]);
+        }
+        bus.send(&Frame::new(0x600 + self.node_id as u32, &request))?;
+
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(format!("SDO timeout, node {} object {:04X}sub{:X}", self.node_id, index, sub));
+            }
+            let frame = match bus.recv(deadline - now)? {
+                Some(frame) => frame,
+                None => continue,
+            };
+            if frame.id != 0x580 + self.node_id as u32 || frame.extended || frame.data.len() != 8 {
+                continue;
+            }
+            let mut response = [0u8; 8];
+            response.copy_from_slice(&frame.data);
+            let same_object = response[1..4] == [index as u8, (index >> 8) as u8, sub];
+            if response[0] == SDO_ABORT {
+                return Ok(Err(u32::from_le_bytes([response[4], response[5], response[6], response[7]])));
+            }
+            if initiate && !same_object {
+                return Err(format!("SDO response for another object: {:02X} {:02X}{:02X}sub{:X}",
+                                   response[0], response[2], response[1], response[3]));
+            }
+            return Ok(Ok(response));
+        }
+    }
+
+    // 向服务器发送中止并返回错误
+    fn abort(&self, bus: &mut dyn Bus, index: u16, sub: u8, code: u32, command: u8) -> Result<SdoResponse, String> {
+        let code_bytes = code.to_le_bytes();
+        let request = [SDO_ABORT, index as u8, (index >> 8) as u8, sub, code_bytes[0], code_bytes[1], code_bytes[2], code_bytes[3]];
+        bus.send(&Frame::new(0x600 + self.node_id as u32, &request))?;
+        Err(format!("SDO transfer aborted: unexpected response {:02X} ({})", command, abort_name(code)))
+    }
+}
+
+/// 执行CANopen语句，Stmt.content为服务名称，参数（可以引用变量）为：
+///   NMT: command(Start/Stop/PreOperational/ResetNode/ResetCommunication), node（默认0，即所有节点）
+///   SdoUpload: node, index, sub（默认0）, result（默认"$sdo"）
+///   SdoDownload: node, index, sub（默认0）, data
+/// 上传的数据以'hex:'格式存入全局变量result；SDO被中止时中止代码存入全局变量$sdo_abort，并返回错误
+pub fn exec_canopen(stmt: &Stmt, locals: &VarBindingList, context: &mut Context) -> Result<(), String> {
+    let service = stmt.content.as_str();
+    let mut args = VarBindingList::new();
+    for name in stmt.args.bindings.keys() {
+        let value = stmt.args.raw_value_of(name).and_then(|value| locals.eval(value, Some(&context.globals), None));
+        if let Some(value) = value {
+            args.set_binding(name, &value);
+        }
+    }
+    let int_arg = |name: &str, max: u64, default: Option<u64>| -> Result<u64, String> {
+        let value = match (args.raw_value_of(name), default) {
+            (Some(value), _) => parse_int(value)?,
+            (None, Some(default)) => default,
+            (None, None) => return Err(format!("{} requires arg: {}", service, name)),
+        };
+        if value > max { Err(format!("Invalid arg {}: {}", name, value)) } else { Ok(value) }
+    };
+    let result_var = args.raw_value_of("result").map_or("$sdo", |name| split_lr(name, "str:").1);
+
+    let bus = match context.bus {
+        Some(ref mut bus) => bus.as_mut(),
+        None => return Err("No bus in context".to_string()),
+    };
+    let bus = &mut CancelBus::new(bus, context.cancel.as_ref());
+    let response = match service {
+        "NMT" => {
+            let command = args.raw_value_of("command").map_or("", |name| split_lr(name, "str:").1);
+            let command = NmtCommand::from_name(command).ok_or_else(|| format!("Invalid NMT command: {}", command))?;
+            return send_nmt(bus, command, int_arg("node", 127, Some(0))? as u8);
+        }
+        "SdoUpload" | "SdoDownload" => {
+            let sdo = SdoClient::new(int_arg("node", 127, None)? as u8);
+            let (index, sub) = (int_arg("index", 0xFFFF, None)? as u16, int_arg("sub", 0xFF, Some(0))? as u8);
+            if service == "SdoUpload" {
+                sdo.upload(bus, index, sub)?
+            } else {
+                let data = parse_hex(args.raw_value_of("data").ok_or_else(|| format!("{} requires arg: data", service))?)?;
+                sdo.download(bus, index, sub, &data)?
+            }
+        }
+        _ => return Err(format!("Unsupport CANopen service: {}", service)),
+    };
+
+    match response {
+        SdoResponse::Data(data) => {
+            context.globals.remove_binding("$sdo_abort");
+            if service == "SdoUpload" {
+                context.globals.set_binding(result_var, &format!("hex:{}", to_hex(&data)));
+            }
+            Ok(())
+        }
+        SdoResponse::Abort(code) => {
+            context.globals.set_binding("$sdo_abort", &format!("int:{}", code));
+            Err(format!("{} aborted: {:08X} ({})", service, code, abort_name(code)))
+        }
+    }
+}
+
+/// EDS中的一个对象（或子对象）
+#[derive(Debug, Clone, PartialEq)]
+pub struct EdsObject {
+    pub name: String,
+    pub data_type: u16,
+    /// ro, wo, rw, const等
+    pub access: String,
+    /// 默认值，可能包含$NODEID
+    pub default: Option<String>,
+}
+
+/// 电子数据表(EDS)，只读取对象字典
+pub struct Eds {
+    /// key为(index, subindex)；简单变量的subindex为0
+    pub objects: BTreeMap<(u16, u8), EdsObject>,
+}
+
+/// 解析EDS文本（INI格式），对象字典以外的节被忽略
+pub fn parse_eds(text: &str) -> Result<Eds, String> {
+    let mut eds = Eds { objects: BTreeMap::new() };
+    // 当前节：(index, subindex, 是否为子对象节, 属性)
+    let mut section: Option<(u16, u8, bool, BTreeMap<String, String>)> = None;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') {
+            continue;
+        }
+        if line.starts_with('[') {
+            if !line.ends_with(']') {
+                return Err(format!("line {}: invalid section: {}", i + 1, line));
+            }
+            if let Some(section) = section.take() {
+                eds.add_section(section)?;
+            }
+            section = parse_section_name(&line[1..line.len() - 1]).map(|(index, sub, is_sub)| (index, sub, is_sub, BTreeMap::new()));
+            continue;
+        }
+        let (key, value) = match line.find('=') {
+            Some(pos) => (&line[..pos], &line[pos + 1..]),
+            None => return Err(format!("line {}: expected key=value: {}", i + 1, line)),
+        };
+        if let Some((_, _, _, ref mut props)) = section {
+            props.insert(key.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+    if let Some(section) = section.take() {
+        eds.add_section(section)?;
+    }
+    Ok(eds)
+}
+
+// "1018"为对象，"1018sub1"为子对象；其他节名返回None
+fn parse_section_name(name: &str) -> Option<(u16, u8, bool)> {
+    if name.len() < 4 || !name.is_char_boundary(4) {
+        return None;
+    }
+    let index = u16::from_str_radix(&name[..4], 16).ok()?;
+    let rest = name[4..].to_lowercase();
+    if rest.is_empty() {
+        return Some((index, 0, false));
+    }
+    let sub = u8::from_str_radix(rest.strip_prefix("sub")?, 16).ok()?;
+    Some((index, sub, true))
+}
+
+// 计算EDS中的数值，如"0x180+$NODEID"
+fn eds_value(text: &str, node_id: u8) -> Result<u64, String> {
+    let mut value = 0u64;
+    for term in text.split('+') {
+        let term = term.trim();
+        value += if term.eq_ignore_ascii_case("$NODEID") { node_id as u64 } else { parse_int(term)? };
+    }
+    Ok(value)
+}
+
+impl Eds {
+    fn add_section(&mut self, section: (u16, u8, bool, BTreeMap<String, String>)) -> Result<(), String> {
+        let (index, sub, is_sub, props) = section;
+        let int_prop = |key: &str| -> Result<Option<u64>, String> {
+            props.get(key).map_or(Ok(None), |value| {
+                parse_int(value).map(Some).map_err(|err| format!("[{:04X}] {}: {}", index, key, err))
+            })
+        };
+        // 记录(0x9)和数组(0x8)的值在子对象节中
+        if !is_sub && int_prop("objecttype")?.is_some_and(|typ| typ == 0x8 || typ == 0x9) {
+            return Ok(());
+        }
+        let object = EdsObject {
+            name: props.get("parametername").cloned().unwrap_or_default(),
+            data_type: int_prop("datatype")?.unwrap_or(0) as u16,
+            access: props.get("accesstype").cloned().unwrap_or_default().to_lowercase(),
+            default: props.get("defaultvalue").filter(|value| !value.is_empty()).cloned(),
+        };
+        self.objects.insert((index, sub), object);
+        Ok(())
+    }
+
+    /// 对象的默认值（$NODEID替换为node_id）；对象不存在或没有默认值时返回None
+    pub fn value(&self, index: u16, sub: u8, node_id: u8) -> Result<Option<u64>, String> {
+        match self.objects.get(&(index, sub)).and_then(|object| object.default.as_ref()) {
+            Some(value) => eds_value(value, node_id).map(Some).map_err(|err| format!("{:04X}sub{:X}: {}", index, sub, err)),
+            None => Ok(None),
+        }
+    }
+
+    /// 按EDS中的默认PDO映射生成指令，名称为"node<节点>_rpdo<n>"和"node<节点>_tpdo<n>"
+    /// RPDO由引擎发给节点，TPDO可用于解码节点发来的数据；参数名为映射对象的名称，按Intel字节序编码
+    /// 无效(COB-ID最高位为1)或没有映射的PDO被跳过；不支持的数据类型记入unsupported，其位置保留为0
+    pub fn pdos(&self, node_id: u8, unsupported: &mut Vec<String>) -> Result<Vec<InsDef>, String> {
+        let mut inss = Vec::new();
+        for &(comm_base, map_base, kind) in &[(0x1400u16, 0x1600u16, "rpdo"), (0x1800, 0x1A00, "tpdo")] {
+            for n in 0..0x200u16 {
+                let cob_id = match self.value(comm_base + n, 1, node_id)? {
+                    Some(cob_id) if cob_id & 0x8000_0000 == 0 => cob_id as u32,
+                    _ => continue,
+                };
+                let count = self.value(map_base + n, 0, node_id)?.unwrap_or(0);
+                if count == 0 {
+                    continue;
+                }
+                if count > 64 {
+                    return Err(format!("{:04X}sub0: invalid number of mapped objects: {}", map_base + n, count));
+                }
+                let name = format!("node{}_{}{}", node_id, kind, n + 1);
+                let extended = cob_id & 0x2000_0000 != 0;
+                let mut insdef = InsDef::new(&name, if extended { cob_id & 0x1FFF_FFFF } else { cob_id & 0x7FF });
+                insdef.extended = extended;
+                insdef.note = Some(format!("{}{} of CANopen node {}", kind.to_uppercase(), n + 1, node_id));
+                let mut bit = 0u32;
+                for sub in 1..count as u8 + 1 {
+                    let entry = self.value(map_base + n, sub, node_id)?
+                        .ok_or_else(|| format!("{}: missing mapping {:04X}sub{:X}", name, map_base + n, sub))?;
+                    let (index, subindex, bit_len) = ((entry >> 16) as u16, (entry >> 8) as u8, entry as u8 as u32);
+                    if bit_len == 0 || bit + bit_len > 64 {
+                        return Err(format!("{}: mapping exceeds 8 bytes", name));
+                    }
+                    let layout = SignalLayout::new(bit, bit_len, ByteOrder::Intel, false);
+                    bit += bit_len;
+                    if index < 0x1000 {
+                        continue; // 占位(dummy)映射
+                    }
+                    let object = self.objects.get(&(index, subindex))
+                        .ok_or_else(|| format!("{}: maps unknown object {:04X}sub{:X}", name, index, subindex))?;
+                    let signed = match object.data_type {
+                        0x0001 | 0x0005 | 0x0006 | 0x0007 | 0x001B => false,
+                        0x0002 | 0x0003 | 0x0004 | 0x0015 => true,
+                        data_type => {
+                            unsupported.push(format!("{}: object {:04X}sub{:X} of data type {:04X} ignored",
+                                                     name, index, subindex, data_type));
+                            continue;
+                        }
+                    };
+                    let layout = SignalLayout { signed, ..layout };
+                    let mut arg_name = dbc_ident(&object.name);
+                    if insdef.args.find(&arg_name).is_some() {
+                        arg_name = format!("{}_{:04X}_{:X}", arg_name, index, subindex);
+                    }
+                    let mut vardef = VarDef::new(arg_name.as_str(), signal_type(&layout));
+                    if let Some(value) = self.value(index, subindex, node_id)? {
+                        vardef.default = value.to_string();
+                    }
+                    vardef.note = Some(format!("{:04X}sub{:X}", index, subindex));
+                    vardef.signal = Some(layout);
+                    insdef.args.add(vardef);
+                }
+                insdef.dlc = bit.div_ceil(8) as u16;
+                inss.push(insdef);
+            }
+        }
+        Ok(inss)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SdoClient, SdoResponse, NmtCommand, send_nmt, parse_eds, eds_value};
+    use bus::{Bus, Frame, LoopbackBus};
+    use engine::{Engine, Context};
+    use function::FnDef;
+    use statement::Stmt;
+    use variable::VarBindingList;
+    use std::collections::HashMap;
+    use std::thread;
+    use std::time::Duration;
+
+    const NODE: u8 = 5;
+
+    const EDS: &str = r#"
+[FileInfo]
+FileName=drive.eds
+
+[1000]
+ParameterName=Device type
+ObjectType=0x7
+DataType=0x0007
+AccessType=ro
+DefaultValue=0x00020192
+
+[1400]
+ParameterName=RPDO1 communication parameter
+ObjectType=0x9
+SubNumber=2
+
+[1400sub1]
+ParameterName=COB-ID
+DataType=0x0007
+AccessType=rw
+DefaultValue=$NODEID+0x200
+
+[1401sub1]
+ParameterName=COB-ID
+DataType=0x0007
+DefaultValue=0x80000300
+
+[1600sub0]
+ParameterName=Number of mapped objects
+DataType=0x0005
+DefaultValue=3
+
+[1600sub1]
+DataType=0x0007
+DefaultValue=0x60400010
+
+[1600sub2]
+DataType=0x0007
+DefaultValue=0x00050008
+
+[1600sub3]
+DataType=0x0007
+DefaultValue=0x60FF0020
+
+[1800sub1]
+DataType=0x0007
+DefaultValue=0x180+$NODEID
+
+[1A00sub0]
+DataType=0x0005
+DefaultValue=2
+
+[1A00sub1]
+DataType=0x0007
+DefaultValue=0x60410010
+
+[1A00sub2]
+DataType=0x0007
+DefaultValue=0x60640020
+
+[6040]
+ParameterName=Controlword
+DataType=0x0006
+AccessType=rw
+DefaultValue=0
+
+[6041]
+ParameterName=Statusword
+DataType=0x0006
+AccessType=ro
+
+[6064]
+ParameterName=Position actual value
+DataType=0x0008
+AccessType=ro
+
+[60FF]
+ParameterName=Target velocity
+DataType=0x0004
+AccessType=rw
+"#;
+
+    // 模拟CANopen节点：NMT状态变化时发送心跳，SDO服务器支持快速和分段传输
+    fn spawn_node(mut bus: LoopbackBus) {
+        thread::spawn(move || {
+            let mut dict: HashMap<(u16, u8), Vec<u8>> = HashMap::new();
+            dict.insert((0x1000, 0), vec![0x92, 0x01, 0x02, 0x00]);
+            dict.insert((0x1008, 0), b"Simulated drive".to_vec());
+            let abort = |request: &[u8], code: u32| -> Vec<u8> {
+                let mut data = vec![0x80, request[1], request[2], request[3]];
+                data.extend_from_slice(&code.to_le_bytes());
+                data
+            };
+            // 进行中的分段传输：(index, subindex, 是否为上传, 数据, 位置)
+            let mut transfer: Option<(u16, u8, bool, Vec<u8>, usize)> = None;
+            while let Ok(frame) = bus.recv(Duration::from_secs(10)) {
+                let frame = match frame { Some(frame) => frame, None => break };
+                if frame.id == 0 && (frame.data[1] == 0 || frame.data[1] == NODE) {
+                    let state = match frame.data[0] { 0x01 => 0x05, 0x02 => 0x04, 0x80 => 0x7F, _ => 0x00 };
+                    bus.send(&Frame::new(0x700 + NODE as u32, &[state])).unwrap();
+                    continue;
+                }
+                if frame.id != 0x600 + NODE as u32 {
+                    continue;
+                }
+                let request = frame.data;
+                let key = (request[1] as u16 | (request[2] as u16) << 8, request[3]);
+                let mut response = vec![0u8; 8];
+                response[1..4].copy_from_slice(&request[1..4]);
+                match request[0] & 0xE0 {
+                    0x40 => match dict.get(&key) {
+                        Some(data) if data.len() <= 4 => {
+                            response[0] = 0x43 | ((4 - data.len() as u8) << 2);
+                            response[4..4 + data.len()].copy_from_slice(data);
+                        }
+                        Some(data) => {
+                            response[0] = 0x41;
+                            response[4..8].copy_from_slice(&(data.len() as u32).to_le_bytes());
+                            transfer = Some((key.0, key.1, true, data.clone(), 0));
+                        }
+                        None => response = abort(&request, 0x0602_0000),
+                    },
+                    0x60 => match transfer {
+                        Some((_, _, true, ref data, ref mut pos)) => {
+                            let end = (*pos + 7).min(data.len());
+                            response = vec![request[0] & 0x10 | ((7 - (end - *pos) as u8) << 1) | (end == data.len()) as u8];
+                            response.extend_from_slice(&data[*pos..end]);
+                            response.resize(8, 0);
+                            *pos = end;
+                        }
+                        _ => response = abort(&request, 0x0504_0001),
+                    },
+                    0x20 if key.0 == 0x1000 => response = abort(&request, 0x0601_0002),
+                    0x20 if request[0] & 0x02 != 0 => {
+                        let len = 4 - ((request[0] >> 2) & 0x03) as usize;
+                        dict.insert(key, request[4..4 + len].to_vec());
+                        response[0] = 0x60;
+                    }
+                    0x20 => {
+                        transfer = Some((key.0, key.1, false, Vec::new(), 0));
+                        response[0] = 0x60;
+                    }
+                    0x00 => match transfer {
+                        Some((index, sub, false, ref mut data, _)) => {
+                            let len = 7 - ((request[0] >> 1) & 0x07) as usize;
+                            data.extend_from_slice(&request[1..1 + len]);
+                            if request[0] & 0x01 != 0 {
+                                dict.insert((index, sub), data.clone());
+                            }
+                            response = vec![0x20 | (request[0] & 0x10), 0, 0, 0, 0, 0, 0, 0];
+                        }
+                        _ => response = abort(&request, 0x0504_0001),
+                    },
+                    _ => continue, // client abort
+                }
+                bus.send(&Frame::new(0x580 + NODE as u32, &response)).unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn test_sdo() {
+        let (mut bus, node) = LoopbackBus::pair();
+        spawn_node(node);
+        let sdo = SdoClient::new(NODE);
+        assert_eq!(sdo.upload(&mut bus, 0x1000, 0), Ok(SdoResponse::Data(vec![0x92, 0x01, 0x02, 0x00])));
+        assert_eq!(sdo.upload(&mut bus, 0x1008, 0), Ok(SdoResponse::Data(b"Simulated drive".to_vec())));
+        assert_eq!(sdo.upload(&mut bus, 0x2000, 1), Ok(SdoResponse::Abort(0x0602_0000)));
+        assert_eq!(sdo.download(&mut bus, 0x1000, 0, &[1]), Ok(SdoResponse::Abort(0x0601_0002)));
+        assert_eq!(sdo.download(&mut bus, 0x6040, 0, &[0x0F, 0x00]), Ok(SdoResponse::Data(vec![])));
+        assert_eq!(sdo.upload(&mut bus, 0x6040, 0), Ok(SdoResponse::Data(vec![0x0F, 0x00])));
+        for &len in &[5, 7, 14, 100] {
+            let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            assert_eq!(sdo.download(&mut bus, 0x2001, 0, &data), Ok(SdoResponse::Data(vec![])));
+            assert_eq!(sdo.upload(&mut bus, 0x2001, 0), Ok(SdoResponse::Data(data)));
+        }
+        assert!(sdo.download(&mut bus, 0x2001, 0, &[]).is_err());
+
+        let mut silent = SdoClient::new(NODE + 1);
+        silent.timeout = Duration::from_millis(20);
+        assert!(silent.upload(&mut bus, 0x1000, 0).is_err());
+    }
+
+    #[test]
+    fn test_nmt() {
+        let (mut bus, node) = LoopbackBus::pair();
+        spawn_node(node);
+        send_nmt(&mut bus, NmtCommand::Start, NODE).expect("ok");
+        assert_eq!(bus.recv(Duration::from_millis(500)), Ok(Some(Frame::new(0x705, &[0x05]))));
+        send_nmt(&mut bus, NmtCommand::PreOperational, 0).expect("ok");
+        assert_eq!(bus.recv(Duration::from_millis(500)), Ok(Some(Frame::new(0x705, &[0x7F]))));
+        send_nmt(&mut bus, NmtCommand::Stop, NODE + 1).expect("ok"); // not for this node
+        assert_eq!(bus.recv(Duration::from_millis(20)), Ok(None));
+        assert_eq!(NmtCommand::from_name("ResetNode"), Some(NmtCommand::ResetNode));
+        assert_eq!(NmtCommand::from_name("Reset"), None);
+    }
+
+    #[test]
+    fn test_eds() {
+        assert_eq!(eds_value("$NODEID+0x180", 5), Ok(0x185));
+        assert_eq!(eds_value("0x200 + $nodeid", 5), Ok(0x205));
+        assert!(eds_value("$NODE", 5).is_err());
+
+        let eds = parse_eds(EDS).expect("ok");
+        assert!(!eds.objects.contains_key(&(0x1400, 0))); // record
+        let device_type = &eds.objects[&(0x1000, 0)];
+        assert_eq!((device_type.name.as_str(), device_type.data_type, device_type.access.as_str()),
+                   ("Device type", 7, "ro"));
+        assert_eq!(eds.value(0x1000, 0, NODE), Ok(Some(0x00020192)));
+        assert_eq!(eds.value(0x1400, 1, NODE), Ok(Some(0x205)));
+        assert_eq!(eds.value(0x6041, 0, NODE), Ok(None));
+
+        let mut unsupported = Vec::new();
+        let inss = eds.pdos(NODE, &mut unsupported).expect("ok");
+        assert_eq!(unsupported, vec!["node5_tpdo1: object 6064sub0 of data type 0008 ignored".to_string()]);
+        assert_eq!(inss.len(), 2); // RPDO2 is invalid
+        let rpdo = &inss[0];
+        assert_eq!((rpdo.name.as_str(), rpdo.canid, rpdo.dlc), ("node5_rpdo1", 0x205, 7));
+        let names: Vec<&str> = rpdo.args.defs.iter().map(|vardef| vardef.name.as_str()).collect();
+        assert_eq!(names, vec!["Controlword", "Target_velocity"]);
+        assert_eq!(rpdo.args.defs[1].typ, "i32");
+        assert_eq!(rpdo.args.defs[1].signal.as_ref().map(|signal| signal.start_bit), Some(24));
+        let mut args = VarBindingList::new();
+        args.set_binding("Target_velocity", "-1000"); // Controlword defaults to 0
+        let mut data = Vec::new();
+        rpdo.exec(&args, &mut data, &mut Context::new()).expect("ok");
+        assert_eq!(data, vec![0x00, 0x00, 0x00, 0x18, 0xFC, 0xFF, 0xFF]);
+        let tpdo = &inss[1];
+        assert_eq!((tpdo.name.as_str(), tpdo.canid, tpdo.dlc, tpdo.args.defs.len()), ("node5_tpdo1", 0x185, 6, 1));
+
+        assert!(parse_eds("[1000]\nDataType").is_err());
+        let eds = parse_eds("[1600sub0]\nDefaultValue=1\n[1600sub1]\nDefaultValue=0x20000008\n[1400sub1]\nDefaultValue=0x201").expect("ok");
+        assert!(eds.pdos(1, &mut Vec::new()).is_err()); // unknown object
+    }
+
+    #[test]
+    fn test_exec_canopen() {
+        let mut engine = {
+            let mut setup = FnDef::new("setup");
+            let mut args = VarBindingList::new();
+            args.set_binding("command", "Start");
+            args.set_binding("node", "5");
+            setup.add_stmt(Stmt::new_canopen("NMT", args));
+            let mut args = VarBindingList::new();
+            args.set_binding("node", "5");
+            args.set_binding("index", "0x6040");
+            args.set_binding("data", "hex:06 00");
+            setup.add_stmt(Stmt::new_canopen("SdoDownload", args));
+            let mut args = VarBindingList::new();
+            args.set_binding("node", "5");
+            args.set_binding("index", "0x1008");
+            args.set_binding("result", "name");
+            setup.add_stmt(Stmt::new_canopen("SdoUpload", args));
+            let mut args = VarBindingList::new();
+            args.set_binding("Target_velocity", "-1000");
+            setup.add_stmt(Stmt::new_call_ins("node5_rpdo1", args));
+
+            let mut bad = FnDef::new("bad");
+            let mut args = VarBindingList::new();
+            args.set_binding("node", "5");
+            args.set_binding("index", "0x2000");
+            bad.add_stmt(Stmt::new_canopen("SdoUpload", args));
+
+            let mut engine = Engine::new();
+            engine.add_fn(setup);
+            engine.add_fn(bad);
+            engine
+        };
+        let mut context = Context::new();
+        let unsupported = engine.import_eds(EDS, NODE, &mut context).expect("ok");
+        assert_eq!(unsupported.len(), 1);
+        // 没有总线时CANopen语句失败，函数的结果是最后一条语句（发送指令）的结果
+        engine.exec_fn("setup", &VarBindingList::new(), &mut context).expect("ok");
+        assert_eq!(context.globals.raw_value_of("name"), None); // no bus
+
+        let (bus, node) = LoopbackBus::pair();
+        spawn_node(node);
+        context.bus = Some(Box::new(bus));
+        engine.exec_fn("setup", &VarBindingList::new(), &mut context).expect("ok");
+        assert_eq!(context.globals.raw_value_of("name"), Some("hex:53 69 6D 75 6C 61 74 65 64 20 64 72 69 76 65"));
+        assert_eq!(context.globals.raw_value_of("$sdo_abort"), None);
+        assert!(engine.exec_fn("bad", &VarBindingList::new(), &mut context).is_err());
+        assert_eq!(context.globals.raw_value_of("$sdo_abort"), Some("int:100794368"));
+    }
+}
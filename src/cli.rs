@@ -0,0 +1,710 @@
+// 命令行工具logic的实现（src/bin/logic.rs），不需要编写Rust代码即可运行脚本
+
+use engine::{Engine, Context};
+use variable::VarBindingList;
+use validate::Severity;
+use pretty::{fn_header, ins_header, with_note};
+use bus::{Bus, DumpBus, DumpFormat};
+use repl::{Repl, run_repl};
+use trace::{Trace, record};
+use coverage::Coverage;
+use profile::{Profiler, Clock};
+#[cfg(target_os = "linux")]
+use socketcan::SocketCan;
+#[cfg(feature = "sqlite")]
+use storage::Storage;
+use script::parse_script;
+use utils::{split_lr, parse_int};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "\
+Usage:
+  logic run FN [--arg NAME=VALUE]... [--bus BUS] [--trace PATH] [--coverage PATH]
+               [--profile PATH] [--fuel N] [--timeout MS] [--dry-run] FILE...
+  logic replay TRACE FILE...  re-run a trace recorded by run --trace and check it matches
+  logic coverage [--lcov] COVERAGE FILE...  report statement coverage collected by run --coverage
+  logic validate FILE...
+  logic list FILE...
+  logic repl [--bus BUS] [FILE...]
+  logic dap                  Debug Adapter Protocol server on stdin/stdout (dap feature)
+  logic lsp                  Language Server Protocol server on stdin/stdout (lsp feature)
+
+FILE       script, DBC (*.dbc), JSON (*.json, serde feature) or SQLite library (*.db, sqlite feature)
+--arg      argument of FN; VALUE may be prefixed (int: float: str: hex:), otherwise it is
+           read as in scripts: 10, 0x1F, -1.5, text
+--bus      where frames go: stdout (candump format, default for run), log:PATH (candump log
+           file) or socketcan:IFACE (Linux); repl always shows the frames it sends
+--trace    record executed statements, variable writes and frames to PATH
+--coverage count executed statements and add them to the coverage file PATH
+--profile  write call stack wall times (folded format for flamegraphs, microseconds) to PATH
+           and print calls, statements, times and frames per fn and instruction; virtual
+           time is the thread CPU time on Linux and falls back to wall time elsewhere
+--lcov     print the coverage report in lcov format
+--fuel     stop with an error after executing N statements
+--timeout  stop with an error after MS milliseconds
+--dry-run  load, validate and compile FN without running it
+
+Exit status: 0 success, 1 script or execution error, 2 usage error";
+
+/// 退出码：成功
+pub const EXIT_OK: i32 = 0;
+/// 退出码：脚本错误（加载、检查、编译或执行失败）
+pub const EXIT_ERROR: i32 = 1;
+/// 退出码：命令行参数错误
+pub const EXIT_USAGE: i32 = 2;
+
+enum CliError {
+    Usage(String),
+    Script(String),
+}
+
+struct Options {
+    command: String,
+    fn_name: String,
+    args: VarBindingList,
+    bus: Option<String>,
+    trace: Option<String>,
+    coverage: Option<String>,
+    profile: Option<String>,
+    fuel: Option<u64>,
+    timeout: Option<u64>,
+    lcov: bool,
+    dry_run: bool,
+    files: Vec<String>,
+}
+
+/// 执行命令行（args不包括程序名称），返回退出码；正常输出写到out，错误写到err
+pub fn run_cli(args: &[String], out: &mut dyn Write, err: &mut dyn Write) -> i32 {
+    let result = parse_options(args).and_then(|options| match options.command.as_str() {
+        "run" => run(&options, out, err),
+        "replay" => replay(&options, out),
+        "coverage" => coverage(&options, out),
+        "validate" => validate(&options, out),
+        "list" => list(&options, out),
+        "repl" => repl(&options),
+        "dap" => dap(),
+        "lsp" => lsp(),
+        _ => {
+            let _ = writeln!(out, "{}", USAGE);
+            Ok(EXIT_OK)
+        }
+    });
+    match result {
+        Ok(code) => code,
+        Err(CliError::Usage(message)) => {
+            let _ = writeln!(err, "error: {}\n\n{}", message, USAGE);
+            EXIT_USAGE
+        }
+        Err(CliError::Script(message)) => {
+            let _ = writeln!(err, "error: {}", message);
+            EXIT_ERROR
+        }
+    }
+}
+
+fn parse_options(args: &[String]) -> Result<Options, CliError> {
+    let mut options = Options {
+        command: args.first().cloned().unwrap_or_default(),
+        fn_name: String::new(),
+        args: VarBindingList::new(),
+        bus: None,
+        trace: None,
+        coverage: None,
+        profile: None,
+        fuel: None,
+        timeout: None,
+        lcov: false,
+        dry_run: false,
+        files: Vec::new(),
+    };
+    match options.command.as_str() {
+        "run" | "replay" | "coverage" | "validate" | "list" | "repl" => {}
+        "dap" | "lsp" if args.len() > 1 => return Err(CliError::Usage(format!("{} takes no arguments", options.command))),
+        "dap" | "lsp" => return Ok(options),
+        "" | "help" | "-h" | "--help" => {
+            options.command = "help".to_string();
+            return Ok(options);
+        }
+        command => return Err(CliError::Usage(format!("unknown command: {}", command))),
+    }
+    let run = options.command == "run";
+    let repl = options.command == "repl";
+    let mut positionals = Vec::new();
+    let mut iter = args[1..].iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--arg" if !run => return Err(CliError::Usage(format!("{} is only for run", arg))),
+            "--bus" if !run && !repl => return Err(CliError::Usage(format!("{} is only for run and repl", arg))),
+            "--trace" | "--coverage" | "--profile" | "--fuel" | "--timeout" if !run => return Err(CliError::Usage(format!("{} is only for run", arg))),
+            "--lcov" if options.command != "coverage" => return Err(CliError::Usage(format!("{} is only for coverage", arg))),
+            "--arg" => {
+                let binding = iter.next().ok_or_else(|| CliError::Usage("--arg requires NAME=VALUE".to_string()))?;
+                let (name, value) = split_lr(binding, "=");
+                if name.is_empty() || !binding.contains('=') {
+                    return Err(CliError::Usage(format!("invalid --arg: {}", binding)));
+                }
+                options.args.set_binding(name, &arg_value(value));
+            }
+            "--bus" => {
+                let bus = iter.next().ok_or_else(|| CliError::Usage("--bus requires a value".to_string()))?;
+                if bus_kind(bus).is_none() {
+                    return Err(CliError::Usage(format!("invalid --bus: {}", bus)));
+                }
+                options.bus = Some(bus.clone());
+            }
+            "--trace" => {
+                let path = iter.next().ok_or_else(|| CliError::Usage("--trace requires a path".to_string()))?;
+                options.trace = Some(path.clone());
+            }
+            "--coverage" => {
+                let path = iter.next().ok_or_else(|| CliError::Usage("--coverage requires a path".to_string()))?;
+                options.coverage = Some(path.clone());
+            }
+            "--profile" => {
+                let path = iter.next().ok_or_else(|| CliError::Usage("--profile requires a path".to_string()))?;
+                options.profile = Some(path.clone());
+            }
+            "--fuel" | "--timeout" => {
+                let value = iter.next().ok_or_else(|| CliError::Usage(format!("{} requires a number", arg)))?;
+                let value = parse_int(value).map_err(|_| CliError::Usage(format!("invalid {}: {}", arg, value)))?;
+                if arg == "--fuel" {
+                    options.fuel = Some(value);
+                } else {
+                    options.timeout = Some(value);
+                }
+            }
+            "--lcov" => options.lcov = true,
+            "--dry-run" if run => options.dry_run = true,
+            option if option.starts_with('-') => return Err(CliError::Usage(format!("unknown option: {}", option))),
+            _ => positionals.push(arg.clone()),
+        }
+    }
+    if run {
+        if positionals.is_empty() {
+            return Err(CliError::Usage("missing FN".to_string()));
+        }
+        options.fn_name = positionals.remove(0);
+    }
+    if options.command == "replay" {
+        if positionals.is_empty() {
+            return Err(CliError::Usage("missing TRACE".to_string()));
+        }
+        options.trace = Some(positionals.remove(0));
+    }
+    if options.command == "coverage" {
+        if positionals.is_empty() {
+            return Err(CliError::Usage("missing COVERAGE".to_string()));
+        }
+        options.coverage = Some(positionals.remove(0));
+    }
+    if positionals.is_empty() && !repl {
+        return Err(CliError::Usage("missing FILE".to_string()));
+    }
+    options.files = positionals;
+    Ok(options)
+}
+
+/// 将命令行中的参数值转换为带前缀的值，如"10" -> "int:10", "1.5" -> "float:1.5", "abc" -> "str:abc"
+pub fn arg_value(value: &str) -> String {
+    if ["int:", "float:", "str:", "hex:", "var:"].iter().any(|prefix| value.starts_with(prefix)) {
+        return value.to_string();
+    }
+    let (negative, number) = match value.strip_prefix('-') {
+        Some(number) => (true, number),
+        None => (false, value),
+    };
+    if let Ok(number) = parse_int(number) {
+        return format!("int:{}{}", if negative { "-" } else { "" }, number);
+    }
+    if value.contains('.') && value.parse::<f64>().is_ok() {
+        return format!("float:{}", value);
+    }
+    format!("str:{}", value)
+}
+
+// 加载文件中的定义
+fn load(files: &[String], context: &mut Context) -> Result<Engine, CliError> {
+    let mut engine = Engine::new();
+    load_files(&mut engine, files, context).map_err(CliError::Script)?;
+    Ok(engine)
+}
+
+/// 按文件扩展名加载定义：*.dbc、*.json、*.db（SQLite函数库），其他为脚本；错误信息包括文件名
+pub fn load_files(engine: &mut Engine, files: &[String], context: &mut Context) -> Result<(), String> {
+    for path in files {
+        let extension = Path::new(path).extension().and_then(|ext| ext.to_str()).unwrap_or("").to_lowercase();
+        let error = |err: String| format!("{}: {}", path, err);
+        if extension == "db" {
+            load_storage(engine, path).map_err(error)?;
+            continue;
+        }
+        let text = fs::read_to_string(path).map_err(|err| error(err.to_string()))?;
+        match extension.as_str() {
+            "dbc" => engine.import_dbc(&text, context).map(|_| ()),
+            "json" => load_json(engine, &text),
+            _ => engine.load_script(&text, context),
+        }.map_err(error)?;
+    }
+    Ok(())
+}
+
+/// 脚本中定义的函数 -> 脚本的路径；后加载的同名函数替换先加载的，其他格式的文件被忽略
+pub fn fn_sources(files: &[String]) -> HashMap<String, String> {
+    let mut sources = HashMap::new();
+    for path in files {
+        let extension = Path::new(path).extension().and_then(|ext| ext.to_str()).unwrap_or("").to_lowercase();
+        if ["dbc", "json", "db"].contains(&extension.as_str()) {
+            continue;
+        }
+        if let Some(script) = fs::read_to_string(path).ok().and_then(|text| parse_script(&text).ok()) {
+            for fndef in script.fns {
+                sources.insert(fndef.name, path.clone());
+            }
+        }
+    }
+    sources
+}
+
+#[cfg(feature = "serde")]
+fn load_json(engine: &mut Engine, text: &str) -> Result<(), String> {
+    let loaded = Engine::from_json(text)?;
+    engine.inss.extend(loaded.inss);
+    engine.fns.extend(loaded.fns);
+    Ok(())
+}
+
+#[cfg(not(feature = "serde"))]
+fn load_json(engine: &mut Engine, text: &str) -> Result<(), String> {
+    Err("JSON files require the serde feature".to_string())
+}
+
+// 加载函数库中全部的指令和函数
+#[cfg(feature = "sqlite")]
+fn load_storage(engine: &mut Engine, path: &str) -> Result<(), String> {
+    let storage = Storage::open(path)?;
+    for insdef in storage.load_inss()? {
+        engine.add_ins(insdef);
+    }
+    for record in storage.list_fns()? {
+        engine.add_fn(storage.load_fn(&record.name)?.ok_or_else(|| format!("No such fn: {}", record.name))?);
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn load_storage(engine: &mut Engine, path: &str) -> Result<(), String> {
+    Err("SQLite libraries require the sqlite feature".to_string())
+}
+
+// (类型, 目标)，如"log:a.log" -> ("log", "a.log")；不支持的写法返回None
+fn bus_kind(bus: &str) -> Option<(&str, &str)> {
+    match split_lr(bus, ":") {
+        ("", "stdout") => Some(("stdout", "")),
+        ("log", target) if !target.is_empty() => Some(("log", target)),
+        ("socketcan", target) if !target.is_empty() && cfg!(target_os = "linux") => Some(("socketcan", target)),
+        _ => None,
+    }
+}
+
+/// 打开总线：stdout（candump格式）、log:PATH（candump日志文件）、socketcan:IFACE（Linux）
+pub fn open_bus(bus: &str) -> Result<Box<dyn Bus>, String> {
+    match bus_kind(bus) {
+        Some(("stdout", _)) => Ok(Box::new(DumpBus::new(Box::new(io::stdout()), DumpFormat::Text))),
+        Some(("log", path)) => {
+            let file = File::create(path).map_err(|err| format!("{}: {}", path, err))?;
+            Ok(Box::new(DumpBus::new(Box::new(file), DumpFormat::Log)))
+        }
+        #[cfg(target_os = "linux")]
+        Some(("socketcan", interface)) => Ok(Box::new(SocketCan::open(interface)?)),
+        _ => Err(format!("Invalid bus: {}", bus)),
+    }
+}
+
+// 输出检查结果，返回错误的数量
+fn report(engine: &Engine, context: &Context, err: &mut dyn Write) -> usize {
+    let diagnostics = engine.validate(context);
+    for diagnostic in &diagnostics {
+        let _ = writeln!(err, "{}", diagnostic);
+    }
+    diagnostics.iter().filter(|diagnostic| diagnostic.severity == Severity::Error).count()
+}
+
+fn run(options: &Options, out: &mut dyn Write, err: &mut dyn Write) -> Result<i32, CliError> {
+    let mut context = Context::new();
+    let engine = load(&options.files, &mut context)?;
+    let errors = report(&engine, &context, err);
+    if errors > 0 {
+        return Err(CliError::Script(format!("{} error(s) found, not running", errors)));
+    }
+    let program = engine.compile(&options.fn_name).map_err(CliError::Script)?;
+    if options.dry_run {
+        let _ = writeln!(out, "dry run: fn {} is ready to run", options.fn_name);
+        return Ok(EXIT_OK);
+    }
+    context.bus = Some(open_bus(options.bus.as_deref().unwrap_or("stdout")).map_err(CliError::Script)?);
+    if options.coverage.is_some() {
+        context.coverage = Some(Coverage::new());
+    }
+    if options.profile.is_some() {
+        context.profiler = Some(Profiler::new());
+    }
+    context.fuel = options.fuel;
+    context.deadline = options.timeout.map(|timeout| Instant::now() + Duration::from_millis(timeout));
+    // 跟踪、覆盖率和性能分析由解释执行记录
+    let result = match options.trace {
+        Some(ref path) => {
+            let trace = record(&engine, &options.fn_name, &options.args, &mut context);
+            fs::write(path, trace.to_text()).map_err(|err| CliError::Script(format!("{}: {}", path, err)))?;
+            trace.result
+        }
+        None if options.coverage.is_some() || options.profile.is_some() => engine.exec_fn(&options.fn_name, &options.args, &mut context),
+        None => program.exec(&options.args, &mut context),
+    };
+    // 执行失败时也保存覆盖率和性能分析的结果
+    if let (Some(path), Some(coverage)) = (options.coverage.as_ref(), context.coverage.take()) {
+        save_coverage(path, coverage).map_err(|err| CliError::Script(format!("{}: {}", path, err)))?;
+    }
+    if let (Some(path), Some(profiler)) = (options.profile.as_ref(), context.profiler.take()) {
+        fs::write(path, profiler.profile.to_folded(Clock::Wall)).map_err(|err| CliError::Script(format!("{}: {}", path, err)))?;
+        let _ = write!(err, "{}", profiler.profile.report());
+    }
+    result.map_err(CliError::Script)?;
+    if let Some(value) = context.globals.raw_value_of("$return") {
+        let _ = writeln!(out, "return: {}", value);
+    }
+    Ok(EXIT_OK)
+}
+
+fn replay(options: &Options, out: &mut dyn Write) -> Result<i32, CliError> {
+    let path = options.trace.as_deref().unwrap_or("");
+    let trace = fs::read_to_string(path).map_err(|err| err.to_string())
+        .and_then(|text| Trace::parse(&text))
+        .map_err(|err| CliError::Script(format!("{}: {}", path, err)))?;
+    let mut context = Context::new();
+    let engine = load(&options.files, &mut context)?;
+    let events = ::trace::replay(&engine, &trace, &mut context).map_err(CliError::Script)?;
+    let _ = writeln!(out, "replay of fn {} matches: {} event(s)", trace.fn_name, events);
+    Ok(EXIT_OK)
+}
+
+// 将覆盖率加到文件中已有的结果上
+fn save_coverage(path: &str, mut coverage: Coverage) -> Result<(), String> {
+    if Path::new(path).exists() {
+        let saved = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        coverage.merge(&Coverage::parse(&saved)?);
+    }
+    fs::write(path, coverage.to_text()).map_err(|err| err.to_string())
+}
+
+fn coverage(options: &Options, out: &mut dyn Write) -> Result<i32, CliError> {
+    let path = options.coverage.as_deref().unwrap_or("");
+    let coverage = fs::read_to_string(path).map_err(|err| err.to_string())
+        .and_then(|text| Coverage::parse(&text))
+        .map_err(|err| CliError::Script(format!("{}: {}", path, err)))?;
+    let engine = load(&options.files, &mut Context::new())?;
+    let sources = fn_sources(&options.files);
+    let report = if options.lcov { coverage.lcov(&engine, &sources) } else { coverage.report(&engine, &sources) };
+    write!(out, "{}", report).map_err(|err| CliError::Script(err.to_string()))?;
+    Ok(EXIT_OK)
+}
+
+fn validate(options: &Options, out: &mut dyn Write) -> Result<i32, CliError> {
+    let mut context = Context::new();
+    let engine = load(&options.files, &mut context)?;
+    let errors = report(&engine, &context, out);
+    let _ = writeln!(out, "{} fn(s) checked, {} error(s)", engine.fns.len(), errors);
+    Ok(if errors > 0 { EXIT_ERROR } else { EXIT_OK })
+}
+
+fn list(options: &Options, out: &mut dyn Write) -> Result<i32, CliError> {
+    let engine = load(&options.files, &mut Context::new())?;
+    let mut inss: Vec<String> = engine.inss.values().map(|insdef| with_note(ins_header(insdef), &insdef.note)).collect();
+    inss.sort();
+    let mut fns: Vec<String> = engine.fns.values().map(|fndef| with_note(fn_header(fndef), &fndef.note)).collect();
+    fns.sort();
+    for line in inss.iter().chain(fns.iter()) {
+        writeln!(out, "{}", line).map_err(|err| CliError::Script(err.to_string()))?;
+    }
+    Ok(EXIT_OK)
+}
+
+fn repl(options: &Options) -> Result<i32, CliError> {
+    let mut context = Context::new();
+    let engine = load(&options.files, &mut context)?;
+    if let Some(ref bus) = options.bus {
+        context.bus = Some(open_bus(bus).map_err(CliError::Script)?);
+    }
+    let mut repl = Repl::new(engine, context);
+    println!("logic repl: {} fn(s), {} ins(s); :help for help", repl.engine.fns.len(), repl.engine.inss.len());
+    run_repl(&mut repl);
+    Ok(EXIT_OK)
+}
+
+#[cfg(feature = "dap")]
+fn dap() -> Result<i32, CliError> {
+    let stdin = io::stdin();
+    ::dap::serve(&mut stdin.lock(), &mut io::stdout()).map_err(CliError::Script)?;
+    Ok(EXIT_OK)
+}
+
+#[cfg(not(feature = "dap"))]
+fn dap() -> Result<i32, CliError> {
+    Err(CliError::Script("logic dap requires the dap feature".to_string()))
+}
+
+#[cfg(feature = "lsp")]
+fn lsp() -> Result<i32, CliError> {
+    let stdin = io::stdin();
+    ::lsp::serve(&mut stdin.lock(), &mut io::stdout()).map_err(CliError::Script)?;
+    Ok(EXIT_OK)
+}
+
+#[cfg(not(feature = "lsp"))]
+fn lsp() -> Result<i32, CliError> {
+    Err(CliError::Script("logic lsp requires the lsp feature".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run_cli, arg_value, EXIT_OK, EXIT_ERROR, EXIT_USAGE};
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    const SCRIPT: &str = r#"
+// 车灯
+ins lamp(a: u32, b: u32 = 1) = 0x321
+
+fn blink(n: i32) {
+    loop 2 {
+        send lamp(a: n)
+    }
+    return n
+}
+
+// 有错误
+fn broken() {
+    send none()
+}
+"#;
+
+    fn temp_file(name: &str, text: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("logic-cli-{}-{}", ::std::process::id(), name));
+        fs::write(&path, text).expect("ok");
+        path
+    }
+
+    // 返回(退出码, 输出, 错误输出)
+    fn cli(args: &[&str]) -> (i32, String, String) {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        let code = run_cli(&args, &mut out, &mut err);
+        (code, String::from_utf8(out).expect("utf8"), String::from_utf8(err).expect("utf8"))
+    }
+
+    #[test]
+    fn test_list_validate() {
+        let script = temp_file("list.logic", SCRIPT);
+        let script = script.to_str().expect("utf8");
+        let (code, out, _) = cli(&["list", script]);
+        assert_eq!(code, EXIT_OK);
+        assert_eq!(out, "ins lamp(a: u32, b: u32 = 1) = 0x321  // 车灯\nfn blink(n: i32)\nfn broken()  // 有错误\n");
+
+        let (code, out, _) = cli(&["validate", script]);
+        assert_eq!(code, EXIT_ERROR);
+        assert_eq!(out, "error: fn broken #0 (line 14, col 5): No such ins: none\n2 fn(s) checked, 1 error(s)\n");
+        let _ = fs::remove_file(script);
+    }
+
+    #[test]
+    fn test_run() {
+        let script = temp_file("run.logic", SCRIPT.replace("send none()", "").as_str());
+        let script = script.to_str().expect("utf8");
+        let log = env::temp_dir().join(format!("logic-cli-{}-run.log", ::std::process::id()));
+        let log = log.to_str().expect("utf8");
+        let bus = format!("log:{}", log);
+
+        let (code, out, err) = cli(&["run", "blink", "--dry-run", "--bus", &bus, script]);
+        assert_eq!((code, out.as_str(), err.as_str()), (EXIT_OK, "dry run: fn blink is ready to run\n", ""));
+        assert!(fs::metadata(log).is_err()); // 没有执行
+
+        let (code, out, _) = cli(&["run", "blink", "--arg", "n=0x10", "--bus", &bus, script]);
+        assert_eq!((code, out.as_str()), (EXIT_OK, "return: int:16\n"));
+        let frames: Vec<String> = fs::read_to_string(log).expect("ok").lines()
+            .map(|line| line.split(' ').skip(1).collect::<Vec<_>>().join(" ")).collect();
+        assert_eq!(frames, vec!["can0 321#0000001000000001", "can0 321#0000001000000001"]);
+
+        let (code, _, err) = cli(&["run", "none", script]);
+        assert_eq!((code, err.as_str()), (EXIT_ERROR, "error: No such fn: none\n"));
+        // 参数值无法编码时执行出错（而不是退出进程）
+        let (code, _, err) = cli(&["run", "blink", "--arg", "n=-1", "--bus", &bus, script]);
+        assert_eq!((code, err.as_str()), (EXIT_ERROR, "error: Invalid value of arg a: -1\n"));
+        let (code, _, err) = cli(&["run", "blink", "--arg", "n=on", "--bus", &bus, script]);
+        assert_eq!((code, err.as_str()), (EXIT_ERROR, "error: Invalid value of arg a: on\n"));
+        let short = temp_file("short.logic", "ins lamp(a: u32) = 0x321\nins text(s: str) = 0x322\n\
+                                              fn f() {\n    send lamp(a: 1)\n    send text(s: \"a\")\n}\n");
+        let (code, _, err) = cli(&["run", "f", "--bus", &bus, short.to_str().expect("utf8")]);
+        assert_eq!(code, EXIT_ERROR);
+        assert_eq!(err.lines().collect::<Vec<_>>(), vec![
+            "error: fn f #0 (line 4, col 5): Invalid layout of ins lamp: Args take 4 bytes, requires 8",
+            "error: fn f #1 (line 5, col 5): Invalid layout of ins text: Unsupport arg type: str",
+            "error: 2 error(s) found, not running",
+        ]);
+        let _ = fs::remove_file(short);
+        let _ = fs::remove_file(script);
+        let _ = fs::remove_file(log);
+    }
+
+    #[test]
+    fn test_trace_replay() {
+        let script = temp_file("trace.logic", SCRIPT.replace("send none()", "").as_str());
+        let script = script.to_str().expect("utf8");
+        let trace = env::temp_dir().join(format!("logic-cli-{}.trace", ::std::process::id()));
+        let trace = trace.to_str().expect("utf8");
+        let log = env::temp_dir().join(format!("logic-cli-{}-trace.log", ::std::process::id()));
+        let bus = format!("log:{}", log.to_str().expect("utf8"));
+
+        let (code, out, _) = cli(&["run", "blink", "--arg", "n=2", "--bus", &bus, "--trace", trace, script]);
+        assert_eq!((code, out.as_str()), (EXIT_OK, "return: int:2\n"));
+        assert_eq!(fs::read_to_string(&log).expect("ok").lines().count(), 2);
+        let (code, out, _) = cli(&["replay", trace, script]);
+        assert_eq!((code, out.as_str()), (EXIT_OK, "replay of fn blink matches: 11 event(s)\n"));
+
+        let changed = temp_file("changed.logic", SCRIPT.replace("send none()", "").replace("loop 2", "loop 3").as_str());
+        let (code, _, err) = cli(&["replay", trace, changed.to_str().expect("utf8")]);
+        assert_eq!((code, err.as_str()), (EXIT_ERROR, "error: Replay diverges at event 10: expected S\tblink\t3\tReturn, found S\tblink\t1\tCallIns\n"));
+        let (code, _, err) = cli(&["replay", script, script]);
+        assert_eq!((code, err), (EXIT_ERROR, format!("error: {}: Not a trace file\n", script)));
+        assert_eq!(cli(&["replay"]).0, EXIT_USAGE);
+        assert_eq!(cli(&["validate", "--trace", trace, script]).0, EXIT_USAGE);
+        let _ = fs::remove_file(script);
+        let _ = fs::remove_file(changed);
+        let _ = fs::remove_file(trace);
+        let _ = fs::remove_file(log);
+    }
+
+    #[test]
+    fn test_coverage() {
+        let script = temp_file("coverage.logic", SCRIPT);
+        let script = script.to_str().expect("utf8");
+        let coverage = env::temp_dir().join(format!("logic-cli-{}.coverage", ::std::process::id()));
+        let coverage = coverage.to_str().expect("utf8");
+        let log = env::temp_dir().join(format!("logic-cli-{}-coverage.log", ::std::process::id()));
+        let bus = format!("log:{}", log.to_str().expect("utf8"));
+        let _ = fs::remove_file(coverage);
+
+        // 有错误的脚本不执行；两次执行的结果相加
+        assert_eq!(cli(&["run", "blink", "--coverage", coverage, script]).0, EXIT_ERROR);
+        assert!(fs::metadata(coverage).is_err());
+        let fixed = temp_file("coverage-fixed.logic", SCRIPT.replace("send none()", "").as_str());
+        let fixed = fixed.to_str().expect("utf8");
+        for _ in 0..2 {
+            let (code, out, _) = cli(&["run", "blink", "--arg", "n=1", "--bus", &bus, "--coverage", coverage, fixed]);
+            assert_eq!((code, out.as_str()), (EXIT_OK, "return: int:1\n"));
+        }
+        let (code, out, _) = cli(&["coverage", coverage, fixed]);
+        assert_eq!(code, EXIT_OK);
+        assert_eq!(out, format!("fn blink  {}:5  4/4 statement(s) (100.0%), 2 call(s)\n\
+                                 fn broken  {}:13  0/0 statement(s) (-), never called\n\
+                                 total: 4/4 statement(s) (100.0%), 1/2 fn(s) called\n", fixed, fixed));
+        let (code, out, _) = cli(&["coverage", "--lcov", coverage, fixed]);
+        assert_eq!(code, EXIT_OK);
+        assert!(out.starts_with(&format!("TN:\nSF:{}\nFN:5,blink\nFN:13,broken\nFNDA:2,blink\n", fixed)));
+        assert!(out.contains("BRDA:6,0,0,4\nBRDA:6,0,1,2\n"));
+
+        assert_eq!(cli(&["coverage", script, fixed]).2, format!("error: {}: Not a coverage file\n", script));
+        assert_eq!(cli(&["coverage"]).0, EXIT_USAGE);
+        assert_eq!(cli(&["list", "--lcov", fixed]).0, EXIT_USAGE);
+        assert_eq!(cli(&["list", "--coverage", coverage, fixed]).0, EXIT_USAGE);
+        for path in [script, fixed, coverage] {
+            let _ = fs::remove_file(path);
+        }
+        let _ = fs::remove_file(log);
+    }
+
+    #[test]
+    fn test_profile() {
+        let script = temp_file("profile.logic", SCRIPT.replace("send none()", "").as_str());
+        let script = script.to_str().expect("utf8");
+        let folded = env::temp_dir().join(format!("logic-cli-{}.folded", ::std::process::id()));
+        let folded = folded.to_str().expect("utf8");
+        let log = env::temp_dir().join(format!("logic-cli-{}-profile.log", ::std::process::id()));
+        let bus = format!("log:{}", log.to_str().expect("utf8"));
+
+        let (code, out, err) = cli(&["run", "blink", "--arg", "n=1", "--bus", &bus, "--profile", folded, script]);
+        assert_eq!((code, out.as_str()), (EXIT_OK, "return: int:1\n"));
+        let rows: Vec<Vec<&str>> = err.lines().map(|line| line.split_whitespace().collect()).collect();
+        assert_eq!(rows[0], vec!["fn", "calls", "stmts", "wall", "self", "virtual", "self"]);
+        assert_eq!(rows[1][..3], ["blink", "1", "8"]);
+        assert_eq!(rows[3], vec!["ins", "calls", "frames", "bytes", "wall", "virtual"]);
+        assert_eq!(rows[4][..4], ["lamp", "2", "2", "16"]);
+        // 每行是调用栈和微秒数
+        let stacks: Vec<String> = fs::read_to_string(folded).expect("folded").lines().map(|line| {
+            let (stack, micros) = line.split_at(line.rfind(' ').expect("space"));
+            micros.trim().parse::<u64>().expect("micros");
+            stack.to_string()
+        }).collect();
+        assert_eq!(stacks, vec!["blink", "blink;lamp"]);
+        assert_eq!(cli(&["list", "--profile", folded, script]).0, EXIT_USAGE);
+        let _ = fs::remove_file(script);
+        let _ = fs::remove_file(folded);
+        let _ = fs::remove_file(log);
+    }
+
+    #[test]
+    fn test_budget() {
+        let script = temp_file("budget.logic", SCRIPT.replace("send none()", "").as_str());
+        let script = script.to_str().expect("utf8");
+        let log = env::temp_dir().join(format!("logic-cli-{}-budget.log", ::std::process::id()));
+        let bus = format!("log:{}", log.to_str().expect("utf8"));
+
+        let (code, _, err) = cli(&["run", "blink", "--arg", "n=1", "--bus", &bus, "--fuel", "3", script]);
+        assert_eq!((code, err.as_str()), (EXIT_ERROR, "error: Statement budget exhausted at blink #0\n"));
+        let (code, out, _) = cli(&["run", "blink", "--arg", "n=1", "--bus", &bus, "--fuel", "8", "--timeout", "10000", script]);
+        assert_eq!((code, out.as_str()), (EXIT_OK, "return: int:1\n"));
+        assert_eq!(cli(&["run", "blink", "--fuel", "many", script]).0, EXIT_USAGE);
+        assert_eq!(cli(&["list", "--timeout", "10", script]).0, EXIT_USAGE);
+        let _ = fs::remove_file(script);
+        let _ = fs::remove_file(log);
+    }
+
+    #[test]
+    fn test_errors() {
+        let (code, _, err) = cli(&["run", "blink"]);
+        assert_eq!(code, EXIT_USAGE);
+        assert!(err.starts_with("error: missing FILE\n\nUsage:"));
+        assert_eq!(cli(&["test"]).0, EXIT_USAGE);
+        assert_eq!(cli(&["list", "--arg", "a=1", "x"]).0, EXIT_USAGE);
+        assert_eq!(cli(&["run", "f", "--arg", "a", "x"]).0, EXIT_USAGE);
+        assert_eq!(cli(&["run", "f", "--verbose", "x"]).0, EXIT_USAGE);
+        assert_eq!(cli(&["help"]).0, EXIT_OK);
+
+        let (code, _, err) = cli(&["list", "/nonexistent/a.logic"]);
+        assert_eq!(code, EXIT_ERROR);
+        assert!(err.starts_with("error: /nonexistent/a.logic: "));
+        let script = temp_file("bad.logic", "fn f( {");
+        let script = script.to_str().expect("utf8");
+        let (code, _, err) = cli(&["validate", script]);
+        assert_eq!((code, err), (EXIT_ERROR, format!("error: {}: Invalid script: line 1, col 7: expected parameter name, found '{{'\n", script)));
+        let script_ok = temp_file("bus.logic", "fn f() { }");
+        let (code, _, err) = cli(&["run", "f", "--bus", "can", script_ok.to_str().expect("utf8")]);
+        assert_eq!((code, err.lines().next()), (EXIT_USAGE, Some("error: invalid --bus: can")));
+        let _ = fs::remove_file(script);
+        let _ = fs::remove_file(script_ok);
+    }
+
+    #[test]
+    fn test_arg_value() {
+        assert_eq!(arg_value("10"), "int:10");
+        assert_eq!(arg_value("0x1F"), "int:31");
+        assert_eq!(arg_value("-3"), "int:-3");
+        assert_eq!(arg_value("-1.5"), "float:-1.5");
+        assert_eq!(arg_value("on"), "str:on");
+        assert_eq!(arg_value("hex:01 02"), "hex:01 02");
+        assert_eq!(arg_value(""), "str:");
+    }
+}
@@ -0,0 +1,577 @@
+// 将函数编译为字节码（Op序列）以加快执行，见Engine::compile()
+//
+// 与FnDef::exec()逐条解释语句相比，编译时：
+// - 循环的跳转目标预先算好，循环计数保存在调用帧中（递归调用、在循环中return都不影响下一次调用）
+// - 局部变量分配固定的槽位，不再按名称查找（值为'var:name'的变量链仍然按名称求值）
+// - 被调用的函数和指令预先解析为索引和引用
+// - SetVar的变量名和运算符预先解析，不再每次求值
+// 执行结果与FnDef::exec()相同；诊断和CANopen语句仍由uds::exec_diag()和canopen::exec_canopen()执行。
+// 编译时不存在的函数和指令，与解释执行一样在执行到调用时报告错误。
+
+use engine::{Engine, Context, exec_insdef};
+use function::{FnDef, x_op_y};
+use instruction::InsDef;
+use statement::{Stmt, StmtKind};
+use variable::VarBindingList;
+use utils::{split_lr, parse_int};
+use uds;
+use canopen;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// 操作数：常量或变量
+enum Operand {
+    Value(String),
+    Var { slot: usize, name: String },
+}
+
+enum Assign {
+    // =
+    Local,
+    // :=
+    Global,
+    // += -= *= /=，保存的是运算符(+ - * /)
+    Update(String),
+}
+
+enum Op {
+    // eval为true时参数是求值后的带类型前缀的值，调用指令前去掉类型前缀
+    CallIns { ins: usize, args: Vec<(String, Operand)>, eval: bool },
+    CallFn { callee: usize, args: Vec<(String, Operand)> },
+    // 调用不存在的函数或指令
+    CallMissing(String),
+    // 循环计数保存在调用帧的counters[counter]中；结束后跳到end之后
+    Loop { counter: usize, count: u32, end: usize },
+    EndLoop { begin: usize },
+    Return(Operand),
+    // name assign operand1 [op2 operand2]
+    SetVar { slot: usize, name: String, assign: Assign, operand1: Operand, op2: Option<String>, operand2: Option<Operand> },
+    SetLocal { slot: usize, value: String },
+    SetGlobal { name: String, value: String },
+    // 由函数中索引为n的原语句执行
+    Diag(usize),
+    CanOpen(usize),
+}
+
+struct CompiledFn {
+    fndef: Rc<FnDef>,
+    // 与fndef.stmts一一对应
+    ops: Vec<Op>,
+    // 局部变量的名称，按槽位排列
+    slots: Vec<String>,
+    slot_of: HashMap<String, usize>,
+    counters: usize,
+}
+
+// 调用帧
+struct Frame {
+    locals: Vec<Option<String>>,
+    // 没有分配槽位的局部变量（调用者传入但函数中没有直接使用的参数）
+    extra: VarBindingList,
+    counters: Vec<u32>,
+}
+
+/// 编译后的函数（及其调用的函数），由Engine::compile()生成
+/// Program引用了Engine中的指令，Engine的函数或指令修改后需要重新编译
+pub struct Program<'a> {
+    inss: Vec<&'a InsDef>,
+    // fns[0]为入口函数
+    fns: Vec<CompiledFn>,
+    // 清理函数，见Engine.cleanup；函数不存在时为Err(函数名称)，执行时记录错误
+    cleanup: Option<Result<usize,String>>,
+}
+
+struct Compiler<'a> {
+    engine: &'a Engine,
+    inss: Vec<&'a InsDef>,
+    ins_index: HashMap<String, usize>,
+    fn_index: HashMap<String, usize>,
+    // 等待编译的函数
+    pending: Vec<Rc<FnDef>>,
+}
+
+impl<'a> Compiler<'a> {
+    fn ins(&mut self, name: &str) -> Option<usize> {
+        if let Some(index) = self.ins_index.get(name) {
+            return Some(*index);
+        }
+        let insdef = self.engine.find_ins(name)?;
+        self.inss.push(insdef);
+        self.ins_index.insert(name.to_string(), self.inss.len() - 1);
+        Some(self.inss.len() - 1)
+    }
+
+    // 从函数库读取失败时返回错误
+    fn callee(&mut self, name: &str) -> Result<Option<usize>,String> {
+        if let Some(index) = self.fn_index.get(name) {
+            return Ok(Some(*index));
+        }
+        let fndef = match self.engine.find_fn(name)? {
+            Some(fndef) => fndef,
+            None => return Ok(None),
+        };
+        self.pending.push(fndef);
+        self.fn_index.insert(name.to_string(), self.pending.len() - 1);
+        Ok(Some(self.pending.len() - 1))
+    }
+
+    fn compile_fn(&mut self, fndef: Rc<FnDef>) -> Result<CompiledFn,String> {
+        let mut slots = Slots { names: Vec::new(), index: HashMap::new() };
+        let mut ops = Vec::with_capacity(fndef.stmts.len());
+        let mut loops: Vec<usize> = Vec::new();
+        let mut counters = 0;
+        for (index, stmt) in fndef.stmts.iter().enumerate() {
+            let error = |message: &str| format!("Cannot compile fn {} #{}: {}", fndef.name, index, message);
+            let op = match stmt.kind {
+                StmtKind::CallIns => match self.ins(&stmt.content) {
+                    Some(ins) => Op::CallIns { ins, args: slots.args(stmt), eval: stmt.eval },
+                    None => Op::CallMissing(stmt.content.clone()),
+                },
+                StmtKind::CallFn => match self.callee(&stmt.content).map_err(|err| error(&err))? {
+                    Some(callee) => Op::CallFn { callee, args: slots.args(stmt) },
+                    None => Op::CallMissing(stmt.content.clone()),
+                },
+                StmtKind::Loop => {
+                    let count = stmt.args.raw_value_of("$count").unwrap_or("");
+                    let count = parse_int(count).ok().filter(|count| *count <= u32::MAX as u64)
+                        .ok_or_else(|| error(&format!("Invalid loop count: {}", count)))?;
+                    loops.push(index);
+                    counters += 1;
+                    Op::Loop { counter: counters - 1, count: count as u32, end: 0 }
+                }
+                StmtKind::EndLoop => {
+                    let begin = loops.pop().ok_or_else(|| error("Unpaired endloop"))?;
+                    if let Op::Loop { ref mut end, .. } = ops[begin] {
+                        *end = index;
+                    }
+                    Op::EndLoop { begin }
+                }
+                StmtKind::Return if stmt.eval => Op::Return(slots.operand(&stmt.content)),
+                StmtKind::Return => Op::Return(Operand::Value(stmt.content.clone())),
+                StmtKind::SetVar => self.compile_set_var(stmt, &mut slots).map_err(|err| error(&err))?,
+                StmtKind::SetLocal | StmtKind::SetGlobal => {
+                    let (name, value) = split_lr(&stmt.content, "=");
+                    if name.is_empty() {
+                        return Err(error(&format!("Invalid variable definition: {}", stmt.content)));
+                    }
+                    match stmt.kind {
+                        StmtKind::SetLocal => Op::SetLocal { slot: slots.slot(name), value: value.to_string() },
+                        _ => Op::SetGlobal { name: name.to_string(), value: value.to_string() },
+                    }
+                }
+                StmtKind::Diag => Op::Diag(index),
+                StmtKind::CanOpen => Op::CanOpen(index),
+            };
+            ops.push(op);
+        }
+        if let Some(index) = loops.pop() {
+            return Err(format!("Cannot compile fn {} #{}: Unclosed loop", fndef.name, index));
+        }
+        Ok(CompiledFn { fndef, ops, slots: slots.names, slot_of: slots.index, counters })
+    }
+
+    fn compile_set_var(&mut self, stmt: &Stmt, slots: &mut Slots) -> Result<Op,String> {
+        let operand = |name: &str| stmt.args.raw_value_of(name).ok_or_else(|| format!("Set var requires arg: {}", name));
+        // 变量名和运算符不能是变量
+        let arg = |name: &str| -> Result<&str,String> {
+            match operand(name)? {
+                value if value.starts_with("var:") => Err(format!("Unsupport variable as {}", name)),
+                value => Ok(value),
+            }
+        };
+        let name = arg("$varname")?;
+        let assign = match arg("$op1")? {
+            "=" => Assign::Local,
+            ":=" => Assign::Global,
+            op if op.len() == 2 && op.ends_with('=') => Assign::Update(op[..1].to_string()),
+            op => return Err(format!("Unsupport set var op: {}", op)),
+        };
+        let operand1 = slots.operand(operand("$operand1")?);
+        let op2 = if stmt.args.contains("$op2") { Some(arg("$op2")?.to_string()) } else { None };
+        let operand2 = stmt.args.raw_value_of("$operand2").map(|value| slots.operand(value));
+        Ok(Op::SetVar { slot: slots.slot(name), name: name.to_string(), assign, operand1, op2, operand2 })
+    }
+}
+
+// 局部变量槽位分配
+struct Slots {
+    names: Vec<String>,
+    index: HashMap<String, usize>,
+}
+
+impl Slots {
+    fn slot(&mut self, name: &str) -> usize {
+        if let Some(slot) = self.index.get(name) {
+            return *slot;
+        }
+        self.names.push(name.to_string());
+        self.index.insert(name.to_string(), self.names.len() - 1);
+        self.names.len() - 1
+    }
+
+    fn operand(&mut self, value: &str) -> Operand {
+        match split_lr(value, ":") {
+            ("var", name) => Operand::Var { slot: self.slot(name), name: name.to_string() },
+            _ => Operand::Value(value.to_string()),
+        }
+    }
+
+    // 按名称排序，保证参数求值的顺序稳定；stmt.eval为false时参数按原样传递
+    fn args(&mut self, stmt: &Stmt) -> Vec<(String, Operand)> {
+        let mut names: Vec<&String> = stmt.args.bindings.keys().collect();
+        names.sort();
+        names.into_iter().map(|name| {
+            let value = stmt.args.raw_value_of(name).unwrap_or("");
+            (name.clone(), if stmt.eval { self.operand(value) } else { Operand::Value(value.to_string()) })
+        }).collect()
+    }
+}
+
+/// 编译函数name及其（直接或间接）调用的函数
+pub fn compile<'a>(engine: &'a Engine, name: &str) -> Result<Program<'a>,String> {
+    let mut compiler = Compiler {
+        engine,
+        inss: Vec::new(),
+        ins_index: HashMap::new(),
+        fn_index: HashMap::new(),
+        pending: Vec::new(),
+    };
+    if compiler.callee(name)?.is_none() {
+        return Err(format!("No such fn: {}", name));
+    }
+    let cleanup = match engine.cleanup {
+        Some(ref cleanup) => Some(compiler.callee(cleanup)?.ok_or_else(|| cleanup.clone())),
+        None => None,
+    };
+    let mut fns = Vec::new();
+    while fns.len() < compiler.pending.len() {
+        let fndef = compiler.pending[fns.len()].clone();
+        fns.push(compiler.compile_fn(fndef)?);
+    }
+    Ok(Program { inss: compiler.inss, fns, cleanup })
+}
+
+impl<'a> Program<'a> {
+    /// 执行入口函数，与Engine::exec_fn()相同
+    pub fn exec(&self, args: &VarBindingList, context: &mut Context) -> Result<(),String> {
+        let result = self.run(0, args, context);
+        // 被取消时调用清理函数，同Engine::exec_cleanup()
+        if let Some(ref cleanup) = self.cleanup {
+            if result.is_err() && context.calls.is_empty() && context.is_cancelled() {
+                context.without_limits(|context| match *cleanup {
+                    Ok(index) => {
+                        let _ = self.run(index, &VarBindingList::new(), context);
+                    }
+                    Err(ref name) => context.log_error(&format!("No such fn: {}", name)),
+                });
+            }
+        }
+        result
+    }
+
+    fn run(&self, index: usize, args: &VarBindingList, context: &mut Context) -> Result<(),String> {
+        let func = &self.fns[index];
+        context.enter_call(&func.fndef.name)?;
+        let mut frame = Frame {
+            locals: vec![None; func.slots.len()],
+            extra: VarBindingList::new(),
+            counters: vec![0; func.counters],
+        };
+        for (name, binding) in &args.bindings {
+            match func.slot_of.get(name) {
+                Some(slot) => frame.locals[*slot] = args.raw_value_of(name).map(|value| value.to_string()),
+                None => frame.extra.add(binding.clone()),
+            }
+        }
+        context.globals.remove_binding("$return");
+
+        let mut result = Ok(());
+        let mut pc = 0;
+        while pc < func.ops.len() {
+            if let Some(call) = context.calls.last_mut() {
+                call.index = pc;
+            }
+            context.check_budget();
+            if let Some(ref err) = context.abort {
+                result = Err(err.clone());
+                break;
+            }
+            match func.ops[pc] {
+                Op::CallIns { ins, ref args, eval } => {
+                    result = self.eval_args(func, &frame, args, eval, context).and_then(|args| exec_insdef(self.inss[ins], &args, context));
+                }
+                Op::CallFn { callee, ref args } => {
+                    result = self.eval_args(func, &frame, args, false, context).and_then(|args| self.run(callee, &args, context));
+                }
+                Op::CallMissing(ref name) => {
+                    let err = format!("No such fn: {}", name);
+                    context.log_error(&err);
+                    result = Err(err);
+                }
+                Op::Loop { counter, count, end } => {
+                    if frame.counters[counter] < count {
+                        frame.counters[counter] += 1;
+                    } else {
+                        frame.counters[counter] = 0;
+                        pc = end + 1;
+                        continue;
+                    }
+                }
+                Op::EndLoop { begin } => {
+                    pc = begin;
+                    continue;
+                }
+                Op::Return(ref operand) => {
+                    match self.load_defined(func, &frame, operand, context) {
+                        Ok(value) => context.globals.set_binding("$return", &value),
+                        Err(err) => result = Err(err),
+                    }
+                    break;
+                }
+                Op::SetVar { slot, ref name, ref assign, ref operand1, ref op2, ref operand2 } => {
+                    let x = match self.load(func, &frame, operand1, context) {
+                        Some(x) => x,
+                        None => {
+                            context.log_error("Set var requires named args at least: varname, op1, operand1");
+                            pc += 1;
+                            continue;
+                        }
+                    };
+                    let y = operand2.as_ref().and_then(|operand2| self.load(func, &frame, operand2, context));
+                    let value = match (op2, y) {
+                        (Some(op2), Some(y)) => x_op_y(op2, &x, &y, context),
+                        (op2, y) => {
+                            if op2.is_some() || y.is_some() {
+                                context.log_error("Both $op2 and $operand2 are requried");
+                            }
+                            x
+                        }
+                    };
+                    match *assign {
+                        Assign::Local => set_local(&mut frame, slot, value),
+                        Assign::Global => context.globals.set_binding(name, &value),
+                        Assign::Update(ref op) => {
+                            let old = self.resolve(func, &frame, name, context).unwrap_or_default();
+                            let value = x_op_y(op, &old, &value, context);
+                            if frame.locals[slot].is_some() {
+                                set_local(&mut frame, slot, value);
+                            } else if context.globals.contains(name) {
+                                context.globals.set_binding(name, &value);
+                            } else {
+                                context.log_error(&format!("Assign to undefined var: {}", name));
+                            }
+                        }
+                    }
+                }
+                Op::SetLocal { slot, ref value } => set_local(&mut frame, slot, value.clone()),
+                Op::SetGlobal { ref name, ref value } => context.globals.set_binding(name, value),
+                Op::Diag(stmt) => {
+                    let locals = frame_locals(func, &frame);
+                    result = uds::exec_diag(&func.fndef.stmts[stmt], &locals, context);
+                    if let Err(ref err) = result {
+                        context.log_error(err);
+                    }
+                }
+                Op::CanOpen(stmt) => {
+                    let locals = frame_locals(func, &frame);
+                    result = canopen::exec_canopen(&func.fndef.stmts[stmt], &locals, context);
+                    if let Err(ref err) = result {
+                        context.log_error(err);
+                    }
+                }
+            }
+            pc += 1;
+        }
+        context.exit_call();
+        result
+    }
+
+    // 在调用处对参数求值，strip为true时去掉类型前缀；与FnDef::eval_args()相同，未定义的变量是错误
+    fn eval_args(&self, func: &CompiledFn, frame: &Frame, args: &[(String, Operand)], strip: bool, context: &mut Context) -> Result<VarBindingList,String> {
+        let mut values = VarBindingList::new();
+        for (name, operand) in args {
+            let value = self.load_defined(func, frame, operand, context)?;
+            values.set_binding(name, if strip { split_lr(&value, ":").1 } else { &value });
+        }
+        Ok(values)
+    }
+
+    fn load_defined(&self, func: &CompiledFn, frame: &Frame, operand: &Operand, context: &mut Context) -> Result<String,String> {
+        self.load(func, frame, operand, context).ok_or_else(|| {
+            let name = match *operand {
+                Operand::Var { ref name, .. } => name.as_str(),
+                Operand::Value(ref value) => split_lr(value, ":").1,
+            };
+            let err = format!("Undefined variable: {}", name);
+            context.log_error(&err);
+            err
+        })
+    }
+
+    fn load(&self, func: &CompiledFn, frame: &Frame, operand: &Operand, context: &Context) -> Option<String> {
+        match *operand {
+            Operand::Value(ref value) => Some(value.clone()),
+            Operand::Var { slot, ref name } => {
+                let value = frame.locals[slot].as_deref().or_else(|| context.globals.raw_value_of(name))?;
+                self.follow(func, frame, value, context)
+            }
+        }
+    }
+
+    // 按名称对变量求值（先局部变量后全局变量），与VarBindingList::eval_var()相同
+    fn resolve(&self, func: &CompiledFn, frame: &Frame, name: &str, context: &Context) -> Option<String> {
+        let value = match func.slot_of.get(name) {
+            Some(slot) => frame.locals[*slot].as_deref(),
+            None => frame.extra.raw_value_of(name),
+        };
+        let value = value.or_else(|| context.globals.raw_value_of(name))?;
+        self.follow(func, frame, value, context)
+    }
+
+    // 值为'var:name'时继续求值
+    fn follow(&self, func: &CompiledFn, frame: &Frame, value: &str, context: &Context) -> Option<String> {
+        match value.strip_prefix("var:") {
+            Some(name) => self.resolve(func, frame, name, context),
+            None => Some(value.to_string()),
+        }
+    }
+}
+
+// 与VarBindingList::set_binding()一致，空值表示删除
+fn set_local(frame: &mut Frame, slot: usize, value: String) {
+    frame.locals[slot] = if value.is_empty() { None } else { Some(value) };
+}
+
+fn frame_locals(func: &CompiledFn, frame: &Frame) -> VarBindingList {
+    let mut locals = VarBindingList::new();
+    locals.add_more(&frame.extra);
+    for (name, value) in func.slots.iter().zip(frame.locals.iter()) {
+        if let Some(value) = value {
+            locals.set_binding(name, value);
+        }
+    }
+    locals
+}
+
+#[cfg(test)]
+mod tests {
+    use engine::{Engine, Context};
+    use variable::VarBindingList;
+    use bus::{Bus, LoopbackBus};
+    use std::time::Duration;
+
+    const SCRIPT: &str = r#"
+ins lamp(a: u32, b: u32 = 1) = 0x321
+
+fn main(n: i32) {
+    local chain = x
+    x = n
+    s = "a"
+    count := 0
+    loop 3 {
+        s += "b"
+        loop 2 {
+            x += n * 2
+            count := count + 1
+            send lamp(a: x)
+        }
+        call sub(v: x, unused: 1)
+    }
+    global g = chain
+    y -= 1
+    call none()
+    return x
+}
+
+fn sub(v: i32) {
+    t = v - 1
+    last := t
+    return
+}
+"#;
+
+    // 全局变量快照，按名称排序
+    fn globals(context: &Context) -> Vec<(String, String)> {
+        let mut globals: Vec<(String, String)> = context.globals.bindings.keys()
+            .map(|name| (name.clone(), context.globals.raw_value_of(name).unwrap_or("").to_string()))
+            .collect();
+        globals.sort();
+        globals
+    }
+
+    // (执行结果, 全局变量, 发送的数据帧中的参数a)
+    type Outcome = (Result<(),String>, Vec<(String, String)>, Vec<u32>);
+
+    fn run(compiled: bool) -> Outcome {
+        let mut engine = Engine::new();
+        engine.load_script(SCRIPT, &mut Context::new()).expect("ok");
+        let (bus, mut peer) = LoopbackBus::pair();
+        let mut context = Context::new();
+        context.bus = Some(Box::new(bus));
+        let mut args = VarBindingList::new();
+        args.set_binding("n", "int:3");
+        let result = if compiled {
+            engine.compile("main").expect("ok").exec(&args, &mut context)
+        } else {
+            engine.exec_fn("main", &args, &mut context)
+        };
+        let mut frames = Vec::new();
+        while let Ok(Some(frame)) = peer.recv(Duration::from_millis(1)) {
+            frames.push(frame.data[3] as u32);
+        }
+        (result, globals(&context), frames)
+    }
+
+    #[test]
+    fn test_same_as_interpreter() {
+        let interpreted = run(false);
+        let compiled = run(true);
+        assert_eq!(compiled.0, Err("No such fn: none".to_string()));
+        assert_eq!(compiled.2, vec![9, 15, 21, 27, 33, 39]);
+        assert!(compiled.1.contains(&("count".to_string(), "int:6".to_string())));
+        assert!(compiled.1.contains(&("g".to_string(), "var:chain".to_string())));
+        assert!(compiled.1.contains(&("last".to_string(), "int:38".to_string())));
+        assert!(compiled.1.contains(&("$return".to_string(), "int:39".to_string())));
+        assert_eq!(compiled, interpreted);
+    }
+
+    #[test]
+    fn test_loop_state() {
+        // 在循环中return，下一次调用时循环重新开始
+        let mut engine = Engine::new();
+        engine.load_script("fn f() { loop 3 { c := c + 1; return } }", &mut Context::new()).expect("ok");
+        let program = engine.compile("f").expect("ok");
+        let mut context = Context::new();
+        context.globals.set_binding("c", "int:0");
+        for _ in 0..4 {
+            program.exec(&VarBindingList::new(), &mut context).expect("ok");
+        }
+        assert_eq!(context.globals.raw_value_of("c"), Some("int:4"));
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        let mut engine = Engine::new();
+        engine.load_script("fn f() { call g() }\nfn g() { x = 1 }", &mut Context::new()).expect("ok");
+        assert_eq!(engine.compile("none").err(), Some("No such fn: none".to_string()));
+        assert!(engine.compile("f").is_ok());
+
+        let mut fndef = ::function::FnDef::new("h");
+        fndef.add_stmt(::statement::Stmt::new_loop(1));
+        engine.add_fn(fndef);
+        assert_eq!(engine.compile("h").err(), Some("Cannot compile fn h #0: Unclosed loop".to_string()));
+        let mut fndef = ::function::FnDef::new("h");
+        fndef.add_stmt(::statement::Stmt::new_end_loop());
+        engine.add_fn(fndef);
+        assert_eq!(engine.compile("h").err(), Some("Cannot compile fn h #0: Unpaired endloop".to_string()));
+        let mut fndef = ::function::FnDef::new("h");
+        fndef.add_stmt(::statement::Stmt::new_set_var("x", "var:op", "1"));
+        engine.add_fn(fndef);
+        assert_eq!(engine.compile("h").err(), Some("Cannot compile fn h #0: Unsupport variable as $op1".to_string()));
+        // 被调用的函数中的错误
+        let mut fndef = ::function::FnDef::new("g");
+        fndef.add_stmt(::statement::Stmt::new_set_var("x", "=>", "1"));
+        engine.add_fn(fndef);
+        assert_eq!(engine.compile("f").err(), Some("Cannot compile fn g #0: Unsupport set var op: =>".to_string()));
+    }
+}
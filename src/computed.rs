@@ -0,0 +1,75 @@
+// 自动计算的指令参数：滚动计数器和校验和
+
+/// 校验和算法
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Checksum {
+    /// 各字节异或
+    Xor,
+    /// 各字节相加（丢弃进位）
+    Sum,
+    /// CRC-8 SAE J1850 (poly 0x1D, init 0xFF, xorout 0xFF)
+    Crc8SaeJ1850,
+    /// CRC-8 AUTOSAR (CRC8H2F: poly 0x2F, init 0xFF, xorout 0xFF)，data_id附加在数据之后参与计算
+    Crc8Autosar { data_id: u8 },
+}
+
+impl Checksum {
+    pub fn compute(&self, data: &[u8]) -> u8 {
+        match *self {
+            Checksum::Xor => data.iter().fold(0, |acc, b| acc ^ b),
+            Checksum::Sum => data.iter().fold(0u8, |acc, b| acc.wrapping_add(*b)),
+            Checksum::Crc8SaeJ1850 => crc8(data.iter(), 0x1D, 0xFF) ^ 0xFF,
+            Checksum::Crc8Autosar { data_id } => crc8(data.iter().chain(Some(&data_id)), 0x2F, 0xFF) ^ 0xFF,
+        }
+    }
+}
+
+// 不反射的CRC-8
+fn crc8<'a, I: Iterator<Item = &'a u8>>(data: I, poly: u8, init: u8) -> u8 {
+    let mut crc = init;
+    for byte in data {
+        crc ^= *byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ poly } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// 由InsDef::exec()自动计算值的参数，调用者不能绑定其值
+/// 先计算所有计数器，再按定义顺序计算校验和：校验和覆盖计数器和之前的校验和，之后的校验和按0计算
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Computed {
+    /// 滚动计数器，占用参数的低width位；每执行一次指令加1，达到wrap后回到0（即取值0..wrap）
+    /// 计数状态保存在Context.counters中，每条指令的每个计数器各自独立
+    Counter { width: u32, wrap: u64 },
+    /// 校验和，对数据帧中除校验和所在字节以外的全部字节计算
+    Checksum(Checksum),
+}
+
+impl Computed {
+    /// 取满width位的计数器，如4位计数器取值0..15；width超过63时wrap为0，执行时报错Invalid counter
+    pub fn counter(width: u32) -> Computed {
+        Computed::Counter { width, wrap: 1u64.checked_shl(width).unwrap_or(0) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Checksum, Computed};
+
+    #[test]
+    fn test_checksums() {
+        let data = b"123456789";
+        assert_eq!(Checksum::Crc8SaeJ1850.compute(data), 0x4B);
+        assert_eq!(Checksum::Crc8Autosar { data_id: b'9' }.compute(&data[..8]), 0xDF); // data id is appended
+        assert_ne!(Checksum::Crc8Autosar { data_id: 1 }.compute(&data[..8]), 0xDF);
+        assert_eq!(Checksum::Xor.compute(&[0x01, 0x02, 0x04, 0x01]), 0x06);
+        assert_eq!(Checksum::Sum.compute(&[0xFF, 0x02, 0x04]), 0x05);
+        assert_eq!(Computed::counter(4), Computed::Counter { width: 4, wrap: 16 });
+        assert_eq!(Computed::counter(63), Computed::Counter { width: 63, wrap: 1 << 63 });
+        assert_eq!(Computed::counter(64), Computed::Counter { width: 64, wrap: 0 });
+    }
+}
@@ -0,0 +1,367 @@
+// 语句覆盖率：统计每个函数被调用的次数、每条语句执行的次数和每个循环进入循环体的次数
+//
+// 设置Context.coverage后由FnDef::exec()（解释执行）统计，编译后的Program和调试器不统计。
+// 统计结果按函数名称和语句索引保存，可以保存为文件并与其他执行的结果合并（脚本修改后应重新统计）：
+//   logic-coverage 1             文件头和版本
+//   F  blink  2                  函数被调用的次数
+//   S  blink  0  2  4            语句：函数、索引、执行次数、进入循环体的次数（仅循环语句）
+// 报告（文本或lcov）按Engine中的函数列出全部语句，没有执行过的函数也包括在内。
+
+use engine::Engine;
+use function::FnDef;
+use statement::StmtKind;
+use pretty::format_stmt;
+use utils::{escape_field, unescape_field};
+use std::collections::{BTreeMap, HashMap};
+
+const HEADER: &str = "logic-coverage";
+const VERSION: u32 = 1;
+
+/// 一个函数的覆盖率
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FnCoverage {
+    /// 被调用的次数
+    pub calls: u64,
+    /// 每条语句的执行次数，按语句索引；超出的语句没有执行过
+    pub hits: Vec<u64>,
+    /// 循环语句的索引 -> 进入循环体的次数
+    pub iterations: BTreeMap<usize, u64>,
+}
+
+impl FnCoverage {
+    pub fn hits_of(&self, index: usize) -> u64 {
+        self.hits.get(index).cloned().unwrap_or(0)
+    }
+}
+
+/// 覆盖率统计结果，见Context.coverage
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Coverage {
+    /// 函数名称 -> 覆盖率
+    pub fns: BTreeMap<String, FnCoverage>,
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage { fns: BTreeMap::new() }
+    }
+
+    fn fn_mut(&mut self, name: &str) -> &mut FnCoverage {
+        if !self.fns.contains_key(name) {
+            self.fns.insert(name.to_string(), FnCoverage::default());
+        }
+        self.fns.get_mut(name).expect("inserted")
+    }
+
+    /// 开始执行函数
+    pub fn enter(&mut self, name: &str) {
+        self.fn_mut(name).calls += 1;
+    }
+
+    /// 执行语句
+    pub fn hit(&mut self, name: &str, index: usize) {
+        let hits = &mut self.fn_mut(name).hits;
+        if hits.len() <= index {
+            hits.resize(index + 1, 0);
+        }
+        hits[index] += 1;
+    }
+
+    /// 进入循环体
+    pub fn iterate(&mut self, name: &str, index: usize) {
+        *self.fn_mut(name).iterations.entry(index).or_insert(0) += 1;
+    }
+
+    /// 加上另一次执行的结果
+    pub fn merge(&mut self, other: &Coverage) {
+        for (name, other) in &other.fns {
+            let coverage = self.fn_mut(name);
+            coverage.calls += other.calls;
+            for (index, hits) in other.hits.iter().enumerate() {
+                if coverage.hits.len() <= index {
+                    coverage.hits.resize(index + 1, 0);
+                }
+                coverage.hits[index] += hits;
+            }
+            for (index, iterations) in &other.iterations {
+                *coverage.iterations.entry(*index).or_insert(0) += iterations;
+            }
+        }
+    }
+
+    /// 转换为覆盖率文件的文本
+    pub fn to_text(&self) -> String {
+        let mut text = format!("{}\t{}\n", HEADER, VERSION);
+        for (name, coverage) in &self.fns {
+            let name = escape_field(name);
+            text.push_str(&format!("F\t{}\t{}\n", name, coverage.calls));
+            for (index, hits) in coverage.hits.iter().enumerate() {
+                match coverage.iterations.get(&index) {
+                    Some(iterations) => text.push_str(&format!("S\t{}\t{}\t{}\t{}\n", name, index, hits, iterations)),
+                    None if *hits > 0 => text.push_str(&format!("S\t{}\t{}\t{}\n", name, index, hits)),
+                    None => {}
+                }
+            }
+        }
+        text
+    }
+
+    /// 解析覆盖率文件
+    pub fn parse(text: &str) -> Result<Coverage, String> {
+        let mut lines = text.lines().enumerate();
+        match lines.next().map(|(_, header)| header.split('\t').collect::<Vec<&str>>()) {
+            Some(ref header) if header.len() == 2 && header[0] == HEADER => {
+                if header[1] != VERSION.to_string() {
+                    return Err(format!("Unsupported coverage version: {}", header[1]));
+                }
+            }
+            _ => return Err("Not a coverage file".to_string()),
+        }
+        let mut coverage = Coverage::new();
+        for (number, line) in lines {
+            if line.is_empty() {
+                continue;
+            }
+            let error = || format!("Invalid coverage line {}: {}", number + 1, line);
+            let fields: Vec<&str> = line.split('\t').collect();
+            let count = |text: &str| text.parse::<u64>().map_err(|_| error());
+            match fields[..] {
+                ["F", name, calls] => coverage.fn_mut(&unescape_field(name)).calls += count(calls)?,
+                ["S", name, index, hits] | ["S", name, index, hits, _] => {
+                    let index = count(index)? as usize;
+                    let name = unescape_field(name);
+                    let fn_coverage = coverage.fn_mut(&name);
+                    if fn_coverage.hits.len() <= index {
+                        fn_coverage.hits.resize(index + 1, 0);
+                    }
+                    fn_coverage.hits[index] += count(hits)?;
+                    if let Some(iterations) = fields.get(4) {
+                        *fn_coverage.iterations.entry(index).or_insert(0) += count(iterations)?;
+                    }
+                }
+                _ => return Err(error()),
+            }
+        }
+        Ok(coverage)
+    }
+
+    /// 文本报告：每个函数执行过的语句数，以及没有执行过的语句和没有进入过的循环；sources为函数 -> 脚本的路径
+    pub fn report(&self, engine: &Engine, sources: &HashMap<String, String>) -> String {
+        let empty = FnCoverage::default();
+        let (mut total, mut covered, mut called) = (0, 0, 0);
+        let mut text = String::new();
+        for fndef in sorted_fns(engine) {
+            let coverage = self.fns.get(&fndef.name).unwrap_or(&empty);
+            let hit = (0..fndef.stmts.len()).filter(|&index| coverage.hits_of(index) > 0).count();
+            total += fndef.stmts.len();
+            covered += hit;
+            if coverage.calls > 0 {
+                called += 1;
+            }
+            let location = match (sources.get(&fndef.name), fndef.pos) {
+                (Some(path), Some(pos)) => format!("  {}:{}", path, pos.line),
+                (Some(path), None) => format!("  {}", path),
+                (None, Some(pos)) => format!("  line {}", pos.line),
+                (None, None) => String::new(),
+            };
+            let calls = match coverage.calls {
+                0 => "never called".to_string(),
+                calls => format!("{} call(s)", calls),
+            };
+            text.push_str(&format!("fn {}{}  {}/{} statement(s) ({}), {}\n",
+                                   fndef.name, location, hit, fndef.stmts.len(), percent(hit, fndef.stmts.len()), calls));
+            for (index, stmt) in fndef.stmts.iter().enumerate() {
+                let line = stmt.pos.map(|pos| format!("line {}  ", pos.line)).unwrap_or_default();
+                if coverage.hits_of(index) == 0 {
+                    text.push_str(&format!("    {}#{}  {}\n", line, index, format_stmt(stmt)));
+                } else if stmt.kind == StmtKind::Loop && !coverage.iterations.contains_key(&index) {
+                    text.push_str(&format!("    {}#{}  {}  (body never entered)\n", line, index, format_stmt(stmt)));
+                }
+            }
+        }
+        text.push_str(&format!("total: {}/{} statement(s) ({}), {}/{} fn(s) called\n",
+                               covered, total, percent(covered, total), called, engine.fns.len()));
+        text
+    }
+
+    /// lcov格式的报告（用于genhtml等工具）：语句按所在的行统计，循环作为分支（进入/不进入循环体）；
+    /// 不在sources中或没有位置的函数不包括在内
+    pub fn lcov(&self, engine: &Engine, sources: &HashMap<String, String>) -> String {
+        let empty = FnCoverage::default();
+        // 脚本的路径 -> 其中的函数
+        let mut files: BTreeMap<&str, Vec<&FnDef>> = BTreeMap::new();
+        for fndef in sorted_fns(engine) {
+            if let (Some(path), Some(_)) = (sources.get(&fndef.name), fndef.pos) {
+                files.entry(path).or_default().push(fndef);
+            }
+        }
+        let mut text = String::new();
+        for (path, fndefs) in files {
+            text.push_str(&format!("TN:\nSF:{}\n", path));
+            let mut fns_hit = 0;
+            for fndef in &fndefs {
+                text.push_str(&format!("FN:{},{}\n", fndef.pos.map_or(0, |pos| pos.line), fndef.name));
+            }
+            for fndef in &fndefs {
+                let calls = self.fns.get(&fndef.name).map_or(0, |coverage| coverage.calls);
+                if calls > 0 {
+                    fns_hit += 1;
+                }
+                text.push_str(&format!("FNDA:{},{}\n", calls, fndef.name));
+            }
+            text.push_str(&format!("FNF:{}\nFNH:{}\n", fndefs.len(), fns_hit));
+
+            // 行号 -> 执行次数（同一行有多条语句时取最大值）
+            let mut lines: BTreeMap<u32, u64> = BTreeMap::new();
+            let mut branches = Vec::new();
+            for fndef in &fndefs {
+                let coverage = self.fns.get(&fndef.name).unwrap_or(&empty);
+                for (index, stmt) in fndef.stmts.iter().enumerate() {
+                    let line = match stmt.pos {
+                        Some(pos) => pos.line,
+                        None => continue,
+                    };
+                    let hits = coverage.hits_of(index);
+                    let count = lines.entry(line).or_insert(0);
+                    *count = (*count).max(hits);
+                    if stmt.kind == StmtKind::Loop {
+                        let iterations = coverage.iterations.get(&index).cloned().unwrap_or(0);
+                        // 每次执行循环语句要么进入循环体，要么结束循环
+                        branches.push((line, hits, iterations, hits.saturating_sub(iterations)));
+                    }
+                }
+            }
+            for (block, &(line, hits, enter, exit)) in branches.iter().enumerate() {
+                for (branch, taken) in [enter, exit].iter().enumerate() {
+                    let taken = if hits == 0 { "-".to_string() } else { taken.to_string() };
+                    text.push_str(&format!("BRDA:{},{},{},{}\n", line, block, branch, taken));
+                }
+            }
+            let branches_hit = branches.iter().map(|&(_, _, enter, exit)| (enter > 0) as usize + (exit > 0) as usize).sum::<usize>();
+            text.push_str(&format!("BRF:{}\nBRH:{}\n", branches.len() * 2, branches_hit));
+            for (line, hits) in &lines {
+                text.push_str(&format!("DA:{},{}\n", line, hits));
+            }
+            text.push_str(&format!("LF:{}\nLH:{}\n", lines.len(), lines.values().filter(|&&hits| hits > 0).count()));
+            text.push_str("end_of_record\n");
+        }
+        text
+    }
+}
+
+fn sorted_fns(engine: &Engine) -> Vec<&FnDef> {
+    let mut fns: Vec<&FnDef> = engine.fns.values().map(|fndef| fndef.as_ref()).collect();
+    fns.sort_by(|a, b| a.name.cmp(&b.name));
+    fns
+}
+
+fn percent(count: usize, total: usize) -> String {
+    if total == 0 {
+        return "-".to_string();
+    }
+    format!("{:.1}%", count as f64 * 100.0 / total as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use coverage::Coverage;
+    use engine::{Engine, Context};
+    use variable::VarBindingList;
+    use std::collections::HashMap;
+
+    const SCRIPT: &str = r#"ins lamp(a: u32, b: u32 = 1) = 0x321
+
+fn main(n: i32) {
+    loop 2 {
+        call blink(n: n)
+    }
+    loop 0 {
+        send lamp(a: 0)
+    }
+    return n
+}
+
+fn blink(n: i32) {
+    send lamp(a: n)
+}
+
+fn unused() {
+    send lamp(a: 1)
+}
+"#;
+
+    fn run(engine: &Engine) -> Coverage {
+        let mut context = Context::new();
+        context.coverage = Some(Coverage::new());
+        let mut args = VarBindingList::new();
+        args.set_binding("n", "int:1");
+        engine.exec_fn("main", &args, &mut context).expect("ok");
+        context.coverage.take().expect("coverage")
+    }
+
+    fn setup() -> Engine {
+        let mut engine = Engine::new();
+        engine.load_script(SCRIPT, &mut Context::new()).expect("ok");
+        engine
+    }
+
+    #[test]
+    fn test_collect() {
+        let engine = setup();
+        let coverage = run(&engine);
+        let main = &coverage.fns["main"];
+        assert_eq!((main.calls, &main.hits), (1, &vec![3, 2, 2, 1, 0, 0, 1]));
+        assert_eq!(main.iterations.iter().map(|(index, count)| (*index, *count)).collect::<Vec<_>>(), vec![(0, 2)]);
+        assert_eq!((coverage.fns["blink"].calls, &coverage.fns["blink"].hits), (2, &vec![2]));
+        assert!(!coverage.fns.contains_key("unused"));
+
+        let mut merged = coverage.clone();
+        merged.merge(&run(&engine));
+        assert_eq!(merged.fns["main"].hits, vec![6, 4, 4, 2, 0, 0, 2]);
+        assert_eq!(merged.fns["main"].iterations[&0], 4);
+        assert_eq!(merged.fns["blink"].calls, 4);
+    }
+
+    #[test]
+    fn test_text() {
+        let coverage = run(&setup());
+        let text = coverage.to_text();
+        assert_eq!(text, "logic-coverage\t1\nF\tblink\t2\nS\tblink\t0\t2\nF\tmain\t1\nS\tmain\t0\t3\t2\nS\tmain\t1\t2\nS\tmain\t2\t2\nS\tmain\t3\t1\nS\tmain\t6\t1\n");
+        assert_eq!(Coverage::parse(&text), Ok(coverage.clone()));
+        // 同一个函数出现多次时相加
+        let mut doubled = coverage.clone();
+        doubled.merge(&coverage);
+        assert_eq!(Coverage::parse(&format!("{}{}", text, &text[text.find('\n').unwrap() + 1..])), Ok(doubled));
+
+        assert_eq!(Coverage::parse("logic-trace\t1\n"), Err("Not a coverage file".to_string()));
+        assert_eq!(Coverage::parse("logic-coverage\t9\n"), Err("Unsupported coverage version: 9".to_string()));
+        assert_eq!(Coverage::parse("logic-coverage\t1\nS\tmain\tx\t1\n"), Err("Invalid coverage line 2: S\tmain\tx\t1".to_string()));
+    }
+
+    #[test]
+    fn test_report() {
+        let engine = setup();
+        let coverage = run(&engine);
+        let mut sources = HashMap::new();
+        sources.insert("main".to_string(), "demo.logic".to_string());
+        sources.insert("blink".to_string(), "demo.logic".to_string());
+        assert_eq!(coverage.report(&engine, &sources), "\
+fn blink  demo.logic:13  1/1 statement(s) (100.0%), 2 call(s)
+fn main  demo.logic:3  5/7 statement(s) (71.4%), 1 call(s)
+    line 7  #3  loop 0 {  (body never entered)
+    line 8  #4  send lamp(a: 0)
+    line 9  #5  }
+fn unused  line 17  0/1 statement(s) (0.0%), never called
+    line 18  #0  send lamp(a: 1)
+total: 6/9 statement(s) (66.7%), 2/3 fn(s) called
+");
+        // unused没有脚本路径，不包括在lcov中
+        assert_eq!(coverage.lcov(&engine, &sources), "\
+TN:\nSF:demo.logic\nFN:13,blink\nFN:3,main\nFNDA:2,blink\nFNDA:1,main\nFNF:2\nFNH:2
+BRDA:4,0,0,2\nBRDA:4,0,1,1\nBRDA:7,1,0,0\nBRDA:7,1,1,1\nBRF:4\nBRH:3
+DA:4,3\nDA:5,2\nDA:6,2\nDA:7,1\nDA:8,0\nDA:9,0\nDA:10,1\nDA:14,2\nLF:8\nLH:6
+end_of_record
+");
+        assert_eq!(Coverage::new().report(&Engine::new(), &sources), "total: 0/0 statement(s) (-), 0/0 fn(s) called\n");
+    }
+}
@@ -0,0 +1,446 @@
+// Debug Adapter Protocol服务器：logic dap，通过标准输入输出与编辑器（如VS Code）通信
+//
+// launch请求的参数：
+//   program      定义文件的路径（字符串或数组），格式同logic run
+//   function     要调试的函数
+//   args         函数参数，如{"n": 1, "mode": "auto"}
+//   stopOnEntry  是否在第一条语句之前暂停
+//   bus          同logic run --bus；默认不连接总线，发送的帧显示在调试控制台
+//
+// 只有一个线程(id 1)。frameId为调用栈的层数(从1开始)；变量引用：1为全局变量，每一帧有参数和局部变量两个引用。
+// 执行是同步的，执行期间不处理请求（不支持pause）。
+
+use engine::{Engine, Context};
+use debugger::{Debugger, Stop, FrameInfo, parse_value};
+use variable::VarBindingList;
+use pretty::format_value;
+use bus::{Frame, TapBus, candump_text};
+use cli::{load_files, open_bus, arg_value, fn_sources};
+use message::{read_message, write_message};
+use utils::split_lr;
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+const THREAD_ID: u64 = 1;
+const GLOBALS_REF: u64 = 1;
+
+// 发送消息并编号
+struct Channel<'a> {
+    output: &'a mut dyn Write,
+    seq: u64,
+}
+
+impl<'a> Channel<'a> {
+    fn send(&mut self, mut message: Value) -> Result<(), String> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        write_message(self.output, &message)
+    }
+
+    fn respond(&mut self, request: &Value, body: Value) -> Result<(), String> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": true,
+            "body": body,
+        }))
+    }
+
+    fn respond_error(&mut self, request: &Value, message: &str) -> Result<(), String> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": false,
+            "message": message,
+        }))
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> Result<(), String> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+}
+
+// launch请求的参数
+struct Launch {
+    function: String,
+    args: VarBindingList,
+    stop_on_entry: bool,
+    bus: Option<String>,
+    // 函数名称 -> 定义所在的脚本
+    sources: HashMap<String, String>,
+}
+
+/// 处理请求直到disconnect或输入结束
+pub fn serve(input: &mut dyn BufRead, output: &mut dyn Write) -> Result<(), String> {
+    let mut channel = Channel { output, seq: 0 };
+    let mut configured = false;
+    while let Some(request) = read_message(input)? {
+        match request["command"].as_str().unwrap_or("") {
+            "initialize" => channel.respond(&request, json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsConditionalBreakpoints": true,
+                "supportsSetVariable": true,
+                "supportsEvaluateForHovers": true,
+            }))?,
+            "launch" => {
+                let mut context = Context::new();
+                let launched = parse_launch(&request["arguments"]).and_then(|launch| {
+                    let mut engine = Engine::new();
+                    load_files(&mut engine, &program(&request["arguments"]), &mut context)?;
+                    engine.find_fn(&launch.function)?.ok_or_else(|| format!("No such fn: {}", launch.function))?;
+                    Ok((engine, launch))
+                });
+                let (engine, launch) = match launched {
+                    Ok(launched) => launched,
+                    Err(err) => {
+                        channel.respond_error(&request, &err)?;
+                        continue;
+                    }
+                };
+                if let Some(ref bus) = launch.bus {
+                    match open_bus(bus) {
+                        Ok(bus) => context.bus = Some(bus),
+                        Err(err) => {
+                            channel.respond_error(&request, &err)?;
+                            continue;
+                        }
+                    }
+                }
+                channel.respond(&request, json!({}))?;
+                channel.event("initialized", json!({}))?;
+                let mut session = Session::new(&engine, context, launch)?;
+                if configured {
+                    session.start(&mut channel)?;
+                }
+                return session.serve(input, &mut channel);
+            }
+            "configurationDone" => {
+                configured = true;
+                channel.respond(&request, json!({}))?;
+            }
+            "threads" => channel.respond(&request, json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] }))?,
+            "setBreakpoints" => {
+                let count = request["arguments"]["breakpoints"].as_array().map_or(0, |breakpoints| breakpoints.len());
+                let breakpoints = vec![json!({ "verified": false, "message": "Not launched" }); count];
+                channel.respond(&request, json!({ "breakpoints": breakpoints }))?;
+            }
+            "disconnect" | "terminate" => {
+                channel.respond(&request, json!({}))?;
+                return Ok(());
+            }
+            command => channel.respond_error(&request, &format!("Unsupported command before launch: {}", command))?,
+        }
+    }
+    Ok(())
+}
+
+fn program(arguments: &Value) -> Vec<String> {
+    match arguments["program"] {
+        Value::String(ref path) => vec![path.clone()],
+        Value::Array(ref paths) => paths.iter().filter_map(|path| path.as_str().map(|path| path.to_string())).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn parse_launch(arguments: &Value) -> Result<Launch, String> {
+    let files = program(arguments);
+    if files.is_empty() {
+        return Err("launch requires program".to_string());
+    }
+    let function = arguments["function"].as_str().ok_or("launch requires function")?.to_string();
+    let mut args = VarBindingList::new();
+    if let Some(values) = arguments["args"].as_object() {
+        for (name, value) in values {
+            let value = match *value {
+                Value::String(ref text) => arg_value(text),
+                Value::Number(ref number) if number.is_f64() => format!("float:{}", number),
+                Value::Number(ref number) => format!("int:{}", number),
+                Value::Bool(flag) => format!("int:{}", flag as u8),
+                _ => return Err(format!("Invalid arg: {}", name)),
+            };
+            args.set_binding(name, &value);
+        }
+    }
+    // 脚本中定义的函数，用于设置断点和显示源代码位置
+    let sources = fn_sources(&files).into_iter().map(|(name, path)| (name, canonical(&path))).collect();
+    Ok(Launch {
+        function,
+        args,
+        stop_on_entry: arguments["stopOnEntry"].as_bool().unwrap_or(false),
+        bus: arguments["bus"].as_str().map(|bus| bus.to_string()),
+        sources,
+    })
+}
+
+fn canonical(path: &str) -> String {
+    fs::canonicalize(path).map(|path| path.to_string_lossy().into_owned()).unwrap_or_else(|_| path.to_string())
+}
+
+struct Session<'a> {
+    engine: &'a Engine,
+    debugger: Debugger<'a>,
+    context: Context,
+    sources: HashMap<String, String>,
+    // 脚本 -> 断点id
+    breakpoints: HashMap<String, Vec<usize>>,
+    stop_on_entry: bool,
+    started: bool,
+    terminated: bool,
+    // 执行期间的日志和发送的帧，执行结束后作为output事件发出
+    output: Rc<RefCell<Vec<String>>>,
+}
+
+impl<'a> Session<'a> {
+    fn new(engine: &'a Engine, mut context: Context, launch: Launch) -> Result<Session<'a>, String> {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let log = output.clone();
+        context.logger = Some(Box::new(move |line: &str| log.borrow_mut().push(line.to_string())));
+        let sent = output.clone();
+        let tap = Box::new(move |frame: &Frame| sent.borrow_mut().push(candump_text(frame, "can0")));
+        context.bus = Some(Box::new(TapBus::new(context.bus.take(), tap)));
+        Ok(Session {
+            engine,
+            debugger: engine.debug(&launch.function, &launch.args)?,
+            context,
+            sources: launch.sources,
+            breakpoints: HashMap::new(),
+            stop_on_entry: launch.stop_on_entry,
+            started: false,
+            terminated: false,
+            output,
+        })
+    }
+
+    fn serve(&mut self, input: &mut dyn BufRead, channel: &mut Channel) -> Result<(), String> {
+        while let Some(request) = read_message(input)? {
+            let arguments = &request["arguments"];
+            let result = match request["command"].as_str().unwrap_or("") {
+                "configurationDone" => {
+                    channel.respond(&request, json!({}))?;
+                    if !self.started {
+                        self.start(channel)?;
+                    }
+                    continue;
+                }
+                "setBreakpoints" => Ok(self.set_breakpoints(arguments)),
+                "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] })),
+                "stackTrace" => Ok(self.stack_trace()),
+                "scopes" => self.scopes(arguments),
+                "variables" => self.variables(arguments),
+                "setVariable" => self.set_variable(arguments),
+                "evaluate" => self.evaluate(arguments),
+                "continue" | "next" | "stepIn" | "stepOut" => {
+                    let body = if request["command"] == "continue" { json!({ "allThreadsContinued": true }) } else { json!({}) };
+                    channel.respond(&request, body)?;
+                    let stop = match request["command"].as_str().unwrap_or("") {
+                        "continue" => self.debugger.cont(&mut self.context),
+                        "next" => self.debugger.step_over(&mut self.context),
+                        "stepIn" => self.debugger.step_into(&mut self.context),
+                        _ => self.debugger.step_out(&mut self.context),
+                    };
+                    self.report(stop, channel)?;
+                    continue;
+                }
+                "disconnect" => {
+                    channel.respond(&request, json!({}))?;
+                    return Ok(());
+                }
+                "terminate" => {
+                    channel.respond(&request, json!({}))?;
+                    self.terminate(channel, 0)?;
+                    continue;
+                }
+                command => Err(format!("Unsupported command: {}", command)),
+            };
+            match result {
+                Ok(body) => channel.respond(&request, body)?,
+                Err(err) => channel.respond_error(&request, &err)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn start(&mut self, channel: &mut Channel) -> Result<(), String> {
+        self.started = true;
+        if self.stop_on_entry {
+            channel.event("stopped", json!({ "reason": "entry", "threadId": THREAD_ID, "allThreadsStopped": true }))
+        } else {
+            let stop = self.debugger.cont(&mut self.context);
+            self.report(stop, channel)
+        }
+    }
+
+    // 发出执行期间的输出和暂停或结束的事件
+    fn report(&mut self, stop: Stop, channel: &mut Channel) -> Result<(), String> {
+        let lines: Vec<String> = self.output.borrow_mut().drain(..).collect();
+        for line in lines {
+            channel.event("output", json!({ "category": "stdout", "output": format!("{}\n", line) }))?;
+        }
+        match stop {
+            Stop::Breakpoint(id) => channel.event("stopped", json!({
+                "reason": "breakpoint", "threadId": THREAD_ID, "allThreadsStopped": true, "hitBreakpointIds": [id],
+            })),
+            Stop::Step => channel.event("stopped", json!({ "reason": "step", "threadId": THREAD_ID, "allThreadsStopped": true })),
+            Stop::Finished(result) => {
+                if let Some(value) = self.context.globals.raw_value_of("$return").filter(|_| result.is_ok()) {
+                    channel.event("output", json!({ "category": "console", "output": format!("return: {}\n", format_value(value)) }))?;
+                }
+                if let Err(ref err) = result {
+                    channel.event("output", json!({ "category": "stderr", "output": format!("error: {}\n", err) }))?;
+                }
+                self.terminate(channel, if result.is_ok() { 0 } else { 1 })
+            }
+        }
+    }
+
+    fn terminate(&mut self, channel: &mut Channel, exit_code: i32) -> Result<(), String> {
+        if !self.terminated {
+            self.terminated = true;
+            channel.event("exited", json!({ "exitCode": exit_code }))?;
+            channel.event("terminated", json!({}))?;
+        }
+        Ok(())
+    }
+
+    // 断点设置在指定行或其后最近的语句上（只在该脚本定义的函数中查找）
+    fn set_breakpoints(&mut self, arguments: &Value) -> Value {
+        let path = canonical(arguments["source"]["path"].as_str().unwrap_or(""));
+        for id in self.breakpoints.remove(&path).unwrap_or_default() {
+            self.debugger.remove_breakpoint(id);
+        }
+        let mut ids = Vec::new();
+        let mut results = Vec::new();
+        for breakpoint in arguments["breakpoints"].as_array().map_or(&[][..], |breakpoints| &breakpoints[..]) {
+            let line = breakpoint["line"].as_u64().unwrap_or(0) as u32;
+            let mut found: Option<(u32, u32, &str, usize)> = None;
+            for (name, source) in &self.sources {
+                if *source != path {
+                    continue;
+                }
+                let fndef = match self.engine.find_fn(name) {
+                    Ok(Some(fndef)) => fndef,
+                    _ => continue,
+                };
+                for (index, stmt) in fndef.stmts.iter().enumerate() {
+                    if let Some(pos) = stmt.pos.filter(|pos| pos.line >= line) {
+                        if found.is_none_or(|found| (pos.line, pos.col) < (found.0, found.1)) {
+                            found = Some((pos.line, pos.col, name, index));
+                        }
+                    }
+                }
+            }
+            let result = match found {
+                Some((actual, _, name, index)) => match self.debugger.add_breakpoint(name, index, breakpoint["condition"].as_str()) {
+                    Ok(id) => {
+                        ids.push(id);
+                        json!({ "id": id, "verified": true, "line": actual })
+                    }
+                    Err(err) => json!({ "verified": false, "message": err }),
+                },
+                None => json!({ "verified": false, "message": format!("No statement at line {}", line) }),
+            };
+            results.push(result);
+        }
+        self.breakpoints.insert(path, ids);
+        json!({ "breakpoints": results })
+    }
+
+    fn stack_trace(&self) -> Value {
+        let stack = self.debugger.stack();
+        let top = stack.len();
+        let frames: Vec<Value> = stack.iter().enumerate().rev().map(|(depth, frame)| {
+            // 调用者的index指向call之后的语句，显示call语句的位置
+            let index = if depth + 1 == top { frame.index } else { frame.index.saturating_sub(1) };
+            let pos = self.engine.find_fn(&frame.fn_name).ok().flatten().and_then(|fndef| fndef.stmts.get(index).and_then(|stmt| stmt.pos));
+            let mut value = json!({
+                "id": depth + 1,
+                "name": frame.fn_name,
+                "line": pos.map_or(0, |pos| pos.line),
+                "column": pos.map_or(0, |pos| pos.col),
+            });
+            if let Some(path) = self.sources.get(&frame.fn_name) {
+                let name = Path::new(path).file_name().map(|name| name.to_string_lossy().into_owned());
+                value["source"] = json!({ "name": name, "path": path });
+            }
+            value
+        }).collect();
+        json!({ "stackFrames": frames, "totalFrames": top })
+    }
+
+    // frameId从1开始
+    fn frame(&self, frame_id: u64) -> Result<(usize, FrameInfo), String> {
+        let index = (frame_id as usize).wrapping_sub(1);
+        self.debugger.stack().into_iter().nth(index).map(|frame| (index, frame)).ok_or_else(|| format!("No such frame: {}", frame_id))
+    }
+
+    fn scopes(&self, arguments: &Value) -> Result<Value, String> {
+        let (index, _) = self.frame(arguments["frameId"].as_u64().unwrap_or(0))?;
+        let reference = 2 * index as u64 + 2;
+        Ok(json!({ "scopes": [
+            { "name": "Arguments", "presentationHint": "arguments", "variablesReference": reference, "expensive": false },
+            { "name": "Locals", "presentationHint": "locals", "variablesReference": reference + 1, "expensive": false },
+            { "name": "Globals", "variablesReference": GLOBALS_REF, "expensive": false },
+        ] }))
+    }
+
+    // 变量引用对应的(帧的索引, 是否为参数)；全局变量为None
+    fn scope(&self, reference: u64) -> Result<Option<(usize, bool)>, String> {
+        if reference == GLOBALS_REF {
+            return Ok(None);
+        }
+        let index = (reference.saturating_sub(2) / 2) as usize;
+        if reference < 2 || index >= self.debugger.stack().len() {
+            return Err(format!("No such variables reference: {}", reference));
+        }
+        Ok(Some((index, reference.is_multiple_of(2))))
+    }
+
+    fn variables(&self, arguments: &Value) -> Result<Value, String> {
+        let stack = self.debugger.stack();
+        let list = match self.scope(arguments["variablesReference"].as_u64().unwrap_or(0))? {
+            None => &self.context.globals,
+            Some((index, true)) => &stack[index].args,
+            Some((index, false)) => &stack[index].locals,
+        };
+        let mut names: Vec<&String> = list.bindings.keys().collect();
+        names.sort();
+        let variables: Vec<Value> = names.iter().map(|name| {
+            let value = list.raw_value_of(name).unwrap_or("");
+            json!({ "name": name, "value": format_value(value), "type": split_lr(value, ":").0, "variablesReference": 0 })
+        }).collect();
+        Ok(json!({ "variables": variables }))
+    }
+
+    // 参数和局部变量都修改为函数的局部变量
+    fn set_variable(&mut self, arguments: &Value) -> Result<Value, String> {
+        let scope = self.scope(arguments["variablesReference"].as_u64().unwrap_or(0))?;
+        let name = arguments["name"].as_str().ok_or("setVariable requires name")?;
+        let frame = scope.map_or(self.debugger.stack().len().saturating_sub(1), |scope| scope.0);
+        let value = parse_value(arguments["value"].as_str().unwrap_or(""))?;
+        let value = self.debugger.eval(frame, &value, &self.context).ok_or_else(|| format!("Undefined: {}", value))?;
+        match scope {
+            None => self.context.globals.set_binding(name, &value),
+            Some((index, _)) => self.debugger.set_local(index, name, &value)?,
+        }
+        Ok(json!({ "value": format_value(&value), "type": split_lr(&value, ":").0 }))
+    }
+
+    fn evaluate(&self, arguments: &Value) -> Result<Value, String> {
+        let frame = match arguments["frameId"].as_u64() {
+            Some(frame_id) => self.frame(frame_id)?.0,
+            None => self.debugger.stack().len().saturating_sub(1),
+        };
+        let value = parse_value(arguments["expression"].as_str().unwrap_or(""))?;
+        let result = self.debugger.eval(frame, &value, &self.context)
+            .or_else(|| self.context.globals.eval(&value, None, None))
+            .ok_or_else(|| format!("Undefined: {}", split_lr(&value, ":").1))?;
+        Ok(json!({ "result": format_value(&result), "type": split_lr(&result, ":").0, "variablesReference": 0 }))
+    }
+}
@@ -0,0 +1,664 @@
+// DBC文件(Vector CAN database)的导入和导出
+// 支持：BO_(报文) SG_(信号) CM_(报文和信号的注释)
+// 不支持的内容（如属性BA_、值表VAL_、扩展多路复用等）不会被静默丢弃，而是记录在Dbc.unsupported中
+
+use instruction::InsDef;
+use variable::VarDef;
+use signal::{SignalLayout, ByteOrder, Mux};
+use std::collections::{HashMap, HashSet};
+
+/// DBC文件的解析结果
+pub struct Dbc {
+    /// 由报文(BO_)生成的指令，保持文件中的顺序
+    pub inss: Vec<InsDef>,
+    /// 不支持而被忽略的内容，每项形如"line 12: BA_ (attribute) ignored"
+    pub unsupported: Vec<String>,
+}
+
+// 扩展帧ID在DBC中以最高位标记
+const EXTENDED_FLAG: u32 = 0x8000_0000;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    Ident(String),
+    Num(String),
+    Str(String),
+    Punct(char),
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    tok: Tok,
+    line: u32,
+    col: u32,
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let (mut i, mut line, mut line_start) = (0usize, 1u32, 0usize);
+    while i < chars.len() {
+        let c = chars[i];
+        let col = (i - line_start) as u32;
+        if c == '\n' {
+            line += 1;
+            line_start = i + 1;
+            i += 1;
+        } else if c.is_whitespace() {
+            i += 1;
+        } else if c == '"' {
+            let (start_line, mut s) = (line, String::new());
+            i += 1;
+            loop {
+                if i >= chars.len() {
+                    return Err(format!("line {}: unterminated string", start_line));
+                }
+                match chars[i] {
+                    '"' => break,
+                    '\\' if i + 1 < chars.len() => {
+                        i += 1;
+                        s.push(chars[i]);
+                    }
+                    ch => {
+                        if ch == '\n' {
+                            line += 1;
+                            line_start = i + 1;
+                        }
+                        s.push(ch);
+                    }
+                }
+                i += 1;
+            }
+            i += 1;
+            tokens.push(Token { tok: Tok::Str(s), line: start_line, col });
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let ident: String = chars[start..i].iter().collect();
+            tokens.push(Token { tok: Tok::Ident(ident), line, col });
+        } else if c.is_ascii_digit() || ((c == '-' || c == '+') && i + 1 < chars.len() && chars[i + 1].is_ascii_digit()) {
+            let start = i;
+            i += 1;
+            while i < chars.len() {
+                let ch = chars[i];
+                let exp_sign = (ch == '-' || ch == '+') && (chars[i - 1] == 'e' || chars[i - 1] == 'E');
+                if ch.is_ascii_digit() || ch == '.' || ch == 'e' || ch == 'E' || exp_sign {
+                    i += 1;
+                } else {
+                    break;
+                }
+            }
+            let num: String = chars[start..i].iter().collect();
+            tokens.push(Token { tok: Tok::Num(num), line, col });
+        } else {
+            tokens.push(Token { tok: Tok::Punct(c), line, col });
+            i += 1;
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    dbc: Dbc,
+    // canid(含扩展帧标记) -> index of dbc.inss
+    msg_index: HashMap<u32, usize>,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn peek_ident(&self) -> Option<&str> {
+        match self.peek() {
+            Some(&Token { tok: Tok::Ident(ref ident), .. }) => Some(ident),
+            _ => None,
+        }
+    }
+
+    fn line(&self) -> u32 {
+        self.peek().or_else(|| self.tokens.last()).map_or(0, |t| t.line)
+    }
+
+    fn next(&mut self) -> Result<Token, String> {
+        match self.tokens.get(self.pos) {
+            Some(token) => {
+                self.pos += 1;
+                Ok(token.clone())
+            }
+            None => Err(format!("line {}: unexpected end of file", self.line())),
+        }
+    }
+
+    fn expect_punct(&mut self, punct: char) -> Result<(), String> {
+        let token = self.next()?;
+        match token.tok {
+            Tok::Punct(c) if c == punct => Ok(()),
+            tok => Err(format!("line {}: expected '{}', found {:?}", token.line, punct, tok)),
+        }
+    }
+
+    fn expect_ident(&mut self) -> Result<String, String> {
+        let token = self.next()?;
+        match token.tok {
+            Tok::Ident(ident) => Ok(ident),
+            tok => Err(format!("line {}: expected identifier, found {:?}", token.line, tok)),
+        }
+    }
+
+    fn expect_str(&mut self) -> Result<String, String> {
+        let token = self.next()?;
+        match token.tok {
+            Tok::Str(s) => Ok(s),
+            tok => Err(format!("line {}: expected string, found {:?}", token.line, tok)),
+        }
+    }
+
+    fn expect_num<T: ::std::str::FromStr>(&mut self) -> Result<T, String> {
+        let token = self.next()?;
+        match token.tok {
+            Tok::Num(ref num) => num.parse().map_err(|_| format!("line {}: invalid number: {}", token.line, num)),
+            ref tok => Err(format!("line {}: expected number, found {:?}", token.line, tok)),
+        }
+    }
+
+    fn unsupported(&mut self, line: u32, what: &str) {
+        self.dbc.unsupported.push(format!("line {}: {} ignored", line, what));
+    }
+
+    // 跳过当前语句（以';'结尾）
+    fn skip_statement(&mut self) -> Result<(), String> {
+        loop {
+            if self.next()?.tok == Tok::Punct(';') {
+                return Ok(());
+            }
+        }
+    }
+
+    // 跳过与指定行相同行内的剩余内容
+    fn skip_line(&mut self, line: u32) {
+        while self.peek().is_some_and(|t| t.line == line) {
+            self.pos += 1;
+        }
+    }
+
+    fn parse(&mut self) -> Result<(), String> {
+        while let Some(token) = self.peek().cloned() {
+            let keyword = match token.tok {
+                Tok::Ident(ref ident) => ident.clone(),
+                ref tok => return Err(format!("line {}: unexpected {:?}", token.line, tok)),
+            };
+            match keyword.as_str() {
+                "VERSION" => {
+                    self.pos += 1;
+                    self.expect_str()?;
+                }
+                "NS_" => {
+                    // 新符号列表，一直到下一个顶格的关键字
+                    self.pos += 1;
+                    self.expect_punct(':')?;
+                    while self.peek().is_some_and(|t| t.col > 0 || t.line == token.line) {
+                        self.pos += 1;
+                    }
+                }
+                "BS_" => {
+                    self.pos += 1;
+                    self.expect_punct(':')?;
+                    if self.peek().is_some_and(|t| t.line == token.line) {
+                        self.unsupported(token.line, "BS_ (bit timing)");
+                        self.skip_line(token.line);
+                    }
+                }
+                "BU_" => {
+                    // 节点列表与指令无关
+                    self.pos += 1;
+                    self.expect_punct(':')?;
+                    self.skip_line(token.line);
+                }
+                "BO_" => {
+                    self.parse_message()?;
+                }
+                "CM_" => {
+                    self.parse_comment()?;
+                }
+                "SG_" => {
+                    return Err(format!("line {}: signal outside of message", token.line));
+                }
+                _ => {
+                    let what = match keyword.as_str() {
+                        "BA_DEF_" | "BA_DEF_DEF_" | "BA_" | "BA_DEF_REL_" | "BA_DEF_DEF_REL_" | "BA_REL_" => {
+                            format!("{} (attribute)", keyword)
+                        }
+                        "VAL_" | "VAL_TABLE_" => format!("{} (value table)", keyword),
+                        "SIG_VALTYPE_" => format!("{} (float signal type)", keyword),
+                        _ => keyword.clone(),
+                    };
+                    self.unsupported(token.line, &what);
+                    self.pos += 1;
+                    self.skip_statement()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // BO_ <id> <name>: <dlc> <transmitter>
+    fn parse_message(&mut self) -> Result<(), String> {
+        let line = self.next()?.line;
+        let id: u32 = self.expect_num()?;
+        let name = self.expect_ident()?;
+        self.expect_punct(':')?;
+        let dlc: u16 = self.expect_num()?;
+        self.skip_line(line); // transmitter
+
+        let mut insdef = InsDef::new(&name, id & !EXTENDED_FLAG);
+        insdef.extended = id & EXTENDED_FLAG != 0;
+        insdef.dlc = dlc;
+        let pseudo = name == "VECTOR__INDEPENDENT_SIG_MSG";
+        while self.peek_ident() == Some("SG_") {
+            if let Some(vardef) = self.parse_signal(&name)? {
+                if !pseudo {
+                    insdef.args.add(vardef);
+                }
+            }
+        }
+        if pseudo {
+            self.unsupported(line, &format!("message '{}' (signals not assigned to any message)", name));
+        } else if self.msg_index.contains_key(&id) {
+            return Err(format!("line {}: duplicated message id: {}", line, id));
+        } else {
+            self.msg_index.insert(id, self.dbc.inss.len());
+            self.dbc.inss.push(insdef);
+        }
+        Ok(())
+    }
+
+    // SG_ <name> [M|m<n>] : <start>|<len>@<order><sign> (<factor>,<offset>) [<min>|<max>] "<unit>" <receivers>
+    fn parse_signal(&mut self, msg_name: &str) -> Result<Option<VarDef>, String> {
+        let line = self.next()?.line;
+        let name = self.expect_ident()?;
+        let mux = if self.peek_ident().is_some() { Some(self.expect_ident()?) } else { None };
+        self.expect_punct(':')?;
+        let start_bit: u32 = self.expect_num()?;
+        self.expect_punct('|')?;
+        let bit_len: u32 = self.expect_num()?;
+        self.expect_punct('@')?;
+        let byte_order = match self.expect_num::<u8>()? {
+            0 => ByteOrder::Motorola,
+            1 => ByteOrder::Intel,
+            n => return Err(format!("line {}: invalid byte order: {}", line, n)),
+        };
+        let signed = match self.next()?.tok {
+            Tok::Punct('+') => false,
+            Tok::Punct('-') => true,
+            tok => return Err(format!("line {}: expected '+' or '-', found {:?}", line, tok)),
+        };
+        self.expect_punct('(')?;
+        let factor: f64 = self.expect_num()?;
+        self.expect_punct(',')?;
+        let offset: f64 = self.expect_num()?;
+        self.expect_punct(')')?;
+        self.expect_punct('[')?;
+        let min: f64 = self.expect_num()?;
+        self.expect_punct('|')?;
+        let max: f64 = self.expect_num()?;
+        self.expect_punct(']')?;
+        let unit = self.expect_str()?;
+        self.skip_line(line); // receivers
+
+        if bit_len == 0 || bit_len > 64 {
+            return Err(format!("line {}: invalid signal length: {}", line, bit_len));
+        }
+        let mux = match mux {
+            None => None,
+            Some(ref mux) if mux == "M" => Some(Mux::Multiplexor),
+            Some(ref mux) => match (mux.starts_with('m'), mux[1..].parse()) {
+                (true, Ok(group)) => Some(Mux::Multiplexed(group)),
+                _ => {
+                    // 扩展多路复用(如m1M)
+                    let what = format!("multiplexed signal '{}' ({}) in message '{}'", name, mux, msg_name);
+                    self.unsupported(line, &what);
+                    return Ok(None);
+                }
+            },
+        };
+
+        let signal = SignalLayout {
+            factor,
+            offset,
+            unit,
+            ..SignalLayout::new(start_bit, bit_len, byte_order, signed)
+        };
+        let mut vardef = VarDef::new(name.as_str(), signal_type(&signal));
+        if min != 0.0 || max != 0.0 {
+            vardef.range = format!("{}...{}", min, max);
+        }
+        vardef.signal = Some(signal);
+        vardef.mux = mux;
+        Ok(Some(vardef))
+    }
+
+    // CM_ "text"; | CM_ BO_ <id> "text"; | CM_ SG_ <id> <name> "text"; | CM_ BU_/EV_ ...
+    fn parse_comment(&mut self) -> Result<(), String> {
+        let line = self.next()?.line;
+        match self.peek_ident().map(|s| s.to_string()) {
+            Some(ref kind) if kind == "BO_" || kind == "SG_" => {
+                self.pos += 1;
+                let id: u32 = self.expect_num()?;
+                let signame = if kind == "SG_" { Some(self.expect_ident()?) } else { None };
+                let text = self.expect_str()?;
+                self.expect_punct(';')?;
+                let insdef = match self.msg_index.get(&id) {
+                    Some(index) => &mut self.dbc.inss[*index],
+                    None => {
+                        self.unsupported(line, &format!("comment of unknown message {}", id));
+                        return Ok(());
+                    }
+                };
+                match signame {
+                    None => insdef.note = Some(text),
+                    Some(signame) => {
+                        match insdef.args.defs.iter_mut().find(|def| def.name == signame) {
+                            Some(vardef) => vardef.note = Some(text),
+                            None => {
+                                let what = format!("comment of unknown signal '{}' in message {}", signame, id);
+                                self.unsupported(line, &what);
+                            }
+                        }
+                    }
+                }
+            }
+            Some(kind) => {
+                self.unsupported(line, &format!("CM_ {} (comment)", kind));
+                self.skip_statement()?;
+            }
+            None => {
+                self.unsupported(line, "CM_ (network comment)");
+                self.skip_statement()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// 根据信号的位长度和编码选择参数类型
+pub fn signal_type(signal: &SignalLayout) -> &'static str {
+    if signal.factor != 1.0 || signal.offset != 0.0 {
+        return "f64";
+    }
+    match (signal.signed, signal.bit_len) {
+        (false, 1..=8) => "u8",
+        (true, 1..=8) => "i8",
+        (false, 9..=16) => "u16",
+        (true, 9..=16) => "i16",
+        (false, 17..=32) => "u32",
+        (true, 17..=32) => "i32",
+        (false, _) => "u64",
+        (true, _) => "i64",
+    }
+}
+
+/// 解析DBC文本
+/// 注释(CM_)可以出现在被注释的报文之后的任意位置
+pub fn parse_dbc(text: &str) -> Result<Dbc, String> {
+    let mut parser = Parser {
+        tokens: tokenize(text)?,
+        pos: 0,
+        dbc: Dbc { inss: Vec::new(), unsupported: Vec::new() },
+        msg_index: HashMap::new(),
+    };
+    parser.parse()?;
+    Ok(parser.dbc)
+}
+
+// 发送/接收节点未知时使用的占位节点名
+const NO_NODE: &str = "Vector__XXX";
+
+// DBC名称必须是C标识符，其他字符替换为'_'
+pub fn dbc_ident(name: &str) -> String {
+    let mut ident: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' })
+        .collect();
+    if !ident.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        ident.insert(0, '_');
+    }
+    ident
+}
+
+fn dbc_str(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// 生成DBC文本；报文按canid排序，以保证输出稳定
+/// 指令名和参数名中不能用于DBC的字符（如空格）被替换为'_'
+pub fn write_dbc<'a, I: IntoIterator<Item = &'a InsDef>>(inss: I) -> Result<String, String> {
+    let mut inss: Vec<&InsDef> = inss.into_iter().collect();
+    inss.sort_by(|a, b| (a.canid, &a.name).cmp(&(b.canid, &b.name)));
+
+    let mut out = String::new();
+    let mut comments = String::new();
+    out.push_str("VERSION \"\"\n\n\nNS_ :\n\tCM_\n\nBS_:\n\nBU_:\n\n");
+    let mut names = HashSet::new();
+    for insdef in inss {
+        let name = dbc_ident(&insdef.name);
+        if !names.insert(name.clone()) {
+            return Err(format!("Duplicated message name: {}", name));
+        }
+        let id = if insdef.extended { insdef.canid | EXTENDED_FLAG } else { insdef.canid };
+        let layouts = insdef.signal_layouts().map_err(|err| format!("{}: {}", insdef.name, err))?;
+        out.push_str(&format!("\nBO_ {} {}: {} {}\n", id, name, insdef.dlc, NO_NODE));
+        if let Some(ref note) = insdef.note {
+            comments.push_str(&format!("CM_ BO_ {} \"{}\";\n", id, dbc_str(note)));
+        }
+        let mut signames = HashSet::new();
+        for (vardef, layout) in insdef.args.defs.iter().zip(layouts.iter()) {
+            if layout.min_len() > insdef.dlc as usize {
+                return Err(format!("{}: signal {} exceeds data length", insdef.name, vardef.name));
+            }
+            let signame = dbc_ident(&vardef.name);
+            if !signames.insert(signame.clone()) {
+                return Err(format!("{}: duplicated signal name: {}", insdef.name, signame));
+            }
+            let (min, max) = vardef.range_bounds().unwrap_or((0.0, 0.0));
+            let mux = match vardef.mux {
+                Some(Mux::Multiplexor) => " M".to_string(),
+                Some(Mux::Multiplexed(group)) => format!(" m{}", group),
+                None => String::new(),
+            };
+            out.push_str(&format!(" SG_ {}{} : {}|{}@{}{} ({},{}) [{}|{}] \"{}\" {}\n",
+                                  signame, mux, layout.start_bit, layout.bit_len,
+                                  if layout.byte_order == ByteOrder::Intel { 1 } else { 0 },
+                                  if layout.signed { '-' } else { '+' },
+                                  layout.factor, layout.offset, min, max, dbc_str(&layout.unit), NO_NODE));
+            if let Some(ref note) = vardef.note {
+                comments.push_str(&format!("CM_ SG_ {} {} \"{}\";\n", id, signame, dbc_str(note)));
+            }
+        }
+    }
+    out.push_str("\n\n");
+    out.push_str(&comments);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_dbc, write_dbc};
+    use engine::{Engine, Context};
+    use instruction::InsDef;
+    use signal::{ByteOrder, Mux};
+    use variable::{VarDef, VarBindingList};
+
+    const DBC: &str = r#"VERSION "1.0"
+
+NS_ :
+	NS_DESC_
+	CM_
+	BA_DEF_
+
+BS_:
+
+BU_: Radar Gateway
+
+BO_ 1000 move_radar: 8 Gateway
+ SG_ angle : 7|16@0- (0.01,0) [-180|180] "deg" Radar
+ SG_ speed : 16|8@1+ (1,0) [0|0] "" Radar,Gateway
+
+BO_ 2566844926 status: 4 Radar
+ SG_ temp : 0|8@1+ (0.5,-40) [-40|87.5] "degC" Gateway
+ SG_ mode M : 8|4@1+ (1,0) [0|15] "" Gateway
+ SG_ detail m1 : 12|4@1+ (1,0) [0|15] "" Gateway
+ SG_ level m2 : 12|4@1+ (1,0) [0|15] "" Gateway
+ SG_ sub m3M : 16|8@1+ (1,0) [0|0] "" Gateway
+
+CM_ "network comment";
+CM_ BO_ 1000 "Moves the radar
+to the given angle";
+CM_ SG_ 1000 angle "Target angle";
+CM_ SG_ 1000 missing "no such signal";
+BA_DEF_ BO_ "GenMsgCycleTime" INT 0 10000;
+BA_ "GenMsgCycleTime" BO_ 1000 100;
+VAL_ 1000 speed 0 "stop" 1 "slow" ;
+"#;
+
+    #[test]
+    fn test_parse_dbc() {
+        let dbc = parse_dbc(DBC).expect("ok");
+        assert_eq!(dbc.inss.len(), 2);
+
+        let movr = &dbc.inss[0];
+        assert_eq!(movr.name, "move_radar");
+        assert_eq!(movr.canid, 1000);
+        assert!(!movr.extended);
+        assert_eq!(movr.dlc, 8);
+        assert_eq!(movr.note, Some("Moves the radar\nto the given angle".to_string()));
+        let angle = movr.args.find("angle").expect("angle");
+        assert_eq!(angle.typ, "f64");
+        assert_eq!(angle.range, "-180...180");
+        assert_eq!(angle.note, Some("Target angle".to_string()));
+        let signal = angle.signal.as_ref().expect("layout");
+        assert_eq!((signal.start_bit, signal.bit_len), (7, 16));
+        assert_eq!(signal.byte_order, ByteOrder::Motorola);
+        assert!(signal.signed);
+        assert_eq!(signal.unit, "deg");
+        let speed = movr.args.find("speed").expect("speed");
+        assert_eq!(speed.typ, "u8");
+        assert_eq!(speed.range, "");
+
+        let status = &dbc.inss[1];
+        assert_eq!(status.canid, 0x18FEF1FE);
+        assert!(status.extended);
+        assert_eq!(status.dlc, 4);
+        assert_eq!(status.args.defs.len(), 4); // extended multiplexing is not supported
+        assert_eq!(status.args.find("mode").unwrap().mux, Some(Mux::Multiplexor));
+        assert_eq!(status.args.find("level").unwrap().mux, Some(Mux::Multiplexed(2)));
+        assert_eq!(status.args.find("temp").unwrap().mux, None);
+        let temp = status.args.find("temp").expect("temp");
+        assert_eq!(temp.signal.as_ref().map(|s| (s.factor, s.offset)), Some((0.5, -40.0)));
+
+        assert_eq!(dbc.unsupported, vec![
+            "line 21: multiplexed signal 'sub' (m3M) in message 'status' ignored",
+            "line 23: CM_ (network comment) ignored",
+            "line 27: comment of unknown signal 'missing' in message 1000 ignored",
+            "line 28: BA_DEF_ (attribute) ignored",
+            "line 29: BA_ (attribute) ignored",
+            "line 30: VAL_ (value table) ignored",
+        ]);
+    }
+
+    #[test]
+    fn test_parse_dbc_errors() {
+        assert_eq!(parse_dbc("BO_ 1 a: 8 X\n SG_ s : 0|8@2+ (1,0) [0|0] \"\" X\n").err(),
+                   Some("line 2: invalid byte order: 2".to_string()));
+        assert_eq!(parse_dbc(" SG_ s : 0|8@1+ (1,0) [0|0] \"\" X\n").err(),
+                   Some("line 1: signal outside of message".to_string()));
+        assert_eq!(parse_dbc("CM_ \"abc").err(), Some("line 1: unterminated string".to_string()));
+        assert!(parse_dbc("BO_ 1 a: 8 X\nBO_ 1 b: 8 X\n").is_err()); // duplicated id
+    }
+
+    #[test]
+    fn test_import_dbc() {
+        let mut engine = Engine::new();
+        let mut context = Context::new();
+        let unsupported = engine.import_dbc(DBC, &mut context).expect("ok");
+        assert_eq!(unsupported.len(), 6);
+        assert!(engine.find_ins("move_radar").is_some());
+        assert!(engine.find_ins("status").is_some());
+
+        let mut args = VarBindingList::new();
+        args.set_binding("angle", "-1.5");
+        args.set_binding("speed", "20");
+        engine.exec_ins("move_radar", &args, &mut context).expect("ok");
+        args.set_binding("speed", "256");
+        assert!(engine.exec_ins("move_radar", &args, &mut context).is_err());
+
+        let mut args = VarBindingList::new();
+        args.set_binding("temp", "20");
+        args.set_binding("mode", "2");
+        args.set_binding("level", "7");
+        engine.exec_ins("status", &args, &mut context).expect("ok");
+        args.set_binding("detail", "1");
+        assert!(engine.exec_ins("status", &args, &mut context).is_err()); // detail is not in group 2
+    }
+
+    #[test]
+    fn test_write_dbc() {
+        let mut engine = Engine::new();
+        let mut movr = InsDef::new("rotate radar", 900);
+        movr.note = Some("Moves the \"radar\"".to_string());
+        movr.args.add(VarDef::new("a", "i32"));
+        movr.args.add(VarDef::new("b", "u8"));
+        movr.args.add(VarDef::new("c", "u16"));
+        movr.args.add(VarDef::new("d", "u8"));
+        movr.args.defs[1].range = "0..100".to_string();
+        movr.args.defs[2].note = Some("Speed".to_string());
+        engine.add_ins(movr);
+        engine.import_dbc(DBC, &mut Context::new()).expect("ok");
+
+        let text = engine.export_dbc().expect("ok");
+        assert_eq!(text, include_str!("../testdata/export.dbc"));
+
+        // re-parse the output
+        let dbc = parse_dbc(&text).expect("ok");
+        assert!(dbc.unsupported.is_empty());
+        assert_eq!(dbc.inss.len(), 3);
+        let movr = &dbc.inss[0];
+        assert_eq!((movr.name.as_str(), movr.canid, movr.dlc), ("rotate_radar", 900, 8));
+        assert_eq!(movr.note, Some("Moves the \"radar\"".to_string()));
+        assert_eq!(movr.args.find("b").map(|def| def.range.as_str()), Some("0...99"));
+        assert_eq!(movr.args.find("c").and_then(|def| def.note.clone()), Some("Speed".to_string()));
+        let original = engine.find_ins("rotate radar").unwrap();
+        assert_eq!(movr.signal_layouts(), original.signal_layouts());
+        let imported = engine.find_ins("status").unwrap();
+        let status = &dbc.inss[2];
+        assert!(status.extended);
+        assert_eq!(status.args.find("temp").unwrap().range, "-40...87.5");
+        assert_eq!(status.signal_layouts(), imported.signal_layouts());
+        assert_eq!(status.args.find("detail").unwrap().mux, Some(Mux::Multiplexed(1)));
+
+        // exporting an imported DBC gives the same text
+        let mut engine2 = Engine::new();
+        engine2.import_dbc(&text, &mut Context::new()).expect("ok");
+        assert_eq!(engine2.export_dbc().expect("ok"), text);
+    }
+
+    #[test]
+    fn test_write_dbc_errors() {
+        let mut ins = InsDef::new("x", 1);
+        ins.args.add(VarDef::new("s", "str"));
+        assert_eq!(write_dbc(vec![&ins]).err(), Some("x: Unsupport arg type: str".to_string()));
+        let mut ins = InsDef::new("x", 1);
+        ins.dlc = 2;
+        ins.args.add(VarDef::new("a", "u32"));
+        assert_eq!(write_dbc(vec![&ins]).err(), Some("x: signal a exceeds data length".to_string()));
+        let (a, b) = (InsDef::new("a b", 1), InsDef::new("a_b", 2));
+        assert!(write_dbc(vec![&a, &b]).is_err());
+        let mut ins = InsDef::new("x", 1);
+        ins.args.add(VarDef::new("a b", "u8"));
+        ins.args.add(VarDef::new("a_b", "u8"));
+        assert_eq!(write_dbc(vec![&ins]).err(), Some("x: duplicated signal name: a_b".to_string()));
+    }
+}
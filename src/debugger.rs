@@ -0,0 +1,670 @@
+// 调试器：逐条执行函数，支持断点、单步和修改变量，见Engine::debug()
+//
+//   let mut debugger = engine.debug("main", &args)?;
+//   debugger.add_breakpoint("blink", 2, Some("n > 1"))?;
+//   match debugger.cont(&mut context) {
+//       Stop::Breakpoint(id) => { debugger.stack(); debugger.step_over(&mut context); }
+//       Stop::Step => {}
+//       Stop::Finished(result) => {}
+//   }
+//
+// 暂停时，栈顶帧的index指向将要执行（尚未执行）的语句。
+// 执行结果与FnDef::exec()相同；循环计数保存在调用帧中，不使用也不影响Stmt中的运行时状态。
+// 执行时与FnDef::exec()一样检查Context中的取消、语句数和截止时间，被取消而结束时调用Engine.cleanup指定的函数；
+// Context.calls在执行时与调用栈一致，暂停时为空。
+
+use engine::{Engine, Context, Call, depth_error};
+use function::FnDef;
+use statement::{StmtKind, SourcePos};
+use variable::VarBindingList;
+use utils::{split_lr, parse_int};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+const COMPARE_OPS: &[&str] = &["==", "!=", "<=", ">=", "<", ">"];
+
+// 断点条件：value [op value]，只有一个值时非零、非空为真
+#[derive(Debug, Clone, PartialEq)]
+struct Condition {
+    left: String,
+    op: Option<(&'static str, String)>,
+}
+
+/// 断点
+#[derive(Debug, Clone, PartialEq)]
+pub struct Breakpoint {
+    pub id: usize,
+    pub fn_name: String,
+    /// 语句在函数中的索引，在执行该语句之前暂停
+    pub index: usize,
+    /// 条件表达式，如"n > 1"、"mode == \"auto\""、"ok"；None表示总是暂停
+    pub condition: Option<String>,
+    parsed: Option<Condition>,
+}
+
+/// 暂停或结束的原因
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stop {
+    /// 遇到断点（断点的id）
+    Breakpoint(usize),
+    /// 单步执行完成
+    Step,
+    /// 函数执行结束，不能再继续执行
+    Finished(Result<(), String>),
+}
+
+/// 调用栈中的一帧
+#[derive(Debug, Clone)]
+pub struct FrameInfo {
+    pub fn_name: String,
+    /// 将要执行的语句的索引；等于语句数量时表示即将返回
+    pub index: usize,
+    /// 该语句在脚本中的位置（如果有）
+    pub pos: Option<SourcePos>,
+    /// 调用时传入的参数（已求值）
+    pub args: VarBindingList,
+    /// 当前的局部变量（包括参数）
+    pub locals: VarBindingList,
+}
+
+struct Frame {
+    fndef: Rc<FnDef>,
+    eip: usize,
+    args: VarBindingList,
+    locals: VarBindingList,
+    // 循环开始语句的索引 -> 已执行的次数
+    counters: HashMap<usize, u32>,
+    // Loop <-> EndLoop
+    pairs: HashMap<usize, usize>,
+    result: Result<(), String>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Continue,
+    Into,
+    Over,
+    Out,
+}
+
+/// 函数调试器，由Engine::debug()创建
+pub struct Debugger<'a> {
+    engine: &'a Engine,
+    // 调用栈，最后一个是正在执行的函数
+    frames: Vec<Frame>,
+    breakpoints: Vec<Breakpoint>,
+    next_id: usize,
+    started: bool,
+    finished: Option<Result<(), String>>,
+}
+
+impl<'a> Debugger<'a> {
+    /// 准备调试函数name，暂停在第一条语句之前
+    pub fn new(engine: &'a Engine, name: &str, args: &VarBindingList) -> Result<Debugger<'a>, String> {
+        let fndef = engine.find_fn(name)?.ok_or_else(|| format!("No such fn: {}", name))?;
+        let frame = new_frame(fndef, args.clone())?;
+        Ok(Debugger { engine, frames: vec![frame], breakpoints: Vec::new(), next_id: 1, started: false, finished: None })
+    }
+
+    /// 添加断点，返回断点的id；函数和语句不存在时也可以添加（函数可能稍后加载）
+    pub fn add_breakpoint(&mut self, fn_name: &str, index: usize, condition: Option<&str>) -> Result<usize, String> {
+        let parsed = match condition {
+            Some(condition) => Some(parse_condition(condition)?),
+            None => None,
+        };
+        let id = self.next_id;
+        self.next_id += 1;
+        self.breakpoints.push(Breakpoint {
+            id,
+            fn_name: fn_name.to_string(),
+            index,
+            condition: condition.map(|condition| condition.to_string()),
+            parsed,
+        });
+        Ok(id)
+    }
+
+    /// 删除断点，断点不存在时返回false
+    pub fn remove_breakpoint(&mut self, id: usize) -> bool {
+        let count = self.breakpoints.len();
+        self.breakpoints.retain(|breakpoint| breakpoint.id != id);
+        self.breakpoints.len() != count
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    /// 调用栈，stack()[0]是被调试的函数，最后一个是正在执行的函数；执行结束后为空
+    pub fn stack(&self) -> Vec<FrameInfo> {
+        self.frames.iter().map(|frame| FrameInfo {
+            fn_name: frame.fndef.name.clone(),
+            index: frame.eip,
+            pos: frame.fndef.stmts.get(frame.eip).and_then(|stmt| stmt.pos),
+            args: frame.args.clone(),
+            locals: frame.locals.clone(),
+        }).collect()
+    }
+
+    /// 执行结果；尚未结束时为None
+    pub fn finished(&self) -> Option<&Result<(), String>> {
+        self.finished.as_ref()
+    }
+
+    /// 修改调用栈中第frame帧的局部变量（编号同stack()），value为空时删除变量；全局变量直接修改context.globals
+    pub fn set_local(&mut self, frame: usize, name: &str, value: &str) -> Result<(), String> {
+        let frame = self.frames.get_mut(frame).ok_or_else(|| format!("No such frame: {}", frame))?;
+        frame.locals.set_binding(name, value);
+        Ok(())
+    }
+
+    /// 在第frame帧中对值求值，如"var:x"、"int:1"
+    pub fn eval(&self, frame: usize, value: &str, context: &Context) -> Option<String> {
+        self.frames.get(frame).and_then(|frame| frame.locals.eval(value, Some(&context.globals), None))
+    }
+
+    /// 继续执行，直到遇到断点或执行结束
+    pub fn cont(&mut self, context: &mut Context) -> Stop {
+        self.run(Mode::Continue, context)
+    }
+
+    /// 执行一条语句；遇到call时进入被调用的函数
+    pub fn step_into(&mut self, context: &mut Context) -> Stop {
+        self.run(Mode::Into, context)
+    }
+
+    /// 执行一条语句；call语句执行完被调用的函数（除非其中有断点）
+    pub fn step_over(&mut self, context: &mut Context) -> Stop {
+        self.run(Mode::Over, context)
+    }
+
+    /// 执行到当前函数返回到调用者
+    pub fn step_out(&mut self, context: &mut Context) -> Stop {
+        self.run(Mode::Out, context)
+    }
+
+    fn run(&mut self, mode: Mode, context: &mut Context) -> Stop {
+        if let Some(ref result) = self.finished {
+            return Stop::Finished(result.clone());
+        }
+        let stop = self.run_until(mode, context);
+        context.calls.clear();
+        context.abort = None;
+        if let Stop::Finished(Err(_)) = stop {
+            if context.is_cancelled() {
+                self.engine.exec_cleanup(context);
+            }
+        }
+        stop
+    }
+
+    fn run_until(&mut self, mode: Mode, context: &mut Context) -> Stop {
+        if !self.started {
+            self.started = true;
+            context.globals.remove_binding("$return");
+            if let Some(id) = self.hit_breakpoint(context) {
+                return Stop::Breakpoint(id);
+            }
+        }
+        let depth = self.frames.len();
+        loop {
+            if let Some(result) = self.unwind() {
+                return Stop::Finished(result);
+            }
+            // 调用者的eip已经指向调用语句之后
+            let top = self.frames.len() - 1;
+            context.calls = self.frames.iter().enumerate()
+                .map(|(i, frame)| Call { fn_name: frame.fndef.name.clone(), index: if i == top { frame.eip } else { frame.eip - 1 } })
+                .collect();
+            context.check_budget();
+            match context.abort.clone() {
+                Some(err) => self.abort(err),
+                None => self.exec_stmt(context),
+            }
+            if let Some(result) = self.unwind() {
+                return Stop::Finished(result);
+            }
+            if let Some(id) = self.hit_breakpoint(context) {
+                return Stop::Breakpoint(id);
+            }
+            let stop = match mode {
+                Mode::Continue => false,
+                Mode::Into => true,
+                Mode::Over => self.frames.len() <= depth,
+                Mode::Out => self.frames.len() < depth,
+            };
+            if stop {
+                return Stop::Step;
+            }
+        }
+    }
+
+    // 执行完的函数返回到调用者；全部执行完时返回结果
+    fn unwind(&mut self) -> Option<Result<(), String>> {
+        while self.frames.last().is_some_and(|frame| frame.eip >= frame.fndef.stmts.len()) {
+            let frame = self.frames.pop().expect("frame");
+            match self.frames.last_mut() {
+                Some(caller) => caller.result = frame.result,
+                None => {
+                    self.finished = Some(frame.result.clone());
+                    return Some(frame.result);
+                }
+            }
+        }
+        None
+    }
+
+    fn hit_breakpoint(&self, context: &Context) -> Option<usize> {
+        let frame = self.frames.last()?;
+        self.breakpoints.iter().find(|breakpoint| {
+            breakpoint.fn_name == frame.fndef.name && breakpoint.index == frame.eip &&
+                breakpoint.parsed.as_ref().is_none_or(|condition| condition.eval(&frame.locals, context))
+        }).map(|breakpoint| breakpoint.id)
+    }
+
+    // 执行栈顶帧的一条语句
+    fn exec_stmt(&mut self, context: &mut Context) {
+        let engine = self.engine;
+        let depth = self.frames.len();
+        let frame = self.frames.last_mut().expect("frame");
+        let fndef = frame.fndef.clone();
+        let stmt = &fndef.stmts[frame.eip];
+        frame.eip += 1;
+        let mut call = None;
+        let mut abort = false;
+        match stmt.kind {
+            StmtKind::CallIns => {
+                frame.result = fndef.eval_args(stmt, &frame.locals, context).and_then(|args| engine.exec_ins(&stmt.content, &args, context));
+            }
+            StmtKind::CallFn => {
+                match fndef.eval_args(stmt, &frame.locals, context) {
+                    Ok(args) => {
+                        let callee = match engine.find_fn(&stmt.content) {
+                            Ok(Some(callee)) => new_frame(callee, args),
+                            Ok(None) => Err(format!("No such fn: {}", stmt.content)),
+                            Err(err) => Err(err),
+                        };
+                        match callee {
+                            Ok(_) if depth >= context.max_depth => abort = true,
+                            Ok(callee) => call = Some(callee),
+                            Err(err) => {
+                                context.log_error(&err);
+                                frame.result = Err(err);
+                            }
+                        }
+                    }
+                    Err(err) => frame.result = Err(err),
+                }
+            }
+            StmtKind::Loop => {
+                let begin = frame.eip - 1;
+                let count: u32 = stmt.args.raw_value_of("$count").and_then(|count| count.parse().ok()).unwrap_or(0);
+                let counter = frame.counters.entry(begin).or_insert(0);
+                if *counter < count {
+                    *counter += 1;
+                } else {
+                    frame.counters.remove(&begin);
+                    frame.eip = frame.pairs[&begin] + 1;
+                }
+            }
+            StmtKind::EndLoop => frame.eip = frame.pairs[&(frame.eip - 1)],
+            StmtKind::Return => {
+                match fndef.eval_return(stmt, &frame.locals, context) {
+                    Ok(value) => context.globals.set_binding("$return", &value),
+                    Err(err) => frame.result = Err(err),
+                }
+                frame.eip = fndef.stmts.len();
+            }
+            _ => {
+                if let Some(result) = fndef.exec_stmt(stmt, &mut frame.locals, context) {
+                    frame.result = result;
+                }
+            }
+        }
+        if abort {
+            // 超过最大调用深度：整个执行以错误结束
+            let calls = self.frames.iter().map(|frame| (frame.fndef.name.as_str(), frame.eip - 1));
+            let err = depth_error(context.max_depth, calls, &stmt.content);
+            context.log_error(&err);
+            self.abort(err);
+        }
+        if let Some(callee) = call {
+            context.globals.remove_binding("$return");
+            self.frames.push(callee);
+        }
+    }
+
+    // 中止执行：所有的帧以错误err结束
+    fn abort(&mut self, err: String) {
+        for frame in &mut self.frames {
+            frame.eip = frame.fndef.stmts.len();
+            frame.result = Err(err.clone());
+        }
+    }
+}
+
+fn new_frame(fndef: Rc<FnDef>, args: VarBindingList) -> Result<Frame, String> {
+    let mut pairs = HashMap::new();
+    let mut loops = Vec::new();
+    for (index, stmt) in fndef.stmts.iter().enumerate() {
+        match stmt.kind {
+            StmtKind::Loop => loops.push(index),
+            StmtKind::EndLoop => {
+                let begin = loops.pop().ok_or_else(|| format!("Unpaired endloop in function: {} line: {}", fndef.name, index))?;
+                pairs.insert(begin, index);
+                pairs.insert(index, begin);
+            }
+            _ => {}
+        }
+    }
+    if let Some(index) = loops.pop() {
+        return Err(format!("Unclosed loop in function: {} line: {}", fndef.name, index));
+    }
+    let mut locals = VarBindingList::new();
+    locals.add_more(&args);
+    Ok(Frame { fndef, eip: 0, args, locals, counters: HashMap::new(), pairs, result: Ok(()) })
+}
+
+/// 解析脚本语法的值（1、-1.5、"text"、x）或带前缀的值，用于断点条件和调试器中修改变量
+pub fn parse_value(text: &str) -> Result<String, String> {
+    let text = text.trim();
+    let (negative, number) = match text.strip_prefix('-') {
+        Some(number) => (true, number),
+        None => (false, text),
+    };
+    let sign = if negative { "-" } else { "" };
+    if text.len() >= 2 && text.starts_with('"') && text.ends_with('"') {
+        Ok(format!("str:{}", &text[1..text.len() - 1]))
+    } else if ["int:", "float:", "str:", "hex:", "var:"].iter().any(|prefix| text.starts_with(prefix)) {
+        Ok(text.to_string())
+    } else if let Ok(number) = parse_int(number) {
+        Ok(format!("int:{}{}", sign, number))
+    } else if number.contains('.') && number.parse::<f64>().is_ok() {
+        Ok(format!("float:{}", text))
+    } else if !text.is_empty() && text.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '$') {
+        Ok(format!("var:{}", text))
+    } else {
+        Err(format!("Invalid value: {}", text))
+    }
+}
+
+fn parse_condition(text: &str) -> Result<Condition, String> {
+    // 先匹配较长的运算符，跳过引号中的内容
+    let mut quoted = false;
+    for (index, c) in text.char_indices() {
+        if c == '"' {
+            quoted = !quoted;
+        }
+        if quoted {
+            continue;
+        }
+        if let Some(op) = COMPARE_OPS.iter().find(|op| text[index..].starts_with(**op)) {
+            let left = parse_value(&text[..index])?;
+            let right = parse_value(&text[index + op.len()..])?;
+            return Ok(Condition { left, op: Some((op, right)) });
+        }
+    }
+    Ok(Condition { left: parse_value(text)?, op: None })
+}
+
+impl Condition {
+    // 未定义的变量使条件为假
+    fn eval(&self, locals: &VarBindingList, context: &Context) -> bool {
+        let left = match locals.eval(&self.left, Some(&context.globals), None) {
+            Some(left) => left,
+            None => return false,
+        };
+        let (op, right) = match self.op {
+            Some((op, ref right)) => match locals.eval(right, Some(&context.globals), None) {
+                Some(right) => (op, right),
+                None => return false,
+            },
+            None => {
+                let (prefix, text) = split_lr(&left, ":");
+                return match prefix {
+                    "int" | "float" => text.parse::<f64>().is_ok_and(|value| value != 0.0),
+                    _ => !text.is_empty(),
+                };
+            }
+        };
+        let (left_prefix, left_text) = split_lr(&left, ":");
+        let (right_prefix, right_text) = split_lr(&right, ":");
+        let numbers = (left_text.parse::<f64>(), right_text.parse::<f64>());
+        let ordering = match numbers {
+            (Ok(x), Ok(y)) if ["int", "float"].contains(&left_prefix) && ["int", "float"].contains(&right_prefix) => x.partial_cmp(&y),
+            _ => Some(left_text.cmp(right_text)),
+        };
+        match (op, ordering) {
+            (_, None) => op == "!=",
+            ("==", Some(ordering)) => ordering.is_eq(),
+            ("!=", Some(ordering)) => ordering.is_ne(),
+            ("<", Some(ordering)) => ordering.is_lt(),
+            ("<=", Some(ordering)) => ordering.is_le(),
+            (">", Some(ordering)) => ordering.is_gt(),
+            (_, Some(ordering)) => ordering.is_ge(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Stop, parse_condition};
+    use engine::{Engine, Context};
+    use variable::VarBindingList;
+    use bus::{Bus, LoopbackBus};
+    use cancel::CancelToken;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    const SCRIPT: &str = r#"
+ins lamp(a: u32, b: u32 = 1) = 0x321
+
+fn main(n: i32) {
+    total := 0
+    loop 3 {
+        call blink(n: n)
+        n += 1
+    }
+    send lamp(a: total)
+    return total
+}
+
+fn blink(n: i32) {
+    send lamp(a: n)
+    total := total + n
+    return n
+}
+"#;
+
+    fn setup() -> (Engine, Context, LoopbackBus, VarBindingList) {
+        let mut engine = Engine::new();
+        let mut context = Context::new();
+        engine.load_script(SCRIPT, &mut context).expect("ok");
+        let (bus, peer) = LoopbackBus::pair();
+        context.bus = Some(Box::new(bus));
+        let mut args = VarBindingList::new();
+        args.set_binding("n", "int:1");
+        (engine, context, peer, args)
+    }
+
+    fn frames(peer: &mut LoopbackBus) -> Vec<u8> {
+        let mut values = Vec::new();
+        while let Ok(Some(frame)) = peer.recv(Duration::from_millis(1)) {
+            values.push(frame.data[3]);
+        }
+        values
+    }
+
+    #[test]
+    fn test_same_as_interpreter() {
+        let (engine, mut context, mut peer, args) = setup();
+        engine.exec_fn("main", &args, &mut context).expect("ok");
+        let expected = (frames(&mut peer), context.globals.raw_value_of("$return").map(|value| value.to_string()));
+
+        let (engine, mut context, mut peer, args) = setup();
+        let mut debugger = engine.debug("main", &args).expect("ok");
+        assert_eq!(debugger.cont(&mut context), Stop::Finished(Ok(())));
+        assert_eq!(debugger.cont(&mut context), Stop::Finished(Ok(())));
+        assert!(debugger.stack().is_empty());
+        assert_eq!((frames(&mut peer), context.globals.raw_value_of("$return").map(|value| value.to_string())), expected);
+        assert_eq!(expected, (vec![1, 2, 3, 6], Some("int:6".to_string())));
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let (engine, mut context, mut peer, args) = setup();
+        let mut debugger = engine.debug("main", &args).expect("ok");
+        let first = debugger.add_breakpoint("main", 0, None).expect("ok");
+        let id = debugger.add_breakpoint("blink", 1, Some("n >= 2")).expect("ok");
+        assert_eq!(debugger.cont(&mut context), Stop::Breakpoint(first));
+        assert!(debugger.remove_breakpoint(first) && !debugger.remove_breakpoint(first));
+
+        assert_eq!(debugger.cont(&mut context), Stop::Breakpoint(id));
+        let stack = debugger.stack();
+        assert_eq!(stack.len(), 2);
+        assert_eq!((stack[0].fn_name.as_str(), stack[0].index), ("main", 3));
+        assert_eq!((stack[1].fn_name.as_str(), stack[1].index), ("blink", 1));
+        assert_eq!(stack[1].pos.map(|pos| (pos.line, pos.col)), Some((16, 5)));
+        assert_eq!(stack[1].args.raw_value_of("n"), Some("int:2"));
+        assert_eq!(frames(&mut peer), vec![1, 2]);
+
+        // 修改变量后继续
+        debugger.set_local(1, "n", "int:10").expect("ok");
+        assert_eq!(debugger.set_local(2, "n", "int:0"), Err("No such frame: 2".to_string()));
+        assert_eq!(debugger.eval(1, "var:n", &context), Some("int:10".to_string()));
+        assert_eq!(debugger.cont(&mut context), Stop::Breakpoint(id));
+        assert_eq!(debugger.stack()[1].locals.raw_value_of("n"), Some("int:3"));
+        assert!(debugger.remove_breakpoint(id));
+        assert_eq!(debugger.cont(&mut context), Stop::Finished(Ok(())));
+        assert_eq!(frames(&mut peer), vec![3, 14]);
+        assert_eq!(context.globals.raw_value_of("total"), Some("int:14"));
+    }
+
+    #[test]
+    fn test_steps() {
+        let (engine, mut context, _peer, args) = setup();
+        let mut debugger = engine.debug("main", &args).expect("ok");
+        let position = |debugger: &super::Debugger| {
+            debugger.stack().iter().map(|frame| format!("{}#{}", frame.fn_name, frame.index)).collect::<Vec<_>>().join(" ")
+        };
+        assert_eq!(position(&debugger), "main#0");
+        assert_eq!(debugger.step_into(&mut context), Stop::Step);
+        assert_eq!(debugger.step_into(&mut context), Stop::Step);
+        assert_eq!(position(&debugger), "main#2");
+        assert_eq!(debugger.step_into(&mut context), Stop::Step);
+        assert_eq!(position(&debugger), "main#3 blink#0");
+        assert_eq!(debugger.step_over(&mut context), Stop::Step);
+        assert_eq!(position(&debugger), "main#3 blink#1");
+        assert_eq!(debugger.step_out(&mut context), Stop::Step);
+        assert_eq!(position(&debugger), "main#3");
+        assert_eq!(debugger.step_over(&mut context), Stop::Step);
+        assert_eq!(position(&debugger), "main#4");
+        // 跳过call
+        for _ in 0..3 {
+            assert_eq!(debugger.step_over(&mut context), Stop::Step);
+        }
+        assert_eq!(position(&debugger), "main#3");
+        assert_eq!(debugger.step_over(&mut context), Stop::Step);
+        assert_eq!(position(&debugger), "main#4");
+        assert_eq!(context.globals.raw_value_of("total"), Some("int:3"));
+        // 在被调用的函数中的断点优先
+        let id = debugger.add_breakpoint("blink", 0, None).expect("ok");
+        debugger.step_over(&mut context);
+        debugger.step_over(&mut context);
+        assert_eq!(debugger.step_over(&mut context), Stop::Breakpoint(id));
+        assert_eq!(position(&debugger), "main#3 blink#0");
+        assert_eq!(debugger.step_out(&mut context), Stop::Step);
+        assert_eq!(debugger.step_out(&mut context), Stop::Finished(Ok(())));
+        assert_eq!(debugger.finished(), Some(&Ok(())));
+        assert_eq!(debugger.step_into(&mut context), Stop::Finished(Ok(())));
+    }
+
+    #[test]
+    fn test_errors() {
+        let (mut engine, mut context, _peer, args) = setup();
+        assert_eq!(engine.debug("none", &args).err(), Some("No such fn: none".to_string()));
+        engine.load_script("fn bad() { call none() }\nfn empty() {}", &mut context).expect("ok");
+        let mut debugger = engine.debug("empty", &args).expect("ok");
+        assert_eq!(debugger.step_into(&mut context), Stop::Finished(Ok(())));
+        let mut debugger = engine.debug("bad", &args).expect("ok");
+        assert_eq!(debugger.cont(&mut context), Stop::Finished(Err("No such fn: none".to_string())));
+        let mut debugger = engine.debug("main", &args).expect("ok");
+        assert!(debugger.add_breakpoint("main", 0, Some("n = 1")).is_err());
+        assert!(debugger.add_breakpoint("main", 0, Some("")).is_err());
+        assert_eq!(debugger.breakpoints().len(), 0);
+    }
+
+    #[test]
+    fn test_call_depth() {
+        let (mut engine, mut context, _peer, args) = setup();
+        engine.load_script("fn deep() { call down() }\nfn down() { call down() }", &mut context).expect("ok");
+        context.max_depth = 3;
+        let expected = engine.exec_fn("deep", &args, &mut context);
+        assert_eq!(expected, Err("Call depth limit exceeded (3): deep #0 > down #0 (x2) > down".to_string()));
+        let mut debugger = engine.debug("deep", &args).expect("ok");
+        assert_eq!(debugger.cont(&mut context), Stop::Finished(expected));
+        assert!(debugger.stack().is_empty());
+    }
+
+    #[test]
+    fn test_budget() {
+        let (mut engine, mut context, _peer, args) = setup();
+        engine.load_script("fn spin() { call wait() }\nfn wait() { loop 4000000000 {} }", &mut context).expect("ok");
+        context.fuel = Some(5);
+        let expected = engine.exec_fn("spin", &args, &mut context);
+        assert_eq!(expected, Err("Statement budget exhausted at spin #0 > wait #0".to_string()));
+        context.fuel = Some(5);
+        let mut debugger = engine.debug("spin", &args).expect("ok");
+        assert_eq!(debugger.cont(&mut context), Stop::Finished(expected));
+        assert!(debugger.stack().is_empty());
+        assert!(context.calls.is_empty() && context.abort.is_none());
+
+        context.fuel = None;
+        context.deadline = Some(Instant::now() + Duration::from_millis(50));
+        let mut debugger = engine.debug("spin", &args).expect("ok");
+        match debugger.cont(&mut context) {
+            Stop::Finished(Err(err)) => assert!(err.starts_with("Deadline exceeded at spin #0 > wait #"), "{}", err),
+            stop => panic!("unexpected stop: {:?}", stop),
+        }
+    }
+
+    #[test]
+    fn test_cancel() {
+        let (mut engine, mut context, mut peer, args) = setup();
+        engine.load_script("fn spin() { loop 4000000000 {} }\nfn safe() { send lamp(a: 0) }", &mut context).expect("ok");
+        engine.cleanup = Some("safe".to_string());
+        let token = CancelToken::new();
+        context.cancel = Some(token.clone());
+        let mut debugger = engine.debug("spin", &args).expect("ok");
+        let canceller = {
+            let token = token.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                token.cancel();
+            })
+        };
+        let stop = debugger.cont(&mut context);
+        canceller.join().expect("join");
+        match stop {
+            Stop::Finished(Err(err)) => assert!(err.starts_with("Cancelled at spin #"), "{}", err),
+            stop => panic!("unexpected stop: {:?}", stop),
+        }
+        // 清理函数在返回前执行
+        assert_eq!(frames(&mut peer), vec![0]);
+        assert!(context.calls.is_empty() && context.abort.is_none());
+    }
+
+    #[test]
+    fn test_condition() {
+        let mut context = Context::new();
+        context.globals.set_binding("mode", "str:auto");
+        context.globals.set_binding("zero", "int:0");
+        let mut locals = VarBindingList::new();
+        locals.set_binding("n", "int:2");
+        locals.set_binding("t", "float:2.5");
+        let eval = |text: &str| parse_condition(text).expect("valid").eval(&locals, &context);
+        assert!(eval("n == 2") && eval("n != 3") && eval("n < t") && eval("t <= 2.5") && eval("n > -1") && eval("n >= 0x2"));
+        assert!(eval("mode == \"auto\"") && eval("mode != \"a == b\"") && eval("n"));
+        assert!(!eval("zero") && !eval("missing") && !eval("missing == 1"));
+    }
+}
@@ -1,7 +1,48 @@
 use variable::{VarBindingList};
 use function::FnDef;
 use instruction::InsDef;
+use dbc::{parse_dbc, write_dbc};
+use canopen::parse_eds;
+use script::parse_script;
+use bus::{Bus, Frame, CAN_MAX_LEN};
+use uds::UdsClient;
+use j1939::{self, J1939};
+use isotp::{self, IsoTp};
+use validate::{validate_fn, Diagnostic};
+use compile::{compile, Program};
+use debugger::Debugger;
+use trace::{Tracer, TraceEvent};
+use coverage::Coverage;
+use profile::Profiler;
+use cancel::{CancelToken, CancelBus, CANCELLED};
 use std::collections::HashMap;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::time::Instant;
+#[cfg(feature = "serde")]
+use serde_json;
+#[cfg(feature = "sqlite")]
+use storage::Storage;
+
+/// JSON格式的版本号，见Engine::to_json()；格式发生不兼容的变化时加1
+#[cfg(feature = "serde")]
+pub const JSON_VERSION: u32 = 1;
+
+// Engine的JSON格式：{"version": 1, "inss": [...], "fns": [...]}，指令和函数按名称排序
+#[cfg(feature = "serde")]
+#[derive(Serialize)]
+struct EngineJsonRef<'a> {
+    version: u32,
+    inss: Vec<&'a InsDef>,
+    fns: Vec<&'a FnDef>,
+}
+
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct EngineJson {
+    inss: Vec<InsDef>,
+    fns: Vec<FnDef>,
+}
 
 /// Logic Engine
 /// 管理指令函数和全局变量，执行函数
@@ -9,7 +50,16 @@ pub struct Engine {
     /// 指令表
     pub inss: HashMap<String, InsDef>,
     /// 函数表
-    pub fns: HashMap<String, FnDef>,
+    pub fns: HashMap<String, Rc<FnDef>>,
+    /// 执行被取消（见Context.cancel）后调用的清理函数，如使设备进入安全状态；没有参数。
+    /// 函数不存在时与其他调用一样在执行时记录错误，Engine::compile()不检查
+    pub cleanup: Option<String>,
+    /// 函数库；函数表中没有的函数在首次使用时从这里加载，见Engine::attach_storage()
+    #[cfg(feature = "sqlite")]
+    storage: Option<Storage>,
+    // 从函数库加载的函数
+    #[cfg(feature = "sqlite")]
+    loaded: RefCell<HashMap<String, Rc<FnDef>>>,
 }
 
 impl Engine {
@@ -17,6 +67,11 @@ impl Engine {
         Engine {
             inss: HashMap::new(),
             fns: HashMap::new(),
+            cleanup: None,
+            #[cfg(feature = "sqlite")]
+            storage: None,
+            #[cfg(feature = "sqlite")]
+            loaded: RefCell::new(HashMap::new()),
         }
     }
 
@@ -24,8 +79,33 @@ impl Engine {
         self.inss.get(name)
     }
 
-    pub fn find_fn(&self, name: &str) -> Option<&FnDef> {
-        self.fns.get(name)
+    /// 查找函数；函数表中没有的，从函数库加载（如果有）；从函数库读取失败时返回错误
+    pub fn find_fn(&self, name: &str) -> Result<Option<Rc<FnDef>>,String> {
+        match self.fns.get(name) {
+            Some(fndef) => Ok(Some(fndef.clone())),
+            None => self.load_fn(name).map_err(|err| format!("Cannot load fn {}: {}", name, err)),
+        }
+    }
+
+    #[cfg(feature = "sqlite")]
+    fn load_fn(&self, name: &str) -> Result<Option<Rc<FnDef>>,String> {
+        if let Some(fndef) = self.loaded.borrow().get(name) {
+            return Ok(Some(fndef.clone()));
+        }
+        let fndef = match self.storage {
+            Some(ref storage) => storage.load_fn(name)?,
+            None => None,
+        };
+        let fndef = fndef.map(Rc::new);
+        if let Some(ref fndef) = fndef {
+            self.loaded.borrow_mut().insert(name.to_string(), fndef.clone());
+        }
+        Ok(fndef)
+    }
+
+    #[cfg(not(feature = "sqlite"))]
+    fn load_fn(&self, _name: &str) -> Result<Option<Rc<FnDef>>,String> {
+        Ok(None)
     }
 
     pub fn add_ins(&mut self, def: InsDef) {
@@ -33,24 +113,165 @@ impl Engine {
     }
 
     pub fn add_fn(&mut self, def: FnDef) {
-        self.fns.insert(def.name.clone(), def);
+        self.fns.insert(def.name.clone(), Rc::new(def));
     }
 
-    pub fn exec_fn(&self, name: &str, args: &VarBindingList, context: &mut Context) -> Result<(),String> {
-        if let Some(fndef) = self.find_fn(name) {
-            fndef.exec(args, context /* &mut Context */, self /* &Engine */)
-        } else {
-            let err = format!("No such fn: {}", name);
+    /// 使用函数库：加载其中全部的指令（同名的将被替换），函数在首次使用时加载
+    #[cfg(feature = "sqlite")]
+    pub fn attach_storage(&mut self, storage: Storage) -> Result<(),String> {
+        for insdef in storage.load_inss()? {
+            self.add_ins(insdef);
+        }
+        self.storage = Some(storage);
+        self.loaded.borrow_mut().clear();
+        Ok(())
+    }
+
+    /// 将指令表和函数表保存到函数库
+    #[cfg(feature = "sqlite")]
+    pub fn save_to_storage(&self, storage: &mut Storage) -> Result<(),String> {
+        for insdef in self.inss.values() {
+            storage.save_ins(insdef)?;
+        }
+        for fndef in self.fns.values() {
+            storage.save_fn(fndef)?;
+        }
+        Ok(())
+    }
+
+    /// 从DBC文本导入指令，每个报文(BO_)对应一条指令
+    /// 返回不支持而被忽略的内容（同时记录为警告）
+    pub fn import_dbc(&mut self, text: &str, context: &mut Context) -> Result<Vec<String>,String> {
+        let dbc = parse_dbc(text).map_err(|err| {
+            context.log_error(&format!("Invalid DBC: {}", err));
+            err
+        })?;
+        for item in &dbc.unsupported {
+            context.log_warning(&format!("DBC: {}", item));
+        }
+        for insdef in dbc.inss {
+            self.add_ins(insdef);
+        }
+        Ok(dbc.unsupported)
+    }
+
+    /// 从EDS文本导入节点node_id的PDO，每个有效的PDO对应一条指令，详见Eds::pdos()
+    /// 返回不支持而被忽略的内容（同时记录为警告）
+    pub fn import_eds(&mut self, text: &str, node_id: u8, context: &mut Context) -> Result<Vec<String>,String> {
+        let mut unsupported = Vec::new();
+        let inss = parse_eds(text).and_then(|eds| eds.pdos(node_id, &mut unsupported)).map_err(|err| {
+            context.log_error(&format!("Invalid EDS: {}", err));
+            err
+        })?;
+        for item in &unsupported {
+            context.log_warning(&format!("EDS: {}", item));
+        }
+        for insdef in inss {
+            self.add_ins(insdef);
+        }
+        Ok(unsupported)
+    }
+
+    /// 加载脚本中定义的指令和函数（同名的将被替换），脚本语法见script模块
+    pub fn load_script(&mut self, text: &str, context: &mut Context) -> Result<(),String> {
+        let script = parse_script(text).map_err(|err| {
+            let err = format!("Invalid script: {}", err);
             context.log_error(&err);
-            Err(err)
+            err
+        })?;
+        for insdef in script.inss {
+            self.add_ins(insdef);
+        }
+        for fndef in script.fns {
+            self.add_fn(fndef);
+        }
+        Ok(())
+    }
+
+    /// 将指令表导出为DBC文本
+    pub fn export_dbc(&self) -> Result<String,String> {
+        write_dbc(self.inss.values())
+    }
+
+    /// 将指令表和函数表序列化为JSON（不包括全局变量等运行时状态）
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> Result<String,String> {
+        let mut inss: Vec<&InsDef> = self.inss.values().collect();
+        inss.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut fns: Vec<&FnDef> = self.fns.values().map(|fndef| fndef.as_ref()).collect();
+        fns.sort_by(|a, b| a.name.cmp(&b.name));
+        let json = EngineJsonRef { version: JSON_VERSION, inss, fns };
+        serde_json::to_string_pretty(&json).map_err(|err| err.to_string())
+    }
+
+    /// 从Engine::to_json()生成的JSON创建引擎；不支持的版本号返回错误
+    #[cfg(feature = "serde")]
+    pub fn from_json(text: &str) -> Result<Engine,String> {
+        let value: serde_json::Value = serde_json::from_str(text).map_err(|err| format!("Invalid JSON: {}", err))?;
+        match value.get("version").and_then(|version| version.as_u64()) {
+            Some(version) if version == JSON_VERSION as u64 => {}
+            Some(version) => return Err(format!("Unsupported JSON version: {}", version)),
+            None => return Err("Missing JSON version".to_string()),
+        }
+        let json: EngineJson = serde_json::from_value(value).map_err(|err| format!("Invalid JSON: {}", err))?;
+        let mut engine = Engine::new();
+        for insdef in json.inss {
+            engine.add_ins(insdef);
+        }
+        for fndef in json.fns {
+            engine.add_fn(fndef);
+        }
+        Ok(engine)
+    }
+
+    /// 执行前检查全部函数，返回发现的全部问题（按函数名称和语句顺序）
+    /// 检查的内容：循环是否配对，调用的函数和指令是否存在，调用参数的名称和类型，
+    /// 变量运算语句的参数，return之后不可达的语句，指令的数据长度是否超过context的传输方式（见Context::max_frame_len()）
+    pub fn validate(&self, context: &Context) -> Vec<Diagnostic> {
+        let mut fns: Vec<&Rc<FnDef>> = self.fns.values().collect();
+        fns.sort_by(|a, b| a.name.cmp(&b.name));
+        fns.iter().flat_map(|fndef| validate_fn(fndef, self, context)).collect()
+    }
+
+    /// 将函数name及其（直接或间接）调用的函数编译为Program，详见compile模块
+    pub fn compile(&self, name: &str) -> Result<Program<'_>,String> {
+        compile(self, name)
+    }
+
+    /// 创建函数name的调试器，详见debugger模块
+    pub fn debug(&self, name: &str, args: &VarBindingList) -> Result<Debugger<'_>,String> {
+        Debugger::new(self, name, args)
+    }
+
+    pub fn exec_fn(&self, name: &str, args: &VarBindingList, context: &mut Context) -> Result<(),String> {
+        match self.find_fn(name).and_then(|fndef| fndef.ok_or_else(|| format!("No such fn: {}", name))) {
+            Ok(fndef) => fndef.exec(args, context /* &mut Context */, self /* &Engine */),
+            Err(err) => {
+                context.log_error(&err);
+                Err(err)
+            }
+        }
+    }
+
+    /// 执行被取消后调用清理函数（如果有）；执行清理函数时不检查取消、语句数和截止时间，清理函数的错误只记录在日志中
+    pub fn exec_cleanup(&self, context: &mut Context) {
+        if let Some(ref name) = self.cleanup {
+            context.without_limits(|context| {
+                let _ = self.exec_fn(name, &VarBindingList::new(), context);
+            });
         }
     }
 
     pub fn exec_ins(&self, name: &str, args: &VarBindingList, context: &mut Context) -> Result<(),String> {
         if let Some(insdef) = self.find_ins(name) {
-            let mut data = Vec::new();
-            insdef.exec(args, &mut data, context);
-            Ok(())
+            if let Some(ref mut profiler) = context.profiler {
+                profiler.enter_ins(name);
+            }
+            let result = exec_insdef(insdef, args, context);
+            if let Some(ref mut profiler) = context.profiler {
+                profiler.exit();
+            }
+            result
         } else {
             let err = format!("No such fn: {}", name);
             context.log_error(&err);
@@ -59,43 +280,270 @@ impl Engine {
     }
 }
 
+impl Default for Engine {
+    fn default() -> Engine {
+        Engine::new()
+    }
+}
+
+/// 执行指令：编码参数并发送数据帧
+pub fn exec_insdef(insdef: &InsDef, args: &VarBindingList, context: &mut Context) -> Result<(),String> {
+    let mut data = Vec::new();
+    let result = insdef.exec(args, &mut data, context).and_then(|_| {
+        context.send_frame(&Frame { id: insdef.canid, extended: insdef.extended, data })
+    });
+    if let Err(ref err) = result {
+        context.log_error(err);
+    }
+    result
+}
+
+/// 日志输出函数，参数为一行日志
+pub type Logger = Box<dyn Fn(&str)>;
+
+/// 默认的最大调用深度，见Context.max_depth
+pub const DEFAULT_MAX_DEPTH: usize = 100;
+
+/// 执行的语句数超过Context.fuel时的错误（后面是调用栈）
+pub const FUEL_EXHAUSTED: &str = "Statement budget exhausted";
+/// 超过Context.deadline时的错误（后面是调用栈）
+pub const DEADLINE_EXCEEDED: &str = "Deadline exceeded";
+
+/// 调用栈中的一层，见Context.calls
+#[derive(Debug, Clone, PartialEq)]
+pub struct Call {
+    pub fn_name: String,
+    /// 正在执行的语句的索引
+    pub index: usize,
+}
+
+/// 调用栈的文本，如"main #2 > blink #0"；连续重复的层合并为"f #0 (x99)"
+pub fn format_calls<'a, I: IntoIterator<Item = (&'a str, usize)>>(calls: I) -> String {
+    let mut groups: Vec<((&str, usize), usize)> = Vec::new();
+    for call in calls {
+        match groups.last_mut() {
+            Some((last, count)) if *last == call => *count += 1,
+            _ => groups.push((call, 1)),
+        }
+    }
+    groups.iter().map(|&((fn_name, index), count)| match count {
+        1 => format!("{} #{}", fn_name, index),
+        _ => format!("{} #{} (x{})", fn_name, index, count),
+    }).collect::<Vec<_>>().join(" > ")
+}
+
+/// 超过最大调用深度的错误，calls是调用callee时的调用栈
+pub fn depth_error<'a, I: IntoIterator<Item = (&'a str, usize)>>(max_depth: usize, calls: I, callee: &str) -> String {
+    let calls = format_calls(calls);
+    if calls.is_empty() {
+        format!("Call depth limit exceeded ({}): {}", max_depth, callee)
+    } else {
+        format!("Call depth limit exceeded ({}): {} > {}", max_depth, calls, callee)
+    }
+}
+
 // 引擎执行的上下文对象
 // 被 Engine::exec_fn() 和 FnDef::exec() 使用
 pub struct Context {
     pub globals: VarBindingList, // 全局变量表
-    // TODO: logs, errors
+    pub counters: HashMap<String, u64>, // 指令滚动计数器的下一个值，key为"指令名/参数名"
+    pub bus: Option<Box<dyn Bus>>, // 指令产生的数据帧从这里发出；None表示不发送
+    pub uds: Option<UdsClient>, // 诊断语句(StmtKind::Diag)使用的UDS客户端
+    pub j1939: Option<J1939>, // 设置后扩展帧使用节点声明的源地址，超过8字节的按J1939传输协议发送
+    pub isotp: Option<IsoTp>, // 设置后超过8字节的其他数据帧按ISO-TP分段发送：发送ID为帧的ID，从isotp.rx_id接收流控帧
+    pub logger: Option<Logger>, // 日志输出（每次一行，如"[info] ..."）；None表示打印到标准输出
+    pub tracer: Option<Rc<RefCell<Tracer>>>, // 执行跟踪，见trace::record()；None表示不记录
+    pub coverage: Option<Coverage>, // 语句覆盖率，由FnDef::exec()统计；None表示不统计
+    pub profiler: Option<Profiler>, // 性能分析，由FnDef::exec()和Engine::exec_ins()统计；None表示不统计
+    pub max_depth: usize, // 最大调用深度（正在执行的函数的数量），超过时整个执行以错误结束
+    pub calls: Vec<Call>, // 调用栈，calls[0]是最外层的函数；由FnDef::exec()和Program::exec()维护
+    pub abort: Option<String>, // 设置后正在执行的函数全部结束并返回此错误；最外层的函数返回时清除
+    pub fuel: Option<u64>, // 还可以执行的语句数，每执行一条语句减1，用完时执行以错误结束；None表示不限制
+    pub deadline: Option<Instant>, // 执行的截止时间，到达时执行以错误结束；None表示不限制
+    pub cancel: Option<CancelToken>, // 取消执行的标志，可以在其他线程中设置，见cancel模块；None表示不检查
 }
 
 impl Context {
     pub fn new() -> Context {
         Context {
             globals: VarBindingList::new(),
+            counters: HashMap::new(),
+            bus: None,
+            uds: None,
+            j1939: None,
+            isotp: None,
+            logger: None,
+            tracer: None,
+            coverage: None,
+            profiler: None,
+            max_depth: DEFAULT_MAX_DEPTH,
+            calls: Vec::new(),
+            abort: None,
+            fuel: None,
+            deadline: None,
+            cancel: None,
+        }
+    }
+
+    /// 数据帧最多可以发送的字节数：经典CAN为8，扩展帧设置了j1939或设置了isotp时可以分段发送
+    pub fn max_frame_len(&self, extended: bool) -> usize {
+        match (&self.j1939, &self.isotp) {
+            (Some(_), _) if extended => j1939::MAX_LEN,
+            (_, Some(_)) => isotp::MAX_LEN,
+            _ => CAN_MAX_LEN,
+        }
+    }
+
+    /// 发送数据帧；数据超过max_frame_len()时返回错误，没有设置bus时什么也不做
+    pub fn send_frame(&mut self, frame: &Frame) -> Result<(),String> {
+        let max_len = self.max_frame_len(frame.extended);
+        if frame.data.len() > max_len {
+            return Err(format!("Frame {:#X} too long: {} bytes, at most {}", frame.id, frame.data.len(), max_len));
+        }
+        let cancel = self.cancel.as_ref();
+        let result = match (&mut self.bus, &self.j1939, &self.isotp) {
+            (Some(bus), Some(j1939), _) if frame.extended => j1939.send_frame(&mut CancelBus::new(bus.as_mut(), cancel), frame),
+            (Some(bus), _, Some(isotp)) if frame.data.len() > CAN_MAX_LEN => {
+                let isotp = IsoTp { tx_id: frame.id, extended: frame.extended, ..isotp.clone() };
+                isotp.send(&mut CancelBus::new(bus.as_mut(), cancel), &frame.data)
+            }
+            (Some(bus), _, _) => CancelBus::new(bus.as_mut(), cancel).send(frame),
+            (None, _, _) => return Ok(()),
+        };
+        if let (Ok(_), Some(profiler)) = (&result, &mut self.profiler) {
+            profiler.frame(frame);
+        }
+        result
+    }
+
+    /// 当前调用栈的文本，见format_calls()
+    pub fn call_stack(&self) -> String {
+        format_calls(self.calls.iter().map(|call| (call.fn_name.as_str(), call.index)))
+    }
+
+    /// 开始执行函数fn_name：超过最大调用深度时设置abort并返回错误
+    pub fn enter_call(&mut self, fn_name: &str) -> Result<(),String> {
+        if self.calls.len() >= self.max_depth {
+            let err = depth_error(self.max_depth, self.calls.iter().map(|call| (call.fn_name.as_str(), call.index)), fn_name);
+            if !self.calls.is_empty() {
+                self.abort = Some(err.clone());
+            }
+            self.log_error(&err);
+            return Err(err);
+        }
+        self.calls.push(Call { fn_name: fn_name.to_string(), index: 0 });
+        Ok(())
+    }
+
+    /// 执行是否已被取消
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(|cancel| cancel.is_cancelled())
+    }
+
+    /// 执行一条语句前调用：检查取消，消耗fuel并检查deadline，超出时设置abort
+    pub fn check_budget(&mut self) {
+        if self.abort.is_some() {
+            return;
+        }
+        let err = match self.fuel {
+            _ if self.is_cancelled() => Some(CANCELLED),
+            Some(0) => Some(FUEL_EXHAUSTED),
+            Some(ref mut fuel) => {
+                *fuel -= 1;
+                None
+            }
+            None => None,
+        };
+        let err = err.or_else(|| match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Some(DEADLINE_EXCEEDED),
+            _ => None,
+        });
+        if let Some(err) = err {
+            let err = format!("{} at {}", err, self.call_stack());
+            self.abort = Some(err.clone());
+            self.log_error(&err);
+        }
+    }
+
+    /// 暂时去掉cancel、fuel和deadline后调用f（执行清理函数），之后恢复
+    pub fn without_limits<T, F: FnOnce(&mut Context) -> T>(&mut self, f: F) -> T {
+        let (cancel, fuel, deadline) = (self.cancel.take(), self.fuel.take(), self.deadline.take());
+        let result = f(self);
+        self.cancel = cancel;
+        self.fuel = fuel;
+        self.deadline = deadline;
+        result
+    }
+
+    /// 结束最近开始的函数；最外层的函数结束时清除abort
+    pub fn exit_call(&mut self) {
+        self.calls.pop();
+        if self.calls.is_empty() {
+            self.abort = None;
+        }
+    }
+
+    /// 记录执行跟踪；没有设置tracer时不调用event
+    pub fn trace<F: FnOnce() -> TraceEvent>(&self, event: F) {
+        if let Some(ref tracer) = self.tracer {
+            tracer.borrow_mut().push(event());
+        }
+    }
+
+    fn log(&self, line: &str) {
+        match self.logger {
+            Some(ref logger) => logger(line),
+            None => println!("{}", line),
         }
     }
 
     pub fn log_info(&self, text: &str) {
-        println!("[info] {}", text);
+        self.log(&format!("[info] {}", text));
     }
 
     pub fn log_warning(&self, text: &str) {
-        println!("[Warning] {}", text);
+        self.log(&format!("[Warning] {}", text));
     }
 
+    /// 执行函数时附加出错时的调用栈，如"[ERROR] No such fn: f (at main #2)"；中止执行的错误本身带有调用栈
     pub fn log_error(&self, text: &str) {
-        println!("[ERROR] {}", text);
+        if self.calls.is_empty() || self.abort.is_some() {
+            self.log(&format!("[ERROR] {}", text));
+        } else {
+            self.log(&format!("[ERROR] {} (at {})", text, self.call_stack()));
+        }
     }
 
     pub fn log_fatal(&self, text: &str) {
-        println!("[!!FATAL!!] {}", text);
+        self.log(&format!("[!!FATAL!!] {}", text));
+    }
+}
+
+impl Default for Context {
+    fn default() -> Context {
+        Context::new()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use engine::{Engine, Context};
+    use engine::{Engine, Context, format_calls, DEFAULT_MAX_DEPTH, DEADLINE_EXCEEDED};
     use function::{FnDef};
     use statement::{Stmt};
-    use variable::{VarBindingList};
+    use variable::{VarDef, VarBindingList};
+    use instruction::InsDef;
+    use bus::{Bus, Frame, LoopbackBus};
+    use j1939::{J1939, GLOBAL_ADDRESS};
+    use isotp::IsoTp;
+    use signal::{SignalLayout, ByteOrder};
+    #[cfg(feature = "serde")]
+    use signal::Mux;
+    #[cfg(feature = "serde")]
+    use computed::{Computed, Checksum};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use std::rc::Rc;
+    use std::cell::RefCell;
 
     #[test]
     fn test_exec_fn() {
@@ -190,4 +638,311 @@ mod tests {
             assert_eq!(context.globals.eval_var("x", None, None), Some("int:18".to_string()));     // 0 + 1x6x3
         }
     }
+
+    #[test]
+    fn test_undefined_args() {
+        let mut engine = Engine::new();
+        let mut context = Context::new();
+        engine.load_script(r#"
+ins lamp(a: u32, b: u32 = 1) = 0x321
+
+fn main() {
+    send lamp(a: y)
+}
+
+fn ret() {
+    return y
+}
+
+fn f(a: i32) {
+}
+
+fn caller() {
+    call f(a: y)
+}
+"#, &mut context).expect("ok");
+        let args = VarBindingList::new();
+        for name in ["main", "ret", "caller"] {
+            let program = engine.compile(name).expect("ok");
+            assert_eq!(engine.exec_fn(name, &args, &mut context), Err("Undefined variable: y".to_string()));
+            assert_eq!(program.exec(&args, &mut context), Err("Undefined variable: y".to_string()));
+        }
+    }
+
+    #[test]
+    fn test_error_stack() {
+        let mut engine = Engine::new();
+        let mut context = Context::new();
+        engine.load_script(r#"
+fn main() {
+    x = 1
+    call f()
+}
+
+fn f() {
+    call missing()
+}
+"#, &mut context).expect("ok");
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let lines = log.clone();
+        context.logger = Some(Box::new(move |line: &str| lines.borrow_mut().push(line.to_string())));
+        let args = VarBindingList::new();
+        let program = engine.compile("main").expect("ok");
+        // 执行时的错误带有调用栈；不在执行中的错误没有
+        for compiled in [false, true] {
+            log.borrow_mut().clear();
+            let result = if compiled { program.exec(&args, &mut context) } else { engine.exec_fn("main", &args, &mut context) };
+            assert_eq!(result, Err("No such fn: missing".to_string()));
+            assert_eq!(*log.borrow(), vec!["[ERROR] No such fn: missing (at main #1 > f #0)"]);
+        }
+        log.borrow_mut().clear();
+        assert!(engine.exec_fn("missing", &args, &mut context).is_err());
+        assert_eq!(*log.borrow(), vec!["[ERROR] No such fn: missing"]);
+    }
+
+    #[test]
+    fn test_call_depth() {
+        let mut engine = Engine::new();
+        let mut context = Context::new();
+        engine.load_script(r#"
+fn main() {
+    count := 0
+    loop 3 {
+        call down(n: 0)
+    }
+    count := 100
+}
+
+fn down(n: i32) {
+    count += 1
+    call down(n: n)
+}
+"#, &mut context).expect("ok");
+        let args = VarBindingList::new();
+        let program = engine.compile("main").expect("ok");
+        context.max_depth = 5;
+        // 超过最大调用深度时整个执行结束（不再继续循环），之后可以再次执行
+        for _ in 0..2 {
+            for compiled in [false, true] {
+                let result = if compiled { program.exec(&args, &mut context) } else { engine.exec_fn("main", &args, &mut context) };
+                assert_eq!(result, Err("Call depth limit exceeded (5): main #2 > down #1 (x4) > down".to_string()));
+                assert_eq!(context.globals.raw_value_of("count"), Some("int:4"));
+                assert!(context.calls.is_empty() && context.abort.is_none());
+            }
+        }
+        // 默认的最大调用深度不会耗尽测试线程的栈
+        context.max_depth = DEFAULT_MAX_DEPTH;
+        let err = Err("Call depth limit exceeded (100): down #1 (x100) > down".to_string());
+        let mut down_args = VarBindingList::new();
+        down_args.set_binding("n", "int:0");
+        assert_eq!(engine.exec_fn("down", &down_args, &mut context), err);
+        assert!(program.exec(&args, &mut context).is_err());
+        context.max_depth = 0;
+        assert_eq!(engine.exec_fn("main", &args, &mut context), Err("Call depth limit exceeded (0): main".to_string()));
+        assert!(context.abort.is_none());
+
+        assert_eq!(format_calls(vec![("main", 2), ("f", 0), ("f", 0), ("g", 1), ("f", 0)]), "main #2 > f #0 (x2) > g #1 > f #0");
+        assert_eq!(format_calls(vec![]), "");
+    }
+
+    #[test]
+    fn test_budget() {
+        let mut engine = Engine::new();
+        let mut context = Context::new();
+        engine.load_script(r#"
+fn main() {
+    call spin()
+}
+
+fn spin() {
+    loop 4000000000 {
+        x := 1
+    }
+}
+
+fn short() {
+    x := 2
+}
+"#, &mut context).expect("ok");
+        let args = VarBindingList::new();
+        let program = engine.compile("main").expect("ok");
+        // 第12条语句（spin中的x := 1）超出预算；之后可以再次执行
+        for compiled in [false, true, false] {
+            context.fuel = Some(11);
+            let result = if compiled { program.exec(&args, &mut context) } else { engine.exec_fn("main", &args, &mut context) };
+            assert_eq!(result, Err("Statement budget exhausted at main #0 > spin #1".to_string()));
+            assert_eq!(context.fuel, Some(0));
+            assert!(context.calls.is_empty() && context.abort.is_none());
+        }
+        context.fuel = Some(3);
+        assert_eq!(engine.exec_fn("short", &args, &mut context), Ok(()));
+        assert_eq!(context.fuel, Some(2));
+
+        context.fuel = None;
+        for compiled in [false, true] {
+            let start = Instant::now();
+            context.deadline = Some(start + Duration::from_millis(20));
+            let result = if compiled { program.exec(&args, &mut context) } else { engine.exec_fn("main", &args, &mut context) };
+            assert!(result.expect_err("deadline").starts_with(&format!("{} at main #0 > spin #", DEADLINE_EXCEEDED)));
+            assert!(start.elapsed() >= Duration::from_millis(20) && start.elapsed() < Duration::from_secs(5));
+        }
+    }
+
+    #[test]
+    fn test_exec_ins_bus() {
+        let mut engine = Engine::new();
+        let mut ins = InsDef::new("lamp", 0x321);
+        ins.args.add(VarDef::new("a", "u32"));
+        ins.args.add(VarDef::new("b", "u32"));
+        engine.add_ins(ins);
+        let mut foo = FnDef::new("foo");
+        let mut args = VarBindingList::new();
+        args.set_binding("a", "1");
+        args.set_binding("b", "2");
+        foo.add_stmt(Stmt::new_call_ins("lamp", args));
+        engine.add_fn(foo);
+
+        let (bus, mut peer) = LoopbackBus::pair();
+        let mut context = Context::new();
+        context.bus = Some(Box::new(bus));
+        engine.exec_fn("foo", &VarBindingList::new(), &mut context).expect("ok");
+        assert_eq!(peer.recv(Duration::from_millis(10)), Ok(Some(Frame::new(0x321, &[0, 0, 0, 1, 0, 0, 0, 2]))));
+    }
+
+    #[test]
+    fn test_exec_ins_j1939() {
+        let mut engine = Engine::new();
+        let mut ins = InsDef::new_j1939("dm1", 6, 0xFECA, 0x10, GLOBAL_ADDRESS);
+        assert_eq!((ins.canid, ins.extended), (0x18FECA10, true));
+        ins.dlc = 20;
+        let mut code = VarDef::new("code", "u32");
+        code.signal = Some(SignalLayout::new(120, 32, ByteOrder::Intel, false));
+        ins.args.add(code);
+        engine.add_ins(ins);
+        let mut args = VarBindingList::new();
+        args.set_binding("code", "305419896");
+
+        let (bus, mut peer) = LoopbackBus::pair();
+        let receiver = thread::spawn(move || J1939::new(2, 0x20).recv(&mut peer, Duration::from_millis(2000)));
+        let mut j1939 = J1939::new(1, 0x10);
+        j1939.bam_interval = Duration::from_millis(1);
+        let mut context = Context::new();
+        context.bus = Some(Box::new(bus));
+        context.j1939 = Some(j1939);
+        engine.exec_ins("dm1", &args, &mut context).expect("ok");
+        let message = receiver.join().unwrap().expect("ok").expect("message");
+        assert_eq!(message.pgn, 0xFECA);
+        assert_eq!(message.data.len(), 20);
+        assert_eq!(message.data[15..19], [0x78, 0x56, 0x34, 0x12]);
+    }
+
+    #[test]
+    fn test_exec_ins_isotp() {
+        let mut engine = Engine::new();
+        let mut ins = InsDef::new("table", 0x600);
+        ins.dlc = 100;
+        let mut code = VarDef::new("code", "u32");
+        code.signal = Some(SignalLayout::new(760, 32, ByteOrder::Intel, false));
+        ins.args.add(code);
+        engine.add_ins(ins);
+        let mut args = VarBindingList::new();
+        args.set_binding("code", "305419896");
+
+        // 经典CAN不能发送超过8字节的数据帧
+        let (bus, mut peer) = LoopbackBus::pair();
+        let mut context = Context::new();
+        context.bus = Some(Box::new(bus));
+        assert_eq!(engine.exec_ins("table", &args, &mut context), Err("Frame 0x600 too long: 100 bytes, at most 8".to_string()));
+        assert_eq!(peer.recv(Duration::from_millis(10)), Ok(None));
+
+        let receiver = thread::spawn(move || IsoTp::new(0x680, 0x600).recv(&mut peer));
+        context.isotp = Some(IsoTp::new(0x7DF, 0x680));
+        engine.exec_ins("table", &args, &mut context).expect("ok");
+        let data = receiver.join().unwrap().expect("ok");
+        assert_eq!(data.len(), 100);
+        assert_eq!(data[95..99], [0x78, 0x56, 0x34, 0x12]);
+    }
+
+    #[cfg(feature = "serde")]
+    fn json_engine() -> Engine {
+        let mut engine = Engine::new();
+        let mut lamp = InsDef::new("lamp", 0x321);
+        lamp.note = Some("Turns the lamp on or off".to_string());
+        lamp.args.add(VarDef::new("a", "u32"));
+        let mut b = VarDef::new("b", "u32");
+        b.range = "0..10".to_string();
+        b.default = "1".to_string();
+        lamp.args.add(b);
+        engine.add_ins(lamp);
+
+        let mut brake = InsDef::new("brake", 0x18FF0010);
+        brake.extended = true;
+        brake.dlc = 4;
+        let mut mode = VarDef::new("mode", "u8");
+        mode.signal = Some(SignalLayout::new(0, 4, ByteOrder::Intel, false));
+        mode.mux = Some(Mux::Multiplexor);
+        brake.args.add(mode);
+        let mut torque = VarDef::new("torque", "f64");
+        torque.signal = Some(SignalLayout { factor: 0.5, unit: "Nm".to_string(), ..SignalLayout::new(15, 8, ByteOrder::Motorola, true) });
+        torque.mux = Some(Mux::Multiplexed(1));
+        brake.args.add(torque);
+        let mut counter = VarDef::new("counter", "u8");
+        counter.signal = Some(SignalLayout::new(4, 4, ByteOrder::Intel, false));
+        counter.computed = Some(Computed::counter(4));
+        brake.args.add(counter);
+        let mut crc = VarDef::new("crc", "u8");
+        crc.signal = Some(SignalLayout::new(24, 8, ByteOrder::Intel, false));
+        crc.computed = Some(Computed::Checksum(Checksum::Crc8Autosar { data_id: 0x1F }));
+        brake.args.add(crc);
+        engine.add_ins(brake);
+
+        let mut main = FnDef::new("main");
+        main.args.add(VarDef::new("n", "i32"));
+        main.add_stmt(Stmt::new_loop(3));
+        let mut args = VarBindingList::new();
+        args.set_binding("a", "5");
+        let mut call = Stmt::new_call_ins("lamp", args);
+        call.note = Some("blink".to_string());
+        main.add_stmt(call);
+        main.add_stmt(Stmt::new_end_loop());
+        main.add_stmt(Stmt::new_set_var("x", "=", "int:1"));
+        let mut args = VarBindingList::new();
+        args.set_binding("did", "0xF190");
+        main.add_stmt(Stmt::new_diag("ReadDataByIdentifier", args));
+        main.add_stmt(Stmt::new_return("var:x"));
+        engine.add_fn(main);
+        engine
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_json() {
+        let engine = json_engine();
+        let json = engine.to_json().expect("ok");
+        assert_eq!(json, include_str!("../testdata/engine.json").trim_end());
+
+        // round trip
+        let loaded = Engine::from_json(&json).expect("ok");
+        assert_eq!(loaded.to_json(), Ok(json));
+        let mut args = VarBindingList::new();
+        args.set_binding("n", "5");
+        let (bus, mut peer) = LoopbackBus::pair();
+        let mut context = Context::new();
+        context.bus = Some(Box::new(bus));
+        let result = loaded.exec_fn("main", &args, &mut context);
+        assert_eq!(result, Err("No UDS client in context".to_string())); // Diag fails without UDS client
+        for _ in 0..3 {
+            assert_eq!(peer.recv(Duration::from_millis(10)), Ok(Some(Frame::new(0x321, &[0, 0, 0, 5, 0, 0, 0, 1]))));
+        }
+        assert_eq!(context.globals.raw_value_of("$return"), Some("var:x"));
+
+        // optional fields may be omitted
+        let minimal = r#"{"version": 1, "inss": [{"name": "x", "canid": 1, "args": [{"name": "a", "typ": "u8"}]}],
+                          "fns": [{"name": "f", "args": [], "stmts": [{"kind": "CallIns", "content": "x"}]}]}"#;
+        let engine = Engine::from_json(minimal).expect("ok");
+        assert_eq!(engine.find_ins("x").map(|ins| (ins.dlc, ins.extended)), Some((8, false)));
+        assert_eq!(Engine::from_json(r#"{"version": 2, "inss": [], "fns": []}"#).err(), Some("Unsupported JSON version: 2".to_string()));
+        assert!(Engine::from_json(r#"{"inss": [], "fns": []}"#).is_err());
+        assert!(Engine::from_json(r#"{"version": 1, "inss": [{"name": "x"}], "fns": []}"#).is_err());
+    }
 }
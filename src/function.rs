@@ -1,16 +1,27 @@
 use engine::{Engine, Context};
-use statement::{Stmt, StmtKind};
+use statement::{Stmt, StmtKind, SourcePos};
 use variable::{VarDefList, VarBindingList};
 use std::collections::HashMap;
 use std::cell::RefCell;
 use utils::split_lr;
+use trace::{TraceEvent, writes};
+use uds;
+use canopen;
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct FnDef {
     pub name: String,
     pub args: VarDefList,
     pub stmts: Vec<Stmt>,
+    /// 注释
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub note: Option<String>,
+    /// 在脚本中的位置；不是由脚本生成的函数为None
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub pos: Option<SourcePos>,
 
     // privates
+    #[cfg_attr(feature = "serde", serde(skip))]
     loop_table: RefCell<Option<HashMap<u32, u32>>>,
 }
 
@@ -20,6 +31,8 @@ impl FnDef {
             name: name.to_string(),
             args: VarDefList::new(),
             stmts: Vec::new(),
+            note: None,
+            pos: None,
             loop_table: RefCell::new(None),
         }
     }
@@ -29,13 +42,25 @@ impl FnDef {
     }
 
     pub fn exec(&self, args: &VarBindingList, context: &mut Context, engine: &Engine) -> Result<(),String> {
-        let mut eip: u32 = 0; // 指向将要执行（或正在执行）的语句
-        let mut result: Result<(),String> = Ok(());
         // 初始化函数局部变量（复制函数参数作为局部变量）
         let mut locals = VarBindingList::new();
         locals.add_more(args);
+        self.exec_with_locals(&mut locals, context, engine)
+    }
+
+    /// 使用调用者提供的局部变量表执行，执行后locals中保留函数定义的局部变量（用于REPL）
+    pub fn exec_with_locals(&self, locals: &mut VarBindingList, context: &mut Context, engine: &Engine) -> Result<(),String> {
+        let mut eip: u32 = 0; // 指向将要执行（或正在执行）的语句
+        let mut result: Result<(),String> = Ok(());
+        context.enter_call(&self.name)?;
         // 清除返回值
         context.globals.remove_binding("$return");
+        if let Some(ref mut coverage) = context.coverage {
+            coverage.enter(&self.name);
+        }
+        if let Some(ref mut profiler) = context.profiler {
+            profiler.enter_fn(&self.name);
+        }
 
         // 下面一个大的循环依次执行每一条语句
         loop {
@@ -43,14 +68,33 @@ impl FnDef {
                 break;
             }
             let stmt = &self.stmts[eip as usize];
+            if let Some(call) = context.calls.last_mut() {
+                call.index = eip as usize;
+            }
+            context.check_budget();
+            if let Some(ref err) = context.abort {
+                // 中止执行：清除循环状态，以备此后再次执行
+                for stmt in self.stmts.iter().filter(|stmt| stmt.kind == StmtKind::Loop) {
+                    stmt.rtargs_clean();
+                }
+                result = Err(err.clone());
+                break;
+            }
+            context.trace(|| TraceEvent::Stmt { fn_name: self.name.clone(), index: eip as usize, kind: stmt.kind });
+            if let Some(ref mut coverage) = context.coverage {
+                coverage.hit(&self.name, eip as usize);
+            }
+            if let Some(ref mut profiler) = context.profiler {
+                profiler.stmt();
+            }
             match stmt.kind {
                 // 调用指令（由用户定义的指令）
                 StmtKind::CallIns => {
-                    result = engine.exec_ins(&stmt.content, &stmt.args, context);
+                    result = self.eval_args(stmt, locals, context).and_then(|args| engine.exec_ins(&stmt.content, &args, context));
                 }
                 // 调用函数（由用户定义的函数）
                 StmtKind::CallFn => {
-                    result = engine.exec_fn(&stmt.content, &stmt.args, context);
+                    result = self.eval_args(stmt, locals, context).and_then(|args| engine.exec_fn(&stmt.content, &args, context));
                 }
                 // 开始循环
                 StmtKind::Loop => {
@@ -74,6 +118,9 @@ impl FnDef {
                     if index < count {
                         // increase $index, then run loop body
                         stmt.rtargs_set("$index", (index + 1).to_string().as_str());
+                        if let Some(ref mut coverage) = context.coverage {
+                            coverage.iterate(&self.name, eip as usize);
+                        }
                         // 从循环体内代码的角度看(其实看不到), $index从1开始递增
                         // 下一条语句就是循环体, 无需跳转
                     } else {
@@ -91,30 +138,118 @@ impl FnDef {
                 }
                 // 返回
                 StmtKind::Return => {
-                    // TODO: evaluates stmt.content
-                    context.globals.set_binding("$return", &stmt.content);
+                    match self.eval_return(stmt, locals, context) {
+                        Ok(value) => {
+                            context.trace(|| TraceEvent::Write {
+                                global: true,
+                                name: "$return".to_string(),
+                                old: context.globals.raw_value_of("$return").unwrap_or("").to_string(),
+                                new: value.clone(),
+                            });
+                            context.globals.set_binding("$return", &value);
+                        }
+                        Err(err) => result = Err(err),
+                    }
                     // TODO: 清理loop.stmt.rtargs，否则再次调用此函数时循环条件永远不成立
                     break;
                 }
-                // 定义变量/绑定变量/变量运算
-                StmtKind::SetVar => {
-                    self.do_set_var(stmt, context, &mut locals);
-                }
-                // 定义局部变量并赋值
-                StmtKind::SetLocal => {
-                    self.do_set_local(&stmt.content, &mut locals, context);
-                }
-                // 定义全局变量并赋值
-                StmtKind::SetGlobal => {
-                    self.do_set_global(&stmt.content, context);
+                // 不影响执行流程的语句
+                _ => {
+                    if let Some(stmt_result) = self.exec_stmt(stmt, locals, context) {
+                        result = stmt_result;
+                    }
                 }
             }
             eip += 1; // we'll execute next statement later
         } // end of loop
 
+        if let Some(ref mut profiler) = context.profiler {
+            profiler.exit();
+        }
+        // 最外层的函数被取消时调用清理函数
+        let cancelled = result.is_err() && context.calls.len() == 1 && context.is_cancelled();
+        context.exit_call();
+        if cancelled {
+            engine.exec_cleanup(context);
+        }
         result
     }
+
+    /// 执行一条不影响执行流程的语句（SetVar、SetLocal、SetGlobal、Diag、CanOpen），供调试器单步执行
+    /// 返回None表示语句没有结果（不改变函数的执行结果）；其他类型的语句什么也不做
+    pub fn exec_stmt(&self, stmt: &Stmt, locals: &mut VarBindingList, context: &mut Context) -> Option<Result<(),String>> {
+        if context.tracer.is_none() {
+            return self.exec_simple_stmt(stmt, locals, context);
+        }
+        // 跟踪时比较执行前后的变量表，记录写入的变量
+        let (old_locals, old_globals) = (locals.clone(), context.globals.clone());
+        let result = self.exec_simple_stmt(stmt, locals, context);
+        for event in writes(false, &old_locals, locals).into_iter().chain(writes(true, &old_globals, &context.globals)) {
+            context.trace(|| event);
+        }
+        result
+    }
+
+    fn exec_simple_stmt(&self, stmt: &Stmt, locals: &mut VarBindingList, context: &mut Context) -> Option<Result<(),String>> {
+        match stmt.kind {
+            // 定义变量/绑定变量/变量运算
+            StmtKind::SetVar => {
+                self.do_set_var(stmt, context, locals);
+                None
+            }
+            // 定义局部变量并赋值
+            StmtKind::SetLocal => {
+                self.do_set_local(&stmt.content, locals, context);
+                None
+            }
+            // 定义全局变量并赋值
+            StmtKind::SetGlobal => {
+                self.do_set_global(&stmt.content, context);
+                None
+            }
+            // 调用UDS诊断服务
+            StmtKind::Diag => {
+                let result = uds::exec_diag(stmt, locals, context);
+                if let Err(ref err) = result {
+                    context.log_error(err);
+                }
+                Some(result)
+            }
+            // CANopen NMT/SDO
+            StmtKind::CanOpen => {
+                let result = canopen::exec_canopen(stmt, locals, context);
+                if let Err(ref err) = result {
+                    context.log_error(err);
+                }
+                Some(result)
+            }
+            _ => None,
+        }
+    }
     
+    /// 调用语句的参数：stmt.eval为true时在调用处求值（'var:name'替换为变量的值，调用指令时去掉类型前缀），
+    /// 否则按原样传递；引用了未定义的变量时返回错误
+    pub fn eval_args(&self, stmt: &Stmt, locals: &VarBindingList, context: &mut Context) -> Result<VarBindingList,String> {
+        if !stmt.eval {
+            return Ok(stmt.args.clone());
+        }
+        let mut values = VarBindingList::new();
+        for name in stmt.args.bindings.keys() {
+            let value = eval_value(stmt.args.raw_value_of(name).unwrap_or(""), locals, context)?;
+            let value = if stmt.kind == StmtKind::CallIns { split_lr(&value, ":").1 } else { &value }; // 'int:1' -> '1'
+            values.set_binding(name, value);
+        }
+        Ok(values)
+    }
+
+    /// 返回语句的值：stmt.eval为true时对stmt.content求值，否则按原样返回；引用了未定义的变量时返回错误
+    pub fn eval_return(&self, stmt: &Stmt, locals: &VarBindingList, context: &mut Context) -> Result<String,String> {
+        if !stmt.eval {
+            return Ok(stmt.content.clone());
+        }
+        eval_value(&stmt.content, locals, context)
+    }
+
     fn find_loop_pair(&self, eip: u32, context: &mut Context) -> Option<u32> {
         self.loop_table.borrow().as_ref().map_or(None, |map| {
             map.get(&eip).map(|i| *i)
@@ -216,50 +351,7 @@ impl FnDef {
     // 对x和y这两个值执行op运算
     fn do_x_op_y(&self, op: &str, x: &str, y: &str,
                  stmt: &Stmt, context: &mut Context, locals: &mut VarBindingList) -> String {
-        let (xl, xr) = split_lr(x, ":");
-        let (yl, yr) = split_lr(y, ":");
-        match op {
-            "+" => {
-                if xl == "int" && yl == "int" {
-                    let x: isize = xr.parse().unwrap_or(0);
-                    let y: isize = yr.parse().unwrap_or(0);
-                    return format!("int:{}", x + y);
-                } else {
-                    return format!("str:{}{}", xr, yr);
-                }
-            }
-            "-" => {
-                if xl == "int" && yl == "int" {
-                    let x: isize = xr.parse().unwrap_or(0);
-                    let y: isize = yr.parse().unwrap_or(0);
-                    return format!("int:{}", x - y);
-                } else {
-                    return "".to_string();
-                }
-            }
-            "*" => {
-                if xl == "int" && yl == "int" {
-                    let x: isize = xr.parse().unwrap_or(0);
-                    let y: isize = yr.parse().unwrap_or(0);
-                    return format!("int:{}", x * y);
-                } else {
-                    return "".to_string();
-                }
-            }
-            "/" => {
-                if xl == "float" && yl == "float" {
-                    let x: f64 = xr.parse().unwrap_or(0.0);
-                    let y: f64 = yr.parse().unwrap_or(0.0);
-                    return format!("int:{}", x / y);
-                } else {
-                    return "".to_string();
-                }
-            }
-            _ => {
-                context.log_error(&format!("Unsupport set var op: {}", op));
-                return "".to_string();
-            }
-        }
+        x_op_y(op, x, y, context)
     }
     
     fn build_loop_table(&self, context: &mut Context) {
@@ -276,7 +368,7 @@ impl FnDef {
                     loop_stack.push(index);
                 }
                 StmtKind::EndLoop => {
-                    if loop_stack.len() > 0 {
+                    if !loop_stack.is_empty() {
                         let begin = loop_stack.pop().expect("exist");
                         loop_pairs.insert(begin, index);
                         loop_pairs.insert(index, begin);
@@ -294,3 +386,60 @@ impl FnDef {
     }
 
 }
+
+// 对值求值，未定义的变量是错误
+fn eval_value(value: &str, locals: &VarBindingList, context: &mut Context) -> Result<String,String> {
+    locals.eval(value, Some(&context.globals), None).ok_or_else(|| {
+        let err = format!("Undefined variable: {}", split_lr(value, ":").1);
+        context.log_error(&err);
+        err
+    })
+}
+
+/// 对x和y这两个值执行op运算（op: + - * /），SetVar语句使用
+pub fn x_op_y(op: &str, x: &str, y: &str, context: &Context) -> String {
+    let (xl, xr) = split_lr(x, ":");
+    let (yl, yr) = split_lr(y, ":");
+    match op {
+        "+" => {
+            if xl == "int" && yl == "int" {
+                let x: isize = xr.parse().unwrap_or(0);
+                let y: isize = yr.parse().unwrap_or(0);
+                format!("int:{}", x + y)
+            } else {
+                format!("str:{}{}", xr, yr)
+            }
+        }
+        "-" => {
+            if xl == "int" && yl == "int" {
+                let x: isize = xr.parse().unwrap_or(0);
+                let y: isize = yr.parse().unwrap_or(0);
+                format!("int:{}", x - y)
+            } else {
+                "".to_string()
+            }
+        }
+        "*" => {
+            if xl == "int" && yl == "int" {
+                let x: isize = xr.parse().unwrap_or(0);
+                let y: isize = yr.parse().unwrap_or(0);
+                format!("int:{}", x * y)
+            } else {
+                "".to_string()
+            }
+        }
+        "/" => {
+            if xl == "float" && yl == "float" {
+                let x: f64 = xr.parse().unwrap_or(0.0);
+                let y: f64 = yr.parse().unwrap_or(0.0);
+                format!("int:{}", x / y)
+            } else {
+                "".to_string()
+            }
+        }
+        _ => {
+            context.log_error(&format!("Unsupport set var op: {}", op));
+            "".to_string()
+        }
+    }
+}
@@ -1,13 +1,33 @@
-use variable::{VarDefList, VarBindingList};
+use variable::{VarDef, VarDefList, VarBindingList};
 use engine::Context;
-use std::slice;
+use signal::{SignalLayout, ByteOrder, Mux};
+use computed::Computed;
+use statement::SourcePos;
+use utils::split_lr;
+use j1939::j1939_id;
 
 // 指令的定义和实现
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct InsDef {
     pub name: String,
     pub canid: u32,
+    /// 是否为扩展帧（29位ID）
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub extended: bool,
+    /// 数据长度（字节数）
+    #[cfg_attr(feature = "serde", serde(default = "default_dlc"))]
+    pub dlc: u16,
     pub args: VarDefList,
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
     pub note: Option<String>,
+    /// 在脚本中的位置；不是由脚本定义的指令为None
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub pos: Option<SourcePos>,
+}
+
+#[cfg(feature = "serde")]
+fn default_dlc() -> u16 {
+    8
 }
 
 impl InsDef {
@@ -15,49 +35,237 @@ impl InsDef {
         InsDef {
             name: name.to_string(),
             canid: canid,
+            extended: false,
+            dlc: 8,
             args: VarDefList::new(),
             note: None,
+            pos: None,
         }
     }
 
-    pub fn exec(&self, args: &VarBindingList, data: &mut Vec<u8>, context: &mut Context) {
+    /// 由J1939的优先级、PGN、源地址和目标地址定义指令，canid自动计算（扩展帧）
+    /// 设置了Context.j1939时，数据超过8字节的由Context::send_frame()按J1939传输协议发送，sa被替换为节点声明的地址
+    pub fn new_j1939(name: &str, priority: u8, pgn: u32, sa: u8, da: u8) -> InsDef {
+        let mut insdef = InsDef::new(name, j1939_id(priority, pgn, sa, da));
+        insdef.extended = true;
+        insdef
+    }
+
+    pub fn exec(&self, args: &VarBindingList, data: &mut Vec<u8>, context: &mut Context) -> Result<(),String> {
         context.log_info(&format!("exec instruction: {}", self.name));
-        for vardef in &self.args.defs {
-            let value: &str = {
-                // TODO: use eval_expr()
-                match args.raw_value_of(vardef.name.as_str()) {
-                    Some(value) => value,
-                    None => {
-                        if vardef.default == "" {
-                            panic!("Require arg: {}", vardef.name);
-                        }
-                        vardef.default.as_str()
+        data.clear();
+        let mut selector = None;
+        if self.args.defs.iter().any(|vardef| vardef.signal.is_some()) {
+            // 参数带有信号布局（如由DBC导入的指令），按位编码
+            data.resize(self.dlc as usize, 0);
+            selector = self.mux_selector(args)?;
+            for vardef in &self.args.defs {
+                if !is_active(vardef, selector) {
+                    // 只能提供当前选中的多路复用组的信号
+                    if args.contains(&vardef.name) {
+                        return Err(format!("Arg {} is not in the active multiplexer group", vardef.name));
                     }
+                    continue;
                 }
-            };
-            match vardef.typ.as_str() {
-                "byte" | "i8" | "u8" => {
-                    data.push(value.parse().expect("invalid byte/i8/u8"));
+                let value = self.arg_value(vardef, args)?;
+                let signal = match vardef.signal {
+                    Some(ref signal) => signal,
+                    None => return Err(format!("Arg without signal layout: {}", vardef.name)),
+                };
+                if vardef.computed.is_some() {
+                    continue; // see fill_computed()
                 }
-                "i16" | "u16" => {
-                    let v: u16 = value.parse().expect("invalid i16/u16");
-                    let v = v.to_be(); // to big endian
-                    assert!(data.len() <= 6);
-                    data.extend_from_slice(unsafe { slice::from_raw_parts(&v as *const u16 as *const u8, 2) });
+                let physical: f64 = split_lr(value, ":").1.trim().parse()
+                    .map_err(|_| format!("Invalid value of arg {}: {}", vardef.name, value))?;
+                let raw = signal.to_raw(physical).map_err(|err| format!("{}: {}", vardef.name, err))?;
+                signal.pack(raw, data).map_err(|err| format!("{}: {}", vardef.name, err))?;
+            }
+        } else {
+            if let Some(vardef) = self.args.defs.iter().find(|vardef| vardef.mux.is_some()) {
+                return Err(format!("Multiplexed arg without signal layout: {}", vardef.name));
+            }
+            self.check_layout()?;
+            for vardef in &self.args.defs {
+                let value = self.arg_value(vardef, args)?;
+                let invalid = || format!("Invalid value of arg {}: {}", vardef.name, value);
+                // 多字节的值按big endian编码
+                match vardef.typ.as_str() {
+                    "byte" | "u8" => data.push(value.parse::<u8>().map_err(|_| invalid())?),
+                    "i8" => data.extend_from_slice(&value.parse::<i8>().map_err(|_| invalid())?.to_be_bytes()),
+                    "u16" => data.extend_from_slice(&value.parse::<u16>().map_err(|_| invalid())?.to_be_bytes()),
+                    "i16" => data.extend_from_slice(&value.parse::<i16>().map_err(|_| invalid())?.to_be_bytes()),
+                    "u32" => data.extend_from_slice(&value.parse::<u32>().map_err(|_| invalid())?.to_be_bytes()),
+                    "i32" => data.extend_from_slice(&value.parse::<i32>().map_err(|_| invalid())?.to_be_bytes()),
+                    _ => return Err(format!("Unsupport arg type: {}", vardef.typ)),
                 }
-                "i32" | "u32" => {
-                    let v: u32 = value.parse().expect("invalid i32/u32");
-                    let v = v.to_be(); // to big endian
-                    assert!(data.len() <= 4);
-                    data.extend_from_slice(unsafe { slice::from_raw_parts(&v as *const u32 as *const u8, 4) });
+            }
+        }
+        self.fill_computed(data, context, selector)?;
+        context.log_info(&format!("instruction data: {:?}", data));
+        Ok(())
+    }
+
+    // 在其他参数编码完成后，计算滚动计数器和校验和并写入data
+    fn fill_computed(&self, data: &mut [u8], context: &mut Context, selector: Option<u64>) -> Result<(), String> {
+        if self.args.defs.iter().all(|vardef| vardef.computed.is_none()) {
+            return Ok(());
+        }
+        let layouts = self.signal_layouts()?;
+        // 先计算计数器，校验和要覆盖计数器
+        for (vardef, layout) in self.args.defs.iter().zip(layouts.iter()) {
+            if !is_active(vardef, selector) {
+                continue;
+            }
+            if let Some(Computed::Counter { width, wrap }) = vardef.computed {
+                if width == 0 || width > layout.bit_len || width > 63 || wrap == 0 || wrap > 1u64 << width {
+                    return Err(format!("Invalid counter: {}", vardef.name));
                 }
-                _ => {
-                    panic!("Unsupport arg type: {}", vardef.typ);
+                let key = format!("{}/{}", self.name, vardef.name);
+                let value = context.counters.get(&key).map_or(0, |v| *v % wrap);
+                context.counters.insert(key, (value + 1) % wrap);
+                layout.pack(value as i64, data).map_err(|err| format!("{}: {}", vardef.name, err))?;
+            }
+        }
+        for (vardef, layout) in self.args.defs.iter().zip(layouts.iter()) {
+            if !is_active(vardef, selector) {
+                continue;
+            }
+            if let Some(Computed::Checksum(checksum)) = vardef.computed {
+                // 校验和所在的字节不参与计算
+                let own_bytes: Vec<usize> = layout.bit_positions().iter().map(|pos| (*pos / 8) as usize).collect();
+                let covered: Vec<u8> = data.iter().enumerate()
+                    .filter(|&(i, _)| !own_bytes.contains(&i))
+                    .map(|(_, b)| *b)
+                    .collect();
+                let mut value = checksum.compute(&covered) as i64;
+                if layout.bit_len < 8 {
+                    value &= (1 << layout.bit_len) - 1;
                 }
+                layout.pack(value, data).map_err(|err| format!("{}: {}", vardef.name, err))?;
             }
         }
-        assert!(data.len() == 8); // we need 8 bytes data here
-        println!("instruction data: {:?}", data);
+        Ok(())
+    }
+
+    /// 解码数据帧，返回各参数的值（多路复用的信号仅返回当前选中组的）
+    /// 没有缩放(factor=1, offset=0)的信号返回原始整数值，否则返回物理值
+    pub fn decode(&self, data: &[u8]) -> Result<VarBindingList, String> {
+        let layouts = self.signal_layouts()?;
+        let mut selector = None;
+        for (vardef, layout) in self.args.defs.iter().zip(layouts.iter()) {
+            if vardef.mux == Some(Mux::Multiplexor) {
+                let raw = layout.unpack(data).map_err(|err| format!("{}: {}", vardef.name, err))?;
+                selector = Some(raw as u64);
+            }
+        }
+        let mut values = VarBindingList::new();
+        for (vardef, layout) in self.args.defs.iter().zip(layouts.iter()) {
+            if !is_active(vardef, selector) {
+                continue;
+            }
+            let raw = layout.unpack(data).map_err(|err| format!("{}: {}", vardef.name, err))?;
+            let value = if layout.factor == 1.0 && layout.offset == 0.0 {
+                raw.to_string()
+            } else {
+                layout.to_physical(raw).to_string()
+            };
+            values.set_binding(&vardef.name, &value);
+        }
+        Ok(values)
+    }
+
+    // 多路复用选择器的原始值；没有多路复用的指令返回None
+    fn mux_selector(&self, args: &VarBindingList) -> Result<Option<u64>, String> {
+        let mut selectors = self.args.defs.iter().filter(|vardef| vardef.mux == Some(Mux::Multiplexor));
+        let vardef = match selectors.next() {
+            Some(vardef) => vardef,
+            None => {
+                if let Some(vardef) = self.args.defs.iter().find(|vardef| vardef.mux.is_some()) {
+                    return Err(format!("Multiplexed arg without multiplexor: {}", vardef.name));
+                }
+                return Ok(None);
+            }
+        };
+        if selectors.next().is_some() {
+            return Err(format!("More than one multiplexor in instruction: {}", self.name));
+        }
+        let signal = match vardef.signal {
+            Some(ref signal) => signal,
+            None => return Err(format!("Arg without signal layout: {}", vardef.name)),
+        };
+        let value = self.arg_value(vardef, args)?;
+        let physical: f64 = split_lr(value, ":").1.trim().parse()
+            .map_err(|_| format!("Invalid value of arg {}: {}", vardef.name, value))?;
+        let raw = signal.to_raw(physical).map_err(|err| format!("{}: {}", vardef.name, err))?;
+        if raw < 0 {
+            return Err(format!("Invalid multiplexor value: {}", value));
+        }
+        Ok(Some(raw as u64))
+    }
+
+    /// 检查参数能否依次按字节编码（参数都没有信号布局时）：类型须为byte/i8/u8/i16/u16/i32/u32，共8个字节
+    pub fn check_layout(&self) -> Result<(), String> {
+        if self.args.defs.iter().any(|vardef| vardef.signal.is_some()) {
+            return Ok(());
+        }
+        let len: u32 = self.signal_layouts()?.iter().map(|layout| layout.bit_len / 8).sum();
+        if len != 8 {
+            return Err(format!("Args take {} bytes, requires 8", len));
+        }
+        Ok(())
+    }
+
+    /// 各参数的信号布局，与args.defs一一对应
+    /// 没有显式信号布局的参数，按exec()的编码方式推算（依次按字节排列，big endian）
+    pub fn signal_layouts(&self) -> Result<Vec<SignalLayout>, String> {
+        let mut layouts = Vec::with_capacity(self.args.defs.len());
+        let mut offset = 0u32; // 字节偏移
+        for vardef in &self.args.defs {
+            if let Some(ref signal) = vardef.signal {
+                layouts.push(signal.clone());
+                continue;
+            }
+            let (len, signed) = match vardef.typ.as_str() {
+                "byte" | "u8" => (1, false),
+                "i8" => (1, true),
+                "u16" => (2, false),
+                "i16" => (2, true),
+                "u32" => (4, false),
+                "i32" => (4, true),
+                _ => return Err(format!("Unsupport arg type: {}", vardef.typ)),
+            };
+            layouts.push(SignalLayout::new(offset * 8 + 7, len * 8, ByteOrder::Motorola, signed));
+            offset += len;
+        }
+        Ok(layouts)
+    }
+
+    // 取参数值，未绑定的使用默认值；自动计算的参数先以0占位
+    fn arg_value<'a>(&self, vardef: &'a VarDef, args: &'a VarBindingList) -> Result<&'a str, String> {
+        if vardef.computed.is_some() {
+            if args.contains(&vardef.name) {
+                return Err(format!("Computed arg cannot be bound: {}", vardef.name));
+            }
+            return Ok("0");
+        }
+        // TODO: use eval_expr()
+        match args.raw_value_of(vardef.name.as_str()) {
+            Some(value) => Ok(value),
+            None => {
+                if vardef.default.is_empty() {
+                    return Err(format!("Require arg: {}", vardef.name));
+                }
+                Ok(vardef.default.as_str())
+            }
+        }
+    }
+}
+
+// 参数是否属于当前选中的多路复用组（非多路复用的参数总是有效）
+fn is_active(vardef: &VarDef, selector: Option<u64>) -> bool {
+    match vardef.mux {
+        Some(Mux::Multiplexed(group)) => selector == Some(group),
+        _ => true,
     }
 }
 
@@ -65,6 +273,8 @@ impl InsDef {
 mod tests {
     use super::InsDef;
     use variable::{VarDef, VarBindingList};
+    use signal::{SignalLayout, ByteOrder, Mux};
+    use computed::{Computed, Checksum};
     use engine::Context;
 
     #[test]
@@ -84,7 +294,180 @@ mod tests {
         args.set_binding("d", "0");
 
         let mut data = Vec::new();
-        movr.exec(&args, &mut data, &mut Context::new());
+        movr.exec(&args, &mut data, &mut Context::new()).expect("ok");
         assert_eq!(data, vec![0x00,0x14,0xb8,0x4c, 0xff, 0x62,0x2f, 0x0]);
+
+        // the derived layouts encode the same data
+        let layouts = movr.signal_layouts().expect("ok");
+        let mut data2 = vec![0u8; 8];
+        for (vardef, layout) in movr.args.defs.iter().zip(layouts.iter()) {
+            let value: i64 = args.raw_value_of(&vardef.name).unwrap().parse().unwrap();
+            layout.pack(value, &mut data2).expect("ok");
+        }
+        assert_eq!(data, data2);
+        assert_eq!(layouts[1], SignalLayout::new(39, 8, ByteOrder::Motorola, false));
+    }
+
+    #[test]
+    fn test_exec_errors() {
+        let mut movr = InsDef::new("move radar", 1000);
+        movr.args.add(VarDef::new("a", "i32"));
+        movr.args.add(VarDef::new("b", "u32"));
+        let mut args = VarBindingList::new();
+        args.set_binding("a", "-2");
+        args.set_binding("b", "-1");
+        let mut data = Vec::new();
+        let mut context = Context::new();
+        assert_eq!(movr.exec(&args, &mut data, &mut context), Err("Invalid value of arg b: -1".to_string()));
+        args.set_binding("b", "on");
+        assert_eq!(movr.exec(&args, &mut data, &mut context), Err("Invalid value of arg b: on".to_string()));
+        args.set_binding("b", "1");
+        movr.exec(&args, &mut data, &mut context).expect("ok");
+        assert_eq!(data, vec![0xff, 0xff, 0xff, 0xfe, 0, 0, 0, 1]);
+
+        movr.args.add(VarDef::new("c", "u8"));
+        args.set_binding("c", "1");
+        assert_eq!(movr.exec(&args, &mut data, &mut context), Err("Args take 9 bytes, requires 8".to_string()));
+        assert_eq!(movr.check_layout(), Err("Args take 9 bytes, requires 8".to_string()));
+        movr.args.defs[2].typ = "str".to_string();
+        assert_eq!(movr.exec(&args, &mut data, &mut context), Err("Unsupport arg type: str".to_string()));
+    }
+
+    #[test]
+    fn test_exec_signals() {
+        let mut ins = InsDef::new("speed", 0x123);
+        ins.dlc = 4;
+        let mut speed = VarDef::new("speed", "f64");
+        speed.signal = Some(SignalLayout {
+            factor: 0.1,
+            offset: -10.0,
+            ..SignalLayout::new(0, 16, ByteOrder::Intel, false)
+        });
+        ins.args.add(speed);
+        let mut gear = VarDef::new("gear", "u8");
+        gear.signal = Some(SignalLayout::new(23, 4, ByteOrder::Motorola, false));
+        gear.default = "1".to_string();
+        ins.args.add(gear);
+
+        let mut args = VarBindingList::new();
+        args.set_binding("speed", "42.5");
+        let mut data = Vec::new();
+        ins.exec(&args, &mut data, &mut Context::new()).expect("ok");
+        assert_eq!(data, vec![0x0D, 0x02, 0x10, 0x00]); // raw speed 525, gear 1 (default)
+
+        args.set_binding("gear", "16"); // does not fit in 4 bits
+        assert!(ins.exec(&args, &mut data, &mut Context::new()).is_err());
+        assert!(ins.exec(&VarBindingList::new(), &mut data, &mut Context::new()).is_err()); // requires speed
+    }
+
+    #[test]
+    fn test_exec_computed() {
+        let mut ins = InsDef::new("brake", 0x200);
+        ins.args.add(VarDef::new("torque", "u16"));
+        ins.args.add(VarDef::new("mode", "u8"));
+        ins.args.add(VarDef::new("reserved", "u16"));
+        let mut counter = VarDef::new("counter", "u8");
+        counter.computed = Some(Computed::Counter { width: 4, wrap: 15 }); // 0..14
+        ins.args.add(counter);
+        let mut crc = VarDef::new("crc", "u8");
+        crc.computed = Some(Computed::Checksum(Checksum::Crc8SaeJ1850));
+        ins.args.add(crc);
+        let mut xor = VarDef::new("xor", "u8");
+        xor.computed = Some(Computed::Checksum(Checksum::Xor));
+        ins.args.add(xor);
+
+        let mut args = VarBindingList::new();
+        args.set_binding("torque", "300");
+        args.set_binding("mode", "2");
+        args.set_binding("reserved", "0");
+        let mut context = Context::new();
+        for i in 0..17u8 {
+            let mut data = Vec::new();
+            ins.exec(&args, &mut data, &mut context).expect("ok");
+            assert_eq!(&data[..5], &[0x01, 0x2C, 0x02, 0x00, 0x00]);
+            assert_eq!(data[5], i % 15); // rolling counter wraps after 14
+            assert_eq!(data[6], Checksum::Crc8SaeJ1850.compute(&[data[0], data[1], data[2], data[3], data[4], data[5], 0]));
+            assert_eq!(data[7], Checksum::Xor.compute(&data[..7])); // xor covers crc
+        }
+        // another context has its own counters
+        let mut data = Vec::new();
+        ins.exec(&args, &mut data, &mut Context::new()).expect("ok");
+        assert_eq!(data[5], 0);
+
+        args.set_binding("counter", "3");
+        assert!(ins.exec(&args, &mut data, &mut context).is_err()); // computed args cannot be bound
+    }
+
+    #[test]
+    fn test_exec_computed_signals() {
+        let mut ins = InsDef::new("status", 0x300);
+        ins.dlc = 3;
+        let mut value = VarDef::new("value", "u16");
+        value.signal = Some(SignalLayout::new(8, 16, ByteOrder::Intel, false));
+        ins.args.add(value);
+        let mut crc = VarDef::new("crc", "u8");
+        crc.signal = Some(SignalLayout::new(0, 8, ByteOrder::Intel, false));
+        crc.computed = Some(Computed::Checksum(Checksum::Crc8Autosar { data_id: 0x42 }));
+        ins.args.add(crc);
+
+        let mut args = VarBindingList::new();
+        args.set_binding("value", "4660");
+        let mut data = Vec::new();
+        ins.exec(&args, &mut data, &mut Context::new()).expect("ok");
+        assert_eq!(&data[1..], &[0x34, 0x12]);
+        assert_eq!(data[0], Checksum::Crc8Autosar { data_id: 0x42 }.compute(&[0x34, 0x12]));
+    }
+
+    #[test]
+    fn test_exec_multiplexed() {
+        let mut ins = InsDef::new("config", 0x400);
+        ins.dlc = 4;
+        let mut page = VarDef::new("page", "u8");
+        page.signal = Some(SignalLayout::new(0, 8, ByteOrder::Intel, false));
+        page.mux = Some(Mux::Multiplexor);
+        ins.args.add(page);
+        let mut volume = VarDef::new("volume", "u8");
+        volume.signal = Some(SignalLayout::new(8, 8, ByteOrder::Intel, false));
+        volume.mux = Some(Mux::Multiplexed(1));
+        ins.args.add(volume);
+        let mut gain = VarDef::new("gain", "f64");
+        gain.signal = Some(SignalLayout { factor: 0.5, ..SignalLayout::new(8, 16, ByteOrder::Intel, true) });
+        gain.mux = Some(Mux::Multiplexed(2));
+        ins.args.add(gain);
+        let mut crc = VarDef::new("crc", "u8");
+        crc.signal = Some(SignalLayout::new(24, 8, ByteOrder::Intel, false));
+        crc.computed = Some(Computed::Checksum(Checksum::Sum));
+        ins.args.add(crc);
+
+        let mut context = Context::new();
+        let mut data = Vec::new();
+        let mut args = VarBindingList::new();
+        args.set_binding("page", "1");
+        args.set_binding("volume", "200");
+        ins.exec(&args, &mut data, &mut context).expect("ok");
+        assert_eq!(data, vec![0x01, 0xC8, 0x00, 0xC9]);
+        let values = ins.decode(&data).expect("ok");
+        assert_eq!(values.raw_value_of("page"), Some("1"));
+        assert_eq!(values.raw_value_of("volume"), Some("200"));
+        assert_eq!(values.raw_value_of("gain"), None);
+        assert_eq!(values.raw_value_of("crc"), Some("201"));
+
+        args.set_binding("gain", "-1.5");
+        assert!(ins.exec(&args, &mut data, &mut context).is_err()); // gain is not in group 1
+        args.set_binding("page", "2");
+        assert!(ins.exec(&args, &mut data, &mut context).is_err()); // volume is not in group 2
+        args.remove_binding("volume");
+        ins.exec(&args, &mut data, &mut context).expect("ok");
+        assert_eq!(data, vec![0x02, 0xFD, 0xFF, 0xFE]);
+        let values = ins.decode(&data).expect("ok");
+        assert_eq!(values.raw_value_of("gain"), Some("-1.5"));
+        assert_eq!(values.raw_value_of("volume"), None);
+
+        args.set_binding("page", "3"); // no signals in group 3
+        args.remove_binding("gain");
+        ins.exec(&args, &mut data, &mut context).expect("ok");
+        assert_eq!(data, vec![0x03, 0x00, 0x00, 0x03]);
+        args.remove_binding("page");
+        assert!(ins.exec(&args, &mut data, &mut context).is_err()); // requires page
     }
 }
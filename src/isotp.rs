@@ -0,0 +1,275 @@
+// ISO-TP (ISO 15765-2) 传输层：将最长4095字节的数据分段为多个CAN帧收发
+// 只支持经典CAN（8字节数据帧）和normal addressing
+// UdsClient用于诊断；设置Context.isotp后，超过8字节的指令数据帧也按ISO-TP发送
+
+use bus::{Bus, Frame};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// 单帧最多容纳的数据长度
+const SF_MAX_LEN: usize = 7;
+/// 最大数据长度（12位长度字段）
+pub const MAX_LEN: usize = 4095;
+// 发送方连续收到FC.WAIT的最大次数(N_WFTmax)
+const MAX_WAIT_FRAMES: u32 = 10;
+
+const PCI_SF: u8 = 0x00;
+const PCI_FF: u8 = 0x10;
+const PCI_CF: u8 = 0x20;
+const PCI_FC: u8 = 0x30;
+const FC_CTS: u8 = 0;
+const FC_WAIT: u8 = 1;
+const FC_OVERFLOW: u8 = 2;
+
+/// 一个ISO-TP通道：用tx_id发送，只接收rx_id的帧（其他帧被忽略）
+#[derive(Debug, Clone)]
+pub struct IsoTp {
+    pub tx_id: u32,
+    pub rx_id: u32,
+    pub extended: bool,
+    /// 接收时在流控帧中告知对方的块大小，0表示不限
+    pub block_size: u8,
+    /// 接收时在流控帧中告知对方的最小帧间隔(STmin)，编码同ISO 15765-2
+    pub st_min: u8,
+    /// 等待流控帧(N_Bs)和连续帧(N_Cr)的超时
+    pub timeout: Duration,
+    /// 不足8字节的帧用此值填充；None表示不填充
+    pub padding: Option<u8>,
+}
+
+impl IsoTp {
+    pub fn new(tx_id: u32, rx_id: u32) -> IsoTp {
+        IsoTp {
+            tx_id,
+            rx_id,
+            extended: false,
+            block_size: 0,
+            st_min: 0,
+            timeout: Duration::from_millis(1000),
+            padding: Some(0xCC),
+        }
+    }
+
+    /// 发送数据，必要时分段并按接收方的流控帧控制发送节奏
+    pub fn send(&self, bus: &mut dyn Bus, payload: &[u8]) -> Result<(), String> {
+        if payload.is_empty() || payload.len() > MAX_LEN {
+            return Err(format!("Invalid ISO-TP payload length: {}", payload.len()));
+        }
+        if payload.len() <= SF_MAX_LEN {
+            let mut data = vec![PCI_SF | payload.len() as u8];
+            data.extend_from_slice(payload);
+            return self.send_frame(bus, data);
+        }
+
+        let len = payload.len();
+        let mut data = vec![PCI_FF | (len >> 8) as u8, len as u8];
+        data.extend_from_slice(&payload[..6]);
+        self.send_frame(bus, data)?;
+
+        let mut offset = 6;
+        let mut sn = 1u8;
+        while offset < len {
+            let (block_size, st_min) = self.wait_flow_control(bus)?;
+            let mut sent = 0u32;
+            while offset < len && (block_size == 0 || sent < block_size as u32) {
+                if sent > 0 {
+                    thread::sleep(st_min);
+                }
+                let end = (offset + 7).min(len);
+                let mut data = vec![PCI_CF | sn];
+                data.extend_from_slice(&payload[offset..end]);
+                self.send_frame(bus, data)?;
+                offset = end;
+                sn = (sn + 1) & 0x0F;
+                sent += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// 接收一条完整的数据（单帧，或首帧+连续帧），并向发送方回复流控帧
+    /// timeout内没有收到首帧或单帧时返回错误
+    pub fn recv(&self, bus: &mut dyn Bus) -> Result<Vec<u8>, String> {
+        self.recv_within(bus, self.timeout)
+    }
+
+    /// 同recv()，但等待首帧或单帧的超时由参数指定（如UDS的P2/P2*）
+    pub fn recv_within(&self, bus: &mut dyn Bus, timeout: Duration) -> Result<Vec<u8>, String> {
+        let data = self.recv_frame(bus, timeout, "SF/FF")?;
+        match data[0] & 0xF0 {
+            PCI_SF => {
+                let len = (data[0] & 0x0F) as usize;
+                if len == 0 || len > data.len() - 1 {
+                    return Err(format!("Invalid ISO-TP single frame length: {}", len));
+                }
+                Ok(data[1..len + 1].to_vec())
+            }
+            PCI_FF => {
+                if data.len() < 8 {
+                    return Err("Invalid ISO-TP first frame".to_string());
+                }
+                let len = ((data[0] as usize & 0x0F) << 8) | data[1] as usize;
+                if len <= SF_MAX_LEN {
+                    return Err(format!("Invalid ISO-TP first frame length: {}", len));
+                }
+                let mut payload = data[2..8].to_vec();
+                let mut sn = 1u8;
+                while payload.len() < len {
+                    self.send_frame(bus, vec![PCI_FC | FC_CTS, self.block_size, self.st_min])?;
+                    let mut received = 0u32;
+                    while payload.len() < len && (self.block_size == 0 || received < self.block_size as u32) {
+                        let data = self.recv_frame(bus, self.timeout, "CF")?;
+                        if data[0] & 0xF0 != PCI_CF {
+                            return Err(format!("Unexpected ISO-TP frame, expected CF: {:02X}", data[0]));
+                        }
+                        if data[0] & 0x0F != sn {
+                            return Err(format!("ISO-TP sequence error: expected {}, got {}", sn, data[0] & 0x0F));
+                        }
+                        let end = (len - payload.len()).min(7).min(data.len() - 1);
+                        payload.extend_from_slice(&data[1..end + 1]);
+                        sn = (sn + 1) & 0x0F;
+                        received += 1;
+                    }
+                }
+                Ok(payload)
+            }
+            _ => Err(format!("Unexpected ISO-TP frame, expected SF/FF: {:02X}", data[0])),
+        }
+    }
+
+    fn send_frame(&self, bus: &mut dyn Bus, mut data: Vec<u8>) -> Result<(), String> {
+        if let Some(padding) = self.padding {
+            data.resize(8, padding);
+        }
+        bus.send(&Frame { id: self.tx_id, extended: self.extended, data })
+    }
+
+    // 接收rx_id的下一帧（忽略其他帧）
+    fn recv_frame(&self, bus: &mut dyn Bus, timeout: Duration, expected: &str) -> Result<Vec<u8>, String> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(format!("ISO-TP timeout, waiting for {}", expected));
+            }
+            if let Some(frame) = bus.recv(deadline - now)? {
+                if frame.id == self.rx_id && frame.extended == self.extended && !frame.data.is_empty() {
+                    return Ok(frame.data);
+                }
+            }
+        }
+    }
+
+    // 等待流控帧，返回(块大小, 帧间隔)
+    fn wait_flow_control(&self, bus: &mut dyn Bus) -> Result<(u8, Duration), String> {
+        let mut waits = 0;
+        loop {
+            let data = self.recv_frame(bus, self.timeout, "FC")?;
+            if data[0] & 0xF0 != PCI_FC || data.len() < 3 {
+                return Err(format!("Unexpected ISO-TP frame, expected FC: {:02X}", data[0]));
+            }
+            match data[0] & 0x0F {
+                FC_CTS => return Ok((data[1], st_min_duration(data[2]))),
+                FC_WAIT => {
+                    waits += 1;
+                    if waits > MAX_WAIT_FRAMES {
+                        return Err("ISO-TP receiver keeps waiting".to_string());
+                    }
+                }
+                FC_OVERFLOW => return Err("ISO-TP receiver overflow".to_string()),
+                status => return Err(format!("Invalid ISO-TP flow status: {}", status)),
+            }
+        }
+    }
+}
+
+// STmin: 0x00-0x7F为毫秒，0xF1-0xF9为100-900微秒，其余保留值按最大的127毫秒处理
+fn st_min_duration(st_min: u8) -> Duration {
+    match st_min {
+        0x00..=0x7F => Duration::from_millis(st_min as u64),
+        0xF1..=0xF9 => Duration::from_micros((st_min - 0xF0) as u64 * 100),
+        _ => Duration::from_millis(0x7F),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IsoTp, st_min_duration};
+    use bus::{Bus, Frame, LoopbackBus};
+    use std::thread;
+    use std::time::Duration;
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn test_single_frame() {
+        let (mut a, mut b) = LoopbackBus::pair();
+        IsoTp::new(0x7E0, 0x7E8).send(&mut a, &[0x22, 0xF1, 0x90]).expect("ok");
+        let frame = b.recv(Duration::from_millis(10)).unwrap().unwrap();
+        assert_eq!(frame, Frame::new(0x7E0, &[0x03, 0x22, 0xF1, 0x90, 0xCC, 0xCC, 0xCC, 0xCC]));
+        b.send(&frame).unwrap(); // echo back
+        let mut echo = IsoTp::new(0x7E8, 0x7E0);
+        echo.timeout = Duration::from_millis(50);
+        assert_eq!(echo.recv(&mut a), Ok(vec![0x22, 0xF1, 0x90]));
+        assert!(echo.recv(&mut a).is_err()); // timeout
+    }
+
+    #[test]
+    fn test_segmented() {
+        for &(len, block_size, st_min) in &[(8, 0, 0), (100, 0, 0), (300, 4, 1), (4095, 8, 0xF5)] {
+            let (mut a, mut b) = LoopbackBus::pair();
+            let peer = thread::spawn(move || {
+                let mut isotp = IsoTp::new(0x7E8, 0x7E0);
+                isotp.block_size = block_size;
+                isotp.st_min = st_min;
+                isotp.recv(&mut b)
+            });
+            IsoTp::new(0x7E0, 0x7E8).send(&mut a, &payload(len)).expect("ok");
+            assert_eq!(peer.join().unwrap(), Ok(payload(len)));
+        }
+    }
+
+    #[test]
+    fn test_flow_control() {
+        let (mut a, mut b) = LoopbackBus::pair();
+        let peer = thread::spawn(move || {
+            let timeout = Duration::from_millis(100);
+            let mut frames = vec![b.recv(timeout).unwrap().unwrap()]; // FF
+            b.send(&Frame::new(0x7E8, &[0x31, 0, 0])).unwrap(); // wait
+            b.send(&Frame::new(0x123, &[0x30, 0, 0])).unwrap(); // not for us
+            b.send(&Frame::new(0x7E8, &[0x30, 2, 0])).unwrap(); // 2 frames
+            frames.push(b.recv(timeout).unwrap().unwrap());
+            frames.push(b.recv(timeout).unwrap().unwrap());
+            assert_eq!(b.recv(Duration::from_millis(20)).unwrap(), None); // waits for next FC
+            b.send(&Frame::new(0x7E8, &[0x32, 0, 0])).unwrap(); // overflow
+            frames
+        });
+        let result = IsoTp::new(0x7E0, 0x7E8).send(&mut a, &payload(40));
+        assert_eq!(result, Err("ISO-TP receiver overflow".to_string()));
+        let frames = peer.join().unwrap();
+        assert_eq!(frames[0].data, vec![0x10, 40, 0, 1, 2, 3, 4, 5]);
+        assert_eq!(frames[1].data, vec![0x21, 6, 7, 8, 9, 10, 11, 12]);
+        assert_eq!(frames[2].data, vec![0x22, 13, 14, 15, 16, 17, 18, 19]);
+    }
+
+    #[test]
+    fn test_recv_errors() {
+        let (mut a, mut b) = LoopbackBus::pair();
+        b.send(&Frame::new(0x7E8, &[0x10, 20, 0, 1, 2, 3, 4, 5])).unwrap();
+        b.send(&Frame::new(0x7E8, &[0x22, 6, 7, 8, 9, 10, 11, 12])).unwrap(); // wrong sequence number
+        let isotp = IsoTp::new(0x7E0, 0x7E8);
+        assert_eq!(isotp.recv(&mut a), Err("ISO-TP sequence error: expected 1, got 2".to_string()));
+        assert_eq!(b.recv(Duration::from_millis(10)).unwrap().unwrap().data[..3], [0x30, 0, 0]);
+        assert!(isotp.send(&mut a, &[]).is_err());
+        assert!(isotp.send(&mut a, &payload(4096)).is_err());
+    }
+
+    #[test]
+    fn test_st_min() {
+        assert_eq!(st_min_duration(0x0A), Duration::from_millis(10));
+        assert_eq!(st_min_duration(0xF3), Duration::from_micros(300));
+        assert_eq!(st_min_duration(0x80), Duration::from_millis(127));
+    }
+}
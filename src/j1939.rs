@@ -0,0 +1,433 @@
+// SAE J1939：29位ID与PGN/优先级/地址的换算、传输协议(BAM和RTS/CTS)、地址声明
+// 传输协议一次只处理一个会话；会话进行中收到的其他帧被忽略
+
+use bus::{Bus, Frame};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// 全局地址（广播）
+pub const GLOBAL_ADDRESS: u8 = 0xFF;
+/// 空地址，无法声明地址的节点使用
+pub const NULL_ADDRESS: u8 = 0xFE;
+/// 传输协议最多能传输的数据长度
+pub const MAX_LEN: usize = 255 * 7;
+
+pub const PGN_REQUEST: u32 = 0xEA00;
+pub const PGN_ADDRESS_CLAIMED: u32 = 0xEE00;
+pub const PGN_TP_CM: u32 = 0xEC00;
+pub const PGN_TP_DT: u32 = 0xEB00;
+
+const CM_RTS: u8 = 16;
+const CM_CTS: u8 = 17;
+const CM_END_OF_MSG_ACK: u8 = 19;
+const CM_BAM: u8 = 32;
+const CM_ABORT: u8 = 255;
+
+// 传输协议帧的优先级
+const TP_PRIORITY: u8 = 7;
+
+/// 由优先级、PGN、源地址和目标地址得到29位CAN ID
+/// PDU2格式(PF >= 240)的PGN没有目标地址，da被忽略
+pub fn j1939_id(priority: u8, pgn: u32, sa: u8, da: u8) -> u32 {
+    let pf = (pgn >> 8) & 0xFF;
+    let pgn = if pf < 240 { (pgn & 0x3FF00) | da as u32 } else { pgn & 0x3FFFF };
+    ((priority as u32 & 0x07) << 26) | (pgn << 8) | sa as u32
+}
+
+/// 将29位CAN ID分解为(优先级, PGN, 源地址, 目标地址)；PDU2格式的目标地址为GLOBAL_ADDRESS
+pub fn parse_j1939_id(id: u32) -> (u8, u32, u8, u8) {
+    let priority = ((id >> 26) & 0x07) as u8;
+    let pgn = (id >> 8) & 0x3FFFF;
+    let sa = id as u8;
+    if (pgn >> 8) & 0xFF < 240 {
+        (priority, pgn & 0x3FF00, sa, pgn as u8)
+    } else {
+        (priority, pgn, sa, GLOBAL_ADDRESS)
+    }
+}
+
+/// J1939报文（可能由传输协议的多个帧组成）
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub priority: u8,
+    pub pgn: u32,
+    pub sa: u8,
+    pub da: u8,
+    pub data: Vec<u8>,
+}
+
+/// 总线上的一个J1939节点
+pub struct J1939 {
+    /// 64位NAME，数值越小优先级越高；最高位为1表示可以任意选择地址(arbitrary address capable)
+    pub name: u64,
+    /// 当前（已声明的）地址
+    pub address: u8,
+    /// 首先尝试声明的地址
+    pub preferred_address: u8,
+    /// 声明地址后等待其他节点争用的时间
+    pub claim_timeout: Duration,
+    /// 传输协议等待对方的超时(T1-T4)
+    pub timeout: Duration,
+    /// BAM数据帧之间的间隔
+    pub bam_interval: Duration,
+}
+
+impl J1939 {
+    pub fn new(name: u64, preferred_address: u8) -> J1939 {
+        J1939 {
+            name,
+            address: preferred_address,
+            preferred_address,
+            claim_timeout: Duration::from_millis(250),
+            timeout: Duration::from_millis(1250),
+            bam_interval: Duration::from_millis(50),
+        }
+    }
+
+    fn arbitrary_address_capable(&self) -> bool {
+        self.name >> 63 == 1
+    }
+
+    fn send_pgn(&self, bus: &mut dyn Bus, priority: u8, pgn: u32, sa: u8, da: u8, data: &[u8]) -> Result<(), String> {
+        bus.send(&Frame { id: j1939_id(priority, pgn, sa, da), extended: true, data: data.to_vec() })
+    }
+
+    /// 发送报文；超过8字节的报文使用传输协议：目标为全局地址时用BAM，否则用RTS/CTS
+    pub fn send(&self, bus: &mut dyn Bus, message: &Message) -> Result<(), String> {
+        if message.data.len() <= 8 {
+            return self.send_pgn(bus, message.priority, message.pgn, message.sa, message.da, &message.data);
+        }
+        if message.data.len() > MAX_LEN {
+            return Err(format!("J1939 message too long: {} bytes", message.data.len()));
+        }
+        let (len, packets) = (message.data.len(), message.data.len().div_ceil(7));
+        let pgn = [message.pgn as u8, (message.pgn >> 8) as u8, (message.pgn >> 16) as u8];
+        let packet = |seq: usize| -> Vec<u8> {
+            let mut data = vec![seq as u8];
+            data.extend(message.data.iter().skip((seq - 1) * 7).take(7));
+            data.resize(8, 0xFF);
+            data
+        };
+        let (sa, da) = (message.sa, message.da);
+
+        if da == GLOBAL_ADDRESS {
+            let cm = [CM_BAM, len as u8, (len >> 8) as u8, packets as u8, 0xFF, pgn[0], pgn[1], pgn[2]];
+            self.send_pgn(bus, TP_PRIORITY, PGN_TP_CM, sa, da, &cm)?;
+            for seq in 1..packets + 1 {
+                thread::sleep(self.bam_interval);
+                self.send_pgn(bus, TP_PRIORITY, PGN_TP_DT, sa, da, &packet(seq))?;
+            }
+            return Ok(());
+        }
+
+        let cm = [CM_RTS, len as u8, (len >> 8) as u8, packets as u8, 0xFF, pgn[0], pgn[1], pgn[2]];
+        self.send_pgn(bus, TP_PRIORITY, PGN_TP_CM, sa, da, &cm)?;
+        loop {
+            let data = self.recv_cm(bus, da, sa)?;
+            match data[0] {
+                CM_CTS => {
+                    let (count, next) = (data[1] as usize, data[2] as usize);
+                    if count > 0 && (next == 0 || next + count - 1 > packets) {
+                        return Err(format!("Invalid J1939 CTS: {} packets from {}", count, next));
+                    }
+                    for seq in next..next + count {
+                        self.send_pgn(bus, TP_PRIORITY, PGN_TP_DT, sa, da, &packet(seq))?;
+                    }
+                }
+                CM_END_OF_MSG_ACK => return Ok(()),
+                CM_ABORT => return Err(format!("J1939 transfer aborted by receiver, reason {}", data[1])),
+                cm => return Err(format!("Unexpected J1939 TP.CM: {}", cm)),
+            }
+        }
+    }
+
+    // 等待from发给to的TP.CM帧
+    fn recv_cm(&self, bus: &mut dyn Bus, from: u8, to: u8) -> Result<Vec<u8>, String> {
+        self.recv_tp(bus, PGN_TP_CM, from, to)
+    }
+
+    fn recv_tp(&self, bus: &mut dyn Bus, pgn: u32, from: u8, to: u8) -> Result<Vec<u8>, String> {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(format!("J1939 transport timeout, waiting for PGN {:04X}", pgn));
+            }
+            if let Some(frame) = bus.recv(deadline - now)? {
+                let (_, frame_pgn, sa, da) = parse_j1939_id(frame.id);
+                if frame.extended && frame_pgn == pgn && sa == from && da == to && frame.data.len() == 8 {
+                    return Ok(frame.data);
+                }
+            }
+        }
+    }
+
+    /// 声明地址：广播Address Claimed，等待claim_timeout
+    /// 如果有NAME优先级更高的节点争用同一地址，可任意选择地址的节点改用下一个地址，否则声明失败
+    pub fn claim_address(&mut self, bus: &mut dyn Bus) -> Result<u8, String> {
+        let mut address = self.preferred_address;
+        'claim: loop {
+            self.address = address;
+            self.send_address_claimed(bus)?;
+            let deadline = Instant::now() + self.claim_timeout;
+            loop {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Ok(address);
+                }
+                let frame = match bus.recv(deadline - now)? {
+                    Some(frame) => frame,
+                    None => continue,
+                };
+                if !self.handle_network_management(bus, &frame)? {
+                    continue;
+                }
+                if self.address != address {
+                    // 地址被优先级更高的节点夺走
+                    if self.arbitrary_address_capable() && address < 253 {
+                        address += 1;
+                        continue 'claim;
+                    }
+                    self.address = NULL_ADDRESS;
+                    self.send_address_claimed(bus)?; // cannot claim
+                    return Err(format!("Cannot claim J1939 address {}", address));
+                }
+            }
+        }
+    }
+
+    fn send_address_claimed(&self, bus: &mut dyn Bus) -> Result<(), String> {
+        let name = self.name.to_le_bytes();
+        self.send_pgn(bus, 6, PGN_ADDRESS_CLAIMED, self.address, GLOBAL_ADDRESS, &name)
+    }
+
+    // 处理地址声明相关的帧，返回是否为此类帧
+    // 别的节点声明了我们的地址时：对方NAME优先级低，重新声明以捍卫地址；否则放弃地址（设为NULL_ADDRESS）
+    fn handle_network_management(&mut self, bus: &mut dyn Bus, frame: &Frame) -> Result<bool, String> {
+        if !frame.extended {
+            return Ok(false);
+        }
+        let (_, pgn, sa, da) = parse_j1939_id(frame.id);
+        match pgn {
+            PGN_REQUEST if frame.data.len() >= 3 => {
+                let requested = frame.data[0] as u32 | (frame.data[1] as u32) << 8 | (frame.data[2] as u32) << 16;
+                if requested == PGN_ADDRESS_CLAIMED && (da == GLOBAL_ADDRESS || da == self.address) {
+                    self.send_address_claimed(bus)?;
+                }
+                Ok(requested == PGN_ADDRESS_CLAIMED)
+            }
+            PGN_ADDRESS_CLAIMED if frame.data.len() == 8 => {
+                if sa == self.address {
+                    let mut name = [0u8; 8];
+                    name.copy_from_slice(&frame.data);
+                    if self.name < u64::from_le_bytes(name) {
+                        self.send_address_claimed(bus)?;
+                    } else {
+                        self.address = NULL_ADDRESS;
+                    }
+                }
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// 接收发给本节点（或广播）的下一条报文，自动处理地址声明和传输协议
+    /// timeout内没有收到报文时返回Ok(None)
+    pub fn recv(&mut self, bus: &mut dyn Bus, timeout: Duration) -> Result<Option<Message>, String> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            let frame = match bus.recv(deadline - now)? {
+                Some(frame) => frame,
+                None => continue,
+            };
+            if !frame.extended || self.handle_network_management(bus, &frame)? {
+                continue;
+            }
+            let (priority, pgn, sa, da) = parse_j1939_id(frame.id);
+            if da != GLOBAL_ADDRESS && da != self.address {
+                continue;
+            }
+            match pgn {
+                PGN_TP_CM if frame.data.len() == 8 && (frame.data[0] == CM_BAM || frame.data[0] == CM_RTS) => {
+                    return self.recv_transport(bus, &frame.data, sa, da).map(Some);
+                }
+                PGN_TP_CM | PGN_TP_DT => continue, // 不属于任何会话
+                _ => return Ok(Some(Message { priority, pgn, sa, da, data: frame.data })),
+            }
+        }
+    }
+
+    // 接收传输协议的数据帧（BAM或RTS/CTS），cm为收到的BAM/RTS
+    fn recv_transport(&self, bus: &mut dyn Bus, cm: &[u8], sa: u8, da: u8) -> Result<Message, String> {
+        let len = cm[1] as usize | (cm[2] as usize) << 8;
+        let packets = cm[3] as usize;
+        let pgn = cm[5] as u32 | (cm[6] as u32) << 8 | (cm[7] as u32) << 16;
+        if packets != len.div_ceil(7) || len > MAX_LEN {
+            return Err(format!("Invalid J1939 TP.CM: {} bytes in {} packets", len, packets));
+        }
+        let rts = cm[0] == CM_RTS;
+        if rts {
+            let cts = [CM_CTS, packets as u8, 1, 0xFF, 0xFF, cm[5], cm[6], cm[7]];
+            self.send_pgn(bus, TP_PRIORITY, PGN_TP_CM, da, sa, &cts)?;
+        }
+        let mut data = Vec::with_capacity(packets * 7);
+        for seq in 1..packets + 1 {
+            let packet = self.recv_tp(bus, PGN_TP_DT, sa, da)?;
+            if packet[0] as usize != seq {
+                if rts {
+                    let abort = [CM_ABORT, 3, 0xFF, 0xFF, 0xFF, cm[5], cm[6], cm[7]];
+                    self.send_pgn(bus, TP_PRIORITY, PGN_TP_CM, da, sa, &abort)?;
+                }
+                return Err(format!("J1939 sequence error: expected {}, got {}", seq, packet[0]));
+            }
+            data.extend_from_slice(&packet[1..]);
+        }
+        data.truncate(len);
+        if rts {
+            let ack = [CM_END_OF_MSG_ACK, cm[1], cm[2], cm[3], 0xFF, cm[5], cm[6], cm[7]];
+            self.send_pgn(bus, TP_PRIORITY, PGN_TP_CM, da, sa, &ack)?;
+        }
+        Ok(Message { priority: TP_PRIORITY, pgn, sa, da, data })
+    }
+
+    /// 发送数据帧；扩展帧的源地址替换为本节点的地址（claim_address()声明的地址），超过8字节时按传输协议发送
+    /// 没有地址（NULL_ADDRESS，声明失败）时不能发送扩展帧
+    pub fn send_frame(&self, bus: &mut dyn Bus, frame: &Frame) -> Result<(), String> {
+        if !frame.extended {
+            return bus.send(frame);
+        }
+        if self.address == NULL_ADDRESS {
+            return Err("No J1939 address claimed".to_string());
+        }
+        let (priority, pgn, _, da) = parse_j1939_id(frame.id);
+        self.send(bus, &Message { priority, pgn, sa: self.address, da, data: frame.data.clone() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{J1939, Message, j1939_id, parse_j1939_id, GLOBAL_ADDRESS, NULL_ADDRESS, PGN_REQUEST};
+    use bus::{Bus, Frame, LoopbackBus};
+    use std::thread;
+    use std::time::Duration;
+
+    const ARBITRARY: u64 = 1 << 63;
+
+    fn node(name: u64, address: u8) -> J1939 {
+        let mut node = J1939::new(name, address);
+        node.claim_timeout = Duration::from_millis(50);
+        node.bam_interval = Duration::from_millis(1);
+        node
+    }
+
+    #[test]
+    fn test_id() {
+        // EEC1 (PGN 61444, PDU2) from engine (0x00), priority 3
+        assert_eq!(j1939_id(3, 61444, 0x00, GLOBAL_ADDRESS), 0x0CF00400);
+        assert_eq!(parse_j1939_id(0x0CF00400), (3, 61444, 0x00, GLOBAL_ADDRESS));
+        // TSC1 (PGN 0, PDU1) from 0x03 to 0x00
+        assert_eq!(j1939_id(3, 0, 0x03, 0x00), 0x0C000003);
+        assert_eq!(parse_j1939_id(0x0C000003), (3, 0, 0x03, 0x00));
+        // request (PGN 0xEA00) from 0xF9 to global
+        assert_eq!(j1939_id(6, PGN_REQUEST, 0xF9, GLOBAL_ADDRESS), 0x18EAFFF9);
+        assert_eq!(parse_j1939_id(0x18EAFFF9), (6, PGN_REQUEST, 0xF9, GLOBAL_ADDRESS));
+    }
+
+    fn message(len: usize, da: u8) -> Message {
+        Message { priority: 6, pgn: 0xFECA, sa: 0x10, da, data: (0..len).map(|i| i as u8).collect() }
+    }
+
+    #[test]
+    fn test_transport() {
+        for &(len, da) in &[(8, GLOBAL_ADDRESS), (9, GLOBAL_ADDRESS), (100, GLOBAL_ADDRESS), (9, 0x20), (1785, 0x20)] {
+            let (mut a, mut b) = LoopbackBus::pair();
+            let receiver = thread::spawn(move || node(2, 0x20).recv(&mut b, Duration::from_millis(2000)));
+            node(1, 0x10).send(&mut a, &message(len, da)).expect("ok");
+            let received = receiver.join().unwrap().expect("ok").expect("message");
+            assert_eq!(received.data, message(len, da).data);
+            assert_eq!((received.pgn, received.sa, received.da), (0xFECA, 0x10, da));
+        }
+    }
+
+    #[test]
+    fn test_transport_errors() {
+        let (mut a, mut b) = LoopbackBus::pair();
+        let receiver = thread::spawn(move || {
+            let cm = b.recv(Duration::from_secs(5)).unwrap().unwrap(); // RTS
+            assert_eq!(cm.data[0], 16);
+            b.send(&Frame { id: j1939_id(7, 0xEC00, 0x20, 0x10), extended: true,
+                            data: vec![255, 1, 0xFF, 0xFF, 0xFF, 0xCA, 0xFE, 0x00] }).unwrap();
+        });
+        let result = node(1, 0x10).send(&mut a, &message(20, 0x20));
+        assert_eq!(result, Err("J1939 transfer aborted by receiver, reason 1".to_string()));
+        receiver.join().unwrap();
+        assert!(node(1, 0x10).send(&mut a, &message(1786, 0x20)).is_err());
+    }
+
+    #[test]
+    fn test_address_claim() {
+        // an arbitrary address capable node loses 0x80 to a node with lower NAME and moves to 0x81
+        let (mut a, mut b) = LoopbackBus::pair();
+        let mut owner = node(0x100, 0x80);
+        let defender = thread::spawn(move || {
+            owner.claim_address(&mut b).expect("ok");
+            while owner.recv(&mut b, Duration::from_millis(10)).is_ok() {}
+            owner.address
+        });
+        // wait for the owner's first claim; the newcomer waits long enough for the owner's defense
+        assert_eq!(a.recv(Duration::from_secs(5)).unwrap().map(|f| f.data[0]), Some(0x00));
+        let mut newcomer = node(ARBITRARY | 0x200, 0x80);
+        newcomer.claim_timeout = Duration::from_millis(500);
+        assert_eq!(newcomer.claim_address(&mut a), Ok(0x81));
+        assert_eq!(newcomer.address, 0x81);
+        drop(a);
+        assert_eq!(defender.join().unwrap(), 0x80);
+
+        // a node which is not arbitrary address capable cannot claim
+        let (mut a, mut b) = LoopbackBus::pair();
+        let defender = thread::spawn(move || {
+            let mut owner = node(0x100, 0x80);
+            owner.address = 0x80;
+            while owner.recv(&mut b, Duration::from_millis(10)).is_ok() {}
+        });
+        let mut newcomer = node(0x200, 0x80);
+        newcomer.claim_timeout = Duration::from_secs(5);
+        assert!(newcomer.claim_address(&mut a).is_err());
+        assert_eq!(newcomer.address, NULL_ADDRESS);
+        drop(a);
+        defender.join().unwrap();
+
+        // a node with higher priority NAME keeps its address, and answers requests for address claimed
+        let (mut a, mut b) = LoopbackBus::pair();
+        let mut owner = node(0x100, 0x80);
+        b.send(&Frame { id: j1939_id(6, 0xEE00, 0x80, GLOBAL_ADDRESS), extended: true,
+                        data: 0x200u64.to_le_bytes().to_vec() }).unwrap();
+        assert_eq!(owner.claim_address(&mut a), Ok(0x80));
+        assert_eq!(b.recv(Duration::from_secs(5)).unwrap().unwrap().data, 0x100u64.to_le_bytes().to_vec());
+        assert_eq!(b.recv(Duration::from_secs(5)).unwrap().unwrap().data, 0x100u64.to_le_bytes().to_vec());
+        b.send(&Frame { id: j1939_id(6, PGN_REQUEST, 0xF9, GLOBAL_ADDRESS), extended: true,
+                        data: vec![0x00, 0xEE, 0x00] }).unwrap();
+        assert_eq!(owner.recv(&mut a, Duration::from_millis(10)), Ok(None));
+        let claim = b.recv(Duration::from_secs(5)).unwrap().unwrap();
+        assert_eq!(parse_j1939_id(claim.id), (6, 0xEE00, 0x80, GLOBAL_ADDRESS));
+    }
+
+    #[test]
+    fn test_send_frame_address() {
+        // 扩展帧使用节点声明的地址，标准帧不变
+        let (mut a, mut b) = LoopbackBus::pair();
+        let mut owner = node(0x100, 0x81);
+        owner.send_frame(&mut a, &Frame { id: j1939_id(6, 0xFECA, 0x10, GLOBAL_ADDRESS), extended: true, data: vec![1] }).expect("ok");
+        assert_eq!(parse_j1939_id(b.recv(Duration::from_secs(5)).unwrap().unwrap().id), (6, 0xFECA, 0x81, GLOBAL_ADDRESS));
+        owner.send_frame(&mut a, &Frame::new(0x310, &[1])).expect("ok");
+        assert_eq!(b.recv(Duration::from_secs(5)).unwrap().unwrap().id, 0x310);
+        owner.address = NULL_ADDRESS;
+        let frame = Frame { id: j1939_id(6, 0xFECA, 0x10, GLOBAL_ADDRESS), extended: true, data: vec![1] };
+        assert_eq!(owner.send_frame(&mut a, &frame), Err("No J1939 address claimed".to_string()));
+    }
+}
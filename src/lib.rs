@@ -1,9 +1,67 @@
 #![allow(dead_code)]
 #![allow(unused_variables)]
 
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde_derive;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde_json;
+#[cfg(feature = "sqlite")]
+extern crate rusqlite;
+#[cfg(target_os = "linux")]
+extern crate libc;
+#[cfg(feature = "repl")]
+extern crate rustyline;
+
 mod engine;
 mod variable;
 mod instruction;
 mod function;
 mod statement;
 mod utils;
+mod signal;
+mod dbc;
+mod computed;
+mod bus;
+mod isotp;
+mod uds;
+mod j1939;
+mod canopen;
+mod script;
+mod pretty;
+mod validate;
+mod compile;
+mod debugger;
+mod trace;
+mod coverage;
+mod profile;
+mod cancel;
+#[cfg(feature = "sqlite")]
+mod storage;
+#[cfg(target_os = "linux")]
+mod socketcan;
+mod cli;
+mod repl;
+#[cfg(any(feature = "dap", feature = "lsp"))]
+mod message;
+#[cfg(feature = "dap")]
+mod dap;
+#[cfg(feature = "lsp")]
+mod lsp;
+
+pub use engine::{Engine, Context, Call, DEFAULT_MAX_DEPTH, FUEL_EXHAUSTED, DEADLINE_EXCEEDED};
+pub use function::FnDef;
+pub use instruction::InsDef;
+pub use statement::{Stmt, StmtKind};
+pub use variable::{VarDef, VarDefList, VarBindingList};
+pub use compile::Program;
+pub use debugger::{Debugger, Breakpoint, Stop, FrameInfo};
+pub use trace::{Trace, TraceEvent, record, replay};
+pub use coverage::{Coverage, FnCoverage};
+pub use profile::{Profiler, Profile, FnProfile, InsProfile, Clock};
+pub use cancel::{CancelToken, CANCELLED};
+pub use cli::run_cli;
+pub use repl::{Repl, run_repl};
@@ -0,0 +1,810 @@
+// Language Server Protocol服务器：logic lsp，通过标准输入输出与编辑器通信，为脚本（*.logic，语法见script.rs）提供：
+//   诊断       语法错误，以及validate_fn()的检查结果（未定义的指令/函数、参数名错误、循环不配对等）
+//   补全       send/call之后补全指令/函数名称，括号中补全参数名称，语句开头补全关键字
+//   悬停       指令的声明、注释、CAN ID和参数类型；函数的声明和注释
+//   跳转定义   跨文件跳转到指令/函数的定义
+//
+// 启动时读取工作区（rootUri）中的所有脚本；已打开的文档以编辑器中的内容为准（全量同步）。
+// 定义的索引在文档变化（打开、修改、保存、关闭）后重建。
+// 同名定义以URI排序靠后的文件为准。
+
+use engine::{Engine, Context};
+use instruction::InsDef;
+use function::FnDef;
+use variable::{VarDef, VarDefList};
+use statement::SourcePos;
+use script::parse_script;
+use validate::{validate_fn, Severity};
+use pretty::{fn_header, ins_header, format_name, format_value};
+use message::{read_message, write_message};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::fs;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+const SCRIPT_EXTENSION: &str = "logic";
+// 扫描工作区的最大目录深度
+const MAX_DEPTH: usize = 8;
+
+// JSON-RPC错误码
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+// CompletionItemKind
+const KIND_FUNCTION: u64 = 3;
+const KIND_FIELD: u64 = 5;
+const KIND_KEYWORD: u64 = 14;
+const KIND_EVENT: u64 = 23;
+
+struct Document {
+    text: String,
+    // 是否在编辑器中打开；未打开的文档不发布诊断
+    open: bool,
+}
+
+// 所有文档中的定义
+struct Index {
+    engine: Engine,
+    // 名称 -> 定义所在的文档
+    ins_uris: HashMap<String, String>,
+    fn_uris: HashMap<String, String>,
+}
+
+impl Index {
+    fn find_ins(&self, name: &str) -> Option<(&InsDef, &str)> {
+        Some((self.engine.find_ins(name)?, self.ins_uris.get(name)?))
+    }
+
+    fn find_fn(&self, name: &str) -> Option<(&FnDef, &str)> {
+        Some((self.engine.fns.get(name)?, self.fn_uris.get(name)?))
+    }
+}
+
+// 光标前的文本所在的上下文
+#[derive(Debug, PartialEq)]
+enum Scope {
+    // 语句开头
+    Stmt,
+    // send/call之后，true为send
+    Callee(bool),
+    // send/call的括号中；named为光标之前已绑定的参数，value为是否在参数值的位置
+    Args { send: bool, name: String, named: Vec<String>, value: bool },
+    Other,
+}
+
+struct Server {
+    // URI -> 文档，按URI排序
+    documents: BTreeMap<String, Document>,
+    // 所有文档中的定义；文档变化时清除，在下次使用时重建
+    index: RefCell<Option<Rc<Index>>>,
+    shutdown: bool,
+}
+
+/// 处理来自编辑器的消息，直到收到exit通知或输入结束
+pub fn serve(input: &mut dyn BufRead, output: &mut dyn Write) -> Result<(), String> {
+    let mut server = Server { documents: BTreeMap::new(), index: RefCell::new(None), shutdown: false };
+    while let Some(message) = read_message(input)? {
+        let method = message["method"].as_str().unwrap_or("").to_string();
+        if method == "exit" {
+            break;
+        }
+        let id = match message.get("id") {
+            Some(id) => id.clone(),
+            None => {
+                server.notify(&method, &message["params"], output)?;
+                continue;
+            }
+        };
+        let response = match server.request(&method, &message["params"]) {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err((code, error)) => json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": error } }),
+        };
+        write_message(output, &response)?;
+    }
+    Ok(())
+}
+
+impl Server {
+    fn request(&mut self, method: &str, params: &Value) -> Result<Value, (i64, String)> {
+        if self.shutdown {
+            return Err((INVALID_REQUEST, "Server is shut down".to_string()));
+        }
+        match method {
+            "initialize" => Ok(self.initialize(params)),
+            "shutdown" => {
+                self.shutdown = true;
+                Ok(Value::Null)
+            }
+            "textDocument/completion" => self.at(params).map(|(line, index)| self.completion(&line, index)),
+            "textDocument/hover" => self.at(params).map(|(line, index)| self.hover(&line, index)),
+            "textDocument/definition" => self.at(params).map(|(line, index)| self.definition(&line, index)),
+            _ => Err((METHOD_NOT_FOUND, format!("Unsupported method: {}", method))),
+        }
+    }
+
+    fn notify(&mut self, method: &str, params: &Value, output: &mut dyn Write) -> Result<(), String> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("").to_string();
+        match method {
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or("").to_string();
+                self.documents.insert(uri, Document { text, open: true });
+            }
+            "textDocument/didChange" => {
+                // 全量同步：最后一次修改即为完整内容
+                let changes = params["contentChanges"].as_array().map(|changes| changes.as_slice()).unwrap_or(&[]);
+                if let Some(text) = changes.last().and_then(|change| change["text"].as_str()) {
+                    self.documents.insert(uri, Document { text: text.to_string(), open: true });
+                }
+            }
+            "textDocument/didSave" => {
+                // 保存时可能带有完整内容(includeText)
+                if let Some(text) = params["text"].as_str() {
+                    self.documents.insert(uri, Document { text: text.to_string(), open: true });
+                }
+            }
+            "textDocument/didClose" => {
+                // 关闭后以磁盘上的内容为准
+                match uri_to_path(&uri).and_then(|path| fs::read_to_string(path).ok()) {
+                    Some(text) => self.documents.insert(uri.clone(), Document { text, open: false }),
+                    None => self.documents.remove(&uri),
+                };
+                publish(output, &uri, Vec::new())?;
+            }
+            _ => return Ok(()),
+        }
+        self.index.borrow_mut().take();
+        self.publish_all(output)
+    }
+
+    fn initialize(&mut self, params: &Value) -> Value {
+        let mut roots: Vec<&str> = params["workspaceFolders"].as_array()
+            .map(|folders| folders.iter().filter_map(|folder| folder["uri"].as_str()).collect())
+            .unwrap_or_default();
+        if roots.is_empty() {
+            roots.extend(params["rootUri"].as_str());
+        }
+        let mut paths = Vec::new();
+        for root in roots {
+            if let Some(root) = uri_to_path(root) {
+                scan(&root, MAX_DEPTH, &mut paths);
+            }
+        }
+        for path in paths {
+            if let Ok(text) = fs::read_to_string(&path) {
+                self.documents.insert(path_to_uri(&path), Document { text, open: false });
+            }
+        }
+        self.index.borrow_mut().take();
+        json!({
+            "capabilities": {
+                "textDocumentSync": 1,
+                "completionProvider": { "triggerCharacters": ["(", ",", " "] },
+                "hoverProvider": true,
+                "definitionProvider": true,
+            },
+            "serverInfo": { "name": "logic", "version": env!("CARGO_PKG_VERSION") },
+        })
+    }
+
+    fn index(&self) -> Rc<Index> {
+        if let Some(ref index) = *self.index.borrow() {
+            return index.clone();
+        }
+        let mut index = Index { engine: Engine::new(), ins_uris: HashMap::new(), fn_uris: HashMap::new() };
+        for (uri, document) in &self.documents {
+            if let Ok(script) = parse_script(&document.text) {
+                for insdef in script.inss {
+                    index.ins_uris.insert(insdef.name.clone(), uri.clone());
+                    index.engine.add_ins(insdef);
+                }
+                for fndef in script.fns {
+                    index.fn_uris.insert(fndef.name.clone(), uri.clone());
+                    index.engine.add_fn(fndef);
+                }
+            }
+        }
+        let index = Rc::new(index);
+        *self.index.borrow_mut() = Some(index.clone());
+        index
+    }
+
+    // 重新检查所有打开的文档（定义可能在其他文档中）
+    fn publish_all(&self, output: &mut dyn Write) -> Result<(), String> {
+        let index = self.index();
+        for (uri, document) in &self.documents {
+            if document.open {
+                publish(output, uri, diagnostics(&document.text, &index.engine))?;
+            }
+        }
+        Ok(())
+    }
+
+    // 请求中的位置：(光标所在的行, 光标在行中的字符索引)
+    fn at(&self, params: &Value) -> Result<(String, usize), (i64, String)> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+        let document = self.documents.get(uri).ok_or_else(|| (INVALID_PARAMS, format!("Unknown document: {}", uri)))?;
+        let line = params["position"]["line"].as_u64().unwrap_or(0) as usize;
+        let line = document.text.lines().nth(line).unwrap_or("").to_string();
+        let index = char_index(&line, params["position"]["character"].as_u64().unwrap_or(0) as usize);
+        Ok((line, index))
+    }
+
+    fn completion(&self, line: &str, index: usize) -> Value {
+        let chars: Vec<char> = line.chars().collect();
+        let defs = self.index();
+        let mut items = Vec::new();
+        match scope(&chars[..index.min(chars.len())]) {
+            Scope::Stmt => {
+                for keyword in &["loop", "return", "send", "call", "diag", "canopen", "local", "global"] {
+                    items.push(json!({ "label": keyword, "kind": KIND_KEYWORD }));
+                }
+            }
+            Scope::Callee(true) => {
+                for insdef in defs.engine.inss.values() {
+                    items.push(item(&insdef.name, KIND_EVENT, ins_header(insdef), &insdef.note));
+                }
+            }
+            Scope::Callee(false) => {
+                for fndef in defs.engine.fns.values() {
+                    items.push(item(&fndef.name, KIND_FUNCTION, fn_header(fndef), &fndef.note));
+                }
+            }
+            Scope::Args { send, name, named, value: false } => {
+                let args = if send {
+                    defs.engine.find_ins(&name).map(|insdef| &insdef.args)
+                } else {
+                    defs.engine.fns.get(&name).map(|fndef| &fndef.args)
+                };
+                for vardef in args.map(|args| args.defs.as_slice()).unwrap_or(&[]) {
+                    if !named.contains(&vardef.name) && vardef.computed.is_none() {
+                        let mut item = item(&vardef.name, KIND_FIELD, param(vardef), &vardef.note);
+                        item["insertText"] = json!(format!("{}: ", format_name(&vardef.name)));
+                        items.push(item);
+                    }
+                }
+            }
+            _ => {}
+        }
+        items.sort_by(|a, b| a["label"].as_str().cmp(&b["label"].as_str()));
+        json!({ "isIncomplete": false, "items": items })
+    }
+
+    fn hover(&self, line: &str, index: usize) -> Value {
+        let chars: Vec<char> = line.chars().collect();
+        let (word, start) = match word_at(&chars, index) {
+            Some(word) => word,
+            None => return Value::Null,
+        };
+        let defs = self.index();
+        let mut text = String::new();
+        if let Scope::Args { send, name, .. } = scope(&chars[..start]) {
+            // 参数名称
+            let args = if send {
+                defs.engine.find_ins(&name).map(|insdef| &insdef.args)
+            } else {
+                defs.engine.fns.get(&name).map(|fndef| &fndef.args)
+            };
+            if let Some(vardef) = args.and_then(|args| find_arg(args, &word)) {
+                text = code(&param(vardef));
+                push_note(&mut text, &vardef.note);
+            }
+        } else {
+            match lookup(&defs, &chars[..start], &word) {
+                Some(Def::Ins(insdef, _)) => {
+                    text = code(&ins_header(insdef));
+                    push_note(&mut text, &insdef.note);
+                    let frame = if insdef.extended { "extended" } else { "standard" };
+                    text.push_str(&format!("\n\nCAN ID: `{:#X}` ({}), DLC: {}", insdef.canid, frame, insdef.dlc));
+                    if !insdef.args.defs.is_empty() {
+                        text.push_str("\n\nArgs:\n");
+                        for vardef in &insdef.args.defs {
+                            text.push_str(&format!("- `{}`", param(vardef)));
+                            if let Some(note) = vardef.note.as_ref().and_then(|note| note.lines().next()) {
+                                text.push_str(&format!(" {}", note));
+                            }
+                            text.push('\n');
+                        }
+                    }
+                }
+                Some(Def::Fn(fndef, _)) => {
+                    text = code(&fn_header(fndef));
+                    push_note(&mut text, &fndef.note);
+                }
+                None => {}
+            }
+        }
+        if text.is_empty() {
+            return Value::Null;
+        }
+        json!({ "contents": { "kind": "markdown", "value": text.trim_end() } })
+    }
+
+    fn definition(&self, line: &str, index: usize) -> Value {
+        let chars: Vec<char> = line.chars().collect();
+        let (word, start) = match word_at(&chars, index) {
+            Some(word) => word,
+            None => return Value::Null,
+        };
+        let defs = self.index();
+        let (uri, pos) = match lookup(&defs, &chars[..start], &word) {
+            Some(Def::Ins(insdef, uri)) => (uri, insdef.pos),
+            Some(Def::Fn(fndef, uri)) => (uri, fndef.pos),
+            None => return Value::Null,
+        };
+        let (pos, text) = match (pos, self.documents.get(uri)) {
+            (Some(pos), Some(document)) => (pos, &document.text),
+            _ => return Value::Null,
+        };
+        let start = position(text, pos);
+        json!({ "uri": uri, "range": { "start": start, "end": start } })
+    }
+}
+
+enum Def<'a> {
+    Ins(&'a InsDef, &'a str),
+    Fn(&'a FnDef, &'a str),
+}
+
+// 根据名称之前的关键字查找定义；没有关键字时先找指令再找函数
+fn lookup<'a>(index: &'a Index, before: &[char], name: &str) -> Option<Def<'a>> {
+    let ins = || index.find_ins(name).map(|(insdef, uri)| Def::Ins(insdef, uri));
+    let fun = || index.find_fn(name).map(|(fndef, uri)| Def::Fn(fndef, uri));
+    match last_word(before).as_str() {
+        "send" | "ins" => ins(),
+        "call" | "fn" => fun(),
+        _ => ins().or_else(fun),
+    }
+}
+
+fn find_arg<'a>(args: &'a VarDefList, name: &str) -> Option<&'a VarDef> {
+    args.defs.iter().find(|vardef| vardef.name == name)
+}
+
+fn item(name: &str, kind: u64, detail: String, note: &Option<String>) -> Value {
+    let mut item = json!({ "label": name, "kind": kind, "detail": detail, "insertText": format_name(name) });
+    if let Some(note) = note {
+        item["documentation"] = json!(note);
+    }
+    item
+}
+
+// 参数的声明，如"a: u8 = 1 in 0..10"
+fn param(vardef: &VarDef) -> String {
+    let mut text = format!("{}: {}", format_name(&vardef.name), vardef.typ);
+    if !vardef.default.is_empty() {
+        text.push_str(&format!(" = {}", format_value(&vardef.default)));
+    }
+    if !vardef.range.is_empty() {
+        text.push_str(&format!(" in {}", vardef.range));
+    }
+    text
+}
+
+fn code(text: &str) -> String {
+    format!("```logic\n{}\n```", text)
+}
+
+fn push_note(text: &mut String, note: &Option<String>) {
+    if let Some(note) = note {
+        text.push_str("\n\n");
+        text.push_str(note);
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// 光标处的名称和起始索引；名称可以带引号（光标在引号中）
+fn word_at(chars: &[char], index: usize) -> Option<(String, usize)> {
+    let index = index.min(chars.len());
+    let quotes: Vec<usize> = (0..chars.len()).filter(|&i| chars[i] == '"').collect();
+    let before = quotes.iter().filter(|&&i| i < index).count();
+    if before % 2 == 1 {
+        let start = quotes[before - 1];
+        let end = quotes.get(before).cloned().unwrap_or(chars.len());
+        return Some((chars[start + 1..end].iter().collect(), start));
+    }
+    let mut start = index;
+    while start > 0 && is_name_char(chars[start - 1]) {
+        start -= 1;
+    }
+    let mut end = index;
+    while end < chars.len() && is_name_char(chars[end]) {
+        end += 1;
+    }
+    if start == end {
+        return None;
+    }
+    Some((chars[start..end].iter().collect(), start))
+}
+
+// 文本末尾的名称（不含引号）
+fn last_word(chars: &[char]) -> String {
+    let mut end = chars.len();
+    while end > 0 && chars[end - 1].is_whitespace() {
+        end -= 1;
+    }
+    let mut start = end;
+    while start > 0 && is_name_char(chars[start - 1]) {
+        start -= 1;
+    }
+    chars[start..end].iter().collect()
+}
+
+// 文本末尾的被调用的名称（标识符或带引号的名称）和它之前的文本
+fn callee(chars: &[char]) -> Option<(String, &[char])> {
+    let mut end = chars.len();
+    while end > 0 && chars[end - 1].is_whitespace() {
+        end -= 1;
+    }
+    if end > 0 && chars[end - 1] == '"' {
+        let start = chars[..end - 1].iter().rposition(|&c| c == '"')?;
+        return Some((chars[start + 1..end - 1].iter().collect(), &chars[..start]));
+    }
+    let mut start = end;
+    while start > 0 && is_name_char(chars[start - 1]) {
+        start -= 1;
+    }
+    if start == end {
+        return None;
+    }
+    Some((chars[start..end].iter().collect(), &chars[..start]))
+}
+
+// 判断光标之前的文本所在的上下文
+fn scope(chars: &[char]) -> Scope {
+    // 不在引号中的、未配对的'('
+    let mut quoted = false;
+    let mut paren = None;
+    for (i, &c) in chars.iter().enumerate() {
+        match c {
+            '"' => quoted = !quoted,
+            '(' if !quoted => paren = Some(i),
+            ')' if !quoted => paren = None,
+            _ => {}
+        }
+    }
+    if quoted {
+        return Scope::Other;
+    }
+    if let Some(paren) = paren {
+        let (name, before) = match callee(&chars[..paren]) {
+            Some(callee) => callee,
+            None => return Scope::Other,
+        };
+        let send = match last_word(before).as_str() {
+            "send" => true,
+            "call" => false,
+            _ => return Scope::Other,
+        };
+        let inside: String = chars[paren + 1..].iter().collect();
+        let parts: Vec<&str> = inside.split(',').collect();
+        let named = parts.iter()
+            .filter(|part| part.contains(':'))
+            .map(|part| part.split(':').next().unwrap_or("").trim().trim_matches('"').to_string())
+            .collect();
+        let value = parts.last().is_some_and(|part| part.contains(':'));
+        return Scope::Args { send, name, named, value };
+    }
+    // 去掉正在输入的名称
+    let mut end = chars.len();
+    while end > 0 && is_name_char(chars[end - 1]) {
+        end -= 1;
+    }
+    let before = &chars[..end];
+    if end > 0 && before[end - 1].is_whitespace() {
+        match last_word(before).as_str() {
+            "send" => return Scope::Callee(true),
+            "call" => return Scope::Callee(false),
+            _ => {}
+        }
+    }
+    let text: String = before.iter().collect();
+    let text = text.trim();
+    if text.is_empty() || text.ends_with('{') || text.ends_with('}') {
+        Scope::Stmt
+    } else {
+        Scope::Other
+    }
+}
+
+// 文档的诊断：语法错误，或函数的检查结果
+fn diagnostics(text: &str, engine: &Engine) -> Vec<Value> {
+    let script = match parse_script(text) {
+        Ok(script) => script,
+        Err(err) => return vec![diagnostic(text, err.pos, Severity::Error, &err.message)],
+    };
+    // 编辑器中没有设置传输方式，指令数据帧的长度按经典CAN检查
+    let context = Context::new();
+    let mut diagnostics = Vec::new();
+    for fndef in &script.fns {
+        for found in validate_fn(fndef, engine, &context) {
+            if let Some(pos) = found.pos.or(fndef.pos) {
+                diagnostics.push(diagnostic(text, pos, found.severity, &found.message));
+            }
+        }
+    }
+    diagnostics
+}
+
+// 诊断的范围从出错的位置到行尾
+fn diagnostic(text: &str, pos: SourcePos, severity: Severity, message: &str) -> Value {
+    let line = text.lines().nth(pos.line.saturating_sub(1) as usize).unwrap_or("");
+    let end = line.trim_end().encode_utf16().count();
+    let start = position(text, pos);
+    let severity = match severity {
+        Severity::Error => 1,
+        Severity::Warning => 2,
+    };
+    json!({
+        "range": { "start": start, "end": { "line": start["line"], "character": end.max(start["character"].as_u64().unwrap_or(0) as usize) } },
+        "severity": severity,
+        "source": "logic",
+        "message": message,
+    })
+}
+
+fn publish(output: &mut dyn Write, uri: &str, diagnostics: Vec<Value>) -> Result<(), String> {
+    write_message(output, &json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": { "uri": uri, "diagnostics": diagnostics },
+    }))
+}
+
+// SourcePos（从1开始，列按字符计）转换为LSP的位置（从0开始，列按UTF-16计）
+fn position(text: &str, pos: SourcePos) -> Value {
+    let line = pos.line.saturating_sub(1);
+    let text = text.lines().nth(line as usize).unwrap_or("");
+    let character: usize = text.chars().take(pos.col.saturating_sub(1) as usize).map(char::len_utf16).sum();
+    json!({ "line": line, "character": character })
+}
+
+// LSP的列（UTF-16）转换为字符索引
+fn char_index(line: &str, character: usize) -> usize {
+    let mut units = 0;
+    for (index, c) in line.chars().enumerate() {
+        if units >= character {
+            return index;
+        }
+        units += c.len_utf16();
+    }
+    line.chars().count()
+}
+
+// 递归查找目录中的脚本，跳过隐藏目录和target
+fn scan(dir: &Path, depth: usize, paths: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if path.is_dir() {
+            if depth > 0 && !name.starts_with('.') && name != "target" {
+                scan(&path, depth - 1, paths);
+            }
+        } else if path.extension().is_some_and(|ext| ext == SCRIPT_EXTENSION) {
+            paths.push(path);
+        }
+    }
+}
+
+fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let path = uri.strip_prefix("file://")?;
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let Some(byte) = std::str::from_utf8(&bytes[i + 1..i + 3]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8(decoded).ok().map(PathBuf::from)
+}
+
+fn path_to_uri(path: &Path) -> String {
+    let mut uri = String::from("file://");
+    for byte in path.to_string_lossy().bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => uri.push(byte as char),
+            _ => uri.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    uri
+}
+
+#[cfg(test)]
+mod tests {
+    use lsp::{serve, scope, path_to_uri, uri_to_path, Scope, Server};
+    use message::read_message;
+    use serde_json::Value;
+    use std::collections::BTreeMap;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::env;
+    use std::fs;
+    use std::io::Cursor;
+    use std::path::Path;
+
+    const DEFS: &str = "// 车灯\nins lamp(a: u32, b: u32 = 1) = 0x18FF0001 extended\n\nfn blink(n: i32) {\n    send lamp(a: n)\n}\n";
+
+    fn run(messages: &[Value]) -> Vec<Value> {
+        let mut input = Vec::new();
+        for message in messages {
+            let body = message.to_string();
+            input.extend(format!("Content-Length: {}\r\n\r\n{}", body.len(), body).into_bytes());
+        }
+        let mut output = Vec::new();
+        serve(&mut Cursor::new(input), &mut output).expect("serve");
+        let mut output = Cursor::new(output);
+        let mut messages = Vec::new();
+        while let Some(message) = read_message(&mut output).expect("message") {
+            messages.push(message);
+        }
+        messages
+    }
+
+    fn request(id: u64, method: &str, uri: &str, line: u64, character: u64) -> Value {
+        json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": {
+            "textDocument": { "uri": uri }, "position": { "line": line, "character": character },
+        } })
+    }
+
+    fn result(messages: &[Value], id: u64) -> &Value {
+        &messages.iter().find(|message| message["id"] == json!(id)).expect("response")["result"]
+    }
+
+    fn labels(result: &Value) -> Vec<&str> {
+        result["items"].as_array().expect("items").iter().map(|item| item["label"].as_str().expect("label")).collect()
+    }
+
+    #[test]
+    fn test_session() {
+        let dir = env::temp_dir().join(format!("logic-lsp-{}", std::process::id()));
+        fs::create_dir_all(dir.join("lib")).expect("ok");
+        fs::write(dir.join("lib").join("defs.logic"), DEFS).expect("ok");
+        let defs = path_to_uri(&dir.join("lib").join("defs.logic"));
+        let main = path_to_uri(&dir.join("main.logic"));
+        let text = "fn main() {\n    send lamp(a: 1, c: 2)\n    call blink()\n    send lamp(\n    call missing()\n}\n";
+        let fixed = "fn main() {\n    send lamp(a: 1)\n    call blink(n: 2)\n    loop 2 {\n        \n    }\n}\n";
+        let messages = run(&[
+            json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": { "rootUri": path_to_uri(&dir) } }),
+            json!({ "jsonrpc": "2.0", "method": "initialized", "params": {} }),
+            json!({ "jsonrpc": "2.0", "method": "textDocument/didOpen", "params": {
+                "textDocument": { "uri": main, "languageId": "logic", "version": 1, "text": text },
+            } }),
+            request(2, "textDocument/completion", &main, 3, 14),
+            request(3, "textDocument/hover", &main, 1, 10),
+            request(4, "textDocument/definition", &main, 2, 11),
+            json!({ "jsonrpc": "2.0", "method": "textDocument/didChange", "params": {
+                "textDocument": { "uri": main, "version": 2 }, "contentChanges": [{ "text": fixed }],
+            } }),
+            request(5, "textDocument/completion", &main, 2, 9),
+            request(6, "textDocument/completion", &main, 4, 8),
+            request(7, "textDocument/hover", &main, 1, 15),
+            request(8, "textDocument/unknown", &main, 0, 0),
+            json!({ "jsonrpc": "2.0", "id": 9, "method": "shutdown" }),
+            json!({ "jsonrpc": "2.0", "method": "exit" }),
+            json!({ "jsonrpc": "2.0", "id": 10, "method": "shutdown" }),
+        ]);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(result(&messages, 1)["capabilities"]["hoverProvider"], true);
+
+        // 第一次诊断：未知参数、缺少参数、括号不完整
+        let published: Vec<&Value> = messages.iter().filter(|message| message["method"] == "textDocument/publishDiagnostics").collect();
+        assert_eq!(published.len(), 2);
+        assert_eq!(published[0]["params"]["uri"], json!(main));
+        let diagnostics = published[0]["params"]["diagnostics"].as_array().expect("diagnostics");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0]["range"]["start"], json!({ "line": 4, "character": 4 }));
+
+        // 参数名称补全
+        assert_eq!(labels(result(&messages, 2)), vec!["a", "b"]);
+        assert_eq!(result(&messages, 2)["items"][0]["insertText"], "a: ");
+        assert_eq!(result(&messages, 2)["items"][0]["detail"], "a: u32");
+
+        let hover = result(&messages, 3)["contents"]["value"].as_str().expect("hover");
+        assert!(hover.starts_with("```logic\nins lamp(a: u32, b: u32 = 1) = 0x18FF0001 extended\n```\n\n车灯"));
+        assert!(hover.contains("CAN ID: `0x18FF0001` (extended), DLC: 8"));
+        assert!(hover.contains("- `b: u32 = 1`"));
+
+        assert_eq!(result(&messages, 4), &json!({ "uri": defs, "range": {
+            "start": { "line": 3, "character": 0 }, "end": { "line": 3, "character": 0 },
+        } }));
+
+        // 修改后没有错误
+        assert_eq!(published[1]["params"]["diagnostics"], json!([]));
+        assert_eq!(labels(result(&messages, 5)), vec!["blink", "main"]);
+        assert_eq!(labels(result(&messages, 6)), vec!["call", "canopen", "diag", "global", "local", "loop", "return", "send"]);
+        assert_eq!(result(&messages, 7)["contents"]["value"], "```logic\na: u32\n```");
+
+        let error = &messages.iter().find(|message| message["id"] == json!(8)).expect("response")["error"];
+        assert_eq!(error["code"], -32601);
+        assert_eq!(result(&messages, 9), &Value::Null);
+        // exit之后不再处理
+        assert!(messages.iter().all(|message| message["id"] != json!(10)));
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let uri = "file:///tmp/logic-lsp-diagnostics.logic";
+        let text = "ins lamp(a: u32, b: u32 = 1) = 0x321\n\nfn main() {\n    send lamp(a: 1, \"é x\": 2)\n    send none()\n    call missing()\n}\n\nfn bad( {\n";
+        let open = |text: &str| json!({ "jsonrpc": "2.0", "method": "textDocument/didOpen", "params": {
+            "textDocument": { "uri": uri, "languageId": "logic", "version": 1, "text": text },
+        } });
+        let messages = run(&[open(text)]);
+        let diagnostics = &messages[0]["params"]["diagnostics"];
+        assert_eq!(diagnostics.as_array().expect("diagnostics").len(), 1);
+        assert_eq!(diagnostics[0]["range"]["start"]["line"], 8);
+
+        let text = text.replace("fn bad( {\n", "");
+        let messages = run(&[open(&text)]);
+        let diagnostics: Vec<(u64, u64, &str)> = messages[0]["params"]["diagnostics"].as_array().expect("diagnostics").iter()
+            .map(|diagnostic| (diagnostic["range"]["start"]["line"].as_u64().expect("line"), diagnostic["severity"].as_u64().expect("severity"), diagnostic["message"].as_str().expect("message")))
+            .collect();
+        assert_eq!(diagnostics, vec![
+            (3, 1, "Unknown arg é x for ins lamp"),
+            (4, 1, "No such ins: none"),
+            (5, 1, "No such fn: missing"),
+        ]);
+        let end = &messages[0]["params"]["diagnostics"][0]["range"]["end"];
+        assert_eq!(end["character"], 29);
+    }
+
+    #[test]
+    fn test_index_cache() {
+        let mut server = Server { documents: BTreeMap::new(), index: RefCell::new(None), shutdown: false };
+        let uri = "file:///tmp/logic-lsp-cache.logic";
+        let mut output = Vec::new();
+        server.notify("textDocument/didOpen", &json!({ "textDocument": { "uri": uri, "text": DEFS } }), &mut output).expect("ok");
+        let index = server.index();
+        assert!(Rc::ptr_eq(&index, &server.index())); // 文档没有变化时不重建
+        assert!(index.find_fn("blink").is_some());
+
+        let change = json!({ "textDocument": { "uri": uri }, "contentChanges": [{ "text": "fn other() { }" }] });
+        server.notify("textDocument/didChange", &change, &mut output).expect("ok");
+        let changed = server.index();
+        assert!(!Rc::ptr_eq(&index, &changed));
+        assert!(changed.find_fn("blink").is_none() && changed.find_fn("other").is_some());
+        let save = json!({ "textDocument": { "uri": uri }, "text": DEFS });
+        server.notify("textDocument/didSave", &save, &mut output).expect("ok");
+        assert!(server.index().find_fn("blink").is_some());
+    }
+
+    #[test]
+    fn test_scope() {
+        let scope = |text: &str| scope(&text.chars().collect::<Vec<char>>());
+        assert_eq!(scope("    "), Scope::Stmt);
+        assert_eq!(scope("    lo"), Scope::Stmt);
+        assert_eq!(scope("    send "), Scope::Callee(true));
+        assert_eq!(scope("    call bl"), Scope::Callee(false));
+        assert_eq!(scope("    send lamp(a: 1, "), Scope::Args { send: true, name: "lamp".to_string(), named: vec!["a".to_string()], value: false });
+        assert_eq!(scope("    call \"a b\"(n: "), Scope::Args { send: false, name: "a b".to_string(), named: vec!["n".to_string()], value: true });
+        assert_eq!(scope("    send lamp(a: 1)"), Scope::Other);
+        assert_eq!(scope("    x := \"send "), Scope::Other);
+        assert_eq!(scope("    x := "), Scope::Other);
+    }
+
+    #[test]
+    fn test_uri() {
+        let path = Path::new("/tmp/a b/é.logic");
+        let uri = path_to_uri(path);
+        assert_eq!(uri, "file:///tmp/a%20b/%C3%A9.logic");
+        assert_eq!(uri_to_path(&uri).expect("path"), path);
+        assert_eq!(uri_to_path("untitled:1"), None);
+    }
+}
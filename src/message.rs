@@ -0,0 +1,55 @@
+// DAP和LSP共用的消息格式：头部"Content-Length: n"，空行，n字节的JSON
+
+use utils::split_lr;
+use serde_json::{self, Value};
+use std::io::{BufRead, Write};
+
+/// 读取一条消息("Content-Length: n\r\n\r\n{json}")；输入结束时返回Ok(None)
+pub fn read_message(input: &mut dyn BufRead) -> Result<Option<Value>, String> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line).map_err(|err| err.to_string())? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            if length.is_some() {
+                break;
+            }
+            continue;
+        }
+        let (name, value) = split_lr(line, ":");
+        if name.eq_ignore_ascii_case("Content-Length") {
+            length = Some(value.trim().parse::<usize>().map_err(|_| format!("Invalid header: {}", line))?);
+        }
+    }
+    let mut body = vec![0; length.unwrap_or(0)];
+    input.read_exact(&mut body).map_err(|err| err.to_string())?;
+    serde_json::from_slice(&body).map(Some).map_err(|err| format!("Invalid message: {}", err))
+}
+
+pub fn write_message(output: &mut dyn Write, message: &Value) -> Result<(), String> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body).and_then(|_| output.flush()).map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_message, write_message};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_messages() {
+        let mut output = Vec::new();
+        write_message(&mut output, &json!({ "seq": 1, "command": "启动" })).expect("ok");
+        write_message(&mut output, &json!({ "seq": 2 })).expect("ok");
+        assert!(String::from_utf8_lossy(&output).starts_with("Content-Length: 28\r\n\r\n{\"command\":\"启动\",\"seq\":1}"));
+        let mut input = Cursor::new(output);
+        assert_eq!(read_message(&mut input), Ok(Some(json!({ "seq": 1, "command": "启动" }))));
+        assert_eq!(read_message(&mut input), Ok(Some(json!({ "seq": 2 }))));
+        assert_eq!(read_message(&mut input), Ok(None));
+        assert!(read_message(&mut Cursor::new("Content-Length: x\r\n\r\n")).is_err());
+        assert!(read_message(&mut Cursor::new("Content-Length: 2\r\n\r\n{]")).is_err());
+    }
+}
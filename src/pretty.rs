@@ -0,0 +1,285 @@
+// 将FnDef/InsDef格式化为脚本文本（语法见script.rs），用于显示和代码评审时对比差异
+//
+// 输出是规范的：同样的定义总是得到同样的文本
+// - 缩进4个空格，循环体逐层缩进
+// - 调用参数按名称排序
+// - CAN ID以十六进制显示，dlc为8时省略
+// - 注释（note）显示在定义或语句之前的一行或多行'//'中
+// 格式化的结果可以由parse_script()再次解析（非规范的值，如没有前缀的文本，会显示为"文本"）
+// 信号布局、多路复用等在脚本中无法表示的内容不显示
+
+use function::FnDef;
+use instruction::InsDef;
+use statement::{Stmt, StmtKind};
+use variable::{VarDefList, VarBindingList};
+use script::is_ident;
+use utils::{split_lr, parse_int};
+
+const INDENT: &str = "    ";
+
+/// 格式化函数定义
+pub fn format_fn(fndef: &FnDef) -> String {
+    let mut text = String::new();
+    push_note(&mut text, "", &fndef.note);
+    text.push_str(&fn_header(fndef));
+    text.push_str(" {\n");
+    let mut depth = 1;
+    for stmt in &fndef.stmts {
+        if let StmtKind::EndLoop = stmt.kind {
+            depth = usize::max(depth - 1, 1);
+        }
+        let indent = INDENT.repeat(depth);
+        push_note(&mut text, &indent, &stmt.note);
+        text.push_str(&indent);
+        text.push_str(&format_stmt(stmt));
+        text.push('\n');
+        if let StmtKind::Loop = stmt.kind {
+            depth += 1;
+        }
+    }
+    text.push_str("}\n");
+    text
+}
+
+/// 格式化指令定义
+pub fn format_ins(insdef: &InsDef) -> String {
+    let mut text = String::new();
+    push_note(&mut text, "", &insdef.note);
+    text.push_str(&ins_header(insdef));
+    text.push('\n');
+    text
+}
+
+/// 函数的声明（不含注释和函数体），如"fn scan(n: i32)"
+pub fn fn_header(fndef: &FnDef) -> String {
+    format!("fn {}{}", format_name(&fndef.name), format_params(&fndef.args))
+}
+
+/// 指令的声明（不含注释），如"ins lamp(a: u8) = 0x321"
+pub fn ins_header(insdef: &InsDef) -> String {
+    let mut text = format!("ins {}{} = {:#X}", format_name(&insdef.name), format_params(&insdef.args), insdef.canid);
+    if insdef.extended {
+        text.push_str(" extended");
+    }
+    if insdef.dlc != 8 {
+        text.push_str(&format!(" dlc {}", insdef.dlc));
+    }
+    text
+}
+
+/// 声明加上注释的第一行，用于列表，如"fn scan(n: i32)  // 扫描"
+pub fn with_note(header: String, note: &Option<String>) -> String {
+    match note.as_ref().and_then(|note| note.lines().next()) {
+        Some(note) => format!("{}  // {}", header, note),
+        None => header,
+    }
+}
+
+/// 格式化单条语句（不含注释和缩进）
+pub fn format_stmt(stmt: &Stmt) -> String {
+    let arg = |name: &str| stmt.args.raw_value_of(name).unwrap_or("");
+    match stmt.kind {
+        StmtKind::CallIns => format!("send {}{}", format_name(&stmt.content), format_args(&stmt.args)),
+        StmtKind::CallFn => format!("call {}{}", format_name(&stmt.content), format_args(&stmt.args)),
+        StmtKind::Diag => format!("diag {}{}", format_name(&stmt.content), format_args(&stmt.args)),
+        StmtKind::CanOpen => format!("canopen {}{}", format_name(&stmt.content), format_args(&stmt.args)),
+        StmtKind::Loop => format!("loop {} {{", split_lr(arg("$count"), "int:").1),
+        StmtKind::EndLoop => "}".to_string(),
+        StmtKind::Return => {
+            if stmt.content.is_empty() {
+                "return".to_string()
+            } else {
+                format!("return {}", format_value(&stmt.content))
+            }
+        }
+        StmtKind::SetVar => {
+            let mut text = format!("{} {} {}", format_name(arg("$varname")), arg("$op1"), format_value(arg("$operand1")));
+            if let Some(op2) = stmt.args.raw_value_of("$op2") {
+                text.push_str(&format!(" {} {}", op2, format_value(arg("$operand2"))));
+            }
+            text
+        }
+        StmtKind::SetLocal | StmtKind::SetGlobal => {
+            let keyword = if let StmtKind::SetLocal = stmt.kind { "local" } else { "global" };
+            let (name, value) = split_lr(&stmt.content, "=");
+            format!("{} {} = {}", keyword, format_name(name), format_value(value))
+        }
+    }
+}
+
+/// 格式化带前缀的值，如"int:10" -> 10, "str:a b" -> "a b", "var:x" -> x
+pub fn format_value(value: &str) -> String {
+    let (prefix, text) = split_lr(value, ":");
+    match prefix {
+        "int" if is_number(text, false) => text.to_string(),
+        "float" if is_number(text, true) => {
+            if text.contains('.') { text.to_string() } else { format!("{}.0", text) }
+        }
+        "str" => quote(text),
+        "hex" => format!("hex{}", quote(text)),
+        "var" => text.to_string(),
+        _ if is_number(value, true) => value.to_string(),
+        _ if parse_int(value).is_ok() && !value.starts_with("int:") => value.to_string(),
+        _ => quote(value),
+    }
+}
+
+/// 标识符原样显示，其他名称（如包含空格或与关键字相同）加引号
+pub fn format_name(name: &str) -> String {
+    if is_ident(name) { name.to_string() } else { quote(name) }
+}
+
+fn format_params(params: &VarDefList) -> String {
+    let params: Vec<String> = params.defs.iter().map(|vardef| {
+        let mut text = format!("{}: {}", format_name(&vardef.name), vardef.typ);
+        if !vardef.default.is_empty() {
+            text.push_str(&format!(" = {}", format_value(&vardef.default)));
+        }
+        if !vardef.range.is_empty() {
+            text.push_str(&format!(" in {}", vardef.range));
+        }
+        text
+    }).collect();
+    format!("({})", params.join(", "))
+}
+
+fn format_args(args: &VarBindingList) -> String {
+    let mut names: Vec<&String> = args.bindings.keys().collect();
+    names.sort();
+    let args: Vec<String> = names.iter().map(|name| {
+        format!("{}: {}", format_name(name), format_value(args.raw_value_of(name).unwrap_or("")))
+    }).collect();
+    format!("({})", args.join(", "))
+}
+
+fn push_note(text: &mut String, indent: &str, note: &Option<String>) {
+    if let Some(note) = note {
+        for line in note.lines() {
+            text.push_str(indent);
+            text.push_str(if line.is_empty() { "//" } else { "// " });
+            text.push_str(line);
+            text.push('\n');
+        }
+    }
+}
+
+fn quote(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// 能被脚本解析为同样数值的文本，如"-12", "1.5"
+fn is_number(text: &str, float: bool) -> bool {
+    let text = text.strip_prefix('-').unwrap_or(text);
+    let (int, frac) = match text.find('.') {
+        Some(index) if float => (&text[..index], Some(&text[index + 1..])),
+        _ => (text, None),
+    };
+    let digits = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+    digits(int) && frac.is_none_or(digits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_fn, format_ins, format_value};
+    use script::parse_script;
+    use function::FnDef;
+    use statement::Stmt;
+    use variable::VarBindingList;
+
+    const CANONICAL: &str = r#"// 转动雷达
+ins "move radar"(angle: i32, speed: u8 = 10 in 0..100) = 0x3E8
+ins status(mode: u8, 温度: i16 in -40...125) = 0x18FF0010 extended dlc 4
+fn scan(n: i32) {
+    x = 0
+    // 每次转动n*2度
+    // 第二行
+    loop 3 {
+        x += n * 2
+        send "move radar"(angle: x, speed: 20)
+        loop 2 {
+            call report(raw: hex"01 02", text: "a \"b\"", value: -1.5)
+        }
+    }
+    diag ReadDataByIdentifier(did: 61840, result: "vin")
+    local y = x
+    global 速度 = -1.5
+    return x
+}
+// 汇报结果
+fn report(value: i32, text: str) {
+    total := value + 1
+    return
+}
+"#;
+
+    #[test]
+    fn test_format_script() {
+        let script = parse_script(r#"
+// 转动雷达
+ins "move radar"(angle:i32,speed:u8=10 in 0..100)=1000
+ins status(mode: u8, "温度": i16 in -40...125) = 0x18FF0010 extended dlc 4
+fn scan(n: i32) { x = 0
+  // 每次转动n*2度
+  // 第二行
+  loop 3 { x += n*2 // trailing comment
+    send "move radar"(speed: 20, angle: x)
+    loop 2 { call report(value: -1.5, text: "a \"b\"", raw: hex"01 02") } }
+  diag ReadDataByIdentifier(did: 0xF190, result: "vin"); local y = x
+  global 速度 = -1.5
+  return x
+}
+// 汇报结果
+fn report(value: i32, text: str) { total := value + 1; return }
+"#).expect("ok");
+        let mut text = String::new();
+        script.inss.iter().for_each(|insdef| text.push_str(&format_ins(insdef)));
+        script.fns.iter().for_each(|fndef| text.push_str(&format_fn(fndef)));
+        assert_eq!(text, CANONICAL);
+
+        // 再次解析和格式化，结果不变
+        let script = parse_script(&text).expect("ok");
+        let mut again = String::new();
+        script.inss.iter().for_each(|insdef| again.push_str(&format_ins(insdef)));
+        script.fns.iter().for_each(|fndef| again.push_str(&format_fn(fndef)));
+        assert_eq!(again, text);
+    }
+
+    #[test]
+    fn test_format_stmts() {
+        let mut main = FnDef::new("main");
+        main.add_stmt(Stmt::new_loop(2));
+        let mut args = VarBindingList::new();
+        args.set_binding("a", "5");
+        args.set_binding("b", "str:on");
+        args.set_binding("c", "0xF190");
+        args.set_binding("d", "var:speed");
+        main.add_stmt(Stmt::new_call_ins("lamp", args));
+        main.add_stmt(Stmt::new_end_loop());
+        main.add_stmt(Stmt::new_end_loop()); // 不配对的endloop不影响缩进
+        main.add_stmt(Stmt::new_set_var_ex("x", "=", "int:1", "+", "var:y"));
+        main.add_stmt(Stmt::new_set_local("name=str:a b"));
+        let mut ret = Stmt::new_return("float:2");
+        ret.note = Some("done\n\nbye".to_string());
+        main.add_stmt(ret);
+        assert_eq!(format_fn(&main), "fn main() {\n    loop 2 {\n        send lamp(a: 5, b: \"on\", c: 0xF190, d: speed)\n    }\n    }\n    \
+                                      x = 1 + y\n    local name = \"a b\"\n    // done\n    //\n    // bye\n    return 2.0\n}\n");
+
+        assert_eq!(format_value("int:-3"), "-3");
+        assert_eq!(format_value("int:x"), "\"int:x\"");
+        assert_eq!(format_value("float:1e3"), "\"float:1e3\"");
+        assert_eq!(format_value("str:a\\b\n"), "\"a\\\\b\\n\"");
+        assert_eq!(format_value("12:30"), "\"12:30\"");
+        assert_eq!(format_value(""), "\"\"");
+    }
+}
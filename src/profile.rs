@@ -0,0 +1,390 @@
+// 性能分析：统计每个函数的调用次数、执行的语句数、墙上时间和虚拟时间（包含/不包含被调用者），
+// 以及每条指令的调用次数、发出的数据帧和字节数
+//
+// 设置Context.profiler后由FnDef::exec()（解释执行）和Engine::exec_ins()统计，编译后的Program和调试器不统计。
+// 虚拟时间是线程占用的CPU时间（Linux），不包括等待总线和sleep的时间；其他系统上等于墙上时间。
+// 函数的自身时间（self）不包括它调用的函数和指令的时间；递归调用的包含时间只计算最外层的一次。
+// 每个调用栈（如"main;blink;lamp"）的自身时间可以导出为火焰图工具（flamegraph.pl、inferno）使用的folded格式：
+//   main 120
+//   main;blink 35
+//   main;blink;lamp 80
+// 数值为微秒，自身时间不足1微秒的调用栈被省略。
+
+use bus::Frame;
+#[cfg(target_os = "linux")]
+use libc;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// 时间的种类
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Clock {
+    /// 墙上时间
+    Wall,
+    /// 虚拟时间：Linux上为线程占用的CPU时间，其他系统上等于墙上时间
+    Virtual,
+}
+
+/// 一个函数的统计结果
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FnProfile {
+    /// 被调用的次数
+    pub calls: u64,
+    /// 执行的语句数（不包括被调用的函数中的语句）
+    pub stmts: u64,
+    /// 包含被调用者的墙上时间
+    pub wall: Duration,
+    /// 自身的墙上时间
+    pub self_wall: Duration,
+    /// 包含被调用者的虚拟时间（Linux上为线程占用的CPU时间，不包括等待总线和sleep的时间；其他系统上等于墙上时间）
+    pub virtual_time: Duration,
+    /// 自身的虚拟时间，定义同virtual_time
+    pub self_virtual: Duration,
+}
+
+/// 一条指令的统计结果
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InsProfile {
+    /// 被调用的次数
+    pub calls: u64,
+    /// 发出的数据帧（J1939多包报文计为一帧）
+    pub frames: u64,
+    /// 发出的数据帧的字节数
+    pub bytes: u64,
+    pub wall: Duration,
+    /// 虚拟时间，定义同FnProfile.virtual_time
+    pub virtual_time: Duration,
+}
+
+/// 性能分析的结果，见Context.profiler
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Profile {
+    /// 函数名称 -> 统计结果
+    pub fns: BTreeMap<String, FnProfile>,
+    /// 指令名称 -> 统计结果
+    pub inss: BTreeMap<String, InsProfile>,
+    /// 调用栈（以";"分隔的名称）-> 自身的(墙上时间, 虚拟时间)
+    pub stacks: BTreeMap<String, (Duration, Duration)>,
+}
+
+impl Profile {
+    /// 导出为folded格式，clock指定使用的时间
+    pub fn to_folded(&self, clock: Clock) -> String {
+        let mut text = String::new();
+        for (stack, &(wall, virtual_time)) in &self.stacks {
+            let time = if clock == Clock::Wall { wall } else { virtual_time };
+            let micros = time.as_micros();
+            if micros > 0 {
+                text.push_str(&format!("{} {}\n", stack, micros));
+            }
+        }
+        text
+    }
+
+    /// 文本报告：函数按包含的墙上时间从多到少排列，指令按名称排列，时间单位为微秒
+    pub fn report(&self) -> String {
+        let mut fns: Vec<(&String, &FnProfile)> = self.fns.iter().collect();
+        fns.sort_by(|a, b| b.1.wall.cmp(&a.1.wall).then(a.0.cmp(b.0)));
+        let mut rows = vec![vec!["fn", "calls", "stmts", "wall", "self", "virtual", "self"].into_iter().map(String::from).collect()];
+        for (name, profile) in fns {
+            rows.push(vec![
+                name.clone(), profile.calls.to_string(), profile.stmts.to_string(),
+                micros(profile.wall), micros(profile.self_wall), micros(profile.virtual_time), micros(profile.self_virtual),
+            ]);
+        }
+        let mut text = table(&rows);
+        if !self.inss.is_empty() {
+            let mut rows = vec![vec!["ins", "calls", "frames", "bytes", "wall", "virtual"].into_iter().map(String::from).collect()];
+            for (name, profile) in &self.inss {
+                rows.push(vec![
+                    name.clone(), profile.calls.to_string(), profile.frames.to_string(), profile.bytes.to_string(),
+                    micros(profile.wall), micros(profile.virtual_time),
+                ]);
+            }
+            text.push('\n');
+            text.push_str(&table(&rows));
+        }
+        text
+    }
+}
+
+fn micros(time: Duration) -> String {
+    time.as_micros().to_string()
+}
+
+// 第一列左对齐，其他列右对齐
+fn table(rows: &[Vec<String>]) -> String {
+    let mut widths = vec![0; rows[0].len()];
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let mut text = String::new();
+    for row in rows {
+        let mut line = String::new();
+        for (index, (cell, &width)) in row.iter().zip(&widths).enumerate() {
+            if index == 0 {
+                line.push_str(&format!("{:<1$}", cell, width));
+            } else {
+                line.push_str(&format!("  {:>1$}", cell, width));
+            }
+        }
+        text.push_str(line.trim_end());
+        text.push('\n');
+    }
+    text
+}
+
+// 调用栈中的一层
+struct Entry {
+    name: String,
+    ins: bool,
+    stack: String,
+    // 开始时的时间
+    wall: Duration,
+    virtual_time: Duration,
+    // 被调用者的时间
+    child_wall: Duration,
+    child_virtual: Duration,
+}
+
+/// 性能分析器，见Context.profiler
+pub struct Profiler {
+    pub profile: Profile,
+    start: Instant,
+    cpu_start: Option<Duration>,
+    entries: Vec<Entry>,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler { profile: Profile::default(), start: Instant::now(), cpu_start: cpu_time(), entries: Vec::new() }
+    }
+
+    // 开始以来的(墙上时间, 虚拟时间)
+    fn now(&self) -> (Duration, Duration) {
+        let wall = self.start.elapsed();
+        match (self.cpu_start, cpu_time()) {
+            (Some(start), Some(now)) => (wall, now.saturating_sub(start)),
+            _ => (wall, wall),
+        }
+    }
+
+    fn enter(&mut self, name: &str, ins: bool) {
+        let (wall, virtual_time) = self.now();
+        let stack = match self.entries.last() {
+            Some(parent) => format!("{};{}", parent.stack, name),
+            None => name.to_string(),
+        };
+        self.entries.push(Entry {
+            name: name.to_string(), ins, stack, wall, virtual_time,
+            child_wall: Duration::default(), child_virtual: Duration::default(),
+        });
+    }
+
+    /// 开始执行函数
+    pub fn enter_fn(&mut self, name: &str) {
+        self.profile.fns.entry(name.to_string()).or_default().calls += 1;
+        self.enter(name, false);
+    }
+
+    /// 开始执行指令
+    pub fn enter_ins(&mut self, name: &str) {
+        self.profile.inss.entry(name.to_string()).or_default().calls += 1;
+        self.enter(name, true);
+    }
+
+    /// 结束最近开始的函数或指令
+    pub fn exit(&mut self) {
+        let entry = match self.entries.pop() {
+            Some(entry) => entry,
+            None => return,
+        };
+        let (now_wall, now_virtual) = self.now();
+        let wall = now_wall.saturating_sub(entry.wall);
+        let virtual_time = now_virtual.saturating_sub(entry.virtual_time);
+        let self_wall = wall.saturating_sub(entry.child_wall);
+        let self_virtual = virtual_time.saturating_sub(entry.child_virtual);
+        if let Some(parent) = self.entries.last_mut() {
+            parent.child_wall += wall;
+            parent.child_virtual += virtual_time;
+        }
+        let times = self.profile.stacks.entry(entry.stack.clone()).or_default();
+        times.0 += self_wall;
+        times.1 += self_virtual;
+        if entry.ins {
+            let profile = self.profile.inss.entry(entry.name).or_default();
+            profile.wall += wall;
+            profile.virtual_time += virtual_time;
+        } else {
+            // 递归调用时只计算最外层的包含时间
+            let recursive = self.entries.iter().any(|outer| !outer.ins && outer.name == entry.name);
+            let profile = self.profile.fns.entry(entry.name).or_default();
+            if !recursive {
+                profile.wall += wall;
+                profile.virtual_time += virtual_time;
+            }
+            profile.self_wall += self_wall;
+            profile.self_virtual += self_virtual;
+        }
+    }
+
+    /// 当前函数执行了一条语句
+    pub fn stmt(&mut self) {
+        if let Some(entry) = self.entries.iter().rev().find(|entry| !entry.ins) {
+            if let Some(profile) = self.profile.fns.get_mut(&entry.name) {
+                profile.stmts += 1;
+            }
+        }
+    }
+
+    /// 当前指令发出了数据帧；不在指令中时忽略
+    pub fn frame(&mut self, frame: &Frame) {
+        if let Some(entry) = self.entries.last() {
+            if entry.ins {
+                if let Some(profile) = self.profile.inss.get_mut(&entry.name) {
+                    profile.frames += 1;
+                    profile.bytes += frame.data.len() as u64;
+                }
+            }
+        }
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Profiler {
+        Profiler::new()
+    }
+}
+
+// 当前线程占用的CPU时间
+#[cfg(target_os = "linux")]
+fn cpu_time() -> Option<Duration> {
+    let mut time = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    let result = unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut time) };
+    if result == 0 {
+        Some(Duration::new(time.tv_sec as u64, time.tv_nsec as u32))
+    } else {
+        None
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn cpu_time() -> Option<Duration> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use profile::{Profiler, Clock};
+    use engine::{Engine, Context};
+    use variable::VarBindingList;
+    use bus::LoopbackBus;
+    use std::thread;
+    use std::time::Duration;
+
+    const SCRIPT: &str = r#"ins lamp(a: u32, b: u32 = 1) = 0x321
+ins short(a: u32, b: u32 = 2) = 0x322
+
+fn main(n: i32) {
+    loop 2 {
+        call blink(n: n)
+    }
+    send short(a: 1)
+    return n
+}
+
+fn blink(n: i32) {
+    send lamp(a: n)
+}
+"#;
+
+    #[test]
+    fn test_profile() {
+        let mut engine = Engine::new();
+        engine.load_script(SCRIPT, &mut Context::new()).expect("ok");
+        let (bus, _peer) = LoopbackBus::pair();
+        let mut context = Context::new();
+        context.bus = Some(Box::new(bus));
+        context.profiler = Some(Profiler::new());
+        let mut args = VarBindingList::new();
+        args.set_binding("n", "int:1");
+        engine.exec_fn("main", &args, &mut context).expect("ok");
+        let profile = context.profiler.take().expect("profiler").profile;
+
+        let main = &profile.fns["main"];
+        let blink = &profile.fns["blink"];
+        // main: loop执行3次、call两次、endloop两次、send、return
+        assert_eq!((main.calls, main.stmts, blink.calls, blink.stmts), (1, 9, 2, 2));
+        assert!(main.wall >= blink.wall && main.wall >= main.self_wall);
+        assert!(main.virtual_time >= main.self_virtual);
+        let lamp = &profile.inss["lamp"];
+        let short = &profile.inss["short"];
+        assert_eq!((lamp.calls, lamp.frames, lamp.bytes), (2, 2, 16));
+        assert_eq!((short.calls, short.frames, short.bytes), (1, 1, 8));
+        let stacks: Vec<&str> = profile.stacks.keys().map(|stack| stack.as_str()).collect();
+        assert_eq!(stacks, vec!["main", "main;blink", "main;blink;lamp", "main;short"]);
+
+        // 没有总线时不发出数据帧
+        let mut context = Context::new();
+        context.profiler = Some(Profiler::new());
+        engine.exec_fn("main", &args, &mut context).expect("ok");
+        let profile = context.profiler.take().expect("profiler").profile;
+        assert_eq!((profile.inss["lamp"].calls, profile.inss["lamp"].frames), (2, 0));
+    }
+
+    #[test]
+    fn test_times() {
+        let mut profiler = Profiler::new();
+        profiler.enter_fn("main");
+        thread::sleep(Duration::from_millis(5));
+        profiler.enter_fn("main");
+        profiler.enter_fn("blink");
+        thread::sleep(Duration::from_millis(5));
+        profiler.exit();
+        profiler.exit();
+        profiler.exit();
+        let elapsed = profiler.start.elapsed();
+        let profile = profiler.profile;
+
+        let main = &profile.fns["main"];
+        let blink = &profile.fns["blink"];
+        assert_eq!(main.calls, 2);
+        // 递归调用的包含时间只计算一次
+        assert!(main.wall >= Duration::from_millis(10) && main.wall <= elapsed);
+        assert!(main.self_wall >= Duration::from_millis(5) && main.self_wall < main.wall);
+        assert!(blink.wall >= Duration::from_millis(5) && blink.wall == blink.self_wall);
+        if cfg!(target_os = "linux") {
+            // sleep不占用CPU时间
+            assert!(main.virtual_time < Duration::from_millis(5));
+        }
+
+        let folded = profile.to_folded(Clock::Wall);
+        assert!(folded.starts_with(&format!("main {}\n", profile.stacks["main"].0.as_micros())));
+        assert!(folded.ends_with(&format!("main;main;blink {}\n", blink.self_wall.as_micros())));
+        // 多余的exit被忽略
+        let mut profiler = Profiler::new();
+        profiler.exit();
+        profiler.stmt();
+        assert!(profiler.profile.fns.is_empty());
+    }
+
+    #[test]
+    fn test_report() {
+        let mut profiler = Profiler::new();
+        profiler.enter_fn("main");
+        profiler.enter_ins("lamp");
+        profiler.exit();
+        profiler.stmt();
+        profiler.exit();
+        let report = profiler.profile.report();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].starts_with("fn    calls  stmts  wall"));
+        assert!(lines[1].starts_with("main      1      1  "));
+        assert_eq!(lines[2], "");
+        assert!(lines[3].starts_with("ins   calls  frames  bytes  wall"));
+        assert!(lines[4].starts_with("lamp      1       0      0  "));
+    }
+}
@@ -0,0 +1,362 @@
+// 交互式执行语句和定义：logic repl [--bus BUS] [FILE...]
+//
+//   logic> ins lamp(a: u32, b: u32 = 1) = 0x321
+//   ins lamp(a: u32, b: u32 = 1) = 0x321
+//   logic> x := 5; send lamp(a: x)
+//     can0  321   [8]  00 00 00 05 00 00 00 01
+//   x = int:5
+//
+// 一行中可以有多条语句；未完成的定义（如缺少'}'）继续读取下一行
+
+use engine::{Engine, Context};
+use function::FnDef;
+use variable::VarBindingList;
+use statement::StmtKind;
+use script::{parse_script, parse_stmts};
+use pretty::{format_fn, format_ins, fn_header, ins_header, with_note};
+use validate::validate_fn;
+use bus::{Frame, TapBus, candump_text};
+use cli::load_files;
+use utils::split_lr;
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+
+const HELP: &str = "\
+Statements are executed at once, e.g. `x = 1`, `send lamp(a: x)`, `call blink(n: 2)`.
+`ins ...` and `fn ... { ... }` define instructions and functions (replacing existing ones).
+Commands:
+  :vars         show local and global variables
+  :fns          list functions
+  :ins          list instructions
+  :show NAME    show the definition of a function or instruction
+  :load FILE    load definitions from a file
+  :reset        clear local variables
+  :quit         exit (also Ctrl-D)";
+
+/// 保持一个Engine和Context，逐行执行输入
+pub struct Repl {
+    pub engine: Engine,
+    pub context: Context,
+    /// 语句定义的局部变量，在多次输入之间保留
+    pub locals: VarBindingList,
+    /// 输入了:quit
+    pub quit: bool,
+    // 未完成的多行输入
+    pending: String,
+    sent: Rc<RefCell<Vec<Frame>>>,
+}
+
+impl Repl {
+    /// context.bus（如果有）仍然用于发送，此外发出的帧都会显示
+    pub fn new(engine: Engine, mut context: Context) -> Repl {
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let tap = sent.clone();
+        context.bus = Some(Box::new(TapBus::new(context.bus.take(), Box::new(move |frame: &Frame| tap.borrow_mut().push(frame.clone())))));
+        Repl { engine, context, locals: VarBindingList::new(), quit: false, pending: String::new(), sent }
+    }
+
+    /// 提示符；等待多行输入的后续行时不同
+    pub fn prompt(&self) -> &'static str {
+        if self.pending.is_empty() { "logic> " } else { "  ...> " }
+    }
+
+    /// 放弃未完成的多行输入（如按下Ctrl-C）
+    pub fn cancel(&mut self) {
+        self.pending.clear();
+    }
+
+    /// 处理一行输入，结果写到out；出错时已发出的帧仍会写到out
+    pub fn eval(&mut self, line: &str, out: &mut dyn Write) -> Result<(), String> {
+        if self.pending.is_empty() && line.trim_start().starts_with(':') {
+            return self.command(line.trim(), out);
+        }
+        self.pending.push_str(line);
+        self.pending.push('\n');
+        let first = self.pending.split(|c: char| !c.is_alphanumeric() && c != '_').find(|word| !word.is_empty());
+        let result = if first == Some("ins") || first == Some("fn") { self.define(out) } else { self.exec(out) };
+        match result {
+            Err(Some(err)) => {
+                self.pending.clear();
+                Err(err)
+            }
+            Err(None) => Ok(()),
+            Ok(()) => {
+                self.pending.clear();
+                Ok(())
+            }
+        }
+    }
+
+    // 未完成时返回Err(None)
+    fn define(&mut self, out: &mut dyn Write) -> Result<(), Option<String>> {
+        let script = match parse_script(&self.pending) {
+            Ok(script) => script,
+            Err(ref err) if err.is_incomplete() => return Err(None),
+            Err(err) => return Err(Some(format!("Invalid script: {}", err))),
+        };
+        let mut lines: Vec<String> = script.inss.iter().map(ins_header).collect();
+        for fndef in &script.fns {
+            lines.push(fn_header(fndef));
+            lines.extend(validate_fn(fndef, &self.engine, &self.context).iter().map(|diagnostic| diagnostic.to_string()));
+        }
+        self.engine.load_script(&self.pending, &mut self.context)?;
+        for line in lines {
+            let _ = writeln!(out, "{}", line);
+        }
+        Ok(())
+    }
+
+    fn exec(&mut self, out: &mut dyn Write) -> Result<(), Option<String>> {
+        let stmts = match parse_stmts(&self.pending) {
+            Ok(stmts) => stmts,
+            Err(ref err) if err.is_incomplete() => return Err(None),
+            Err(err) => return Err(Some(format!("Invalid statement: {}", err))),
+        };
+        // 显示顶层语句赋值的变量和return、call的返回值
+        let mut names: Vec<String> = Vec::new();
+        let mut returned = false;
+        let mut depth = 0;
+        for stmt in &stmts {
+            match stmt.kind {
+                StmtKind::Loop => depth += 1,
+                StmtKind::EndLoop => depth -= 1,
+                StmtKind::Return | StmtKind::CallFn => returned = true,
+                _ if depth > 0 => {}
+                StmtKind::SetVar => names.extend(stmt.args.raw_value_of("$varname").map(|name| name.to_string())),
+                StmtKind::SetLocal | StmtKind::SetGlobal => names.push(split_lr(&stmt.content, "=").0.trim().to_string()),
+                _ => {}
+            }
+        }
+        let mut seen = Vec::new();
+        names.retain(|name| if seen.contains(name) { false } else { seen.push(name.clone()); true });
+        let mut fndef = FnDef::new("$repl");
+        fndef.stmts = stmts;
+        let result = fndef.exec_with_locals(&mut self.locals, &mut self.context, &self.engine);
+        for frame in self.sent.borrow_mut().drain(..) {
+            let _ = writeln!(out, "{}", candump_text(&frame, "can0"));
+        }
+        result?;
+        for name in names {
+            let value = self.locals.raw_value_of(&name).or_else(|| self.context.globals.raw_value_of(&name));
+            let _ = writeln!(out, "{} = {}", name, value.unwrap_or("(undefined)"));
+        }
+        if let Some(value) = self.context.globals.raw_value_of("$return").filter(|_| returned) {
+            let _ = writeln!(out, "return: {}", value);
+        }
+        Ok(())
+    }
+
+    fn command(&mut self, line: &str, out: &mut dyn Write) -> Result<(), String> {
+        let (command, arg) = match line.find(char::is_whitespace) {
+            Some(index) => (&line[..index], line[index..].trim()),
+            None => (line, ""),
+        };
+        let lines: Vec<String> = match command {
+            ":help" | ":h" => vec![HELP.to_string()],
+            ":quit" | ":q" => {
+                self.quit = true;
+                Vec::new()
+            }
+            ":vars" => {
+                let mut lines = bindings("local", &self.locals);
+                lines.extend(bindings("global", &self.context.globals));
+                lines
+            }
+            ":fns" => {
+                let mut fns: Vec<String> = self.engine.fns.values().map(|fndef| with_note(fn_header(fndef), &fndef.note)).collect();
+                fns.sort();
+                fns
+            }
+            ":ins" => {
+                let mut inss: Vec<String> = self.engine.inss.values().map(|insdef| with_note(ins_header(insdef), &insdef.note)).collect();
+                inss.sort();
+                inss
+            }
+            ":show" => match (self.engine.find_fn(arg)?, self.engine.find_ins(arg)) {
+                (Some(fndef), _) => vec![format_fn(&fndef).trim_end().to_string()],
+                (None, Some(insdef)) => vec![format_ins(insdef).trim_end().to_string()],
+                (None, None) => return Err(format!("No such fn or ins: {}", arg)),
+            },
+            ":load" if !arg.is_empty() => {
+                load_files(&mut self.engine, &[arg.to_string()], &mut self.context)?;
+                vec![format!("{} fn(s), {} ins(s)", self.engine.fns.len(), self.engine.inss.len())]
+            }
+            ":reset" => {
+                self.locals = VarBindingList::new();
+                Vec::new()
+            }
+            _ => return Err(format!("Unknown command: {} (try :help)", line)),
+        };
+        for line in lines {
+            let _ = writeln!(out, "{}", line);
+        }
+        Ok(())
+    }
+}
+
+// 按名称排序，如"local x = int:1"
+fn bindings(scope: &str, list: &VarBindingList) -> Vec<String> {
+    let mut lines: Vec<String> = list.bindings.keys().map(|name| format!("{} {} = {}", scope, name, list.raw_value_of(name).unwrap_or(""))).collect();
+    lines.sort();
+    lines
+}
+
+/// 交互式读取并执行标准输入，直到:quit或输入结束；启用repl特性时支持行编辑和历史记录(~/.logic_history)
+pub fn run_repl(repl: &mut Repl) {
+    let mut stdout = ::std::io::stdout();
+    let mut editor = LineEditor::new();
+    while !repl.quit {
+        let line = match editor.read_line(repl.prompt()) {
+            Some(Ok(line)) => line,
+            Some(Err(())) => {
+                repl.cancel();
+                continue;
+            }
+            None => break,
+        };
+        if let Err(err) = repl.eval(&line, &mut stdout) {
+            eprintln!("error: {}", err);
+        }
+    }
+    editor.save();
+}
+
+#[cfg(feature = "repl")]
+struct LineEditor {
+    editor: Option<::rustyline::DefaultEditor>,
+    history: Option<::std::path::PathBuf>,
+}
+
+#[cfg(feature = "repl")]
+impl LineEditor {
+    fn new() -> LineEditor {
+        let editor = ::rustyline::DefaultEditor::new().ok();
+        let history = ::std::env::var_os("HOME").map(|home| ::std::path::Path::new(&home).join(".logic_history"));
+        let mut line_editor = LineEditor { editor, history };
+        if let (Some(editor), Some(history)) = (line_editor.editor.as_mut(), line_editor.history.as_ref()) {
+            let _ = editor.load_history(history);
+        }
+        line_editor
+    }
+
+    // None表示输入结束，Some(Err(()))表示按下了Ctrl-C；无法使用行编辑（如没有终端）时逐行读取标准输入
+    fn read_line(&mut self, prompt: &str) -> Option<Result<String, ()>> {
+        use rustyline::error::ReadlineError;
+        let editor = match self.editor.as_mut() {
+            Some(editor) => editor,
+            None => return read_stdin_line(prompt),
+        };
+        match editor.readline(prompt) {
+            Ok(line) => {
+                if !line.trim().is_empty() {
+                    let _ = editor.add_history_entry(line.as_str());
+                }
+                Some(Ok(line))
+            }
+            Err(ReadlineError::Interrupted) => Some(Err(())),
+            Err(_) => None,
+        }
+    }
+
+    fn save(&mut self) {
+        if let (Some(editor), Some(history)) = (self.editor.as_mut(), self.history.as_ref()) {
+            let _ = editor.save_history(history);
+        }
+    }
+}
+
+// 没有repl特性时逐行读取标准输入，没有行编辑和历史记录
+#[cfg(not(feature = "repl"))]
+struct LineEditor;
+
+#[cfg(not(feature = "repl"))]
+impl LineEditor {
+    fn new() -> LineEditor {
+        LineEditor
+    }
+
+    fn read_line(&mut self, prompt: &str) -> Option<Result<String, ()>> {
+        read_stdin_line(prompt)
+    }
+
+    fn save(&mut self) {}
+}
+
+// 从标准输入读取一行（不含换行符），None表示输入结束
+fn read_stdin_line(prompt: &str) -> Option<Result<String, ()>> {
+    use std::io::BufRead;
+    print!("{}", prompt);
+    let _ = ::std::io::stdout().flush();
+    let mut line = String::new();
+    match ::std::io::stdin().lock().read_line(&mut line) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(Ok(line.trim_end_matches(['\r', '\n']).to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Repl;
+    use engine::{Engine, Context};
+    use bus::{Bus, Frame, LoopbackBus};
+    use std::time::Duration;
+    
+    // 返回(结果, 输出)
+    fn eval(repl: &mut Repl, line: &str) -> (Result<(), String>, String) {
+        let mut out = Vec::new();
+        let result = repl.eval(line, &mut out);
+        (result, String::from_utf8(out).expect("utf8"))
+    }
+
+    #[test]
+    fn test_repl() {
+        let mut context = Context::new();
+        let (bus, mut peer) = LoopbackBus::pair();
+        context.bus = Some(Box::new(bus));
+        let mut repl = Repl::new(Engine::new(), context);
+
+        assert_eq!(eval(&mut repl, "ins lamp(a: u32, b: u32 = 1) = 0x321"), (Ok(()), "ins lamp(a: u32, b: u32 = 1) = 0x321\n".to_string()));
+        assert_eq!(eval(&mut repl, "x = 5; g := 2; x += 0").1, "x = int:5\ng = int:2\n");
+        assert_eq!(eval(&mut repl, "send lamp(a: x)").1, "  can0  321   [8]  00 00 00 05 00 00 00 01\n");
+        assert_eq!(peer.recv(Duration::from_millis(10)), Ok(Some(Frame::new(0x321, &[0, 0, 0, 5, 0, 0, 0, 1]))));
+
+        // 多行定义
+        assert_eq!(repl.prompt(), "logic> ");
+        assert_eq!(eval(&mut repl, "fn twice(n: i32) {"), (Ok(()), String::new()));
+        assert_eq!(repl.prompt(), "  ...> ");
+        assert_eq!(eval(&mut repl, "    loop 2 { send lamp(a: n) }"), (Ok(()), String::new()));
+        assert_eq!(eval(&mut repl, "    send none()\n}").1, "fn twice(n: i32)\nerror: fn twice #3 (line 3, col 5): No such ins: none\n");
+        assert_eq!(repl.prompt(), "logic> ");
+        assert_eq!(eval(&mut repl, "fn twice(n: i32) { loop 2 { send lamp(a: n) }; return n }").1, "fn twice(n: i32)\n");
+        assert_eq!(eval(&mut repl, "call twice(n: g)").1,
+                   "  can0  321   [8]  00 00 00 02 00 00 00 01\n  can0  321   [8]  00 00 00 02 00 00 00 01\nreturn: int:2\n");
+        assert_eq!(eval(&mut repl, "x += 1; return x").1, "x = int:6\nreturn: int:6\n");
+
+        assert_eq!(eval(&mut repl, ":vars").1, "local x = int:6\nglobal $return = int:6\nglobal g = int:2\n");
+        assert_eq!(eval(&mut repl, ":fns").1, "fn twice(n: i32)\n");
+        assert_eq!(eval(&mut repl, ":ins").1, "ins lamp(a: u32, b: u32 = 1) = 0x321\n");
+        assert_eq!(eval(&mut repl, ":show twice").1, "fn twice(n: i32) {\n    loop 2 {\n        send lamp(a: n)\n    }\n    return n\n}\n");
+        assert_eq!(eval(&mut repl, ":reset"), (Ok(()), String::new()));
+        assert_eq!(eval(&mut repl, ":vars").1, "global $return = int:6\nglobal g = int:2\n");
+        assert!(!repl.quit);
+        assert_eq!(eval(&mut repl, ":quit"), (Ok(()), String::new()));
+        assert!(repl.quit);
+    }
+
+    #[test]
+    fn test_repl_errors() {
+        let mut repl = Repl::new(Engine::new(), Context::new());
+        assert_eq!(eval(&mut repl, "x = ;").0, Err("Invalid statement: line 1, col 5: expected value, found ';'".to_string()));
+        assert_eq!(eval(&mut repl, "ins a(x: u8) = 0x800").0, Err("Invalid script: line 1, col 16: CAN id 0x800 requires 'extended'".to_string()));
+        assert_eq!(eval(&mut repl, "call none()").0, Err("No such fn: none".to_string()));
+        assert_eq!(eval(&mut repl, ":show none").0, Err("No such fn or ins: none".to_string()));
+        assert_eq!(eval(&mut repl, ":what").0, Err("Unknown command: :what (try :help)".to_string()));
+        assert!(eval(&mut repl, ":load /nonexistent.logic").0.is_err());
+
+        // 放弃未完成的输入
+        assert_eq!(eval(&mut repl, "loop 2 {"), (Ok(()), String::new()));
+        repl.cancel();
+        assert_eq!(eval(&mut repl, "y = 1").1, "y = int:1\n");
+    }
+}
@@ -0,0 +1,767 @@
+// 脚本语言：用文本定义指令和函数，解析为InsDef和FnDef
+//
+//   // 注释；紧邻在定义或语句之前的注释作为其note
+//   ins "move radar"(angle: i32, speed: u8 = 10 in 0..100) = 1000
+//   ins status(mode: u8) = 0x18FF0010 extended dlc 4
+//
+//   fn scan(n: i32) {
+//       x = 0                      // SetVar: = 局部变量, := 全局变量, += -= *= /= 修改已有变量
+//       loop 3 {
+//           x += n * 2
+//           send "move radar"(angle: x, speed: 20)
+//       }
+//       call report(value: x)
+//       diag ReadDataByIdentifier(did: 0xF190, result: "vin")
+//       return x
+//   }
+//
+// 名称可以是标识符（可以包含非ASCII字母，如`速度`）或双引号括起来的文本（可以包含空格）
+// 值：整数(int:)、小数(float:)、"文本"(str:)、hex"1A FF"(hex:)、变量名(var:)
+// send/call的参数和return的值在执行时求值（生成的语句Stmt.eval为true），引用未定义的变量是错误
+// 语句之间不需要分隔符，也可以用';'分隔
+
+use instruction::InsDef;
+use isotp::MAX_LEN;
+use function::FnDef;
+use statement::{Stmt, StmtKind, SourcePos};
+use variable::{VarDef, VarDefList, VarBindingList};
+use std::fmt;
+
+/// 脚本中的定义
+pub struct Script {
+    pub inss: Vec<InsDef>,
+    pub fns: Vec<FnDef>,
+}
+
+/// 解析错误，带有出错的位置
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub pos: SourcePos,
+    pub message: String,
+    /// 在文本结束处出错（如函数体缺少'}'），即文本不完整
+    pub incomplete: bool,
+}
+
+impl ParseError {
+    pub fn new(pos: SourcePos, message: String) -> ParseError {
+        ParseError { pos, message, incomplete: false }
+    }
+
+    /// 是否因为文本不完整而出错，用于REPL继续读取下一行
+    pub fn is_incomplete(&self) -> bool {
+        self.incomplete
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, col {}: {}", self.pos.line, self.pos.col, self.message)
+    }
+}
+
+/// 脚本的关键字，作为名称时需要加引号
+pub const KEYWORDS: &[&str] = &[
+    "ins", "fn", "loop", "return", "send", "call", "diag", "canopen", "local", "global", "extended", "dlc", "in",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    Ident(String),
+    /// 双引号文本：在名称的位置是名称，在值的位置是文本
+    Str(String),
+    /// hex"..."
+    Hex(String),
+    Int(u64),
+    Float(String),
+    Punct(&'static str),
+    Comment(String),
+    Eof,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    tok: Tok,
+    pos: SourcePos,
+}
+
+// 按最长匹配的顺序排列
+const PUNCTS: &[&str] = &[
+    "...", ":=", "+=", "-=", "*=", "/=", "..", "(", ")", "{", "}", ",", ":", "=", "+", "-", "*", "/", ";",
+];
+
+/// 是否可以作为不加引号的标识符
+pub fn is_ident(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_alphanumeric() || c == '_') && !KEYWORDS.contains(&name)
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, ParseError> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let (mut i, mut line, mut col) = (0, 1u32, 1u32);
+    while i < chars.len() {
+        let c = chars[i];
+        let pos = SourcePos::new(line, col);
+        let start = i;
+        let tok = if c == '\n' {
+            i += 1;
+            line += 1;
+            col = 1;
+            continue;
+        } else if c.is_whitespace() {
+            i += 1;
+            col += 1;
+            continue;
+        } else if c == '/' && chars.get(i + 1) == Some(&'/') {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+            let comment: String = chars[start + 2..i].iter().collect();
+            Tok::Comment(comment.trim().to_string())
+        } else if c == '"' || (c == 'h' && chars[i..].starts_with(&['h', 'e', 'x', '"'])) {
+            let hex = c == 'h';
+            i += if hex { 4 } else { 1 };
+            let mut value = String::new();
+            loop {
+                match chars.get(i) {
+                    None | Some(&'\n') => return Err(ParseError::new(pos, "unterminated string".to_string())),
+                    Some(&'"') => break,
+                    Some(&'\\') => {
+                        match chars.get(i + 1) {
+                            Some(&'n') => value.push('\n'),
+                            Some(&'t') => value.push('\t'),
+                            Some(&c) if c == '"' || c == '\\' => value.push(c),
+                            _ => return Err(ParseError::new(SourcePos::new(line, col + (i - start) as u32), "invalid escape".to_string())),
+                        }
+                        i += 2;
+                    }
+                    Some(&c) => {
+                        value.push(c);
+                        i += 1;
+                    }
+                }
+            }
+            i += 1;
+            if hex { Tok::Hex(value) } else { Tok::Str(value) }
+        } else if c.is_ascii_digit() {
+            if c == '0' && (chars.get(i + 1) == Some(&'x') || chars.get(i + 1) == Some(&'X')) {
+                i += 2;
+                while i < chars.len() && chars[i].is_ascii_hexdigit() {
+                    i += 1;
+                }
+            } else {
+                while i < chars.len() && chars[i].is_ascii_digit() {
+                    i += 1;
+                }
+                if chars.get(i) == Some(&'.') && chars.get(i + 1).is_some_and(|c| c.is_ascii_digit()) {
+                    i += 1;
+                    while i < chars.len() && chars[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                }
+            }
+            if chars.get(i).is_some_and(|c| c.is_alphanumeric() || *c == '_') {
+                return Err(ParseError::new(pos, "invalid number".to_string()));
+            }
+            let number: String = chars[start..i].iter().collect();
+            if number.contains('.') {
+                Tok::Float(number)
+            } else {
+                let value = match number.strip_prefix("0x").or_else(|| number.strip_prefix("0X")) {
+                    Some(hex) => u64::from_str_radix(hex, 16),
+                    None => number.parse(),
+                };
+                Tok::Int(value.map_err(|_| ParseError::new(pos, format!("invalid number: {}", number)))?)
+            }
+        } else if c.is_alphabetic() || c == '_' {
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            Tok::Ident(chars[start..i].iter().collect())
+        } else {
+            match PUNCTS.iter().find(|p| p.chars().enumerate().all(|(k, pc)| chars.get(i + k) == Some(&pc))) {
+                Some(p) => {
+                    i += p.chars().count();
+                    Tok::Punct(p)
+                }
+                None => return Err(ParseError::new(pos, format!("unexpected character '{}'", c))),
+            }
+        };
+        col += (i - start) as u32;
+        tokens.push(Token { tok, pos });
+    }
+    tokens.push(Token { tok: Tok::Eof, pos: SourcePos::new(line, col) });
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    index: usize,
+    // 紧邻下一个记号之前的注释(行号, 注释)
+    notes: Vec<(u32, String)>,
+    // 上一个记号所在的行，用于区分行尾注释
+    last_line: u32,
+}
+
+impl Parser {
+    // 跳过注释（收集为note），返回下一个记号
+    // 行尾注释和与下一个记号之间隔着空行的注释不作为note
+    fn peek(&mut self) -> &Token {
+        while let Tok::Comment(ref comment) = self.tokens[self.index].tok {
+            let line = self.tokens[self.index].pos.line;
+            if self.notes.last().is_some_and(|note| note.0 + 1 != line) {
+                self.notes.clear();
+            }
+            if line != self.last_line {
+                self.notes.push((line, comment.clone()));
+            }
+            self.index += 1;
+        }
+        let line = self.tokens[self.index].pos.line;
+        if self.notes.last().is_some_and(|note| note.0 + 1 != line) {
+            self.notes.clear();
+        }
+        &self.tokens[self.index]
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.peek().clone();
+        if token.tok != Tok::Eof {
+            self.index += 1;
+        }
+        self.notes.clear();
+        self.last_line = token.pos.line;
+        token
+    }
+
+    fn take_note(&mut self) -> Option<String> {
+        self.peek();
+        if self.notes.is_empty() {
+            None
+        } else {
+            Some(self.notes.iter().map(|note| note.1.as_str()).collect::<Vec<_>>().join("\n"))
+        }
+    }
+
+    fn error<T>(&self, pos: SourcePos, message: String) -> Result<T, ParseError> {
+        Err(ParseError::new(pos, message))
+    }
+
+    fn unexpected<T>(&self, token: &Token, expected: &str) -> Result<T, ParseError> {
+        let found = match token.tok {
+            Tok::Ident(ref s) => format!("'{}'", s),
+            Tok::Str(ref s) => format!("\"{}\"", s),
+            Tok::Hex(ref s) => format!("hex\"{}\"", s),
+            Tok::Int(n) => n.to_string(),
+            Tok::Float(ref s) => s.clone(),
+            Tok::Punct(p) => format!("'{}'", p),
+            Tok::Comment(_) => "comment".to_string(),
+            Tok::Eof => "end of script".to_string(),
+        };
+        let mut err = ParseError::new(token.pos, format!("expected {}, found {}", expected, found));
+        err.incomplete = token.tok == Tok::Eof;
+        Err(err)
+    }
+
+    fn is_punct(&mut self, punct: &str) -> bool {
+        match self.peek().tok {
+            Tok::Punct(p) => p == punct,
+            _ => false,
+        }
+    }
+
+    fn is_keyword(&mut self, keyword: &str) -> bool {
+        match self.peek().tok {
+            Tok::Ident(ref s) => s == keyword,
+            _ => false,
+        }
+    }
+
+    fn expect_punct(&mut self, punct: &'static str) -> Result<SourcePos, ParseError> {
+        let token = self.next();
+        if token.tok == Tok::Punct(punct) {
+            Ok(token.pos)
+        } else {
+            self.unexpected(&token, &format!("'{}'", punct))
+        }
+    }
+
+    // 标识符或加引号的名称
+    fn expect_name(&mut self, what: &str) -> Result<(String, SourcePos), ParseError> {
+        let token = self.next();
+        match token.tok {
+            Tok::Ident(ref s) if !KEYWORDS.contains(&s.as_str()) => Ok((s.clone(), token.pos)),
+            Tok::Str(ref s) if !s.is_empty() => Ok((s.clone(), token.pos)),
+            _ => self.unexpected(&token, what),
+        }
+    }
+
+    fn expect_int(&mut self) -> Result<u64, ParseError> {
+        let token = self.next();
+        match token.tok {
+            Tok::Int(n) => Ok(n),
+            _ => self.unexpected(&token, "integer"),
+        }
+    }
+
+    // 值，转换为带前缀的文本
+    fn expect_value(&mut self) -> Result<String, ParseError> {
+        let token = self.next();
+        match token.tok {
+            Tok::Int(n) => Ok(format!("int:{}", n)),
+            Tok::Float(ref s) => Ok(format!("float:{}", s)),
+            Tok::Str(ref s) => Ok(format!("str:{}", s)),
+            Tok::Hex(ref s) => Ok(format!("hex:{}", s)),
+            Tok::Ident(ref s) if !KEYWORDS.contains(&s.as_str()) => Ok(format!("var:{}", s)),
+            Tok::Punct("-") => {
+                let token = self.next();
+                match token.tok {
+                    Tok::Int(n) => Ok(format!("int:-{}", n)),
+                    Tok::Float(ref s) => Ok(format!("float:-{}", s)),
+                    _ => self.unexpected(&token, "number"),
+                }
+            }
+            _ => self.unexpected(&token, "value"),
+        }
+    }
+
+    // 不带前缀的数值，用于范围
+    fn expect_number(&mut self) -> Result<String, ParseError> {
+        let negative = self.is_punct("-");
+        if negative {
+            self.next();
+        }
+        let token = self.next();
+        let number = match token.tok {
+            Tok::Int(n) => n.to_string(),
+            Tok::Float(ref s) => s.clone(),
+            _ => return self.unexpected(&token, "number"),
+        };
+        Ok(if negative { format!("-{}", number) } else { number })
+    }
+
+    fn parse_script(&mut self) -> Result<Script, ParseError> {
+        let mut script = Script { inss: Vec::new(), fns: Vec::new() };
+        loop {
+            let note = self.take_note();
+            let token = self.peek().clone();
+            match token.tok {
+                Tok::Eof => return Ok(script),
+                Tok::Ident(ref s) if s == "ins" => {
+                    let mut insdef = self.parse_ins()?;
+                    insdef.note = note;
+                    script.inss.push(insdef);
+                }
+                Tok::Ident(ref s) if s == "fn" => {
+                    let mut fndef = self.parse_fn()?;
+                    fndef.note = note;
+                    script.fns.push(fndef);
+                }
+                Tok::Punct(";") => {
+                    self.next();
+                }
+                _ => return self.unexpected(&token, "'ins' or 'fn'"),
+            }
+        }
+    }
+
+    fn parse_stmts(&mut self) -> Result<Vec<Stmt>, ParseError> {
+        let mut stmts = Vec::new();
+        loop {
+            if self.peek().tok == Tok::Eof {
+                return Ok(stmts);
+            }
+            if self.is_punct(";") {
+                self.next();
+                continue;
+            }
+            self.parse_stmt(&mut stmts)?;
+        }
+    }
+
+    // (name: type [= default] [in a..b], ...)
+    fn parse_params(&mut self) -> Result<VarDefList, ParseError> {
+        let mut params = VarDefList::new();
+        self.expect_punct("(")?;
+        while !self.is_punct(")") {
+            let note = self.take_note();
+            let (name, pos) = self.expect_name("parameter name")?;
+            if params.find(&name).is_some() {
+                return self.error(pos, format!("duplicate parameter: {}", name));
+            }
+            self.expect_punct(":")?;
+            let (typ, _) = self.expect_name("parameter type")?;
+            let mut vardef = VarDef::new(name, typ);
+            vardef.note = note;
+            if self.is_punct("=") {
+                self.next();
+                vardef.default = self.expect_value()?;
+            }
+            if self.is_keyword("in") {
+                self.next();
+                let min = self.expect_number()?;
+                let op = if self.is_punct("...") { "..." } else { ".." };
+                self.expect_punct(op)?;
+                vardef.range = format!("{}{}{}", min, op, self.expect_number()?);
+            }
+            params.add(vardef);
+            if !self.is_punct(")") {
+                self.expect_punct(",")?;
+            }
+        }
+        self.expect_punct(")")?;
+        Ok(params)
+    }
+
+    // ins name(params) = canid [extended] [dlc n]
+    // dlc最大为ISO-TP的4095字节；超过8字节时是否能发送取决于Context的传输方式，由Engine::validate()检查
+    fn parse_ins(&mut self) -> Result<InsDef, ParseError> {
+        let pos = self.next().pos;
+        let (name, _) = self.expect_name("instruction name")?;
+        let mut args = self.parse_params()?;
+        // 指令参数的默认值不带类型前缀，与导入DBC/EDS的相同
+        for vardef in &mut args.defs {
+            let value = vardef.default.strip_prefix("int:").or_else(|| vardef.default.strip_prefix("float:")).map(str::to_string);
+            if let Some(value) = value {
+                vardef.default = value;
+            }
+        }
+        self.expect_punct("=")?;
+        let canid_pos = self.peek().pos;
+        let canid = self.expect_int()?;
+        if canid > 0x1FFF_FFFF {
+            return self.error(canid_pos, format!("invalid CAN id: {}", canid));
+        }
+        let mut insdef = InsDef::new(&name, canid as u32);
+        insdef.args = args;
+        insdef.pos = Some(pos);
+        if self.is_keyword("extended") {
+            self.next();
+            insdef.extended = true;
+        } else if canid > 0x7FF {
+            return self.error(canid_pos, format!("CAN id {:#X} requires 'extended'", canid));
+        }
+        if self.is_keyword("dlc") {
+            self.next();
+            let dlc_pos = self.peek().pos;
+            let dlc = self.expect_int()?;
+            if dlc == 0 || dlc > MAX_LEN as u64 {
+                return self.error(dlc_pos, format!("invalid dlc: {}", dlc));
+            }
+            insdef.dlc = dlc as u16;
+        }
+        Ok(insdef)
+    }
+
+    // fn name(params) { stmts }
+    fn parse_fn(&mut self) -> Result<FnDef, ParseError> {
+        let pos = self.next().pos;
+        let (name, _) = self.expect_name("function name")?;
+        let mut fndef = FnDef::new(&name);
+        fndef.args = self.parse_params()?;
+        fndef.pos = Some(pos);
+        self.parse_block(&mut fndef.stmts)?;
+        Ok(fndef)
+    }
+
+    // { stmts }，返回'}'的位置
+    fn parse_block(&mut self, stmts: &mut Vec<Stmt>) -> Result<SourcePos, ParseError> {
+        self.expect_punct("{")?;
+        loop {
+            if self.is_punct("}") {
+                return Ok(self.next().pos);
+            }
+            if self.is_punct(";") {
+                self.next();
+                continue;
+            }
+            self.parse_stmt(stmts)?;
+        }
+    }
+
+    // (name: value, ...)
+    fn parse_args(&mut self) -> Result<VarBindingList, ParseError> {
+        let mut args = VarBindingList::new();
+        self.expect_punct("(")?;
+        while !self.is_punct(")") {
+            let (name, pos) = self.expect_name("argument name")?;
+            if args.contains(&name) {
+                return self.error(pos, format!("duplicate argument: {}", name));
+            }
+            self.expect_punct(":")?;
+            let value = self.expect_value()?;
+            args.set_binding(&name, &value);
+            if !self.is_punct(")") {
+                self.expect_punct(",")?;
+            }
+        }
+        self.expect_punct(")")?;
+        Ok(args)
+    }
+
+    fn parse_stmt(&mut self, stmts: &mut Vec<Stmt>) -> Result<(), ParseError> {
+        let note = self.take_note();
+        let token = self.peek().clone();
+        let pos = token.pos;
+        let keyword = match token.tok {
+            Tok::Ident(ref s) if KEYWORDS.contains(&s.as_str()) => s.clone(),
+            _ => String::new(),
+        };
+        let mut stmt = match keyword.as_str() {
+            "loop" => {
+                self.next();
+                let count_pos = self.peek().pos;
+                let count = self.expect_int()?;
+                if count > u32::MAX as u64 {
+                    return self.error(count_pos, format!("invalid loop count: {}", count));
+                }
+                let mut stmt = Stmt::new_loop(count as u32);
+                stmt.note = note;
+                stmt.pos = Some(pos);
+                stmts.push(stmt);
+                let end_pos = self.parse_block(stmts)?;
+                let mut end = Stmt::new_end_loop();
+                end.pos = Some(end_pos);
+                stmts.push(end);
+                return Ok(());
+            }
+            "return" => {
+                self.next();
+                let next = self.peek().clone();
+                // 返回值必须与return在同一行
+                let value = if next.pos.line == pos.line && next.tok != Tok::Punct("}") && next.tok != Tok::Punct(";") {
+                    self.expect_value()?
+                } else {
+                    String::new()
+                };
+                let mut stmt = Stmt::new_return(&value);
+                stmt.eval = true;
+                stmt
+            }
+            "send" | "call" | "diag" | "canopen" => {
+                self.next();
+                let (name, _) = self.expect_name("name")?;
+                let args = self.parse_args()?;
+                match keyword.as_str() {
+                    "send" | "call" => {
+                        let mut stmt = if keyword == "send" { Stmt::new_call_ins(&name, args) } else { Stmt::new_call_fn(&name, args) };
+                        stmt.eval = true;
+                        stmt
+                    }
+                    "diag" => Stmt::new_diag(&name, args),
+                    _ => Stmt::new_canopen(&name, args),
+                }
+            }
+            "local" | "global" => {
+                self.next();
+                let (name, _) = self.expect_name("variable name")?;
+                self.expect_punct("=")?;
+                let expr = format!("{}={}", name, self.expect_value()?);
+                Stmt::new(if keyword == "local" { StmtKind::SetLocal } else { StmtKind::SetGlobal }, &expr)
+            }
+            "" => self.parse_set_var()?,
+            _ => return self.unexpected(&token, "statement"),
+        };
+        stmt.note = note;
+        stmt.pos = Some(pos);
+        stmts.push(stmt);
+        Ok(())
+    }
+
+    // name op1 operand1 [op2 operand2]
+    fn parse_set_var(&mut self) -> Result<Stmt, ParseError> {
+        let (name, _) = self.expect_name("statement")?;
+        let token = self.next();
+        let op1 = match token.tok {
+            Tok::Punct(p) if ["=", ":=", "+=", "-=", "*=", "/="].contains(&p) => p,
+            _ => return self.unexpected(&token, "assignment operator"),
+        };
+        let operand1 = self.expect_value()?;
+        let token = self.peek().clone();
+        match token.tok {
+            Tok::Punct(p) if ["+", "-", "*", "/"].contains(&p) => {
+                self.next();
+                let operand2 = self.expect_value()?;
+                Ok(Stmt::new_set_var_ex(&name, op1, &operand1, p, &operand2))
+            }
+            _ => Ok(Stmt::new_set_var(&name, op1, &operand1)),
+        }
+    }
+}
+
+/// 解析脚本文本
+pub fn parse_script(text: &str) -> Result<Script, ParseError> {
+    let mut parser = Parser {
+        tokens: tokenize(text)?,
+        index: 0,
+        notes: Vec::new(),
+        last_line: 0,
+    };
+    parser.parse_script()
+}
+
+/// 解析函数体之外的语句，如"x = 1; send lamp(a: x)"
+pub fn parse_stmts(text: &str) -> Result<Vec<Stmt>, ParseError> {
+    let mut parser = Parser {
+        tokens: tokenize(text)?,
+        index: 0,
+        notes: Vec::new(),
+        last_line: 0,
+    };
+    parser.parse_stmts()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_script, parse_stmts, is_ident, ParseError};
+    use statement::{StmtKind, SourcePos};
+    use engine::{Engine, Context};
+    use variable::VarBindingList;
+    use bus::{Bus, Frame, LoopbackBus};
+    use std::time::Duration;
+
+    const SCRIPT: &str = r#"
+// 转动雷达
+ins "move radar"(angle: i32, speed: u8 = 10 in 0..100) = 1000
+ins status(mode: u8, "温度": i16 in -40...125) = 0x18FF0010 extended dlc 4
+
+fn scan(n: i32) {
+    x = 0
+    // 每次转动n*2度
+    loop 3 {
+        x += n * 2 // trailing comment
+        send "move radar"(angle: x, speed: 20)
+    }
+    call report(value: x, text: "done", raw: hex"01 02")
+    diag ReadDataByIdentifier(did: 0xF190, result: "vin")
+    global 速度 = -1.5
+    return x
+}
+
+// 汇报结果
+fn report(value: i32, text: str) {
+    total := value + 1; return
+}
+"#;
+
+    #[test]
+    fn test_parse_script() {
+        let script = parse_script(SCRIPT).expect("ok");
+        assert_eq!(script.inss.len(), 2);
+        let movr = &script.inss[0];
+        assert_eq!((movr.name.as_str(), movr.canid, movr.extended, movr.dlc), ("move radar", 1000, false, 8));
+        assert_eq!(movr.note, Some("转动雷达".to_string()));
+        assert_eq!(movr.pos, Some(SourcePos::new(3, 1)));
+        let speed = movr.args.find("speed").expect("speed");
+        assert_eq!((speed.typ.as_str(), speed.default.as_str(), speed.range.as_str()), ("u8", "10", "0..100"));
+        let status = &script.inss[1];
+        assert_eq!((status.canid, status.extended, status.dlc), (0x18FF0010, true, 4));
+        assert_eq!(status.args.find("温度").map(|arg| arg.range.as_str()), Some("-40...125"));
+
+        let scan = &script.fns[0];
+        assert_eq!(scan.name, "scan");
+        assert_eq!(scan.pos, Some(SourcePos::new(6, 1)));
+        assert_eq!(scan.args.defs[0].typ, "i32");
+        let kinds: Vec<&str> = scan.stmts.iter().map(|stmt| match stmt.kind {
+            StmtKind::SetVar => "set", StmtKind::Loop => "loop", StmtKind::EndLoop => "end",
+            StmtKind::CallIns => "ins", StmtKind::CallFn => "fn", StmtKind::Diag => "diag",
+            StmtKind::SetGlobal => "global", StmtKind::Return => "return", _ => "?",
+        }).collect();
+        assert_eq!(kinds, vec!["set", "loop", "set", "ins", "end", "fn", "diag", "global", "return"]);
+        let stmts = &scan.stmts;
+        assert_eq!(stmts[1].note, Some("每次转动n*2度".to_string()));
+        assert_eq!(stmts[1].args.raw_value_of("$count"), Some("3"));
+        assert_eq!(stmts[1].pos, Some(SourcePos::new(9, 5)));
+        assert_eq!(stmts[4].pos, Some(SourcePos::new(12, 5))); // '}'
+        let add = &stmts[2];
+        assert_eq!(add.note, None);
+        assert_eq!(add.args.raw_value_of("$varname"), Some("x"));
+        assert_eq!(add.args.raw_value_of("$op1"), Some("+="));
+        assert_eq!(add.args.raw_value_of("$operand1"), Some("var:n"));
+        assert_eq!(add.args.raw_value_of("$op2"), Some("*"));
+        assert_eq!(add.args.raw_value_of("$operand2"), Some("int:2"));
+        assert_eq!(stmts[3].content, "move radar");
+        assert_eq!(stmts[3].args.raw_value_of("angle"), Some("var:x"));
+        assert_eq!(stmts[5].args.raw_value_of("text"), Some("str:done"));
+        assert_eq!(stmts[5].args.raw_value_of("raw"), Some("hex:01 02"));
+        assert_eq!(stmts[6].args.raw_value_of("did"), Some("int:61840"));
+        assert_eq!(stmts[7].content, "速度=float:-1.5");
+        assert_eq!(stmts[8].content, "var:x");
+
+        let report = &script.fns[1];
+        assert_eq!(report.note, Some("汇报结果".to_string()));
+        assert_eq!(report.stmts.len(), 2);
+        assert_eq!(report.stmts[0].args.raw_value_of("$op1"), Some(":="));
+        assert_eq!(report.stmts[1].content, "");
+
+        // 与定义之间隔着空行的注释不作为note
+        let script = parse_script("// header\n\n// 说明\nins a() = 1\nfn f() {\n    // b\n\n    return\n}\n").expect("ok");
+        assert_eq!(script.inss[0].note, Some("说明".to_string()));
+        assert_eq!(script.fns[0].note, None);
+        assert_eq!(script.fns[0].stmts[0].note, None);
+    }
+
+    fn error_of(text: &str) -> String {
+        parse_script(text).err().map(|err: ParseError| err.to_string()).unwrap_or_default()
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(error_of("fn f() {\n  x = \n}"), "line 3, col 1: expected value, found '}'");
+        assert_eq!(error_of("fn f() {\n  loop 2 {\n}"), "line 3, col 2: expected statement, found end of script");
+        assert_eq!(error_of("ins a(x: u8) = 0x800"), "line 1, col 16: CAN id 0x800 requires 'extended'");
+        assert_eq!(error_of("ins a(x: u8) = 1 dlc 4096"), "line 1, col 22: invalid dlc: 4096");
+        assert_eq!(error_of("ins a(x: u8) = 1 dlc 4095"), "");
+        assert_eq!(error_of("ins a(x: u8, x: u8) = 1"), "line 1, col 14: duplicate parameter: x");
+        assert_eq!(error_of("fn loop() {}"), "line 1, col 4: expected function name, found 'loop'");
+        assert_eq!(error_of("fn f() {\n  x = \"abc\n}"), "line 2, col 7: unterminated string");
+        assert_eq!(error_of("fn f() { x = 1a }"), "line 1, col 14: invalid number");
+        assert_eq!(error_of("fn f() { x ? 1 }"), "line 1, col 12: unexpected character '?'");
+        assert_eq!(error_of("fn f() { send a(b: 1 c: 2) }"), "line 1, col 22: expected ',', found 'c'");
+        assert_eq!(error_of("x = 1"), "line 1, col 1: expected 'ins' or 'fn', found 'x'");
+        assert!(is_ident("速度") && is_ident("_a1") && !is_ident("move radar") && !is_ident("1a") && !is_ident("loop"));
+    }
+
+    #[test]
+    fn test_parse_stmts() {
+        let stmts = parse_stmts("x = 1; send lamp(a: x)\nloop 2 { x += 1 }").expect("ok");
+        assert_eq!(stmts.len(), 5);
+        assert!(matches!(stmts[1].kind, StmtKind::CallIns) && matches!(stmts[2].kind, StmtKind::Loop));
+        assert!(matches!(stmts[4].kind, StmtKind::EndLoop));
+        assert_eq!(stmts[1].pos, Some(SourcePos::new(1, 8)));
+        assert!(parse_stmts("").expect("ok").is_empty());
+        assert!(parse_stmts("loop 2 {").err().is_some_and(|err| err.is_incomplete()));
+        assert!(parse_script("fn f(a: u8").err().is_some_and(|err| err.is_incomplete()));
+        assert!(parse_stmts("x = ;").err().is_some_and(|err| !err.is_incomplete()));
+    }
+
+    #[test]
+    fn test_load_script() {
+        let mut engine = Engine::new();
+        let mut context = Context::new();
+        assert!(engine.load_script("fn f() {", &mut context).is_err());
+        engine.load_script(r#"
+            ins lamp(a: u32, b: u32 = 7) = 0x321
+            fn blink(n: i32) {
+                count = 0
+                loop 2 {
+                    count += 1
+                    send lamp(a: n)
+                }
+                return count
+            }
+        "#, &mut context).expect("ok");
+        let (bus, mut peer) = LoopbackBus::pair();
+        context.bus = Some(Box::new(bus));
+        let mut args = VarBindingList::new();
+        args.set_binding("n", "int:5");
+        engine.exec_fn("blink", &args, &mut context).expect("ok");
+        for _ in 0..2 {
+            assert_eq!(peer.recv(Duration::from_millis(10)), Ok(Some(Frame::new(0x321, &[0, 0, 0, 5, 0, 0, 0, 7]))));
+        }
+    }
+}
@@ -0,0 +1,191 @@
+/// 信号的字节序
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ByteOrder {
+    /// Motorola (big endian), DBC中记为 @0
+    Motorola,
+    /// Intel (little endian), DBC中记为 @1
+    Intel,
+}
+
+/// 多路复用：同一报文中，由选择器(multiplexor)的值决定哪一组信号有效
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Mux {
+    /// 选择器信号，DBC中记为 M
+    Multiplexor,
+    /// 仅当选择器的原始值等于此值时有效的信号，DBC中记为 m<n>
+    Multiplexed(u64),
+}
+
+/// 信号在CAN数据帧中的布局和编码方式（兼容DBC的SG_定义）
+/// 物理值 = 原始值 * factor + offset
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SignalLayout {
+    /// 起始位；Intel格式为最低有效位的位置，Motorola格式为最高有效位的位置
+    pub start_bit: u32,
+    /// 位长度 (1..64)
+    pub bit_len: u32,
+    pub byte_order: ByteOrder,
+    /// 原始值是否为有符号数（二进制补码）
+    pub signed: bool,
+    pub factor: f64,
+    pub offset: f64,
+    /// 物理单位，如"km/h"
+    pub unit: String,
+}
+
+impl SignalLayout {
+    pub fn new(start_bit: u32, bit_len: u32, byte_order: ByteOrder, signed: bool) -> SignalLayout {
+        SignalLayout {
+            start_bit,
+            bit_len,
+            byte_order,
+            signed,
+            factor: 1.0,
+            offset: 0.0,
+            unit: String::new(),
+        }
+    }
+
+    /// 信号占用的数据位（按DBC的位编号，即 byte*8 + bit），从最高有效位到最低有效位排列
+    pub fn bit_positions(&self) -> Vec<u32> {
+        let mut positions = Vec::with_capacity(self.bit_len as usize);
+        match self.byte_order {
+            ByteOrder::Intel => {
+                for i in (0..self.bit_len).rev() {
+                    positions.push(self.start_bit + i);
+                }
+            }
+            ByteOrder::Motorola => {
+                // start_bit是MSB；同一字节内向低位走，走到bit0后跳到下一字节的bit7
+                let mut pos = self.start_bit;
+                for _ in 0..self.bit_len {
+                    positions.push(pos);
+                    if pos.is_multiple_of(8) {
+                        pos += 15;
+                    } else {
+                        pos -= 1;
+                    }
+                }
+            }
+        }
+        positions
+    }
+
+    /// 信号所需的最小数据长度（字节数）
+    pub fn min_len(&self) -> usize {
+        self.bit_positions().iter().max().map_or(0, |pos| *pos as usize / 8 + 1)
+    }
+
+    /// 将物理值转换为原始值（按factor/offset换算并取整），并检查是否超出位长度所能表示的范围
+    pub fn to_raw(&self, physical: f64) -> Result<i64, String> {
+        if self.factor == 0.0 {
+            return Err("Invalid signal factor: 0".to_string());
+        }
+        let raw = ((physical - self.offset) / self.factor).round();
+        let (min, max) = self.raw_limits();
+        if raw.is_nan() || raw < min as f64 || raw > max as f64 {
+            return Err(format!("Value {} out of signal range ({} bits)", physical, self.bit_len));
+        }
+        Ok(raw as i64)
+    }
+
+    /// 将原始值转换为物理值
+    pub fn to_physical(&self, raw: i64) -> f64 {
+        raw as f64 * self.factor + self.offset
+    }
+
+    // 原始值的取值范围
+    fn raw_limits(&self) -> (i128, i128) {
+        let len = self.bit_len as i128;
+        if self.signed {
+            (-(1i128 << (len - 1)), (1i128 << (len - 1)) - 1)
+        } else {
+            (0, (1i128 << len) - 1)
+        }
+    }
+
+    /// 把原始值写入data（data长度须足够）
+    pub fn pack(&self, raw: i64, data: &mut [u8]) -> Result<(), String> {
+        let positions = self.bit_positions();
+        if positions.iter().any(|pos| *pos as usize / 8 >= data.len()) {
+            return Err(format!("Signal exceeds data length: {} bytes", data.len()));
+        }
+        let bits = raw as u64;
+        for (i, pos) in positions.iter().enumerate() {
+            let bit = (bits >> (positions.len() - 1 - i)) & 1;
+            let (byte, mask) = ((*pos / 8) as usize, 1u8 << (*pos % 8));
+            if bit == 1 {
+                data[byte] |= mask;
+            } else {
+                data[byte] &= !mask;
+            }
+        }
+        Ok(())
+    }
+
+    /// 从data中读取原始值（有符号信号会做符号扩展）
+    pub fn unpack(&self, data: &[u8]) -> Result<i64, String> {
+        let positions = self.bit_positions();
+        if positions.iter().any(|pos| *pos as usize / 8 >= data.len()) {
+            return Err(format!("Signal exceeds data length: {} bytes", data.len()));
+        }
+        let mut bits = 0u64;
+        for pos in &positions {
+            bits = (bits << 1) | ((data[(*pos / 8) as usize] >> (*pos % 8)) & 1) as u64;
+        }
+        if self.signed && self.bit_len < 64 && (bits >> (self.bit_len - 1)) & 1 == 1 {
+            bits |= !0u64 << self.bit_len; // sign extend
+        }
+        Ok(bits as i64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SignalLayout, ByteOrder};
+
+    #[test]
+    fn test_intel() {
+        let sig = SignalLayout::new(4, 12, ByteOrder::Intel, false);
+        let mut data = vec![0u8; 8];
+        sig.pack(0xABC, &mut data).expect("ok");
+        assert_eq!(data, vec![0xC0, 0xAB, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(sig.unpack(&data), Ok(0xABC));
+        assert_eq!(sig.min_len(), 2);
+    }
+
+    #[test]
+    fn test_motorola() {
+        // 16-bit big endian value in byte 0 and byte 1
+        let sig = SignalLayout::new(7, 16, ByteOrder::Motorola, false);
+        let mut data = vec![0u8; 8];
+        sig.pack(0x1234, &mut data).expect("ok");
+        assert_eq!(data, vec![0x12, 0x34, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(sig.unpack(&data), Ok(0x1234));
+
+        // 12 bits starting at byte0.bit3
+        let sig = SignalLayout::new(3, 12, ByteOrder::Motorola, false);
+        let mut data = vec![0u8; 2];
+        sig.pack(0xABC, &mut data).expect("ok");
+        assert_eq!(data, vec![0x0A, 0xBC]);
+        assert_eq!(sig.unpack(&data), Ok(0xABC));
+    }
+
+    #[test]
+    fn test_signed_and_scaled() {
+        let mut sig = SignalLayout::new(0, 8, ByteOrder::Intel, true);
+        sig.factor = 0.5;
+        sig.offset = -10.0;
+        assert_eq!(sig.to_raw(-20.0), Ok(-20));
+        let mut data = vec![0u8; 1];
+        sig.pack(-20, &mut data).expect("ok");
+        assert_eq!(data, vec![0xEC]);
+        assert_eq!(sig.unpack(&data), Ok(-20));
+        assert_eq!(sig.to_physical(-20), -20.0);
+        assert!(sig.to_raw(100.0).is_err()); // raw 220 > 127
+        assert!(sig.pack(0, &mut []).is_err());
+    }
+}
@@ -0,0 +1,142 @@
+// Linux SocketCAN总线（CAN_RAW，经典CAN帧，最多8字节）
+
+use bus::{Bus, Frame};
+use libc;
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::time::Duration;
+
+const CAN_EFF_FLAG: u32 = 0x8000_0000;
+const CAN_RTR_FLAG: u32 = 0x4000_0000;
+const CAN_ERR_FLAG: u32 = 0x2000_0000;
+const CAN_EFF_MASK: u32 = 0x1FFF_FFFF;
+const CAN_SFF_MASK: u32 = 0x0000_07FF;
+
+// struct can_frame
+#[repr(C)]
+struct CanFrame {
+    can_id: u32,
+    can_dlc: u8,
+    pad: u8,
+    res0: u8,
+    res1: u8,
+    data: [u8; 8],
+}
+
+// struct sockaddr_can（can_addr是16字节的union）
+#[repr(C)]
+struct SockAddrCan {
+    can_family: libc::sa_family_t,
+    can_ifindex: libc::c_int,
+    can_addr: [u64; 2],
+}
+
+/// SocketCAN接口，如"can0"、"vcan0"
+pub struct SocketCan {
+    fd: libc::c_int,
+}
+
+fn last_error(what: &str) -> String {
+    format!("SocketCAN {}: {}", what, io::Error::last_os_error())
+}
+
+impl SocketCan {
+    pub fn open(interface: &str) -> Result<SocketCan, String> {
+        let name = CString::new(interface).map_err(|_| format!("Invalid CAN interface: {}", interface))?;
+        let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if ifindex == 0 {
+            return Err(format!("No such CAN interface: {}", interface));
+        }
+        let fd = unsafe { libc::socket(libc::PF_CAN, libc::SOCK_RAW, libc::CAN_RAW) };
+        if fd < 0 {
+            return Err(last_error("socket"));
+        }
+        let socket = SocketCan { fd };
+        let addr = SockAddrCan {
+            can_family: libc::AF_CAN as libc::sa_family_t,
+            can_ifindex: ifindex as libc::c_int,
+            can_addr: [0; 2],
+        };
+        let result = unsafe {
+            libc::bind(fd, &addr as *const SockAddrCan as *const libc::sockaddr, mem::size_of::<SockAddrCan>() as libc::socklen_t)
+        };
+        if result < 0 {
+            return Err(last_error("bind"));
+        }
+        Ok(socket)
+    }
+}
+
+impl Bus for SocketCan {
+    fn send(&mut self, frame: &Frame) -> Result<(), String> {
+        if frame.data.len() > 8 {
+            return Err(format!("SocketCAN frame too long: {} bytes", frame.data.len()));
+        }
+        let mut raw = CanFrame {
+            can_id: if frame.extended { (frame.id & CAN_EFF_MASK) | CAN_EFF_FLAG } else { frame.id & CAN_SFF_MASK },
+            can_dlc: frame.data.len() as u8,
+            pad: 0,
+            res0: 0,
+            res1: 0,
+            data: [0; 8],
+        };
+        raw.data[..frame.data.len()].copy_from_slice(&frame.data);
+        let size = mem::size_of::<CanFrame>();
+        let written = unsafe { libc::write(self.fd, &raw as *const CanFrame as *const libc::c_void, size) };
+        if written != size as isize {
+            return Err(last_error("write"));
+        }
+        Ok(())
+    }
+
+    // 忽略远程帧和错误帧
+    fn recv(&mut self, timeout: Duration) -> Result<Option<Frame>, String> {
+        let mut pollfd = libc::pollfd { fd: self.fd, events: libc::POLLIN, revents: 0 };
+        let ready = unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis().min(i32::MAX as u128) as libc::c_int) };
+        if ready < 0 {
+            return Err(last_error("poll"));
+        }
+        if ready == 0 {
+            return Ok(None);
+        }
+        let mut raw: CanFrame = unsafe { mem::zeroed() };
+        let size = mem::size_of::<CanFrame>();
+        let read = unsafe { libc::read(self.fd, &mut raw as *mut CanFrame as *mut libc::c_void, size) };
+        if read != size as isize {
+            return Err(last_error("read"));
+        }
+        if raw.can_id & (CAN_RTR_FLAG | CAN_ERR_FLAG) != 0 {
+            return Ok(None);
+        }
+        let extended = raw.can_id & CAN_EFF_FLAG != 0;
+        let len = (raw.can_dlc as usize).min(8);
+        Ok(Some(Frame {
+            id: raw.can_id & if extended { CAN_EFF_MASK } else { CAN_SFF_MASK },
+            extended,
+            data: raw.data[..len].to_vec(),
+        }))
+    }
+}
+
+impl Drop for SocketCan {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SocketCan, CanFrame, SockAddrCan};
+    use std::mem;
+
+    #[test]
+    fn test_open() {
+        assert_eq!(mem::size_of::<CanFrame>(), 16);
+        assert_eq!(mem::size_of::<SockAddrCan>(), 24);
+        assert_eq!(SocketCan::open("nocan9").err(), Some("No such CAN interface: nocan9".to_string()));
+        assert!(SocketCan::open("bad\0name").is_err());
+    }
+}
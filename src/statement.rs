@@ -7,6 +7,8 @@ trait Exec {
 }
 
 /// 语句类型
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum StmtKind {
     /// 调用指令（由用户定义的指令）；Stmt.content为指令名称，Stmt.args为调用参数。
     CallIns,
@@ -24,20 +26,66 @@ pub enum StmtKind {
     SetLocal,
     /// 定义全局变量并赋值；Stmt.content为"name=value"的表达式
     SetGlobal,
+    /// 调用UDS诊断服务；Stmt.content为服务名称，Stmt.args为服务参数，详见uds::exec_diag()
+    Diag,
+    /// CANopen的NMT命令或SDO传输；Stmt.content为服务名称，Stmt.args为服务参数，详见canopen::exec_canopen()
+    CanOpen,
+}
+
+const KIND_NAMES: &[(StmtKind, &str)] = &[
+    (StmtKind::CallIns, "CallIns"), (StmtKind::CallFn, "CallFn"), (StmtKind::Loop, "Loop"),
+    (StmtKind::EndLoop, "EndLoop"), (StmtKind::Return, "Return"), (StmtKind::SetVar, "SetVar"),
+    (StmtKind::SetLocal, "SetLocal"), (StmtKind::SetGlobal, "SetGlobal"), (StmtKind::Diag, "Diag"),
+    (StmtKind::CanOpen, "CanOpen"),
+];
+
+impl StmtKind {
+    /// 类型的名称，与JSON格式中的相同，如"CallIns"
+    pub fn name(&self) -> &'static str {
+        KIND_NAMES.iter().find(|&&(kind, _)| kind == *self).map(|&(_, name)| name).expect("all kinds are named")
+    }
+
+    pub fn from_name(name: &str) -> Option<StmtKind> {
+        KIND_NAMES.iter().find(|&&(_, kind_name)| kind_name == name).map(|&(kind, _)| kind)
+    }
+}
+
+/// 源代码中的位置，行号和列号均从1开始（列号按字符计）
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SourcePos {
+    pub line: u32,
+    pub col: u32,
+}
+
+impl SourcePos {
+    pub fn new(line: u32, col: u32) -> SourcePos {
+        SourcePos { line, col }
+    }
 }
 
 /// 表示函数内的任意一条可执行语句
-/// 这个结构可以方便的序列化至数据库或JSON
+/// 这个结构可以方便的序列化至数据库或JSON（启用serde特性）
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Stmt {
     /// 语句类型，详见StmtKind的说明
     pub kind: StmtKind,
     /// 其含义取决于指令类型，详见StmtKind的说明
     pub content: String,
     /// 语句参数
+    #[cfg_attr(feature = "serde", serde(default))]
     pub args: VarBindingList,
     /// 注释
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
     pub note: Option<String>,
+    /// 在脚本中的位置；不是由脚本生成的语句为None
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub pos: Option<SourcePos>,
+    /// 执行时对调用参数和返回值求值（由脚本生成的语句为true）；为false时按原样传递
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "is_false"))]
+    pub eval: bool,
     // 运行时参数
+    #[cfg_attr(feature = "serde", serde(skip))]
     rt_args: RefCell<Option<VarBindingList>>,
 }
 
@@ -52,6 +100,8 @@ impl Stmt {
             content: content.to_string(),
             args: args,
             note: None,
+            pos: None,
+            eval: false,
             rt_args: RefCell::new(None),
         }
     }
@@ -64,6 +114,14 @@ impl Stmt {
         Stmt::new_with_args(StmtKind::CallFn, name, args)
     }
 
+    pub fn new_diag(service: &str, args: VarBindingList) -> Stmt {
+        Stmt::new_with_args(StmtKind::Diag, service, args)
+    }
+
+    pub fn new_canopen(service: &str, args: VarBindingList) -> Stmt {
+        Stmt::new_with_args(StmtKind::CanOpen, service, args)
+    }
+
     pub fn new_loop(count: u32) -> Stmt {
         let mut stmt = Stmt::new(StmtKind::Loop, "");
         stmt.args.set_binding("$count", &count.to_string());
@@ -148,6 +206,11 @@ impl Stmt {
     }
 }
 
+#[cfg(feature = "serde")]
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
 #[cfg(test)]
 mod tests {
     use statement::Stmt;
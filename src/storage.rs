@@ -0,0 +1,448 @@
+// 指令库和函数库的SQLite存储（启用sqlite特性）
+//
+// 表结构：
+//   inss  (name, canid, extended, dlc, args, note, line, col, version, created, updated)
+//   fns   (name, args, note, line, col, version, created, updated)
+//   stmts (fn, seq, kind, content, args, note, line, col, eval)
+// 其中args为JSON格式的参数定义(VarDefList)或参数绑定(VarBindingList)，line和col为在脚本中的位置(SourcePos)，
+// eval为Stmt.eval（版本1的数据库打开时添加这一列）
+// version从1开始，每次保存了有变化的定义时加1；created和updated为Unix时间戳（秒）
+// 数据库的格式版本记录在PRAGMA user_version中
+
+use instruction::InsDef;
+use function::FnDef;
+use statement::{Stmt, StmtKind, SourcePos};
+use variable::{VarDefList, VarBindingList};
+use rusqlite::{Connection, OptionalExtension, params};
+use serde_json;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 数据库的格式版本；格式发生不兼容的变化时加1
+pub const SCHEMA_VERSION: u32 = 2;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS inss (
+    name TEXT PRIMARY KEY,
+    canid INTEGER NOT NULL,
+    extended INTEGER NOT NULL,
+    dlc INTEGER NOT NULL,
+    args TEXT NOT NULL,
+    note TEXT,
+    line INTEGER,
+    col INTEGER,
+    version INTEGER NOT NULL,
+    created INTEGER NOT NULL,
+    updated INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS fns (
+    name TEXT PRIMARY KEY,
+    args TEXT NOT NULL,
+    note TEXT,
+    line INTEGER,
+    col INTEGER,
+    version INTEGER NOT NULL,
+    created INTEGER NOT NULL,
+    updated INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS stmts (
+    fn TEXT NOT NULL,
+    seq INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    content TEXT NOT NULL,
+    args TEXT NOT NULL,
+    note TEXT,
+    line INTEGER,
+    col INTEGER,
+    eval INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (fn, seq)
+);
+";
+
+/// 存储中的一条指令或函数的概要，用于列表和搜索
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub name: String,
+    pub note: Option<String>,
+    pub version: u32,
+    /// 创建时间（Unix时间戳，秒）
+    pub created: u64,
+    /// 最后修改时间（Unix时间戳，秒）
+    pub updated: u64,
+}
+
+/// SQLite存储
+pub struct Storage {
+    conn: Connection,
+}
+
+impl Storage {
+    /// 打开（或创建）数据库文件
+    pub fn open(path: &str) -> Result<Storage,String> {
+        Storage::init(Connection::open(path).map_err(db_error)?)
+    }
+
+    /// 创建内存数据库，主要用于测试
+    pub fn open_in_memory() -> Result<Storage,String> {
+        Storage::init(Connection::open_in_memory().map_err(db_error)?)
+    }
+
+    fn init(conn: Connection) -> Result<Storage,String> {
+        let version: u32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0)).map_err(db_error)?;
+        if version > SCHEMA_VERSION {
+            return Err(format!("Unsupported storage version: {}", version));
+        }
+        if version == 1 {
+            conn.execute_batch("ALTER TABLE stmts ADD COLUMN eval INTEGER NOT NULL DEFAULT 0").map_err(db_error)?;
+        }
+        conn.execute_batch(SCHEMA).map_err(db_error)?;
+        conn.execute_batch(&format!("PRAGMA user_version = {}", SCHEMA_VERSION)).map_err(db_error)?;
+        Ok(Storage { conn })
+    }
+
+    /// 保存指令，返回保存后的版本号；定义没有变化时不修改版本号和时间
+    pub fn save_ins(&mut self, insdef: &InsDef) -> Result<u32,String> {
+        let old = self.load_ins(&insdef.name)?;
+        if old.as_ref().is_some_and(|old| to_json(old) == to_json(insdef)) {
+            return self.version_of("inss", &insdef.name);
+        }
+        let args = to_json(&insdef.args)?;
+        let now = now();
+        let tx = self.conn.transaction().map_err(db_error)?;
+        tx.execute("INSERT INTO inss (name, canid, extended, dlc, args, note, line, col, version, created, updated)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, 1, ?9, ?9)
+                    ON CONFLICT(name) DO UPDATE SET canid = ?2, extended = ?3, dlc = ?4, args = ?5, note = ?6,
+                    line = ?7, col = ?8, version = version + 1, updated = ?9",
+                   params![insdef.name, insdef.canid, insdef.extended, insdef.dlc, args, insdef.note,
+                           insdef.pos.map(|pos| pos.line), insdef.pos.map(|pos| pos.col), now])
+            .map_err(db_error)?;
+        tx.commit().map_err(db_error)?;
+        self.version_of("inss", &insdef.name)
+    }
+
+    /// 保存函数及其语句，返回保存后的版本号；定义没有变化时不修改版本号和时间
+    pub fn save_fn(&mut self, fndef: &FnDef) -> Result<u32,String> {
+        let old = self.load_fn(&fndef.name)?;
+        if old.as_ref().is_some_and(|old| to_json(old) == to_json(fndef)) {
+            return self.version_of("fns", &fndef.name);
+        }
+        let args = to_json(&fndef.args)?;
+        let now = now();
+        let tx = self.conn.transaction().map_err(db_error)?;
+        tx.execute("INSERT INTO fns (name, args, note, line, col, version, created, updated)
+                    VALUES (?1, ?2, ?3, ?4, ?5, 1, ?6, ?6)
+                    ON CONFLICT(name) DO UPDATE SET args = ?2, note = ?3, line = ?4, col = ?5,
+                    version = version + 1, updated = ?6",
+                   params![fndef.name, args, fndef.note, fndef.pos.map(|pos| pos.line), fndef.pos.map(|pos| pos.col), now])
+            .map_err(db_error)?;
+        tx.execute("DELETE FROM stmts WHERE fn = ?1", params![fndef.name]).map_err(db_error)?;
+        for (seq, stmt) in fndef.stmts.iter().enumerate() {
+            let kind = serde_json::to_value(stmt.kind).map_err(|err| err.to_string())?;
+            tx.execute("INSERT INTO stmts (fn, seq, kind, content, args, note, line, col, eval)
+                        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                       params![fndef.name, seq, kind.as_str(), stmt.content, to_json(&stmt.args)?, stmt.note,
+                               stmt.pos.map(|pos| pos.line), stmt.pos.map(|pos| pos.col), stmt.eval])
+                .map_err(db_error)?;
+        }
+        tx.commit().map_err(db_error)?;
+        self.version_of("fns", &fndef.name)
+    }
+
+    /// 读取指令，不存在时返回None
+    pub fn load_ins(&self, name: &str) -> Result<Option<InsDef>,String> {
+        let sql = "SELECT canid, extended, dlc, args, note, line, col FROM inss WHERE name = ?1";
+        let row = self.conn.query_row(sql, params![name], |row| {
+            Ok((row.get::<_, u32>(0)?, row.get::<_, bool>(1)?, row.get::<_, u16>(2)?, row.get::<_, String>(3)?,
+                row.get::<_, Option<String>>(4)?, source_pos(row.get(5)?, row.get(6)?)))
+        }).optional().map_err(db_error)?;
+        let (canid, extended, dlc, args, note, pos) = match row {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let mut insdef = InsDef::new(name, canid);
+        insdef.extended = extended;
+        insdef.dlc = dlc;
+        insdef.args = from_json::<VarDefList>(&args)?;
+        insdef.note = note;
+        insdef.pos = pos;
+        Ok(Some(insdef))
+    }
+
+    /// 读取函数及其语句，不存在时返回None
+    pub fn load_fn(&self, name: &str) -> Result<Option<FnDef>,String> {
+        let row = self.conn.query_row("SELECT args, note, line, col FROM fns WHERE name = ?1", params![name], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?, source_pos(row.get(2)?, row.get(3)?)))
+        }).optional().map_err(db_error)?;
+        let (args, note, pos) = match row {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let mut fndef = FnDef::new(name);
+        fndef.args = from_json::<VarDefList>(&args)?;
+        fndef.note = note;
+        fndef.pos = pos;
+        let mut query = self.conn.prepare("SELECT kind, content, args, note, line, col, eval FROM stmts WHERE fn = ?1 ORDER BY seq")
+            .map_err(db_error)?;
+        let rows = query.query_map(params![name], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?,
+                row.get::<_, Option<String>>(3)?, source_pos(row.get(4)?, row.get(5)?), row.get::<_, bool>(6)?))
+        }).map_err(db_error)?;
+        for row in rows {
+            let (kind, content, args, note, pos, eval) = row.map_err(db_error)?;
+            let kind: StmtKind = serde_json::from_value(serde_json::Value::String(kind))
+                .map_err(|err| format!("Invalid statement in fn {}: {}", name, err))?;
+            let mut stmt = Stmt::new_with_args(kind, &content, from_json::<VarBindingList>(&args)?);
+            stmt.note = note;
+            stmt.pos = pos;
+            stmt.eval = eval;
+            fndef.add_stmt(stmt);
+        }
+        Ok(Some(fndef))
+    }
+
+    /// 删除指令，返回是否存在
+    pub fn remove_ins(&mut self, name: &str) -> Result<bool,String> {
+        let count = self.conn.execute("DELETE FROM inss WHERE name = ?1", params![name]).map_err(db_error)?;
+        Ok(count > 0)
+    }
+
+    /// 删除函数及其语句，返回是否存在
+    pub fn remove_fn(&mut self, name: &str) -> Result<bool,String> {
+        let tx = self.conn.transaction().map_err(db_error)?;
+        tx.execute("DELETE FROM stmts WHERE fn = ?1", params![name]).map_err(db_error)?;
+        let count = tx.execute("DELETE FROM fns WHERE name = ?1", params![name]).map_err(db_error)?;
+        tx.commit().map_err(db_error)?;
+        Ok(count > 0)
+    }
+
+    /// 列出全部指令，按名称排序
+    pub fn list_inss(&self) -> Result<Vec<Record>,String> {
+        self.query_records("inss", "")
+    }
+
+    /// 列出全部函数，按名称排序
+    pub fn list_fns(&self) -> Result<Vec<Record>,String> {
+        self.query_records("fns", "")
+    }
+
+    /// 搜索名称或注释中包含text的指令（不区分ASCII字母大小写），按名称排序
+    pub fn search_inss(&self, text: &str) -> Result<Vec<Record>,String> {
+        self.query_records("inss", text)
+    }
+
+    /// 搜索名称或注释中包含text的函数（不区分ASCII字母大小写），按名称排序
+    pub fn search_fns(&self, text: &str) -> Result<Vec<Record>,String> {
+        self.query_records("fns", text)
+    }
+
+    /// 读取全部指令
+    pub fn load_inss(&self) -> Result<Vec<InsDef>,String> {
+        let mut inss = Vec::new();
+        for record in self.list_inss()? {
+            inss.extend(self.load_ins(&record.name)?);
+        }
+        Ok(inss)
+    }
+
+    // table: "inss"或"fns"; text为空时返回全部
+    fn query_records(&self, table: &str, text: &str) -> Result<Vec<Record>,String> {
+        let pattern = format!("%{}%", text.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"));
+        let sql = format!("SELECT name, note, version, created, updated FROM {} \
+                           WHERE name LIKE ?1 ESCAPE '\\' OR note LIKE ?1 ESCAPE '\\' ORDER BY name", table);
+        let mut query = self.conn.prepare(&sql).map_err(db_error)?;
+        let rows = query.query_map(params![pattern], |row| {
+            Ok(Record {
+                name: row.get(0)?,
+                note: row.get(1)?,
+                version: row.get(2)?,
+                created: row.get(3)?,
+                updated: row.get(4)?,
+            })
+        }).map_err(db_error)?;
+        rows.collect::<Result<Vec<_>,_>>().map_err(db_error)
+    }
+
+    fn version_of(&self, table: &str, name: &str) -> Result<u32,String> {
+        self.conn.query_row(&format!("SELECT version FROM {} WHERE name = ?1", table), params![name], |row| row.get(0))
+            .map_err(db_error)
+    }
+}
+
+fn source_pos(line: Option<u32>, col: Option<u32>) -> Option<SourcePos> {
+    line.and_then(|line| col.map(|col| SourcePos::new(line, col)))
+}
+
+fn db_error(err: rusqlite::Error) -> String {
+    format!("Storage error: {}", err)
+}
+
+fn to_json<T: ::serde::Serialize>(value: &T) -> Result<String,String> {
+    serde_json::to_string(value).map_err(|err| err.to_string())
+}
+
+fn from_json<'a, T: ::serde::Deserialize<'a>>(text: &'a str) -> Result<T,String> {
+    serde_json::from_str(text).map_err(|err| format!("Invalid storage data: {}", err))
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use storage::{Storage, SCHEMA_VERSION};
+    use engine::{Engine, Context};
+    use script::parse_script;
+    use pretty::{format_fn, format_ins};
+    use variable::VarBindingList;
+
+    const SCRIPT: &str = r#"
+// 转动雷达
+ins "move radar"(angle: i32, speed: u32 = 10 in 0..100) = 1000
+ins status(mode: u8) = 0x18FF0010 extended dlc 4
+
+// 扫描一周
+fn scan(n: i32) {
+    // 每次转动n*2度
+    loop 3 {
+        x += n * 2
+        send "move radar"(angle: x, speed: 20)
+    }
+    call report(value: x)
+    return x
+}
+
+fn report(value: i32) {
+    total := value
+}
+"#;
+
+    #[test]
+    fn test_save_load() {
+        let script = parse_script(SCRIPT).expect("ok");
+        let mut storage = Storage::open_in_memory().expect("ok");
+        for insdef in &script.inss {
+            assert_eq!(storage.save_ins(insdef), Ok(1));
+        }
+        for fndef in &script.fns {
+            assert_eq!(storage.save_fn(fndef), Ok(1));
+        }
+
+        // 读出的定义与保存的一致（包括注释和源代码位置）
+        for insdef in &script.inss {
+            let loaded = storage.load_ins(&insdef.name).expect("ok").expect("exists");
+            assert_eq!(format_ins(&loaded), format_ins(insdef));
+        }
+        let scan = storage.load_fn("scan").expect("ok").expect("exists");
+        assert_eq!(format_fn(&scan), format_fn(&script.fns[0]));
+        assert_eq!(scan.stmts[0].pos, script.fns[0].stmts[0].pos);
+        assert_eq!(scan.stmts.iter().map(|stmt| stmt.eval).collect::<Vec<_>>(),
+                   script.fns[0].stmts.iter().map(|stmt| stmt.eval).collect::<Vec<_>>());
+        assert!(storage.load_fn("none").expect("ok").is_none());
+        assert!(storage.load_ins("none").expect("ok").is_none());
+
+        // 没有变化时版本号不变，有变化时加1
+        assert_eq!(storage.save_fn(&script.fns[0]), Ok(1));
+        let mut changed = parse_script("fn scan(n: i32) { return n }").expect("ok").fns.remove(0);
+        assert_eq!(storage.save_fn(&changed), Ok(2));
+        assert_eq!(storage.load_fn("scan").expect("ok").map(|fndef| fndef.stmts.len()), Some(1));
+        changed.note = Some("v3".to_string());
+        assert_eq!(storage.save_fn(&changed), Ok(3));
+
+        let fns = storage.list_fns().expect("ok");
+        assert_eq!(fns.iter().map(|record| (record.name.as_str(), record.version)).collect::<Vec<_>>(),
+                   vec![("report", 1), ("scan", 3)]);
+        assert!(fns.iter().all(|record| record.created > 0 && record.created <= record.updated));
+
+        assert_eq!(storage.remove_fn("report"), Ok(true));
+        assert_eq!(storage.remove_fn("report"), Ok(false));
+        assert_eq!(storage.remove_ins("status"), Ok(true));
+        assert_eq!(storage.load_inss().map(|inss| inss.len()), Ok(1));
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let script = parse_script(SCRIPT).expect("ok");
+        let mut storage = Storage::open_in_memory().expect("ok");
+        storage.save_fn(&script.fns[0]).expect("ok");
+        // 版本1的数据库没有stmts.eval，打开时添加，原有的语句按原样传递参数
+        storage.conn.execute_batch("ALTER TABLE stmts DROP COLUMN eval; PRAGMA user_version = 1").expect("ok");
+        let storage = Storage::init(storage.conn).expect("ok");
+        let scan = storage.load_fn("scan").expect("ok").expect("exists");
+        assert_eq!(scan.stmts.len(), script.fns[0].stmts.len());
+        assert!(scan.stmts.iter().all(|stmt| !stmt.eval));
+        let version: u32 = storage.conn.query_row("PRAGMA user_version", [], |row| row.get(0)).expect("ok");
+        assert_eq!(version, SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn test_load_error() {
+        let script = parse_script(SCRIPT).expect("ok");
+        let mut storage = Storage::open_in_memory().expect("ok");
+        storage.save_fn(&script.fns[0]).expect("ok");
+        storage.conn.execute_batch("UPDATE stmts SET args = 'x' WHERE fn = 'scan'").expect("ok");
+        let mut engine = Engine::new();
+        engine.attach_storage(storage).expect("ok");
+        // 读取失败时返回错误，而不是当作函数不存在
+        let result = engine.exec_fn("scan", &VarBindingList::new(), &mut Context::new());
+        assert!(result.expect_err("invalid").starts_with("Cannot load fn scan: "));
+        assert!(engine.compile("scan").err().expect("invalid").starts_with("Cannot load fn scan: "));
+    }
+
+    #[test]
+    fn test_search() {
+        let script = parse_script(SCRIPT).expect("ok");
+        let mut storage = Storage::open_in_memory().expect("ok");
+        for insdef in &script.inss {
+            storage.save_ins(insdef).expect("ok");
+        }
+        for fndef in &script.fns {
+            storage.save_fn(fndef).expect("ok");
+        }
+        let names = |records: Result<Vec<::storage::Record>,String>| {
+            records.expect("ok").into_iter().map(|record| record.name).collect::<Vec<_>>()
+        };
+        assert_eq!(names(storage.list_inss()), vec!["move radar", "status"]);
+        assert_eq!(names(storage.search_inss("RADAR")), vec!["move radar"]);
+        assert_eq!(names(storage.search_inss("雷达")), vec!["move radar"]); // note
+        assert_eq!(names(storage.search_fns("扫描")), vec!["scan"]);
+        assert_eq!(names(storage.search_fns("r")), vec!["report"]);
+        assert_eq!(names(storage.search_fns("%")), Vec::<String>::new());
+        assert_eq!(names(storage.search_fns("")), vec!["report", "scan"]);
+    }
+
+    #[test]
+    fn test_lazy_load() {
+        let path = ::std::env::temp_dir().join(format!("logic-storage-{}.db", ::std::process::id()));
+        let path = path.to_str().expect("utf8");
+        let _ = ::std::fs::remove_file(path);
+        {
+            let mut engine = Engine::new();
+            engine.load_script(SCRIPT, &mut Context::new()).expect("ok");
+            let mut storage = Storage::open(path).expect("ok");
+            engine.save_to_storage(&mut storage).expect("ok");
+        }
+
+        let mut engine = Engine::new();
+        assert!(engine.find_fn("scan").expect("ok").is_none());
+        engine.attach_storage(Storage::open(path).expect("ok")).expect("ok");
+        assert_eq!(engine.inss.len(), 2); // 指令全部加载
+        assert!(engine.fns.is_empty()); // 函数在首次使用时加载
+        let mut context = Context::new();
+        let mut args = VarBindingList::new();
+        args.set_binding("n", "int:1");
+        args.set_binding("x", "int:0");
+        engine.exec_fn("scan", &args, &mut context).expect("ok");
+        assert_eq!(context.globals.raw_value_of("total"), Some("int:6"));
+        assert_eq!(context.globals.raw_value_of("$return"), Some("int:6"));
+        assert!(engine.find_fn("report").expect("ok").is_some());
+        assert!(engine.find_fn("none").expect("ok").is_none());
+        drop(engine);
+
+        // 不支持更新版本的数据库
+        let conn = ::rusqlite::Connection::open(path).expect("ok");
+        conn.execute_batch(&format!("PRAGMA user_version = {}", SCHEMA_VERSION + 1)).expect("ok");
+        drop(conn);
+        assert_eq!(Storage::open(path).err(), Some(format!("Unsupported storage version: {}", SCHEMA_VERSION + 1)));
+        let _ = ::std::fs::remove_file(path);
+    }
+}
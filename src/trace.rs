@@ -0,0 +1,489 @@
+// 执行跟踪和重放：记录执行的语句、变量的写入和收发的数据帧，保存为跟踪文件；
+// 重放时按跟踪中接收的帧重新执行，检查执行过程是否与记录的完全相同
+//
+// 跟踪由FnDef::exec()（解释执行）记录，编译后的Program不记录
+//
+// 跟踪文件为文本，每行一条记录，字段以tab分隔（转义见utils::escape_field()）：
+//   logic-trace 1                文件头和版本
+//   F  main                      执行的函数
+//   A  n  int:1                  函数参数
+//   G  total  int:0              执行前的全局变量
+//   S  main  3  CallIns          执行语句：函数、索引、类型
+//   W  L  x  int:1  int:2        写入变量：L局部/G全局、名称、旧值、新值（空为不存在）
+//   T  1520  321#0102  [错误]    发送的帧：时间（微秒，从开始执行起）、帧、发送失败时的错误
+//   R  2010  7E8#0262            接收的帧；'-'为超时，'!'之后为错误
+//   E  ok | E  err  错误         执行结果
+
+use engine::{Engine, Context};
+use statement::StmtKind;
+use variable::VarBindingList;
+use bus::{Bus, Frame, format_frame, parse_frame};
+use utils::{escape_field, unescape_field};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const HEADER: &str = "logic-trace";
+const VERSION: u32 = 1;
+
+/// 跟踪中的一条记录
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceEvent {
+    /// 执行语句，index为语句在函数中的索引（从0开始）
+    Stmt { fn_name: String, index: usize, kind: StmtKind },
+    /// 写入变量；global为false时为局部变量；值为空表示变量不存在
+    Write { global: bool, name: String, old: String, new: String },
+    /// 发送的帧，time为从开始执行起的时间
+    Send { time: Duration, frame: Frame, result: Result<(), String> },
+    /// 接收的帧，Ok(None)为超时
+    Recv { time: Duration, result: Result<Option<Frame>, String> },
+}
+
+/// 一次执行的跟踪，由record()生成
+#[derive(Debug, Clone)]
+pub struct Trace {
+    pub fn_name: String,
+    pub args: VarBindingList,
+    /// 执行前的全局变量
+    pub globals: VarBindingList,
+    pub events: Vec<TraceEvent>,
+    pub result: Result<(), String>,
+}
+
+/// 记录中的跟踪，见Context.tracer
+pub struct Tracer {
+    trace: Trace,
+    start: Instant,
+    // 被记录的总线
+    bus: Option<Box<dyn Bus>>,
+}
+
+impl Tracer {
+    pub fn push(&mut self, event: TraceEvent) {
+        self.trace.events.push(event);
+    }
+}
+
+// 记录收发的帧，再转给被记录的总线
+struct TraceBus {
+    tracer: Rc<RefCell<Tracer>>,
+}
+
+impl TraceBus {
+    // 从开始执行起的时间，精确到微秒（与跟踪文件相同）
+    fn elapsed(&self) -> Duration {
+        Duration::from_micros(self.tracer.borrow().start.elapsed().as_micros() as u64)
+    }
+}
+
+impl Bus for TraceBus {
+    fn send(&mut self, frame: &Frame) -> Result<(), String> {
+        let result = match self.tracer.borrow_mut().bus {
+            Some(ref mut bus) => bus.send(frame),
+            None => Ok(()),
+        };
+        let time = self.elapsed();
+        self.tracer.borrow_mut().push(TraceEvent::Send { time, frame: frame.clone(), result: result.clone() });
+        result
+    }
+
+    fn recv(&mut self, timeout: Duration) -> Result<Option<Frame>, String> {
+        let result = match self.tracer.borrow_mut().bus {
+            Some(ref mut bus) => bus.recv(timeout),
+            None => Ok(None),
+        };
+        let time = self.elapsed();
+        self.tracer.borrow_mut().push(TraceEvent::Recv { time, result: result.clone() });
+        result
+    }
+}
+
+// 重放跟踪中收发的结果；超时按请求的时间等待，使依赖时间的逻辑（如诊断的P2超时）与记录时相同
+struct ReplayBus {
+    sends: VecDeque<Result<(), String>>,
+    recvs: VecDeque<Result<Option<Frame>, String>>,
+}
+
+impl Bus for ReplayBus {
+    fn send(&mut self, frame: &Frame) -> Result<(), String> {
+        self.sends.pop_front().unwrap_or(Ok(()))
+    }
+
+    fn recv(&mut self, timeout: Duration) -> Result<Option<Frame>, String> {
+        let result = self.recvs.pop_front().unwrap_or_else(|| Err("No more recorded frames to replay".to_string()));
+        if let Ok(None) = result {
+            thread::sleep(timeout);
+        }
+        result
+    }
+}
+
+/// 执行函数并记录跟踪；执行期间context.bus的收发被记录，执行后恢复
+pub fn record(engine: &Engine, name: &str, args: &VarBindingList, context: &mut Context) -> Trace {
+    let trace = Trace {
+        fn_name: name.to_string(),
+        args: args.clone(),
+        globals: context.globals.clone(),
+        events: Vec::new(),
+        result: Ok(()),
+    };
+    let tracer = Rc::new(RefCell::new(Tracer { trace, start: Instant::now(), bus: context.bus.take() }));
+    context.bus = Some(Box::new(TraceBus { tracer: tracer.clone() }));
+    context.tracer = Some(tracer.clone());
+    let result = engine.exec_fn(name, args, context);
+    context.tracer = None;
+    context.bus = None;
+    let tracer = Rc::try_unwrap(tracer).ok().expect("tracer is not shared after execution").into_inner();
+    context.bus = tracer.bus;
+    Trace { result, ..tracer.trace }
+}
+
+/// 按跟踪重新执行：全局变量恢复为执行前的值，收发的结果来自跟踪（不使用context.bus），
+/// 比较执行的语句、变量的写入、收发的帧（不比较时间）和执行结果；一致时返回比较的记录数，否则返回第一处不同
+pub fn replay(engine: &Engine, trace: &Trace, context: &mut Context) -> Result<usize, String> {
+    let mut bus = ReplayBus { sends: VecDeque::new(), recvs: VecDeque::new() };
+    for event in &trace.events {
+        match *event {
+            TraceEvent::Send { ref result, .. } => bus.sends.push_back(result.clone()),
+            TraceEvent::Recv { ref result, .. } => bus.recvs.push_back(result.clone()),
+            _ => {}
+        }
+    }
+    let saved = context.bus.replace(Box::new(bus));
+    context.globals = trace.globals.clone();
+    context.counters.clear();
+    let replayed = record(engine, &trace.fn_name, &trace.args, context);
+    context.bus = saved;
+
+    for (index, expected) in trace.events.iter().enumerate() {
+        let found = match replayed.events.get(index) {
+            Some(found) => found,
+            None => return Err(format!("Replay diverges at event {}: expected {}, found end of trace", index + 1, format_event(expected, false))),
+        };
+        if untimed(found) != untimed(expected) {
+            return Err(format!("Replay diverges at event {}: expected {}, found {}",
+                               index + 1, format_event(expected, false), format_event(found, false)));
+        }
+    }
+    if let Some(found) = replayed.events.get(trace.events.len()) {
+        return Err(format!("Replay diverges at event {}: expected end of trace, found {}", trace.events.len() + 1, format_event(found, false)));
+    }
+    if replayed.result != trace.result {
+        return Err(format!("Replay result differs: expected {}, found {}", format_result(&trace.result), format_result(&replayed.result)));
+    }
+    Ok(trace.events.len())
+}
+
+/// 比较执行前后的变量表，得到写入变量的记录（按名称排序）
+pub fn writes(global: bool, before: &VarBindingList, after: &VarBindingList) -> Vec<TraceEvent> {
+    let mut names: Vec<&String> = before.bindings.keys().chain(after.bindings.keys()).collect();
+    names.sort();
+    names.dedup();
+    names.into_iter().filter_map(|name| {
+        let old = before.raw_value_of(name).unwrap_or("");
+        let new = after.raw_value_of(name).unwrap_or("");
+        if old == new {
+            return None;
+        }
+        Some(TraceEvent::Write { global, name: name.clone(), old: old.to_string(), new: new.to_string() })
+    }).collect()
+}
+
+fn untimed(event: &TraceEvent) -> TraceEvent {
+    let mut event = event.clone();
+    match event {
+        TraceEvent::Send { ref mut time, .. } | TraceEvent::Recv { ref mut time, .. } => *time = Duration::default(),
+        _ => {}
+    }
+    event
+}
+
+fn line(fields: &[&str]) -> String {
+    fields.iter().map(|field| escape_field(field)).collect::<Vec<String>>().join("\t")
+}
+
+fn format_result(result: &Result<(), String>) -> String {
+    match *result {
+        Ok(()) => "ok".to_string(),
+        Err(ref err) => format!("err: {}", err),
+    }
+}
+
+// 跟踪文件中的一行；with_time为false时不显示时间（用于显示不同之处）
+fn format_event(event: &TraceEvent, with_time: bool) -> String {
+    let fields = match *event {
+        TraceEvent::Stmt { ref fn_name, index, kind } => vec!["S".to_string(), fn_name.clone(), index.to_string(), kind.name().to_string()],
+        TraceEvent::Write { global, ref name, ref old, ref new } => {
+            vec!["W".to_string(), if global { "G" } else { "L" }.to_string(), name.clone(), old.clone(), new.clone()]
+        }
+        TraceEvent::Send { ref time, ref frame, ref result } => {
+            let mut fields = vec!["T".to_string(), format_time(time, with_time), format_frame(frame)];
+            if let Err(ref err) = *result {
+                fields.push(err.clone());
+            }
+            fields
+        }
+        TraceEvent::Recv { ref time, ref result } => {
+            let mut fields = vec!["R".to_string(), format_time(time, with_time)];
+            match *result {
+                Ok(Some(ref frame)) => fields.push(format_frame(frame)),
+                Ok(None) => fields.push("-".to_string()),
+                Err(ref err) => fields.extend(vec!["!".to_string(), err.clone()]),
+            }
+            fields
+        }
+    };
+    line(&fields.iter().map(|field| field.as_str()).collect::<Vec<&str>>())
+}
+
+// 时间为微秒；不含时间时显示为'*'
+fn format_time(time: &Duration, with_time: bool) -> String {
+    if with_time { time.as_micros().to_string() } else { "*".to_string() }
+}
+
+impl Trace {
+    /// 转换为跟踪文件的文本
+    pub fn to_text(&self) -> String {
+        let mut text = format!("{}\t{}\n", HEADER, VERSION);
+        text.push_str(&line(&["F", &self.fn_name]));
+        text.push('\n');
+        for (tag, bindings) in &[("A", &self.args), ("G", &self.globals)] {
+            let mut names: Vec<&String> = bindings.bindings.keys().collect();
+            names.sort();
+            for name in names {
+                text.push_str(&line(&[tag, name, bindings.raw_value_of(name).unwrap_or("")]));
+                text.push('\n');
+            }
+        }
+        for event in &self.events {
+            text.push_str(&format_event(event, true));
+            text.push('\n');
+        }
+        text.push_str(&match self.result {
+            Ok(()) => line(&["E", "ok"]),
+            Err(ref err) => line(&["E", "err", err]),
+        });
+        text.push('\n');
+        text
+    }
+
+    /// 解析跟踪文件
+    pub fn parse(text: &str) -> Result<Trace, String> {
+        let mut lines = text.lines().enumerate();
+        match lines.next().map(|(_, header)| header.split('\t').collect::<Vec<&str>>()) {
+            Some(ref header) if header.len() == 2 && header[0] == HEADER => {
+                if header[1] != VERSION.to_string() {
+                    return Err(format!("Unsupported trace version: {}", header[1]));
+                }
+            }
+            _ => return Err("Not a trace file".to_string()),
+        }
+        let mut trace = Trace {
+            fn_name: String::new(),
+            args: VarBindingList::new(),
+            globals: VarBindingList::new(),
+            events: Vec::new(),
+            result: Ok(()),
+        };
+        let mut ended = false;
+        for (number, text) in lines {
+            if text.is_empty() {
+                continue;
+            }
+            let fields: Vec<String> = text.split('\t').map(unescape_field).collect();
+            let fields: Vec<&str> = fields.iter().map(|field| field.as_str()).collect();
+            let error = || format!("Invalid trace line {}: {}", number + 1, text);
+            if ended {
+                return Err(error());
+            }
+            let time = |text: &str| text.parse::<u64>().map(Duration::from_micros).map_err(|_| error());
+            let frame = |text: &str| parse_frame(text).map_err(|err| format!("Invalid trace line {}: {}", number + 1, err));
+            match fields[..] {
+                ["F", name] => trace.fn_name = name.to_string(),
+                ["A", name, value] => trace.args.set_binding(name, value),
+                ["G", name, value] => trace.globals.set_binding(name, value),
+                ["S", fn_name, index, kind] => trace.events.push(TraceEvent::Stmt {
+                    fn_name: fn_name.to_string(),
+                    index: index.parse().map_err(|_| error())?,
+                    kind: StmtKind::from_name(kind).ok_or_else(error)?,
+                }),
+                ["W", scope @ ("L" | "G"), name, old, new] => trace.events.push(TraceEvent::Write {
+                    global: scope == "G",
+                    name: name.to_string(),
+                    old: old.to_string(),
+                    new: new.to_string(),
+                }),
+                ["T", at, data] => trace.events.push(TraceEvent::Send { time: time(at)?, frame: frame(data)?, result: Ok(()) }),
+                ["T", at, data, err] => trace.events.push(TraceEvent::Send { time: time(at)?, frame: frame(data)?, result: Err(err.to_string()) }),
+                ["R", at, "-"] => trace.events.push(TraceEvent::Recv { time: time(at)?, result: Ok(None) }),
+                ["R", at, "!", err] => trace.events.push(TraceEvent::Recv { time: time(at)?, result: Err(err.to_string()) }),
+                ["R", at, data] => trace.events.push(TraceEvent::Recv { time: time(at)?, result: Ok(Some(frame(data)?)) }),
+                ["E", "ok"] => ended = true,
+                ["E", "err", err] => {
+                    trace.result = Err(err.to_string());
+                    ended = true;
+                }
+                _ => return Err(error()),
+            }
+        }
+        if !ended {
+            return Err("Incomplete trace: missing result".to_string());
+        }
+        if trace.fn_name.is_empty() {
+            return Err("Incomplete trace: missing fn".to_string());
+        }
+        Ok(trace)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use trace::{record, replay, Trace, TraceEvent};
+    use engine::{Engine, Context};
+    use statement::StmtKind;
+    use variable::VarBindingList;
+    use bus::{Bus, Frame};
+    use uds::UdsClient;
+    use std::collections::VecDeque;
+    use std::time::Duration;
+
+    const SCRIPT: &str = r#"
+ins lamp(a: u32, b: u32 = 1) = 0x321
+
+fn main(n: i32) {
+    total := 0
+    loop 2 {
+        send lamp(a: n)
+        total += n
+    }
+    diag ReadDataByIdentifier(did: 0xF190, result: "vin")
+    return total
+}
+"#;
+
+    // 按顺序返回预先设定的帧，不再有帧时超时
+    struct ScriptedBus {
+        replies: VecDeque<Frame>,
+    }
+
+    impl Bus for ScriptedBus {
+        fn send(&mut self, frame: &Frame) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn recv(&mut self, timeout: Duration) -> Result<Option<Frame>, String> {
+            Ok(self.replies.pop_front())
+        }
+    }
+
+    fn setup(script: &str) -> (Engine, Context) {
+        let mut engine = Engine::new();
+        let mut context = Context::new();
+        engine.load_script(script, &mut context).expect("ok");
+        context.uds = Some(UdsClient::new(0x7E0, 0x7E8));
+        (engine, context)
+    }
+
+    fn record_main() -> Trace {
+        let (engine, mut context) = setup(SCRIPT);
+        context.bus = Some(Box::new(ScriptedBus { replies: vec![Frame::new(0x7E8, &[0x05, 0x62, 0xF1, 0x90, 0x41, 0x42])].into() }));
+        let mut args = VarBindingList::new();
+        args.set_binding("n", "int:3");
+        let trace = record(&engine, "main", &args, &mut context);
+        assert!(context.bus.is_some() && context.tracer.is_none());
+        assert_eq!(context.globals.raw_value_of("$return"), Some("int:6"));
+        trace
+    }
+
+    fn stmt(index: usize, kind: StmtKind) -> TraceEvent {
+        TraceEvent::Stmt { fn_name: "main".to_string(), index, kind }
+    }
+
+    fn write(name: &str, old: &str, new: &str) -> TraceEvent {
+        TraceEvent::Write { global: true, name: name.to_string(), old: old.to_string(), new: new.to_string() }
+    }
+
+    #[test]
+    fn test_record() {
+        let trace = record_main();
+        assert_eq!(trace.result, Ok(()));
+        let events: Vec<TraceEvent> = trace.events.iter().map(|event| match *event {
+            TraceEvent::Send { ref frame, ref result, .. } => TraceEvent::Send { time: Duration::default(), frame: frame.clone(), result: result.clone() },
+            TraceEvent::Recv { ref result, .. } => TraceEvent::Recv { time: Duration::default(), result: result.clone() },
+            ref event => event.clone(),
+        }).collect();
+        let send = |data: &[u8]| TraceEvent::Send { time: Duration::default(), frame: Frame::new(0x321, data), result: Ok(()) };
+        assert_eq!(&events[..10], &[
+            stmt(0, StmtKind::SetVar),
+            write("total", "", "int:0"),
+            stmt(1, StmtKind::Loop),
+            stmt(2, StmtKind::CallIns),
+            send(&[0, 0, 0, 3, 0, 0, 0, 1]),
+            stmt(3, StmtKind::SetVar),
+            write("total", "int:0", "int:3"),
+            stmt(4, StmtKind::EndLoop),
+            stmt(1, StmtKind::Loop),
+            stmt(2, StmtKind::CallIns),
+        ]);
+        assert_eq!(&events[events.len() - 5..], &[
+            TraceEvent::Send { time: Duration::default(), frame: Frame::new(0x7E0, &[0x03, 0x22, 0xF1, 0x90, 0xCC, 0xCC, 0xCC, 0xCC]), result: Ok(()) },
+            TraceEvent::Recv { time: Duration::default(), result: Ok(Some(Frame::new(0x7E8, &[0x05, 0x62, 0xF1, 0x90, 0x41, 0x42]))) },
+            write("vin", "", "hex:41 42"),
+            stmt(6, StmtKind::Return),
+            write("$return", "", "int:6"),
+        ][..]);
+    }
+
+    #[test]
+    fn test_text() {
+        let mut trace = record_main();
+        trace.globals.set_binding("note", "str:a\tb\\c\nd");
+        trace.events.push(TraceEvent::Recv { time: Duration::from_micros(7), result: Ok(None) });
+        trace.events.push(TraceEvent::Recv { time: Duration::from_micros(8), result: Err("bus off".to_string()) });
+        trace.events.push(TraceEvent::Send { time: Duration::from_micros(9), frame: Frame::new(0x100, &[]), result: Err("bus off".to_string()) });
+        trace.result = Err("failed".to_string());
+        let text = trace.to_text();
+        assert!(text.starts_with("logic-trace\t1\nF\tmain\nA\tn\tint:3\nG\tnote\tstr:a\\tb\\\\c\\nd\nS\tmain\t0\tSetVar\nW\tG\ttotal\t\tint:0\n"));
+        assert!(text.ends_with("R\t7\t-\nR\t8\t!\tbus off\nT\t9\t100#\tbus off\nE\terr\tfailed\n"));
+        let parsed = Trace::parse(&text).expect("ok");
+        assert_eq!(parsed.events, trace.events);
+        assert_eq!(parsed.globals.raw_value_of("note"), Some("str:a\tb\\c\nd"));
+        assert_eq!(parsed.to_text(), text);
+
+        assert_eq!(Trace::parse("logic-trace\t2\n").err(), Some("Unsupported trace version: 2".to_string()));
+        assert_eq!(Trace::parse("candump\n").err(), Some("Not a trace file".to_string()));
+        assert_eq!(Trace::parse("logic-trace\t1\nF\tmain\n").err(), Some("Incomplete trace: missing result".to_string()));
+        assert_eq!(Trace::parse("logic-trace\t1\nF\tmain\nS\tmain\tx\tLoop\nE\tok\n").err(), Some("Invalid trace line 3: S\tmain\tx\tLoop".to_string()));
+        assert_eq!(Trace::parse("logic-trace\t1\nF\tmain\nT\t1\t321#0\nE\tok\n").err(), Some("Invalid trace line 3: Invalid frame: 321#0".to_string()));
+        assert!(Trace::parse("logic-trace\t1\nF\tmain\nE\tok\nS\tmain\t0\tLoop\n").is_err());
+    }
+
+    #[test]
+    fn test_replay() {
+        let trace = record_main();
+        let (engine, mut context) = setup(SCRIPT);
+        assert_eq!(replay(&engine, &trace, &mut context), Ok(trace.events.len()));
+        assert!(context.bus.is_none());
+        assert_eq!(context.globals.raw_value_of("vin"), Some("hex:41 42"));
+
+        // 接收的帧不同
+        let mut changed = trace.clone();
+        for event in changed.events.iter_mut() {
+            if let TraceEvent::Recv { ref mut result, .. } = *event {
+                *result = Ok(Some(Frame::new(0x7E8, &[0x05, 0x62, 0xF1, 0x90, 0x41, 0x43])));
+            }
+        }
+        let (engine, mut context) = setup(SCRIPT);
+        let err = replay(&engine, &changed, &mut context).unwrap_err();
+        assert!(err.ends_with(": expected W\tG\tvin\t\thex:41 42, found W\tG\tvin\t\thex:41 43"), "{}", err);
+
+        // 脚本不同
+        let (engine, mut context) = setup(&SCRIPT.replace("send lamp(a: n)", "send lamp(a: 1)"));
+        assert_eq!(replay(&engine, &trace, &mut context), Err("Replay diverges at event 5: expected T\t*\t321#0000000300000001, found T\t*\t321#0000000100000001".to_string()));
+        let (engine, mut context) = setup(&SCRIPT.replace("return total", "done := 1\n    return total"));
+        let err = replay(&engine, &trace, &mut context).unwrap_err();
+        assert!(err.ends_with(": expected S\tmain\t6\tReturn, found S\tmain\t6\tSetVar"), "{}", err);
+    }
+}
@@ -0,0 +1,414 @@
+// UDS (ISO 14229) 诊断服务，基于ISO-TP收发请求和响应
+// 脚本中通过StmtKind::Diag语句调用，见exec_diag()
+
+use bus::Bus;
+use cancel::CancelBus;
+use isotp::IsoTp;
+use engine::Context;
+use statement::Stmt;
+use variable::VarBindingList;
+use utils::{to_hex, parse_hex, parse_int, split_lr};
+use std::time::Duration;
+
+pub const SID_SESSION_CONTROL: u8 = 0x10;
+pub const SID_ECU_RESET: u8 = 0x11;
+pub const SID_READ_DID: u8 = 0x22;
+pub const SID_SECURITY_ACCESS: u8 = 0x27;
+pub const SID_WRITE_DID: u8 = 0x2E;
+pub const SID_ROUTINE_CONTROL: u8 = 0x31;
+const NEGATIVE_RESPONSE: u8 = 0x7F;
+const POSITIVE_RESPONSE_OFFSET: u8 = 0x40;
+/// 否定响应码：请求已收到，响应稍后发送
+pub const NRC_RESPONSE_PENDING: u8 = 0x78;
+
+/// UDS响应
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    /// 肯定响应，不含响应SID
+    Positive(Vec<u8>),
+    /// 否定响应码(NRC)
+    Negative(u8),
+}
+
+/// 否定响应码的名称
+pub fn nrc_name(nrc: u8) -> &'static str {
+    match nrc {
+        0x10 => "generalReject",
+        0x11 => "serviceNotSupported",
+        0x12 => "subFunctionNotSupported",
+        0x13 => "incorrectMessageLengthOrInvalidFormat",
+        0x14 => "responseTooLong",
+        0x21 => "busyRepeatRequest",
+        0x22 => "conditionsNotCorrect",
+        0x24 => "requestSequenceError",
+        0x31 => "requestOutOfRange",
+        0x33 => "securityAccessDenied",
+        0x35 => "invalidKey",
+        0x36 => "exceedNumberOfAttempts",
+        0x37 => "requiredTimeDelayNotExpired",
+        0x72 => "generalProgrammingFailure",
+        0x78 => "requestCorrectlyReceived-ResponsePending",
+        0x7E => "subFunctionNotSupportedInActiveSession",
+        0x7F => "serviceNotSupportedInActiveSession",
+        _ => "unknown",
+    }
+}
+
+/// SecurityAccess的密钥算法：(level, seed) -> key
+pub type KeyFn = Box<dyn Fn(u8, &[u8]) -> Vec<u8>>;
+
+/// UDS客户端（诊断仪），通过isotp与一个ECU通信
+pub struct UdsClient {
+    pub isotp: IsoTp,
+    /// 等待响应的超时(P2)
+    pub p2: Duration,
+    /// 收到NRC 0x78(response pending)后等待响应的超时(P2*)
+    pub p2_star: Duration,
+    /// 最多接受多少次连续的NRC 0x78
+    pub max_pending: u32,
+    pub key_fn: Option<KeyFn>,
+}
+
+impl UdsClient {
+    pub fn new(tx_id: u32, rx_id: u32) -> UdsClient {
+        UdsClient {
+            isotp: IsoTp::new(tx_id, rx_id),
+            p2: Duration::from_millis(150),
+            p2_star: Duration::from_millis(5000),
+            max_pending: 20,
+            key_fn: None,
+        }
+    }
+
+    /// 发送请求并等待响应；NRC 0x78会被自动处理（继续等待最终响应）
+    pub fn request(&self, bus: &mut dyn Bus, request: &[u8]) -> Result<Response, String> {
+        let sid = match request.first() {
+            Some(sid) => *sid,
+            None => return Err("Empty UDS request".to_string()),
+        };
+        self.isotp.send(bus, request)?;
+        let mut timeout = self.p2;
+        let mut pending = 0;
+        loop {
+            let response = self.isotp.recv_within(bus, timeout)
+                .map_err(|err| format!("No response to UDS service {:02X}: {}", sid, err))?;
+            if response.len() >= 3 && response[0] == NEGATIVE_RESPONSE && response[1] == sid {
+                if response[2] != NRC_RESPONSE_PENDING {
+                    return Ok(Response::Negative(response[2]));
+                }
+                pending += 1;
+                if pending > self.max_pending {
+                    return Err(format!("Too many pending responses to UDS service {:02X}", sid));
+                }
+                timeout = self.p2_star;
+                continue;
+            }
+            if response.first() == Some(&sid.wrapping_add(POSITIVE_RESPONSE_OFFSET)) {
+                return Ok(Response::Positive(response[1..].to_vec()));
+            }
+            return Err(format!("Unexpected response to UDS service {:02X}: {}", sid, to_hex(&response)));
+        }
+    }
+
+    // 发送请求；肯定响应须以echo开头（回显的子功能或标识符），返回的肯定响应数据去掉了echo
+    fn service(&self, bus: &mut dyn Bus, request: &[u8], echo_len: usize) -> Result<Response, String> {
+        match self.request(bus, request)? {
+            Response::Positive(data) => {
+                if data.len() < echo_len || data[..echo_len] != request[1..echo_len + 1] {
+                    return Err(format!("Invalid response to UDS service {:02X}: {}", request[0], to_hex(&data)));
+                }
+                Ok(Response::Positive(data[echo_len..].to_vec()))
+            }
+            negative => Ok(negative),
+        }
+    }
+
+    /// DiagnosticSessionControl (0x10)，肯定响应为会话参数记录(P2, P2*)
+    pub fn session_control(&self, bus: &mut dyn Bus, session: u8) -> Result<Response, String> {
+        self.service(bus, &[SID_SESSION_CONTROL, session], 1)
+    }
+
+    /// ECUReset (0x11)
+    pub fn ecu_reset(&self, bus: &mut dyn Bus, reset_type: u8) -> Result<Response, String> {
+        self.service(bus, &[SID_ECU_RESET, reset_type], 1)
+    }
+
+    /// ReadDataByIdentifier (0x22)，肯定响应为数据记录
+    pub fn read_did(&self, bus: &mut dyn Bus, did: u16) -> Result<Response, String> {
+        self.service(bus, &[SID_READ_DID, (did >> 8) as u8, did as u8], 2)
+    }
+
+    /// WriteDataByIdentifier (0x2E)
+    pub fn write_did(&self, bus: &mut dyn Bus, did: u16, data: &[u8]) -> Result<Response, String> {
+        let mut request = vec![SID_WRITE_DID, (did >> 8) as u8, did as u8];
+        request.extend_from_slice(data);
+        self.service(bus, &request, 2)
+    }
+
+    /// RoutineControl (0x31)，control_type: 1=start, 2=stop, 3=request results；肯定响应为状态记录
+    pub fn routine_control(&self, bus: &mut dyn Bus, control_type: u8, routine: u16, data: &[u8])
+                           -> Result<Response, String> {
+        let mut request = vec![SID_ROUTINE_CONTROL, control_type, (routine >> 8) as u8, routine as u8];
+        request.extend_from_slice(data);
+        self.service(bus, &request, 3)
+    }
+
+    /// SecurityAccess (0x27)：用level(0x01..=0x7D之间的奇数)请求种子，再发送密钥(level+1)
+    /// 密钥取参数key，没有时用key_fn计算；种子全为0表示已经解锁
+    pub fn security_access(&self, bus: &mut dyn Bus, level: u8, key: Option<&[u8]>) -> Result<Response, String> {
+        if level.is_multiple_of(2) || level > 0x7D {
+            return Err(format!("Invalid security access level: {}", level));
+        }
+        let seed = match self.service(bus, &[SID_SECURITY_ACCESS, level], 1)? {
+            Response::Positive(seed) => seed,
+            negative => return Ok(negative),
+        };
+        if seed.iter().all(|b| *b == 0) {
+            return Ok(Response::Positive(Vec::new()));
+        }
+        let key = match (key, self.key_fn.as_ref()) {
+            (Some(key), _) => key.to_vec(),
+            (None, Some(key_fn)) => key_fn(level, &seed),
+            (None, None) => return Err("No key for security access".to_string()),
+        };
+        let mut request = vec![SID_SECURITY_ACCESS, level + 1];
+        request.extend_from_slice(&key);
+        self.service(bus, &request, 1)
+    }
+}
+
+/// 执行诊断语句；stmt.content为服务名称，stmt.args为服务参数：
+/// DiagnosticSessionControl: session
+/// ECUReset: type
+/// SecurityAccess: level, key(可选)
+/// ReadDataByIdentifier: did
+/// WriteDataByIdentifier: did, data
+/// RoutineControl: routine, type(默认1), data(可选)
+/// 参数值可以引用变量('var:name')；data和key为十六进制文本，如'hex:01 02'
+/// 肯定响应的数据以'hex:..'形式存入全局变量（变量名由参数result指定，默认为$diag）；
+/// 否定响应码存入全局变量$nrc，并返回错误
+pub fn exec_diag(stmt: &Stmt, locals: &VarBindingList, context: &mut Context) -> Result<(), String> {
+    let service = stmt.content.as_str();
+    let mut args = VarBindingList::new();
+    for name in stmt.args.bindings.keys() {
+        let value = stmt.args.raw_value_of(name).and_then(|value| locals.eval(value, Some(&context.globals), None));
+        if let Some(value) = value {
+            args.set_binding(name, &value);
+        }
+    }
+    let int_arg = |name: &str| -> Result<u64, String> {
+        match args.raw_value_of(name) {
+            Some(value) => parse_int(value),
+            None => Err(format!("{} requires arg: {}", service, name)),
+        }
+    };
+    let byte_arg = |name: &str| -> Result<u8, String> {
+        let value = int_arg(name)?;
+        if value > 0xFF { Err(format!("Invalid arg {}: {}", name, value)) } else { Ok(value as u8) }
+    };
+    let id_arg = |name: &str| -> Result<u16, String> {
+        let value = int_arg(name)?;
+        if value > 0xFFFF { Err(format!("Invalid arg {}: {}", name, value)) } else { Ok(value as u16) }
+    };
+    let hex_arg = |name: &str| -> Result<Option<Vec<u8>>, String> {
+        args.raw_value_of(name).map_or(Ok(None), |value| parse_hex(value).map(Some))
+    };
+    let result_var = args.raw_value_of("result").map_or("$diag", |name| split_lr(name, "str:").1);
+
+    let response = {
+        let uds = match context.uds {
+            Some(ref uds) => uds,
+            None => return Err("No UDS client in context".to_string()),
+        };
+        let bus = match context.bus {
+            Some(ref mut bus) => bus.as_mut(),
+            None => return Err("No bus in context".to_string()),
+        };
+        let bus = &mut CancelBus::new(bus, context.cancel.as_ref());
+        match service {
+            "DiagnosticSessionControl" => uds.session_control(bus, byte_arg("session")?)?,
+            "ECUReset" => uds.ecu_reset(bus, byte_arg("type")?)?,
+            "SecurityAccess" => {
+                let key = hex_arg("key")?;
+                uds.security_access(bus, byte_arg("level")?, key.as_ref().map(|key| &key[..]))?
+            }
+            "ReadDataByIdentifier" => uds.read_did(bus, id_arg("did")?)?,
+            "WriteDataByIdentifier" => {
+                let data = hex_arg("data")?.ok_or_else(|| format!("{} requires arg: data", service))?;
+                uds.write_did(bus, id_arg("did")?, &data)?
+            }
+            "RoutineControl" => {
+                let control_type = if args.contains("type") { byte_arg("type")? } else { 1 };
+                let data = hex_arg("data")?.unwrap_or_default();
+                uds.routine_control(bus, control_type, id_arg("routine")?, &data)?
+            }
+            _ => return Err(format!("Unsupport diag service: {}", service)),
+        }
+    };
+
+    match response {
+        Response::Positive(data) => {
+            context.globals.remove_binding("$nrc");
+            context.globals.set_binding(result_var, &format!("hex:{}", to_hex(&data)));
+            Ok(())
+        }
+        Response::Negative(nrc) => {
+            context.globals.remove_binding(result_var);
+            context.globals.set_binding("$nrc", &format!("int:{}", nrc));
+            Err(format!("{} negative response: NRC {:02X} ({})", service, nrc, nrc_name(nrc)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{UdsClient, Response, NRC_RESPONSE_PENDING};
+    use bus::LoopbackBus;
+    use isotp::IsoTp;
+    use engine::{Engine, Context};
+    use function::FnDef;
+    use statement::Stmt;
+    use variable::VarBindingList;
+    use std::collections::HashMap;
+    use std::thread;
+
+    const SEED: [u8; 2] = [0x12, 0x34];
+
+    fn key_of(seed: &[u8]) -> Vec<u8> {
+        seed.iter().map(|b| b ^ 0xFF).collect()
+    }
+
+    // 模拟ECU：在单独的线程中运行，直到对端断开
+    fn spawn_ecu(mut bus: LoopbackBus) {
+        thread::spawn(move || {
+            let isotp = IsoTp::new(0x7E8, 0x7E0);
+            let mut dids: HashMap<u16, Vec<u8>> = HashMap::new();
+            dids.insert(0xF190, b"WVWZZZ1JZXW000001".to_vec());
+            let mut unlocked = false;
+            while let Ok(request) = isotp.recv(&mut bus) {
+                let sid = request[0];
+                let negative = |nrc: u8| vec![0x7F, sid, nrc];
+                let mut positive = vec![sid + 0x40];
+                let response = match sid {
+                    0x10 => { positive.extend_from_slice(&[request[1], 0x00, 0x32, 0x01, 0xF4]); positive }
+                    0x11 => { positive.push(request[1]); positive }
+                    0x22 => {
+                        let did = (request[1] as u16) << 8 | request[2] as u16;
+                        match dids.get(&did) {
+                            Some(data) => { positive.extend_from_slice(&request[1..3]); positive.extend_from_slice(data); positive }
+                            None => negative(0x31),
+                        }
+                    }
+                    0x2E if !unlocked => negative(0x33),
+                    0x2E => {
+                        let did = (request[1] as u16) << 8 | request[2] as u16;
+                        dids.insert(did, request[3..].to_vec());
+                        positive.extend_from_slice(&request[1..3]);
+                        positive
+                    }
+                    0x27 if request[1] == 0x01 => {
+                        positive.push(0x01);
+                        positive.extend_from_slice(if unlocked { &[0, 0] } else { &SEED });
+                        positive
+                    }
+                    0x27 if request[1] == 0x02 && request[2..] == key_of(&SEED)[..] => {
+                        unlocked = true;
+                        positive.push(0x02);
+                        positive
+                    }
+                    0x27 => negative(0x35),
+                    0x31 => {
+                        // takes a while: reply "response pending" twice
+                        for _ in 0..2 {
+                            isotp.send(&mut bus, &negative(NRC_RESPONSE_PENDING)).unwrap();
+                        }
+                        positive.extend_from_slice(&request[1..4]);
+                        positive.push(0x00); // routine status ok
+                        positive
+                    }
+                    _ => negative(0x11),
+                };
+                isotp.send(&mut bus, &response).unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn test_uds_client() {
+        let (mut bus, ecu) = LoopbackBus::pair();
+        spawn_ecu(ecu);
+        let mut uds = UdsClient::new(0x7E0, 0x7E8);
+        assert_eq!(uds.session_control(&mut bus, 0x03), Ok(Response::Positive(vec![0x00, 0x32, 0x01, 0xF4])));
+        assert_eq!(uds.read_did(&mut bus, 0xF190), Ok(Response::Positive(b"WVWZZZ1JZXW000001".to_vec())));
+        assert_eq!(uds.request(&mut bus, &[0x22, 0x12, 0x34]), Ok(Response::Negative(0x31)));
+        assert_eq!(uds.write_did(&mut bus, 0x0100, &[1, 2]), Ok(Response::Negative(0x33))); // locked
+        assert!(uds.security_access(&mut bus, 1, None).is_err()); // no key
+        for level in [0, 2, 0x7F, 0xFF] {
+            assert_eq!(uds.security_access(&mut bus, level, None), Err(format!("Invalid security access level: {}", level)));
+        }
+        assert_eq!(uds.security_access(&mut bus, 1, Some(&[0, 0])), Ok(Response::Negative(0x35))); // invalid key
+        uds.key_fn = Some(Box::new(|_, seed| key_of(seed)));
+        assert_eq!(uds.security_access(&mut bus, 1, None), Ok(Response::Positive(vec![])));
+        assert_eq!(uds.security_access(&mut bus, 1, None), Ok(Response::Positive(vec![]))); // already unlocked
+        assert_eq!(uds.write_did(&mut bus, 0x0100, &[1, 2]), Ok(Response::Positive(vec![])));
+        assert_eq!(uds.read_did(&mut bus, 0x0100), Ok(Response::Positive(vec![1, 2])));
+        assert_eq!(uds.routine_control(&mut bus, 1, 0x0203, &[]), Ok(Response::Positive(vec![0x00]))); // pending x2
+        uds.max_pending = 1;
+        assert!(uds.routine_control(&mut bus, 1, 0x0203, &[]).is_err());
+        assert_eq!(uds.isotp.recv(&mut bus), Ok(vec![0x71, 0x01, 0x02, 0x03, 0x00])); // the late response
+        uds.max_pending = 20;
+        assert_eq!(uds.ecu_reset(&mut bus, 0x01), Ok(Response::Positive(vec![])));
+        assert_eq!(uds.request(&mut bus, &[0x19, 0x02]), Ok(Response::Negative(0x11)));
+    }
+
+    #[test]
+    fn test_exec_diag() {
+        let engine = {
+            let mut eol = FnDef::new("eol");
+            let mut args = VarBindingList::new();
+            args.set_binding("session", "3");
+            eol.add_stmt(Stmt::new_diag("DiagnosticSessionControl", args));
+            let mut args = VarBindingList::new();
+            args.set_binding("level", "1");
+            args.set_binding("key", "hex:ED CB");
+            eol.add_stmt(Stmt::new_diag("SecurityAccess", args));
+            eol.add_stmt(Stmt::new_set_var("serial", "=", "hex:00 00 12 34"));
+            let mut args = VarBindingList::new();
+            args.set_binding("did", "0xF18C");
+            args.set_binding("data", "var:serial");
+            eol.add_stmt(Stmt::new_diag("WriteDataByIdentifier", args));
+            let mut args = VarBindingList::new();
+            args.set_binding("did", "0xF18C");
+            args.set_binding("result", "serial");
+            eol.add_stmt(Stmt::new_diag("ReadDataByIdentifier", args));
+            let mut args = VarBindingList::new();
+            args.set_binding("routine", "0x0203");
+            eol.add_stmt(Stmt::new_diag("RoutineControl", args));
+
+            let mut bad = FnDef::new("bad");
+            let mut args = VarBindingList::new();
+            args.set_binding("did", "0x1234");
+            bad.add_stmt(Stmt::new_diag("ReadDataByIdentifier", args));
+
+            let mut engine = Engine::new();
+            engine.add_fn(eol);
+            engine.add_fn(bad);
+            engine
+        };
+        let mut context = Context::new();
+        assert!(engine.exec_fn("eol", &VarBindingList::new(), &mut context).is_err()); // no UDS client
+
+        let (bus, ecu) = LoopbackBus::pair();
+        spawn_ecu(ecu);
+        context.bus = Some(Box::new(bus));
+        context.uds = Some(UdsClient::new(0x7E0, 0x7E8));
+        engine.exec_fn("eol", &VarBindingList::new(), &mut context).expect("ok");
+        assert_eq!(context.globals.raw_value_of("serial"), Some("hex:00 00 12 34"));
+        assert_eq!(context.globals.raw_value_of("$diag"), Some("hex:00")); // routine status
+        assert_eq!(context.globals.raw_value_of("$nrc"), None);
+
+        assert!(engine.exec_fn("bad", &VarBindingList::new(), &mut context).is_err());
+        assert_eq!(context.globals.raw_value_of("$nrc"), Some("int:49"));
+        assert_eq!(context.globals.raw_value_of("$diag"), None);
+    }
+}
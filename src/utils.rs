@@ -9,9 +9,62 @@ pub fn split_lr<'a>(source: &'a str, sep: &str) -> (&'a str, &'a str) {
     }
 }
 
+/// 字节数组转为十六进制文本，如[0x1A, 0xFF] -> "1A FF"
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(" ")
+}
+
+/// 解析十六进制文本，如"1A FF"或"1AFF"；可以带'hex:'前缀
+pub fn parse_hex(text: &str) -> Result<Vec<u8>, String> {
+    let text = text.strip_prefix("hex:").unwrap_or(text);
+    let digits: Vec<char> = text.chars().filter(|c| !c.is_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err(format!("Invalid hex: {}", text));
+    }
+    digits.chunks(2).map(|pair| {
+        let byte: String = pair.iter().collect();
+        u8::from_str_radix(&byte, 16).map_err(|_| format!("Invalid hex: {}", text))
+    }).collect()
+}
+
+/// 解析整数，支持'0x'十六进制和'int:'前缀，如"10", "0x1F", "int:31"
+pub fn parse_int(text: &str) -> Result<u64, String> {
+    let (_, value) = split_lr(text.trim(), "int:");
+    let result = match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    result.map_err(|_| format!("Invalid integer: {}", text))
+}
+
+/// 转义文本中的\ tab 换行（为\\ \t \n \r），用于以tab分隔字段的文件（如跟踪文件）
+pub fn escape_field(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n").replace('\r', "\\r")
+}
+
+/// escape_field()的逆操作
+pub fn unescape_field(text: &str) -> String {
+    let mut result = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => result.push('\t'),
+            Some('n') => result.push('\n'),
+            Some('r') => result.push('\r'),
+            Some(c) => result.push(c),
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
 #[cfg(test)]
 mod tests {
-    use utils::split_lr;
+    use utils::{split_lr, to_hex, parse_hex, parse_int, escape_field, unescape_field};
 
     #[test]
     fn test_split_lr() {
@@ -28,4 +81,27 @@ mod tests {
         let (l,r) = split_lr("str : text", ":");
         assert!(l == "str " && r == " text");
     }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(to_hex(&[0x1A, 0xFF, 0x00]), "1A FF 00");
+        assert_eq!(to_hex(&[]), "");
+        assert_eq!(parse_hex("1A FF 00"), Ok(vec![0x1A, 0xFF, 0x00]));
+        assert_eq!(parse_hex("hex:1aff"), Ok(vec![0x1A, 0xFF]));
+        assert_eq!(parse_hex(""), Ok(vec![]));
+        assert!(parse_hex("1A F").is_err());
+        assert!(parse_hex("XY").is_err());
+        assert_eq!(parse_int("10"), Ok(10));
+        assert_eq!(parse_int("0xF190"), Ok(0xF190));
+        assert_eq!(parse_int("int:31"), Ok(31));
+        assert!(parse_int("abc").is_err());
+    }
+
+    #[test]
+    fn test_escape_field() {
+        let text = "a\tb\\n\nc\r";
+        assert_eq!(escape_field(text), "a\\tb\\\\n\\nc\\r");
+        assert_eq!(unescape_field(&escape_field(text)), text);
+        assert_eq!(unescape_field("x\\"), "x\\");
+    }
 }
@@ -0,0 +1,332 @@
+// 执行前的静态检查，见Engine::validate()
+
+use engine::{Engine, Context};
+use function::FnDef;
+use statement::{Stmt, StmtKind, SourcePos};
+use variable::{VarDefList, VarBindingList};
+use signal::Mux;
+use utils::{split_lr, parse_int};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// 执行时一定会出错或结果不正确
+    Error,
+    /// 可能有问题
+    Warning,
+}
+
+/// 静态检查发现的问题
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// 函数名称
+    pub fn_name: String,
+    /// 语句在函数中的索引（从0开始）
+    pub index: usize,
+    /// 语句在脚本中的位置（如果有）
+    pub pos: Option<SourcePos>,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}: fn {} #{}", severity, self.fn_name, self.index)?;
+        if let Some(pos) = self.pos {
+            write!(f, " (line {}, col {})", pos.line, pos.col)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+const ASSIGN_OPS: &[&str] = &["=", ":=", "+=", "-=", "*=", "/="];
+const OPS: &[&str] = &["+", "-", "*", "/"];
+
+struct Validator<'a> {
+    engine: &'a Engine,
+    // 发送指令使用的传输方式，决定数据帧的最大长度
+    context: &'a Context,
+    fndef: &'a FnDef,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> Validator<'a> {
+    fn report(&mut self, severity: Severity, index: usize, message: String) {
+        self.diagnostics.push(Diagnostic {
+            severity,
+            fn_name: self.fndef.name.clone(),
+            index,
+            pos: self.fndef.stmts[index].pos,
+            message,
+        });
+    }
+
+    fn validate(&mut self) {
+        let mut loops: Vec<usize> = Vec::new();
+        // 在此深度的代码块中，return之后的语句不可达；只报告第一条
+        let mut returned: Option<usize> = None;
+        for (index, stmt) in self.fndef.stmts.iter().enumerate() {
+            if let StmtKind::EndLoop = stmt.kind {
+                if loops.pop().is_none() {
+                    self.report(Severity::Error, index, "Unpaired endloop".to_string());
+                }
+                if returned.is_some_and(|depth| depth > loops.len()) {
+                    returned = None;
+                }
+                continue;
+            }
+            if returned.take().is_some() {
+                self.report(Severity::Warning, index, "Unreachable statement after return".to_string());
+            }
+            match stmt.kind {
+                StmtKind::Loop => {
+                    let count = stmt.args.raw_value_of("$count").unwrap_or("");
+                    if parse_int(count).map_or(true, |count| count > u32::MAX as u64) {
+                        self.report(Severity::Error, index, format!("Invalid loop count: {}", count));
+                    }
+                    loops.push(index);
+                }
+                StmtKind::Return => returned = Some(loops.len()),
+                StmtKind::CallIns => self.check_call_ins(index, stmt),
+                StmtKind::CallFn => self.check_call_fn(index, stmt),
+                StmtKind::SetVar => self.check_set_var(index, stmt),
+                StmtKind::SetLocal | StmtKind::SetGlobal if split_lr(&stmt.content, "=").0.is_empty() => {
+                    self.report(Severity::Error, index, format!("Invalid variable definition: {}", stmt.content));
+                }
+                _ => {}
+            }
+        }
+        for index in loops {
+            self.report(Severity::Error, index, "Unclosed loop".to_string());
+        }
+    }
+
+    fn check_call_ins(&mut self, index: usize, stmt: &Stmt) {
+        let insdef = match self.engine.find_ins(&stmt.content) {
+            Some(insdef) => insdef,
+            None => return self.report(Severity::Error, index, format!("No such ins: {}", stmt.content)),
+        };
+        if let Err(err) = insdef.check_layout() {
+            self.report(Severity::Error, index, format!("Invalid layout of ins {}: {}", insdef.name, err));
+        }
+        let max_len = self.context.max_frame_len(insdef.extended);
+        if insdef.dlc as usize > max_len {
+            self.report(Severity::Error, index, format!("Ins {} takes {} bytes, the bus sends at most {}", insdef.name, insdef.dlc, max_len));
+        }
+        self.check_args(index, &stmt.args, &insdef.args, "ins", &insdef.name);
+        for vardef in &insdef.args.defs {
+            let bound = stmt.args.contains(&vardef.name);
+            if vardef.computed.is_some() && bound {
+                self.report(Severity::Error, index, format!("Computed arg cannot be bound: {}", vardef.name));
+            }
+            // 多路复用的参数只在被选中时需要
+            let optional = vardef.computed.is_some() || !vardef.default.is_empty() || matches!(vardef.mux, Some(Mux::Multiplexed(_)));
+            if !bound && !optional {
+                self.report(Severity::Error, index, format!("Missing arg {} for ins {}", vardef.name, insdef.name));
+            }
+        }
+    }
+
+    fn check_call_fn(&mut self, index: usize, stmt: &Stmt) {
+        let fndef = match self.engine.find_fn(&stmt.content) {
+            Ok(Some(fndef)) => fndef,
+            Ok(None) => return self.report(Severity::Error, index, format!("No such fn: {}", stmt.content)),
+            Err(err) => return self.report(Severity::Error, index, err),
+        };
+        self.check_args(index, &stmt.args, &fndef.args, "fn", &fndef.name);
+        for vardef in &fndef.args.defs {
+            if !stmt.args.contains(&vardef.name) {
+                // 函数参数没有默认值，未传入的参数在函数内是未定义的变量
+                self.report(Severity::Warning, index, format!("Missing arg {} for fn {}", vardef.name, fndef.name));
+            }
+        }
+    }
+
+    // 检查传入的参数名称和值的类型（变量的类型在执行时才能确定，不检查）
+    fn check_args(&mut self, index: usize, args: &VarBindingList, params: &VarDefList, kind: &str, name: &str) {
+        let mut names: Vec<&String> = args.bindings.keys().collect();
+        names.sort();
+        for arg in names {
+            let vardef = match params.find(arg) {
+                Some(vardef) => vardef,
+                None => {
+                    self.report(Severity::Error, index, format!("Unknown arg {} for {} {}", arg, kind, name));
+                    continue;
+                }
+            };
+            let value = args.raw_value_of(arg).unwrap_or("");
+            if !value_matches(value, &vardef.typ) {
+                self.report(Severity::Error, index, format!("Arg {} of {} {} requires {}, found {}", arg, kind, name, vardef.typ, value));
+            }
+        }
+    }
+
+    fn check_set_var(&mut self, index: usize, stmt: &Stmt) {
+        let args = &stmt.args;
+        for name in ["$varname", "$op1", "$operand1"] {
+            if !args.contains(name) {
+                self.report(Severity::Error, index, format!("Set var requires arg: {}", name));
+            }
+        }
+        if let Some(op1) = args.raw_value_of("$op1") {
+            if !ASSIGN_OPS.contains(&op1) {
+                self.report(Severity::Error, index, format!("Unsupport set var op: {}", op1));
+            }
+        }
+        match (args.raw_value_of("$op2"), args.contains("$operand2")) {
+            (Some(op2), true) if !OPS.contains(&op2) => {
+                self.report(Severity::Error, index, format!("Unsupport set var op: {}", op2));
+            }
+            (Some(_), true) | (None, false) => {}
+            _ => self.report(Severity::Error, index, "Both $op2 and $operand2 are requried".to_string()),
+        }
+    }
+}
+
+// 值是否可以作为typ类型的参数；变量总是可以，没有前缀的文本只检查整数类型
+fn value_matches(value: &str, typ: &str) -> bool {
+    let (prefix, text) = split_lr(value, ":");
+    let int = matches!(typ, "byte" | "i8" | "u8" | "i16" | "u16" | "i32" | "u32" | "i64" | "u64");
+    match prefix {
+        "int" => (int || typ == "f64") && parse_int(text.trim_start_matches('-')).is_ok(),
+        "float" => typ == "f64",
+        "str" => typ == "str",
+        "hex" => typ == "hex",
+        "var" => true,
+        _ => !int || parse_int(value).is_ok(),
+    }
+}
+
+/// 检查函数，返回发现的全部问题（按语句顺序）；指令数据帧的长度按context的传输方式检查
+pub fn validate_fn(fndef: &FnDef, engine: &Engine, context: &Context) -> Vec<Diagnostic> {
+    let mut validator = Validator { engine, context, fndef, diagnostics: Vec::new() };
+    validator.validate();
+    validator.diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use validate::{validate_fn, Diagnostic, Severity};
+    use engine::{Engine, Context};
+    use isotp::IsoTp;
+    use function::FnDef;
+    use statement::{Stmt, SourcePos};
+    use variable::VarBindingList;
+
+    const SCRIPT: &str = r#"
+ins lamp(a: u32, b: u32 = 1) = 0x321
+ins text(s: str) = 0x322
+ins short(a: u32) = 0x323
+
+fn main(n: i32) {
+    send lamp(a: 1, b: n)
+    send lamp(b: 2, c: 3)
+    send lamp(a: "x")
+    send none()
+    call helper(x: "a")
+    call missing()
+    loop 2 {
+        return
+        x = 1
+        loop 1 {
+        }
+    }
+    y = 2
+    return n
+    z = 3
+    w = 4
+}
+
+fn layout() {
+    send text(s: "a")
+    send short(a: 1)
+    send long(a: 1, b: 2)
+}
+
+fn helper(x: str, y: i32) {
+    loop 3 {
+        return x
+    }
+}
+
+ins long(a: u32, b: u32) = 0x324 dlc 20
+"#;
+
+    fn engine() -> Engine {
+        let mut engine = Engine::new();
+        engine.load_script(SCRIPT, &mut Context::new()).expect("ok");
+        engine
+    }
+
+    #[test]
+    fn test_validate() {
+        let engine = engine();
+        let mut context = Context::new();
+        let diagnostics: Vec<String> = engine.validate(&context).iter().map(|diagnostic| diagnostic.to_string()).collect();
+        assert_eq!(diagnostics, vec![
+            "error: fn layout #0 (line 26, col 5): Invalid layout of ins text: Unsupport arg type: str",
+            "error: fn layout #1 (line 27, col 5): Invalid layout of ins short: Args take 4 bytes, requires 8",
+            "error: fn layout #2 (line 28, col 5): Ins long takes 20 bytes, the bus sends at most 8",
+            "error: fn main #1 (line 8, col 5): Unknown arg c for ins lamp",
+            "error: fn main #1 (line 8, col 5): Missing arg a for ins lamp",
+            "error: fn main #2 (line 9, col 5): Arg a of ins lamp requires u32, found str:x",
+            "error: fn main #3 (line 10, col 5): No such ins: none",
+            "warning: fn main #4 (line 11, col 5): Missing arg y for fn helper",
+            "error: fn main #5 (line 12, col 5): No such fn: missing",
+            "warning: fn main #8 (line 15, col 9): Unreachable statement after return",
+            "warning: fn main #14 (line 21, col 5): Unreachable statement after return",
+        ]);
+        // ISO-TP分段发送超过8字节的数据帧
+        context.isotp = Some(IsoTp::new(0x7E0, 0x7E8));
+        assert_eq!(engine.validate(&context).len(), diagnostics.len() - 1);
+    }
+
+    #[test]
+    fn test_validate_stmts() {
+        let engine = engine();
+        let mut main = FnDef::new("main");
+        main.add_stmt(Stmt::new_end_loop());
+        main.add_stmt(Stmt::new_loop(2));
+        main.add_stmt(Stmt::new_set_var_ex("x", "=", "int:1", "%", "int:2"));
+        let mut stmt = Stmt::new_set_var("x", "=", "int:1");
+        stmt.args.remove_binding("$varname");
+        stmt.args.set_binding("$op2", "+");
+        main.add_stmt(stmt);
+        main.add_stmt(Stmt::new_set_var("x", "=>", "int:1"));
+        main.add_stmt(Stmt::new_set_local("=1"));
+        let mut args = VarBindingList::new();
+        args.set_binding("a", "0x10"); // 没有前缀的值在执行时解析
+        args.set_binding("b", "abc");
+        main.add_stmt(Stmt::new_call_ins("lamp", args));
+        let mut stmt = Stmt::new_loop(1);
+        stmt.args.set_binding("$count", "many");
+        stmt.pos = Some(SourcePos::new(3, 4));
+        main.add_stmt(stmt);
+        main.add_stmt(Stmt::new_end_loop());
+
+        let diagnostic = |index: usize, message: &str| Diagnostic {
+            severity: Severity::Error,
+            fn_name: "main".to_string(),
+            index,
+            pos: if index == 7 { Some(SourcePos::new(3, 4)) } else { None },
+            message: message.to_string(),
+        };
+        let context = Context::new();
+        assert_eq!(validate_fn(&main, &engine, &context), vec![
+            diagnostic(0, "Unpaired endloop"),
+            diagnostic(2, "Unsupport set var op: %"),
+            diagnostic(3, "Set var requires arg: $varname"),
+            diagnostic(3, "Both $op2 and $operand2 are requried"),
+            diagnostic(4, "Unsupport set var op: =>"),
+            diagnostic(5, "Invalid variable definition: =1"),
+            diagnostic(6, "Arg b of ins lamp requires u32, found abc"),
+            diagnostic(7, "Invalid loop count: many"),
+            diagnostic(1, "Unclosed loop"),
+        ]);
+        assert_eq!(validate_fn(&main, &engine, &context)[7].to_string(), "error: fn main #7 (line 3, col 4): Invalid loop count: many");
+    }
+}
@@ -1,9 +1,15 @@
 use std::collections::HashMap;
 use std::collections::hash_map::Entry;
+#[cfg(feature = "serde")]
+use std::collections::BTreeMap;
 use utils::split_lr;
+use signal::{SignalLayout, Mux};
+use computed::Computed;
 
 /// 变量定义（声明）
 #[derive(Default, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct VarDef {
     pub name: String,
     /// i8,u8,i16,u16,i32,u32,f64,f64,str,hex
@@ -12,9 +18,20 @@ pub struct VarDef {
     pub range: String,
     /// default value if not binded
     pub default: String,
+    /// 注释
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub note: Option<String>,
+    /// 信号布局；如果有，指令按位编码此参数（而不是依次按字节编码）
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub signal: Option<SignalLayout>,
+    /// 多路复用；仅适用于带有信号布局的参数
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub mux: Option<Mux>,
+    /// 由指令自动计算的值（如滚动计数器、校验和）
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub computed: Option<Computed>,
 
     // todo:
-    // pub note: String,
     // pub props: String,
 }
 
@@ -26,14 +43,42 @@ impl VarDef {
             .. Default::default()
         }
     }
+
+    /// 解析range，返回闭区间(min, max)；range为空或无效时返回None
+    /// 'a...z'包含z；整数类型的'a..z'不包含z，即闭区间为(a, z-1)
+    pub fn range_bounds(&self) -> Option<(f64, f64)> {
+        let (inclusive, (l, r)) = if self.range.contains("...") {
+            (true, split_lr(&self.range, "..."))
+        } else if self.range.contains("..") {
+            (false, split_lr(&self.range, ".."))
+        } else {
+            return None;
+        };
+        let min: f64 = l.trim().parse().ok()?;
+        let max: f64 = r.trim().parse().ok()?;
+        let integer = self.typ.starts_with('i') || self.typ.starts_with('u') || self.typ == "byte";
+        if !inclusive && integer {
+            Some((min, max - 1.0))
+        } else {
+            Some((min, max))
+        }
+    }
 }
 
-// 变量定义列表
+// 变量定义列表，序列化为数组
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
 pub struct VarDefList {
    pub defs: Vec<VarDef>, // 保持定义的顺序始终不变
 }
 
+impl Default for VarDefList {
+    fn default() -> VarDefList {
+        VarDefList::new()
+    }
+}
+
 impl VarDefList {
     pub fn new() -> VarDefList {
         VarDefList {
@@ -69,7 +114,7 @@ pub struct VarBinding {
 }
 
 // 变量值绑定列表
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct VarBindingList {
     pub bindings: HashMap<String, VarBinding>,
 }
@@ -182,6 +227,35 @@ impl VarBindingList {
     }
 }
 
+impl Default for VarBindingList {
+    fn default() -> VarBindingList {
+        VarBindingList::new()
+    }
+}
+
+// 序列化为按名称排序的对象{"name": "value", ...}，保证输出稳定
+#[cfg(feature = "serde")]
+impl ::serde::Serialize for VarBindingList {
+    fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let sorted: BTreeMap<&str, &str> = self.bindings.values()
+            .map(|binding| (binding.name.as_str(), binding.value.as_str()))
+            .collect();
+        ::serde::Serialize::serialize(&sorted, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> ::serde::Deserialize<'de> for VarBindingList {
+    fn deserialize<D: ::serde::Deserializer<'de>>(deserializer: D) -> Result<VarBindingList, D::Error> {
+        let map: BTreeMap<String, String> = ::serde::Deserialize::deserialize(deserializer)?;
+        let mut list = VarBindingList::new();
+        for (name, value) in map {
+            list.add(VarBinding::new(name, value));
+        }
+        Ok(list)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::{VarDef, VarDefList, VarBinding, VarBindingList};
@@ -203,6 +277,21 @@ mod tests {
         assert!(vars2.find("b").unwrap().typ  == "u8");
     }
 
+    #[test]
+    fn test_range_bounds() {
+        let mut var = VarDef::new("a", "u8");
+        assert_eq!(var.range_bounds(), None);
+        var.range = "0...255".to_string();
+        assert_eq!(var.range_bounds(), Some((0.0, 255.0)));
+        var.range = "0..256".to_string();
+        assert_eq!(var.range_bounds(), Some((0.0, 255.0)));
+        var.typ = "f64".to_string();
+        var.range = "-1.5..1.5".to_string();
+        assert_eq!(var.range_bounds(), Some((-1.5, 1.5)));
+        var.range = "a..z".to_string();
+        assert_eq!(var.range_bounds(), None);
+    }
+
     #[test]
     fn test_bindings() {
         let binding = VarBinding::new("a", "123");
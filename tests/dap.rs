@@ -0,0 +1,236 @@
+// 用脚本化的DAP客户端测试logic dap：cargo test --features dap
+#![cfg(feature = "dap")]
+
+#[macro_use]
+extern crate serde_json;
+
+use serde_json::Value;
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+// 行号在测试中使用
+const SCRIPT: &str = r#"ins lamp(a: u32, b: u32 = 1) = 0x321
+
+fn main(n: i32) {
+    total := 0
+    loop 2 {
+        call blink(n: n)
+        n += 1
+    }
+    return total
+}
+
+fn blink(n: i32) {
+    send lamp(a: n)
+    total := total + n
+}
+"#;
+
+struct Client {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    seq: u64,
+    // 尚未被wait_event取走的事件
+    events: Vec<Value>,
+}
+
+impl Client {
+    fn start() -> Client {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_logic"))
+            .arg("dap")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("start logic dap");
+        let stdin = child.stdin.take().expect("stdin");
+        let stdout = BufReader::new(child.stdout.take().expect("stdout"));
+        Client { child, stdin, stdout, seq: 0, events: Vec::new() }
+    }
+
+    fn read(&mut self) -> Value {
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            assert!(self.stdout.read_line(&mut line).expect("read") > 0, "unexpected end of output");
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(value) = line.strip_prefix("Content-Length: ") {
+                length = value.parse().expect("length");
+            }
+        }
+        let mut body = vec![0; length];
+        self.stdout.read_exact(&mut body).expect("body");
+        serde_json::from_slice(&body).expect("json")
+    }
+
+    // 发送请求并返回响应，期间收到的事件保存在events中
+    fn request(&mut self, command: &str, arguments: Value) -> Value {
+        self.seq += 1;
+        let body = json!({ "seq": self.seq, "type": "request", "command": command, "arguments": arguments }).to_string();
+        write!(self.stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body).expect("write");
+        self.stdin.flush().expect("flush");
+        loop {
+            let message = self.read();
+            if message["type"] == "response" && message["request_seq"] == json!(self.seq) {
+                assert_eq!(message["command"], command);
+                return message;
+            }
+            self.events.push(message);
+        }
+    }
+
+    fn wait_event(&mut self, event: &str) -> Value {
+        loop {
+            if let Some(index) = self.events.iter().position(|message| message["event"] == event) {
+                return self.events.remove(index);
+            }
+            let message = self.read();
+            self.events.push(message);
+        }
+    }
+
+    // 取走已收到的output事件的文本
+    fn output(&mut self) -> String {
+        let (output, others): (Vec<Value>, Vec<Value>) = self.events.drain(..).partition(|message| message["event"] == "output");
+        self.events = others;
+        output.iter().map(|message| message["body"]["output"].as_str().unwrap_or("").to_string()).collect()
+    }
+
+    fn top(&mut self) -> (String, u64, u64) {
+        let trace = self.request("stackTrace", json!({ "threadId": 1 }));
+        let frame = &trace["body"]["stackFrames"][0];
+        (frame["name"].as_str().expect("name").to_string(), frame["line"].as_u64().expect("line"), frame["id"].as_u64().expect("id"))
+    }
+
+    fn variables(&mut self, reference: &Value) -> Vec<(String, String)> {
+        let response = self.request("variables", json!({ "variablesReference": reference }));
+        response["body"]["variables"].as_array().expect("variables").iter()
+            .map(|variable| (variable["name"].as_str().expect("name").to_string(), variable["value"].as_str().expect("value").to_string()))
+            .collect()
+    }
+
+    fn finish(mut self) {
+        let response = self.request("disconnect", json!({}));
+        assert_eq!(response["success"], true);
+        drop(self.stdin);
+        assert!(self.child.wait().expect("exit").success());
+    }
+}
+
+fn script_file(name: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("logic-dap-{}-{}.logic", std::process::id(), name));
+    fs::write(&path, SCRIPT).expect("write script");
+    path
+}
+
+fn pair(name: &str, value: &str) -> (String, String) {
+    (name.to_string(), value.to_string())
+}
+
+#[test]
+fn test_debug_session() {
+    let script = script_file("session");
+    let path = script.to_str().expect("utf8");
+    let mut client = Client::start();
+
+    let response = client.request("initialize", json!({ "adapterID": "logic", "linesStartAt1": true }));
+    assert_eq!(response["body"]["supportsConfigurationDoneRequest"], true);
+    let response = client.request("launch", json!({ "program": path, "function": "main", "args": { "n": 1 } }));
+    assert_eq!(response["success"], true);
+    client.wait_event("initialized");
+
+    // 第一个断点带条件；第三个断点之后没有语句，无法设置
+    let response = client.request("setBreakpoints", json!({
+        "source": { "path": path },
+        "breakpoints": [{ "line": 13, "condition": "n == 2" }, { "line": 9 }, { "line": 16 }],
+    }));
+    let breakpoints = &response["body"]["breakpoints"];
+    assert_eq!((&breakpoints[0]["verified"], &breakpoints[0]["line"]), (&json!(true), &json!(13)));
+    assert_eq!((&breakpoints[1]["verified"], &breakpoints[1]["line"]), (&json!(true), &json!(9)));
+    assert_eq!(breakpoints[2]["verified"], false);
+
+    client.request("configurationDone", json!({}));
+    let stopped = client.wait_event("stopped");
+    assert_eq!(stopped["body"]["reason"], "breakpoint");
+    assert_eq!(stopped["body"]["hitBreakpointIds"], json!([breakpoints[0]["id"]]));
+    assert!(client.output().contains("  can0  321   [8]  00 00 00 01 00 00 00 01\n"));
+
+    // 调用栈和变量
+    let trace = client.request("stackTrace", json!({ "threadId": 1 }));
+    let frames = trace["body"]["stackFrames"].as_array().expect("frames").clone();
+    let names: Vec<(&str, u64)> = frames.iter().map(|frame| (frame["name"].as_str().expect("name"), frame["line"].as_u64().expect("line"))).collect();
+    assert_eq!(names, vec![("blink", 13), ("main", 6)]);
+    assert_eq!(frames[0]["source"]["path"], json!(fs::canonicalize(&script).expect("path").to_str()));
+    let scopes = client.request("scopes", json!({ "frameId": frames[0]["id"] }));
+    let scopes = scopes["body"]["scopes"].as_array().expect("scopes").clone();
+    let names: Vec<&str> = scopes.iter().map(|scope| scope["name"].as_str().expect("name")).collect();
+    assert_eq!(names, vec!["Arguments", "Locals", "Globals"]);
+    assert_eq!(client.variables(&scopes[0]["variablesReference"]), vec![pair("n", "2")]);
+    assert_eq!(client.variables(&scopes[2]["variablesReference"]), vec![pair("total", "1")]);
+    let scopes = client.request("scopes", json!({ "frameId": frames[1]["id"] }));
+    assert_eq!(client.variables(&scopes["body"]["scopes"][1]["variablesReference"]), vec![pair("n", "2")]);
+
+    // 修改变量和求值
+    let response = client.request("setVariable", json!({ "variablesReference": scopes["body"]["scopes"][2]["variablesReference"], "name": "total", "value": "10" }));
+    assert_eq!(response["body"]["value"], "10");
+    let response = client.request("evaluate", json!({ "expression": "n", "frameId": frames[0]["id"] }));
+    assert_eq!(response["body"]["result"], "2");
+    let response = client.request("evaluate", json!({ "expression": "missing" }));
+    assert_eq!((&response["success"], &response["message"]), (&json!(false), &json!("Undefined: missing")));
+
+    // 单步
+    client.request("next", json!({ "threadId": 1 }));
+    assert_eq!(client.wait_event("stopped")["body"]["reason"], "step");
+    assert_eq!(client.top().0.as_str(), "blink");
+    assert_eq!(client.top().1, 14);
+    client.request("stepOut", json!({ "threadId": 1 }));
+    client.wait_event("stopped");
+    assert_eq!((client.top().0.as_str(), client.top().1), ("main", 7));
+    client.request("continue", json!({ "threadId": 1 }));
+    assert_eq!(client.wait_event("stopped")["body"]["reason"], "breakpoint");
+    assert_eq!(client.top().1, 9);
+    let response = client.request("evaluate", json!({ "expression": "total" }));
+    assert_eq!(response["body"]["result"], "12");
+
+    client.request("continue", json!({ "threadId": 1 }));
+    assert_eq!(client.wait_event("exited")["body"]["exitCode"], 0);
+    client.wait_event("terminated");
+    assert!(client.output().contains("return: 12\n"));
+    client.finish();
+    let _ = fs::remove_file(script);
+}
+
+#[test]
+fn test_stop_on_entry_and_errors() {
+    let script = script_file("entry");
+    let path = script.to_str().expect("utf8");
+    let mut client = Client::start();
+    client.request("initialize", json!({}));
+    let response = client.request("stackTrace", json!({ "threadId": 1 }));
+    assert_eq!(response["success"], false);
+    let response = client.request("launch", json!({ "program": path, "function": "none" }));
+    assert_eq!((&response["success"], &response["message"]), (&json!(false), &json!("No such fn: none")));
+
+    // configurationDone可以在launch之前
+    client.request("configurationDone", json!({}));
+    let response = client.request("launch", json!({ "program": [path], "function": "blink", "args": { "n": "0x10" }, "stopOnEntry": true }));
+    assert_eq!(response["success"], true);
+    assert_eq!(client.wait_event("stopped")["body"]["reason"], "entry");
+    assert_eq!(client.top().1, 13);
+    client.request("stepIn", json!({ "threadId": 1 }));
+    client.wait_event("stopped");
+    assert!(client.output().contains("  can0  321   [8]  00 00 00 10 00 00 00 01\n"));
+    // 执行最后一条语句后结束
+    client.request("stepIn", json!({ "threadId": 1 }));
+    assert_eq!(client.wait_event("exited")["body"]["exitCode"], 0);
+    let response = client.request("pause", json!({ "threadId": 1 }));
+    assert_eq!((&response["success"], &response["message"]), (&json!(false), &json!("Unsupported command: pause")));
+    client.finish();
+    let _ = fs::remove_file(script);
+}